@@ -8,7 +8,7 @@ mod common;
 
 
 use std::thread;
-use common::{config::CLMGR_CONFIG_PATH, utils::Utils};
+use common::{config::CLMGR_CONFIG_PATH, types::{Backoff, ManagerEndpoint}, utils::Utils};
 use vcuda_client_handler::VCudaClientManager;
 
 use crate::resource_manager_handler::ResourceManagerHandler;
@@ -31,10 +31,10 @@ fn get_vcuda_process_monitor_period() -> u64 {
     }
 }
 
-fn get_resource_mgr_address() -> (String, u16) {
+fn get_resource_mgr_endpoints() -> (Vec<ManagerEndpoint>, Backoff) {
     let config = Utils::load_config_file(CLMGR_CONFIG_PATH);
 
-    (config["resource-manager"]["address"].as_str().unwrap().to_string(), config["resource-manager"]["port"].as_integer().unwrap() as u16)
+    (Utils::load_manager_endpoints(&config), Utils::load_reconnect_backoff(&config))
 }
 
 
@@ -42,11 +42,11 @@ fn main() {
 
     env_logger::init();
 
-    let (resource_manager_address, resource_manager_port) = get_resource_mgr_address();
+    let (resource_manager_endpoints, reconnect_backoff) = get_resource_mgr_endpoints();
     let mqueue_path = get_mqueue_path();
 
     let client_mgr = VCudaClientManager::new(&mqueue_path);
-    let res_mgr = ResourceManagerHandler::new(resource_manager_address, resource_manager_port, &client_mgr);
+    let res_mgr = ResourceManagerHandler::new(resource_manager_endpoints, reconnect_backoff, &client_mgr);
 
     thread::scope(|s| {
         s.spawn(|| {
@@ -62,6 +62,14 @@ fn main() {
         s.spawn(|| {
             client_mgr.listen_to_clients(|| res_mgr.get_virt_server(s));
         });
+
+        s.spawn(|| {
+            res_mgr.keep_lease_renewed();
+        });
+
+        s.spawn(|| {
+            client_mgr.listen_for_wake_requests(reconnect_backoff, || res_mgr.wake());
+        });
     });
 
     
@@ -1,38 +1,67 @@
-use std::{io::{BufRead, BufReader, Write}, net::TcpStream, sync::RwLock, thread};
-use crate::common::utils::StreamUtils;
+use std::{io::{BufRead, BufReader, Write}, net::TcpStream, sync::{Mutex, RwLock}, thread, time::{Duration, Instant}};
+use crate::common::types::{Backoff, ManagerEndpoint};
+use crate::common::utils::{StreamUtils, Utils};
 use crate::common::api_commands::FlytApiCommand;
 use crate::vcuda_client_handler::VCudaClientManager;
 
-#[derive(Debug, Clone)]
+/// Renewal period until the resource manager has told us the lease TTL
+const DEFAULT_LEASE_RENEW_PERIOD: Duration = Duration::from_secs(10);
+/// How often the lease thread checks for a new virt server, whose lease is renewed right away
+const LEASE_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VirtServer {
     pub address: String,
     pub rpc_id: u64,
 }
 
 pub struct ResourceManagerHandler<'b> {
-    server_ip: String,
-    server_port: u16,
+    /// Resource managers in the order they are tried
+    endpoints: Vec<ManagerEndpoint>,
+    /// Index of the resource manager that accepted last, tried first
+    active_endpoint: RwLock<usize>,
+    backoff: Backoff,
     virt_server: RwLock<Option<VirtServer>>,
+    /// Set while the resource manager holds the VM's GPU state on disk; `virt_server` is gone then
+    hibernated: RwLock<bool>,
+    /// Lets one wake up request through when several vCUDA clients make CUDA calls
+    wake_lock: Mutex<()>,
     client_mgr: &'b VCudaClientManager
 }
 
 
 impl <'b> ResourceManagerHandler <'b> {
-    pub fn new(server_ip: String, server_port: u16, client_mgr: &'b VCudaClientManager) -> ResourceManagerHandler {
+    pub fn new(endpoints: Vec<ManagerEndpoint>, backoff: Backoff, client_mgr: &'b VCudaClientManager) -> ResourceManagerHandler {
 
         ResourceManagerHandler {
-            server_ip: server_ip,
-            server_port: server_port,
+            endpoints,
+            active_endpoint: RwLock::new(0),
+            backoff,
             virt_server: RwLock::new(None),
+            hibernated: RwLock::new(false),
+            wake_lock: Mutex::new(()),
             client_mgr: client_mgr
         }
     }
 
+    fn connect(&self) -> Result<TcpStream, String> {
+        let first = *self.active_endpoint.read().unwrap();
+        let (stream, index) = Utils::connect_any(&self.endpoints, first)?;
+        if index != first {
+            log::info!("Switched to resource manager {}", self.endpoints[index]);
+        }
+        *self.active_endpoint.write().unwrap() = index;
+        Ok(stream)
+    }
+
     pub fn get_virt_server<'a>(&'a self, scope: &'a thread::Scope<'a,'_>) -> Option<VirtServer> {
+        if *self.hibernated.read().unwrap() && !self.wake() {
+            return None;
+        }
         if self.virt_server.read().unwrap().is_some() {
             return self.virt_server.read().unwrap().clone();
         }
-        let stream = TcpStream::connect(format!("{}:{}", self.server_ip, self.server_port));
+        let stream = self.connect();
         match stream {
             Ok(mut stream) => {
                 let stream_clone = match stream.try_clone() {
@@ -100,6 +129,66 @@ impl <'b> ResourceManagerHandler <'b> {
         }
     }
 
+    /// Registers the virt server this VM already uses, and its number of clients, with a resource manager
+    /// after the connection to the previous one was lost. Returns the virt server the resource manager
+    /// wants the VM to use, or `None` if it no longer exists.
+    fn register_virt_server(reader: &mut BufReader<TcpStream>, stream: &mut TcpStream, virt_server: &VirtServer, num_clients: usize) -> Result<Option<VirtServer>,String> {
+        // format: virt_server_address,rpc_id,num_clients
+        StreamUtils::write_all(stream, format!("{}\n{},{},{}\n", FlytApiCommand::CLIENTD_RMGR_RECONNECT, virt_server.address, virt_server.rpc_id, num_clients))
+            .map_err(|e| e.to_string())?;
+
+        let response = StreamUtils::read_response(reader, 2).map_err(|e| e.to_string())?;
+
+        match response[0].as_str() {
+            "200" => {
+                let server_details = response[1].split(",").collect::<Vec<&str>>();
+                let rpc_id = server_details.get(1).and_then(|rpc_id| rpc_id.parse::<u64>().ok());
+                match rpc_id {
+                    Some(rpc_id) => Ok(Some(VirtServer { address: server_details[0].to_string(), rpc_id })),
+                    None => Err(format!("Server details not in correct format: {}", response[1])),
+                }
+            }
+            "404" => Ok(None),
+            _ => Err(format!("{} {}", response[0], response[1])),
+        }
+    }
+
+    /// Reconnects to a resource manager with backoff and re-registers the current virt server.
+    /// Returns the new command stream, or `None` once there is no virt server left to keep.
+    fn reconnect(&self) -> Option<(BufReader<TcpStream>, TcpStream)> {
+        let mut backoff = self.backoff;
+        loop {
+            let virt_server = self.virt_server.read().unwrap().clone()?;
+
+            let registered = self.connect().and_then(|mut stream| {
+                let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+                let registered = ResourceManagerHandler::register_virt_server(&mut reader, &mut stream, &virt_server, self.client_mgr.num_active_clients())?;
+                Ok((registered, reader, stream))
+            });
+
+            match registered {
+                Ok((Some(registered), reader, stream)) => {
+                    log::info!("Re-registered virt server {:?} with resource manager {}", registered, self.endpoints[*self.active_endpoint.read().unwrap()]);
+                    if registered != virt_server {
+                        self.virt_server.write().unwrap().replace(registered.clone());
+                        self.client_mgr.change_virt_server(&registered);
+                    }
+                    return Some((reader, stream));
+                }
+                Ok((None, _, _)) => {
+                    log::warn!("Resource manager does not know virt server {:?}, clearing it", virt_server);
+                    self.virt_server.write().unwrap().take();
+                    return None;
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    log::error!("Error re-registering with resource manager: {}, retrying in {:?}", e, delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+
     fn change_virt_server(&self, response_str: String) {
         let server_details = response_str.split(",").collect::<Vec<&str>>();
         if server_details.len() != 2 {
@@ -113,7 +202,7 @@ impl <'b> ResourceManagerHandler <'b> {
     }
 
     pub fn notify_zero_clients(&self) -> bool {
-        let mut stream = match TcpStream::connect(format!("{}:{}", self.server_ip, self.server_port)) {
+        let mut stream = match self.connect() {
             Ok(stream) => {
                 stream
             }
@@ -143,6 +232,84 @@ impl <'b> ResourceManagerHandler <'b> {
     }
 
 
+    /// Asks the resource manager to restore the hibernated VM. It moves the vCUDA clients to the new
+    /// virt server and resumes them over the command stream before it answers.
+    pub fn wake(&self) -> bool {
+        let _wake_guard = self.wake_lock.lock().unwrap();
+        if !*self.hibernated.read().unwrap() {
+            return true;
+        }
+
+        let response = self.connect().and_then(|mut stream| {
+            StreamUtils::write_all(&mut stream, format!("{}\n", FlytApiCommand::CLIENTD_RMGR_WAKE)).map_err(|e| e.to_string())?;
+            let mut reader = BufReader::new(stream);
+            StreamUtils::read_response(&mut reader, 2).map_err(|e| e.to_string())
+        });
+
+        match response {
+            Ok(response) if response[0] == "200" => {
+                log::info!("Woke up on virt server {}", response[1]);
+                *self.hibernated.write().unwrap() = false;
+                true
+            }
+            Ok(response) => {
+                log::error!("Error waking up: {} {}", response[0], response[1]);
+                false
+            }
+            Err(e) => {
+                log::error!("Error waking up: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Returns the lease TTL, zero if leases do not expire, or `None` if the resource manager
+    /// holds no virt server for this VM.
+    fn renew_lease(&self) -> Result<Option<Duration>, String> {
+        let mut stream = self.connect()?;
+        StreamUtils::write_all(&mut stream, format!("{}\n", FlytApiCommand::CLIENTD_RMGR_RENEW_LEASE)).map_err(|e| e.to_string())?;
+
+        let mut reader = BufReader::new(stream);
+        let response = StreamUtils::read_response(&mut reader, 2).map_err(|e| e.to_string())?;
+        match response[0].as_str() {
+            "200" => response[1].parse::<u64>().map(|ttl| Some(Duration::from_secs(ttl)))
+                .map_err(|_| format!("Invalid lease TTL: {}", response[1])),
+            "404" => Ok(None),
+            _ => Err(format!("{} {}", response[0], response[1])),
+        }
+    }
+
+    /// Renews the lease on the virt server a few times per TTL, so the resource manager keeps it
+    /// allocated for as long as this daemon runs. A new virt server is renewed as soon as it is seen,
+    /// which also tells the TTL before the first renewal period has passed.
+    pub fn keep_lease_renewed(&self) {
+        let mut period = DEFAULT_LEASE_RENEW_PERIOD;
+        let mut renewed: Option<(VirtServer, Instant)> = None;
+        loop {
+            thread::sleep(LEASE_POLL_PERIOD);
+            let virt_server = match self.virt_server.read().unwrap().clone() {
+                Some(virt_server) if !*self.hibernated.read().unwrap() => virt_server,
+                _ => {
+                    renewed = None;
+                    continue;
+                }
+            };
+
+            if renewed.as_ref().is_some_and(|(renewed_for, renewed_at)| *renewed_for == virt_server && renewed_at.elapsed() < period) {
+                continue;
+            }
+            renewed = Some((virt_server, Instant::now()));
+
+            match self.renew_lease() {
+                Ok(Some(ttl)) if ttl.is_zero() => period = DEFAULT_LEASE_RENEW_PERIOD,
+                Ok(Some(ttl)) => period = (ttl / 3).max(Duration::from_secs(1)),
+                // the command stream is closed when a lease is reclaimed, the reconnect clears the virt server
+                Ok(None) => log::warn!("Resource manager holds no lease for this VM"),
+                Err(e) => log::error!("Error renewing lease: {}", e),
+            }
+        }
+    }
+
     fn launch_cmd_reader_thread<'a>(&'a self, scope: &'a thread::Scope<'a, '_>, mut reader: BufReader<TcpStream>, mut writer: TcpStream) {
         scope.spawn( move || {
 
@@ -154,15 +321,24 @@ impl <'b> ResourceManagerHandler <'b> {
                     }
                     Err(error) => {
                         log::error!("Error reading command: {}", error);
-                        break;
+                        0
                     }
                 };
                 
                 if read_len == 0 {
+                    // the virt server keeps running, so clients are not disturbed while the manager fails over
                     log::info!("Connection closed by server");
-                    log::info!("Clearing Virt server");
-                    self.virt_server.write().unwrap().take();
-                    break;
+                    match self.reconnect() {
+                        Some((new_reader, new_writer)) => {
+                            reader = new_reader;
+                            writer = new_writer;
+                            continue;
+                        }
+                        None => {
+                            log::info!("No virt server to keep, waiting for the next client");
+                            break;
+                        }
+                    }
                 }
 
                 log::info!("Received command: {}", command);
@@ -174,7 +350,16 @@ impl <'b> ResourceManagerHandler <'b> {
                         let _ = StreamUtils::write_all(&mut writer, format!("200\nPaused {} out of {} clients\n", num_paused, total_clients));
                     }
 
+                    FlytApiCommand::RMGR_CLIENTD_HIBERNATE => {
+                        let total_clients = self.client_mgr.num_active_clients();
+                        let num_hibernated = self.client_mgr.hibernate_clients();
+                        *self.hibernated.write().unwrap() = true;
+                        let _ = StreamUtils::write_all(&mut writer, format!("200\nHibernated {} out of {} clients\n", num_hibernated, total_clients));
+                    }
+
                     FlytApiCommand::RMGR_CLIENTD_RESUME => {
+                        // a hibernated VM is resumed once it was restored, even if the reply to its wake up got lost
+                        *self.hibernated.write().unwrap() = false;
                         let num_resumed = self.client_mgr.resume_clients();
                         let _ = StreamUtils::write_all(&mut writer, format!("200\nResumed {} clients\n", num_resumed));
                     }
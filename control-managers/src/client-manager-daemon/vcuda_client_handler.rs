@@ -1,12 +1,14 @@
-use std::{sync::RwLock, time::Duration};
+use std::{sync::RwLock, thread, time::Duration};
 use crate::common::api_commands::FlytApiCommand;
 use crate::common::utils::Utils;
-use crate::common::types::MqueueClientControlCommand;
+use crate::common::types::{Backoff, MqueueClientControlCommand};
 use crate::resource_manager_handler::VirtServer;
 
 use ipc_rs::{MessageQueue, MessageQueueKey, PathProjectIdKey};
 
 const PROJ_ID: i32 = 0x42;
+/// Message type on which hibernated vCUDA clients send their pid when they make a CUDA call
+const WAKE_REQUEST_TYPE: i64 = 2;
 
 #[derive(Debug, Clone, Copy)]
 struct ClientMessageTypeId {
@@ -88,6 +90,58 @@ impl VCudaClientManager {
         pause_count
     }
 
+    /// Pauses the clients like `pause_clients`; a client then asks for a wake up on its next CUDA call.
+    pub fn hibernate_clients(&self) -> u32 {
+
+        log::info!("Hibernating clients...");
+
+        let mut hibernate_count = 0u32;
+
+        let bytes = MqueueClientControlCommand::new(FlytApiCommand::CLIENTD_VCUDA_HIBERNATE, "").as_bytes();
+
+        for client in self.clients.read().unwrap().iter() {
+            if self.message_queue.send(&bytes, client.send_id).is_err() {
+                log::error!("Error sending hibernate command to client: {}", client.send_id);
+                continue;
+            }
+
+            let response = self.message_queue.recv_type_timed(client.recv_id, Duration::from_secs(2)).ok()
+                .and_then(|recv_bytes| Utils::convert_bytes_to_u32(&recv_bytes));
+            match response {
+                Some(200) => hibernate_count += 1,
+                _ => log::error!("Error hibernating client: {}", client.send_id),
+            }
+        }
+
+        log::info!("Hibernated #clients: {}", hibernate_count);
+
+        hibernate_count
+    }
+
+    /// Calls `wake` whenever a hibernated client makes a CUDA call, and again with `backoff` until it succeeds.
+    pub fn listen_for_wake_requests<F>(&self, mut backoff: Backoff, wake: F) where F: Fn() -> bool {
+        let key = PathProjectIdKey::new(self.mqueue_path.clone(), PROJ_ID);
+        let message_queue = MessageQueue::new(MessageQueueKey::PathKey(key)).create().init().unwrap();
+        loop {
+            let client_pid = match message_queue.recv_type(WAKE_REQUEST_TYPE) {
+                Ok(recv_bytes) => Utils::convert_bytes_to_u32(&recv_bytes),
+                Err(_) => {
+                    log::error!("Error receiving wake request from client");
+                    continue;
+                }
+            };
+
+            log::info!("Wake up requested by client: {:?}", client_pid);
+            // the clients stay paused until the VM is woken up
+            while !wake() {
+                let delay = backoff.next_delay();
+                log::error!("Could not wake up, retrying in {:?}", delay);
+                thread::sleep(delay);
+            }
+            backoff.reset();
+        }
+    }
+
     pub fn change_virt_server(&self, virt_server: &VirtServer) -> u32 {
         
         log::info!("Changing virt server for clients...");
@@ -6,8 +6,10 @@ use mongodb::{options::{ClientOptions, ServerAddress, Credential}, sync::Client,
 use serde::{Deserialize, Serialize};
 
 use crate::common::config::RMGR_CONFIG_PATH;
-use crate::common::types::StreamEnds;
+use crate::common::types::{GpuHealth, HostLimits, IsolationLevel, MpsStatus, RestartPolicy, StreamEnds, VirtServerFlavor};
 use crate::common::utils::Utils;
+use crate::ha::HaConfig;
+use crate::http_api::HttpApiConfig;
 
 struct ConfigOptions;
 
@@ -24,6 +26,12 @@ pub struct GPU {
     pub gpu_id: u64,
     pub allocated_memory: u64,
     pub allocated_compute_units: u32,
+    pub isolation: IsolationLevel,
+    pub parent_gpu_id: u64,
+    /// Set while a VM holds this MIG slice as a dedicated unit
+    pub exclusive: bool,
+    pub health: GpuHealth,
+    pub mps: MpsStatus,
 }
 
 impl Default for GPU {
@@ -36,6 +44,11 @@ impl Default for GPU {
             gpu_id: 0,
             allocated_memory: 0,
             allocated_compute_units: 0,
+            isolation: IsolationLevel::Mps,
+            parent_gpu_id: 0,
+            exclusive: false,
+            health: GpuHealth::Healthy,
+            mps: MpsStatus::Unmanaged,
         }
     }
 }
@@ -46,6 +59,7 @@ pub struct ServerNode {
     pub gpus: Vec<Arc<RwLock<GPU>>>,
     pub stream: Arc<RwLock<StreamEnds<TcpStream>>>,
     pub virt_servers: Vec<Arc<RwLock<VirtServer>>>,
+    pub flavors: Vec<VirtServerFlavor>,
 }
 
 impl Clone for ServerNode {
@@ -54,7 +68,8 @@ impl Clone for ServerNode {
             ipaddr: self.ipaddr.clone(),
             gpus: self.gpus.clone(),
             stream: self.stream.clone(),
-            virt_servers: self.virt_servers.clone()
+            virt_servers: self.virt_servers.clone(),
+            flavors: self.flavors.clone(),
         }
     }
 }
@@ -76,6 +91,75 @@ pub struct VMResources {
     pub host_ip: String,
     pub compute_units: u32,
     pub memory: u64,
+    /// One of "mps" (default), "mig" or "mig-mps", see `PlacementPolicy`
+    #[serde(default)]
+    pub isolation: Option<String>,
+    /// One of "never" (default), "on-failure" or "always", see `RestartPolicy`
+    #[serde(default)]
+    pub restart_policy: Option<String>,
+    /// CPUs the virt server may use on its server node, e.g. 1.5
+    #[serde(default)]
+    pub host_cpus: Option<f64>,
+    /// MB of host memory, including pinned memory, the virt server may use
+    #[serde(default)]
+    pub host_memory: Option<u64>,
+    #[serde(default)]
+    pub max_pids: Option<u32>,
+    /// Virt server build to run, the server node's "default" when unset
+    #[serde(default)]
+    pub flavor: Option<String>,
+    /// Seconds the virt server is kept after the VM's last vCUDA client exits, overrides
+    /// `[virt-server-auto-deallocate]`
+    #[serde(default)]
+    pub idle_grace_period: Option<u64>,
+}
+
+/// Where a VM may be placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementPolicy {
+    /// An MPS share of any GPU unit
+    Mps,
+    /// A whole MIG slice, not shared with other VMs
+    MigSlice,
+    /// An MPS share of a MIG slice
+    MigMps,
+}
+
+impl VMResources {
+    pub fn placement(&self) -> PlacementPolicy {
+        match self.isolation.as_deref() {
+            Some("mig") => PlacementPolicy::MigSlice,
+            Some("mig-mps") => PlacementPolicy::MigMps,
+            Some("mps") | None => PlacementPolicy::Mps,
+            Some(other) => {
+                log::warn!("Unknown isolation {} for VM {}, using mps", other, self.vm_ip);
+                PlacementPolicy::Mps
+            }
+        }
+    }
+
+    pub fn host_limits(&self) -> HostLimits {
+        HostLimits {
+            cpu_millis: self.host_cpus.map(|cpus| (cpus * 1000.0) as u32),
+            memory: self.host_memory.map(|memory| memory * 1024 * 1024),
+            pids: self.max_pids,
+        }
+    }
+
+    pub fn flavor(&self) -> &str {
+        self.flavor.as_deref().unwrap_or(VirtServerFlavor::DEFAULT)
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        match self.restart_policy.as_deref().map(|policy| policy.parse::<RestartPolicy>()) {
+            Some(Ok(policy)) => policy,
+            None => RestartPolicy::Never,
+            Some(Err(e)) => {
+                log::warn!("{} for VM {}, using never", e, self.vm_ip);
+                RestartPolicy::Never
+            }
+        }
+    }
 }
 
 pub struct VMResourcesGetter {
@@ -157,6 +241,118 @@ pub fn get_virt_server_deallocate_time() -> Option<u64> {
     deallocate_time
 }
 
+/// Times an allocation or resize that timed out on the virt server is sent again.
+pub fn get_timeout_retries() -> u32 {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let get_retries = || -> Option<i64> {
+        config.get("virt-server-operations")?.get("timeout-retries")?.as_integer()
+    };
+    get_retries().unwrap_or(1).max(0) as u32
+}
+
+/// Returns how long an operator command or wake-up waits for the operation running on the same VM.
+pub fn get_vm_operation_queue_timeout() -> std::time::Duration {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let get_timeout = || -> Option<i64> {
+        config.get("vm-operations")?.get("queue-timeout")?.as_integer()
+    };
+    std::time::Duration::from_secs(get_timeout().unwrap_or(30).max(0) as u64)
+}
+
+/// Returns how long a client's virt server is kept without its client daemon renewing the lease,
+/// or `None` if leases are disabled.
+pub fn get_virt_server_lease_ttl() -> Option<std::time::Duration> {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let leases = config.get("virt-server-leases")?;
+    if !leases.get("enabled")?.as_bool()? {
+        return None;
+    }
+    let ttl = leases.get("ttl").and_then(|ttl| ttl.as_integer()).unwrap_or(60).max(1);
+    Some(std::time::Duration::from_secs(ttl as u64))
+}
+
+/// Returns how long a VM's virt server may leave its SMs unused before the VM is hibernated,
+/// or `None` if hibernation is disabled.
+pub fn get_hibernation_idle_period() -> Option<std::time::Duration> {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let hibernation = config.get("hibernation")?;
+    if !hibernation.get("enabled")?.as_bool()? {
+        return None;
+    }
+    let idle_period = hibernation.get("idle-period").and_then(|period| period.as_integer()).unwrap_or(3600).max(1);
+    Some(std::time::Duration::from_secs(idle_period as u64))
+}
+
+pub fn get_gpu_health_evacuate() -> bool {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let get_evacuate = || -> Option<bool> {
+        config.get("gpu-health")?.get("evacuate")?.as_bool()
+    };
+    get_evacuate().unwrap_or(false)
+}
+
+pub fn get_telemetry_history_length() -> usize {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let get_history_length = || -> Option<i64> {
+        config.get("telemetry")?.get("history-length")?.as_integer()
+    };
+    get_history_length().unwrap_or(60) as usize
+}
+
+/// Returns the `[ha]` settings when this manager runs as one of an active/standby pair.
+pub fn get_ha_config() -> Option<HaConfig> {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let ha = config.get("ha")?;
+    if !ha.get("enabled")?.as_bool()? {
+        return None;
+    }
+
+    let node_id = ha.get("node-id").and_then(|id| id.as_str()).map(|id| id.to_string())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok().map(|id| id.trim().to_string()))
+        .unwrap_or(std::process::id().to_string());
+    let lease_path = ha.get("lease-path").and_then(|path| path.as_str()).unwrap_or("/var/lib/flyt/rmgr-lease").to_string();
+    let journal_path = ha.get("journal-path").and_then(|path| path.as_str()).unwrap_or("/var/lib/flyt/rmgr-journal").to_string();
+    let lease_ttl = ha.get("lease-ttl").and_then(|ttl| ttl.as_integer()).unwrap_or(10).max(1) as u64;
+    let renew_period = ha.get("renew-period").and_then(|period| period.as_integer()).unwrap_or(3).max(1) as u64;
+    let clock_skew = ha.get("max-clock-skew-ms").and_then(|skew| skew.as_integer()).unwrap_or(1000).max(0) as u64;
+
+    Some(HaConfig {
+        node_id,
+        lease_path,
+        journal_path,
+        lease_ttl: std::time::Duration::from_secs(lease_ttl),
+        renew_period: std::time::Duration::from_secs(renew_period.min(lease_ttl)),
+        clock_skew: std::time::Duration::from_millis(clock_skew),
+    })
+}
+
+/// Returns the settings of the HTTP API, or `None` if it is disabled. Tokens are read from `tokens`
+/// and from `token-file`, one per line.
+pub fn get_http_api_config() -> Option<HttpApiConfig> {
+    let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
+    let http_api = config.get("http-api")?;
+    if !http_api.get("enabled")?.as_bool()? {
+        return None;
+    }
+
+    let address = http_api.get("address").and_then(|address| address.as_str()).unwrap_or("127.0.0.1:8090").to_string();
+    let mut tokens = http_api.get("tokens").and_then(|tokens| tokens.as_array())
+        .map(|tokens| tokens.iter().filter_map(|token| token.as_str()).map(|token| token.to_string()).collect::<Vec<String>>())
+        .unwrap_or_default();
+    if let Some(token_file) = http_api.get("token-file").and_then(|path| path.as_str()) {
+        match std::fs::read_to_string(token_file) {
+            Ok(contents) => tokens.extend(contents.lines().map(|token| token.trim().to_string())),
+            Err(e) => log::error!("Error reading HTTP API tokens from {}: {}", token_file, e),
+        }
+    }
+    tokens.retain(|token| !token.is_empty());
+
+    Some(HttpApiConfig {
+        address,
+        tokens,
+    })
+}
+
 pub fn get_ports() -> (u16, u16) {
     let config: Table = Utils::load_config_file(RMGR_CONFIG_PATH);
     let node_port = config.get("ports").unwrap().get("node").unwrap().as_integer().unwrap() as u16;
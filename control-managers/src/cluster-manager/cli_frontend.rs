@@ -27,6 +27,17 @@ enum Commands {
     ListVms,
     ListServernodes,
     ListVirtServers,
+    #[command(about = "List VMs whose idle virt servers are about to be deallocated")]
+    PendingDeallocations,
+    #[command(about = "Show the latest GPU and virt server telemetry of every server node")]
+    Top,
+    #[command(about = "Print the logs of the virt server allocated to a VM")]
+    Logs {
+        #[arg(help = "IP address of the VM")]
+        ip: String,
+        #[arg(short, long, help = "Keep printing new log lines as they are written")]
+        follow: bool,
+    },
     ChangeConfig {
         #[arg(short, long, help = "IP address of the VM to change resources for")]
         ip: String,
@@ -53,6 +64,11 @@ enum Commands {
         #[arg(short, long, help = "Amount of memory to allocate (MB)")]
         memory: u64,
     },
+    #[command(about = "Make a server node rescan its GPUs and update the cluster's view of them")]
+    RescanGpus {
+        #[arg(help = "IP address of the server node")]
+        ip: String,
+    },
 }
 #[derive(Debug, clap::Args, Clone)]
 #[group(required = true)]
@@ -76,6 +92,11 @@ fn main() {
     let args = Args::parse();
     let stream_path = get_stream_path();
 
+    if let Commands::Logs { ip, follow } = args.cmd {
+        logs(&stream_path, ip, follow);
+        return;
+    }
+
     let stream = UnixStream::connect(stream_path).unwrap();
 
     match args.cmd {
@@ -86,6 +107,9 @@ fn main() {
             list_servernodes(stream);
         }
         Commands::ListVirtServers => list_virt_servers(stream),
+        Commands::PendingDeallocations => list_pending_deallocations(stream),
+        Commands::Top => top(stream),
+        Commands::Logs { .. } => unreachable!(),
         Commands::ChangeConfig { ip, mut new_resources } => {
             new_resources.memory = new_resources.memory.map(|x| x * 1024 * 1024);
             change_resources(stream, ip, new_resources);
@@ -108,6 +132,7 @@ fn main() {
             let mem_bytes = memory * 1024 * 1024;
             migrate_vm_auto(stream, ip, sm_cores, mem_bytes);
         }
+        Commands::RescanGpus { ip } => rescan_gpus(stream, ip),
     }
 }
 
@@ -189,6 +214,22 @@ pub fn migrate_vm_auto(mut stream: UnixStream, ip: String, sm_cores: u32, memory
 
 }
 
+fn rescan_gpus(mut stream: UnixStream, ip: String) {
+    match stream.write_all(format!("{}\n{}\n", FrontEndCommand::RESCAN_GPUS, ip).as_bytes()) {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Error writing to stream: {}", e);
+            return;
+        }
+    }
+
+    let mut reader = std::io::BufReader::new(stream);
+
+    match StreamUtils::read_response(&mut reader, 2) {
+        Ok(response) => println!("{}: {}", response[0], response[1]),
+        Err(e) => log::error!("Error reading response: {}", e),
+    }
+}
 
 fn list_vms(mut stream: UnixStream) {
     match stream.write_all(format!("{}\n", FrontEndCommand::LIST_VMS).as_bytes()) {
@@ -241,6 +282,9 @@ fn list_vms(mut stream: UnixStream) {
         "SM Cores",
         "Memory",
         "Is Active",
+        "Lease Expires In",
+        "State",
+        "In State For",
     ]);
 
     for _ in 0..num_vms {
@@ -300,7 +344,7 @@ fn list_servernodes(mut stream: UnixStream) {
 
     let mut response = String::new();
 
-    // format: ipaddr,num_gpus
+    // format: ipaddr,num_gpus[,flavor+flavor]
     for _ in 0..num_servernodes {
         let fields_str = match StreamUtils::read_line(&mut reader) {
             Ok(fields) => fields,
@@ -314,9 +358,12 @@ fn list_servernodes(mut stream: UnixStream) {
         let num_gpus = fields[1].parse::<usize>().unwrap();
 
         response.push_str(format!("ServerNode IP: {}\n", ipaddr).as_str());
+        if let Some(flavors) = fields.get(2).filter(|flavors| !flavors.is_empty()) {
+            response.push_str(format!("Virt server flavors: {}\n", flavors.replace('+', ", ")).as_str());
+        }
         let mut table = Table::new();
 
-        // format: gpuid,name,memory,allocated_memory,compute_units,allocated_compute_units
+        // format: gpuid,name,memory,allocated_memory,compute_units,allocated_compute_units,isolation,parent_gpu_id,health,mps_status
         table.set_header(vec![
             "GPU ID",
             "GPU Name",
@@ -324,6 +371,10 @@ fn list_servernodes(mut stream: UnixStream) {
             "Allocated GPU Memory",
             "GPU Compute Units",
             "Allocated GPU Compute Units",
+            "Isolation",
+            "Physical GPU",
+            "Health",
+            "MPS",
         ]);
 
         for _ in 0..num_gpus {
@@ -344,6 +395,239 @@ fn list_servernodes(mut stream: UnixStream) {
     println!("{}", response);
 }
 
+/// Polls the cluster manager for log lines, every request uses a new connection.
+fn logs(stream_path: &str, ip: String, follow: bool) {
+    let mut offset = 0u64;
+
+    loop {
+        let mut stream = match UnixStream::connect(stream_path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Error connecting to cluster manager: {}", e);
+                return;
+            }
+        };
+
+        match stream.write_all(format!("{}\n{},{}\n", FrontEndCommand::VIRT_SERVER_LOGS, ip, offset).as_bytes()) {
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Error writing to stream: {}", e);
+                return;
+            }
+        }
+
+        let mut reader = std::io::BufReader::new(stream);
+
+        let response = match StreamUtils::read_response(&mut reader, 2) {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Error reading response: {}", e);
+                return;
+            }
+        };
+
+        if response[0] != "200" {
+            eprintln!("Error: {}", response[1]);
+            return;
+        }
+
+        // format: offset,num_lines
+        let parts = response[1].split(',').collect::<Vec<&str>>();
+        let next_offset = parts[0].parse::<u64>().unwrap();
+        let num_lines = parts[1].parse::<usize>().unwrap();
+
+        for _ in 0..num_lines {
+            match StreamUtils::read_line(&mut reader) {
+                Ok(line) => println!("{}", line),
+                Err(e) => {
+                    log::error!("Error reading log line: {}", e);
+                    return;
+                }
+            }
+        }
+
+        offset = next_offset;
+
+        if num_lines == 0 {
+            if !follow {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+    }
+}
+
+fn top(mut stream: UnixStream) {
+    match stream.write_all(format!("{}\n", FrontEndCommand::TOP).as_bytes()) {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Error writing to stream: {}", e);
+            return;
+        }
+    }
+
+    let mut reader = std::io::BufReader::new(stream);
+
+    let status = match StreamUtils::read_line(&mut reader) {
+        Ok(status) => status,
+        Err(e) => {
+            log::error!("Error reading status: {}", e);
+            return;
+        }
+    };
+
+    if status != "200" {
+        log::error!("Error: {}", status);
+        return;
+    }
+
+    let num_nodes = match StreamUtils::read_line(&mut reader) {
+        Ok(num_nodes) => num_nodes.parse::<usize>().unwrap(),
+        Err(e) => {
+            log::error!("Error reading number of server nodes: {}", e);
+            return;
+        }
+    };
+
+    let mut response = String::new();
+
+    for _ in 0..num_nodes {
+        let node_str = match StreamUtils::read_line(&mut reader) {
+            Ok(node) => node,
+            Err(e) => {
+                log::error!("Error reading server node details: {}", e);
+                return;
+            }
+        };
+        // format: ipaddr,num_gpus,num_virt_servers,sample_age
+        let fields = node_str.split(',').collect::<Vec<&str>>();
+        let num_gpus = fields[1].parse::<usize>().unwrap();
+        let num_virt_servers = fields[2].parse::<usize>().unwrap();
+
+        if fields[3].is_empty() {
+            response.push_str(&format!("ServerNode IP: {} (no telemetry)\n\n", fields[0]));
+            continue;
+        }
+        response.push_str(&format!("ServerNode IP: {} (updated {}s ago)\n", fields[0], fields[3]));
+
+        let mut gpu_table = Table::new();
+        gpu_table.set_header(vec![
+            "GPU ID",
+            "Utilization %",
+            "Avg Utilization %",
+            "Memory Used (MB)",
+            "Memory Total (MB)",
+            "Power (W)",
+        ]);
+
+        for _ in 0..num_gpus {
+            let row_str = match StreamUtils::read_line(&mut reader) {
+                Ok(row) => row,
+                Err(e) => {
+                    log::error!("Error reading GPU telemetry: {}", e);
+                    return;
+                }
+            };
+            // format: gpu_id,utilization,avg_utilization,memory_used,memory_total,power
+            let fields = row_str.split(',').collect::<Vec<&str>>();
+            gpu_table.add_row(vec![
+                fields[0].to_string(),
+                fields[1].to_string(),
+                fields[2].to_string(),
+                (fields[3].parse::<u64>().unwrap_or(0) / (1024 * 1024)).to_string(),
+                (fields[4].parse::<u64>().unwrap_or(0) / (1024 * 1024)).to_string(),
+                format!("{:.1}", fields[5].parse::<f64>().unwrap_or(0.0) / 1000.0),
+            ]);
+        }
+        response.push_str(&format!("{gpu_table}\n"));
+
+        let mut virt_server_table = Table::new();
+        virt_server_table.set_header(vec![
+            "RPC ID",
+            "VM IP",
+            "GPU ID",
+            "Memory Used (MB)",
+            "SM Utilization %",
+            "Host CPU (s)",
+            "Host Memory (MB)",
+            "Pids",
+        ]);
+
+        for _ in 0..num_virt_servers {
+            let row_str = match StreamUtils::read_line(&mut reader) {
+                Ok(row) => row,
+                Err(e) => {
+                    log::error!("Error reading virt server telemetry: {}", e);
+                    return;
+                }
+            };
+            // format: rpc_id,vm_ip,gpu_id,memory_used,sm_utilization,host_cpu_usage,host_memory,pids
+            let fields = row_str.split(',').collect::<Vec<&str>>();
+            virt_server_table.add_row(vec![
+                fields[0].to_string(),
+                fields[1].to_string(),
+                fields[2].to_string(),
+                (fields[3].parse::<u64>().unwrap_or(0) / (1024 * 1024)).to_string(),
+                fields[4].to_string(),
+                format!("{:.1}", fields[5].parse::<f64>().unwrap_or(0.0) / 1e6),
+                (fields[6].parse::<u64>().unwrap_or(0) / (1024 * 1024)).to_string(),
+                fields[7].to_string(),
+            ]);
+        }
+        if num_virt_servers > 0 {
+            response.push_str(&format!("{virt_server_table}\n"));
+        }
+        response.push('\n');
+    }
+
+    print!("{}", response);
+}
+
+fn list_pending_deallocations(mut stream: UnixStream) {
+    if let Err(e) = stream.write_all(format!("{}\n", FrontEndCommand::LIST_PENDING_DEALLOCATIONS).as_bytes()) {
+        log::error!("Error writing to stream: {}", e);
+        return;
+    }
+
+    let mut reader = std::io::BufReader::new(stream);
+
+    let response = match StreamUtils::read_response(&mut reader, 2) {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Error reading response: {}", e);
+            return;
+        }
+    };
+    if response[0] != "200" {
+        log::error!("Error: {} {}", response[0], response[1]);
+        return;
+    }
+    let num_pending = response[1].parse::<usize>().unwrap();
+
+    // format: vmip,seconds_left
+    let mut table = Table::new();
+    table.set_header(vec![
+        "VM IP",
+        "Deallocated In",
+    ]);
+
+    for _ in 0..num_pending {
+        let row = match StreamUtils::read_line(&mut reader) {
+            Ok(row) => row,
+            Err(e) => {
+                log::error!("Error reading pending deallocation: {}", e);
+                return;
+            }
+        };
+        match row.split_once(',') {
+            Some((vm_ip, seconds_left)) => table.add_row(vec![vm_ip.to_string(), format!("{}s", seconds_left)]),
+            None => table.add_row(vec![row.clone(), String::new()]),
+        };
+    }
+
+    println!("{}", table);
+}
+
 fn list_virt_servers(mut stream: UnixStream) {
     match stream.write_all(format!("{}\n", FrontEndCommand::LIST_VIRT_SERVERS).as_bytes()) {
         Ok(_) => {}
@@ -411,13 +695,13 @@ fn list_virt_servers(mut stream: UnixStream) {
 }
 
 pub fn change_resources(mut stream: UnixStream, vm_ip: String, new_resources: NewResourcesOption) {
-    let command = if new_resources.sm_cores.is_some() && new_resources.memory.is_some() {
+    let command = if let (Some(sm_cores), Some(memory)) = (new_resources.sm_cores, new_resources.memory) {
         format!(
             "{}\n{},{},{}\n",
             FrontEndCommand::CHANGE_SM_CORES_AND_MEMORY,
             vm_ip,
-            new_resources.sm_cores.unwrap(),
-            new_resources.memory.unwrap()
+            sm_cores,
+            memory
         )
     } else if let Some(sm_cores) = new_resources.sm_cores {
         format!(
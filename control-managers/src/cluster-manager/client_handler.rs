@@ -4,13 +4,19 @@ use crate::common::types::StreamEnds;
 use crate::servernode_handler::ServerNodesManager;
 use crate::common::api_commands::FlytApiCommand;
 use crate::common::utils::StreamUtils;
+use crate::ha::{Journal, JournalEntry, JournalState};
+use crate::hibernation::HibernatedVm;
+use crate::lifecycle::{VmLifecycle, VmState};
+use crate::operations::{VmOperation, VmOperations};
+use crate::timers::TimerService;
 
 
 use std::collections::HashMap;
 use std::io::{BufReader, Read, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct FlytClientNode {
@@ -46,8 +52,27 @@ macro_rules! get_writer {
 pub struct FlytClientManager<'a> {
     clients: Mutex<HashMap<String,FlytClientNode>>,
     server_nodes_manager: &'a ServerNodesManager<'a>,
+    journal: Option<&'a Journal>,
+    /// Assignments replayed from the journal whose virt servers no server node has reported yet
+    pending_assignments: Mutex<HashMap<String, (String, u64)>>,
+    /// Time at which the virt server of a client is reclaimed unless its client daemon renews the lease
+    leases: Mutex<HashMap<String, Instant>>,
+    lease_ttl: Option<Duration>,
+    /// Deallocation of the virt servers of VMs without vCUDA clients
+    deallocation_timers: TimerService,
+    /// VMs whose virt servers were checkpointed and freed while they were idle
+    hibernated: Mutex<HashMap<String, HibernatedVm>>,
+    lifecycles: Mutex<HashMap<String, VmLifecycle>>,
+    operations: VmOperations,
+    operation_queue_timeout: Duration,
 }
 
+fn virt_server_key(virt_server: &Option<Arc<RwLock<VirtServer>>>) -> Option<(String, u64)> {
+    virt_server.as_ref().map(|virt_server| {
+        let virt_server = virt_server.read().unwrap();
+        (virt_server.ipaddr.clone(), virt_server.rpc_id)
+    })
+}
 
 impl<'a> FlytClientManager<'a> {
     
@@ -55,12 +80,52 @@ impl<'a> FlytClientManager<'a> {
         FlytClientManager {
             clients: Mutex::new(HashMap::new()),
             server_nodes_manager: server_nodes_mgr,
+            journal: None,
+            pending_assignments: Mutex::new(HashMap::new()),
+            leases: Mutex::new(HashMap::new()),
+            lease_ttl: get_virt_server_lease_ttl(),
+            deallocation_timers: TimerService::new(),
+            hibernated: Mutex::new(HashMap::new()),
+            lifecycles: Mutex::new(HashMap::new()),
+            operations: VmOperations::new(),
+            operation_queue_timeout: get_vm_operation_queue_timeout(),
         }
     }
 
+    /// Records every change of a client's virt server, so a standby manager can take over.
+    pub fn with_journal(mut self, journal: &'a Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
     pub fn add_client(&self, client: FlytClientNode) {
-        let mut clients = self.clients.lock().unwrap();
-        clients.insert(client.ipaddr.clone(), client);
+        let client_ip = client.ipaddr.clone();
+        let new_virt_server = client.virt_server.clone();
+        let old_virt_server = {
+            let mut clients = self.clients.lock().unwrap();
+            clients.insert(client.ipaddr.clone(), client).and_then(|old| old.virt_server)
+        };
+
+        let new_key = virt_server_key(&new_virt_server);
+        if new_key == virt_server_key(&old_virt_server) {
+            return;
+        }
+
+        // a new virt server comes with a new lease
+        match new_key {
+            Some(_) => self.renew_lease(&client_ip),
+            None => {
+                self.leases.lock().unwrap().remove(&client_ip);
+                self.deallocation_timers.cancel(&client_ip);
+            }
+        }
+
+        if let Some(journal) = self.journal {
+            match new_key {
+                Some((virt_ip, rpc_id)) => journal.record(&JournalEntry::Assign { client_ip, virt_ip, rpc_id }),
+                None => journal.record(&JournalEntry::Release { client_ip }),
+            }
+        }
     }
 
     pub fn update_stream(&self, ipaddr: &str, writer: TcpStream, reader: BufReader<TcpStream>) {
@@ -76,6 +141,21 @@ impl<'a> FlytClientManager<'a> {
         if let Some(client) = client {
             *client.is_active.get_mut().unwrap() = status;
         }
+        drop(clients);
+
+        {
+            let mut lifecycles = self.lifecycles.lock().unwrap();
+            if let Some(lifecycle) = lifecycles.get_mut(ipaddr).filter(|lifecycle| lifecycle.state.is_settled()) {
+                let state = if status { VmState::Running } else { VmState::Idle };
+                if lifecycle.state != state {
+                    *lifecycle = VmLifecycle::new(state);
+                }
+            }
+        }
+
+        if status && self.deallocation_timers.cancel(ipaddr) {
+            log::info!("Client {} is active again, cancelled deallocation of its virt server", ipaddr);
+        }
     }
 
     pub fn get_client_status(&self, ipaddr: &str) -> bool {
@@ -99,8 +179,210 @@ impl<'a> FlytClientManager<'a> {
     }
 
     pub fn remove_client(&self, ipaddr: &str) {
-        let mut clients = self.clients.lock().unwrap();
-        clients.remove(ipaddr);
+        let removed = {
+            let mut clients = self.clients.lock().unwrap();
+            clients.remove(ipaddr)
+        };
+
+        self.leases.lock().unwrap().remove(ipaddr);
+        self.deallocation_timers.cancel(ipaddr);
+        let was_hibernated = self.hibernated.lock().unwrap().remove(ipaddr).is_some();
+        self.lifecycles.lock().unwrap().remove(ipaddr);
+        if let (Some(journal), Some(removed)) = (self.journal, removed) {
+            if removed.virt_server.is_some() || was_hibernated {
+                journal.record(&JournalEntry::Release { client_ip: ipaddr.to_string() });
+            }
+        }
+    }
+
+    /// Starts a control operation on a VM, after the one running on it if it finishes within the queue timeout.
+    pub fn start_operation(&self, ipaddr: &str, operation: &'static str) -> Result<VmOperation<'_>, String> {
+        self.operations.start(ipaddr, operation, self.operation_queue_timeout)
+    }
+
+    /// Starts a control operation on a VM unless another one is running on it.
+    pub fn try_start_operation(&self, ipaddr: &str, operation: &'static str) -> Result<VmOperation<'_>, String> {
+        self.operations.try_start(ipaddr, operation)
+    }
+
+    /// Returns the lifecycle state of a VM, `Pending` for VMs not seen yet.
+    pub fn vm_state(&self, ipaddr: &str) -> VmState {
+        self.vm_lifecycle(ipaddr).map(|lifecycle| lifecycle.state).unwrap_or(VmState::Pending)
+    }
+
+    pub fn vm_lifecycle(&self, ipaddr: &str) -> Option<VmLifecycle> {
+        self.lifecycles.lock().unwrap().get(ipaddr).copied()
+    }
+
+    pub fn all_vm_lifecycles(&self) -> HashMap<String, VmLifecycle> {
+        self.lifecycles.lock().unwrap().clone()
+    }
+
+    /// Moves a VM to `next` if its current state allows it, returning the state it left.
+    pub fn transition(&self, ipaddr: &str, next: VmState) -> Result<VmState, String> {
+        let mut lifecycles = self.lifecycles.lock().unwrap();
+        let current = lifecycles.get(ipaddr).map(|lifecycle| lifecycle.state).unwrap_or(VmState::Pending);
+        if !current.can_become(next) {
+            return Err(format!("VM {} is {}", ipaddr, current));
+        }
+        log::debug!("VM {}: {} -> {}", ipaddr, current, next);
+        lifecycles.insert(ipaddr.to_string(), VmLifecycle::new(next));
+        Ok(current)
+    }
+
+    /// Moves a VM to `next` even if its current state does not allow it, for events the cluster
+    /// manager cannot refuse, e.g. a virt server that exited.
+    pub fn force_transition(&self, ipaddr: &str, next: VmState) {
+        if let Err(e) = self.transition(ipaddr, next) {
+            log::warn!("{}, marking it {}", e, next);
+            self.set_vm_state(ipaddr, next);
+        }
+    }
+
+    /// Sets the state of a VM without validating the transition, for state restored from the journal.
+    pub fn set_vm_state(&self, ipaddr: &str, state: VmState) {
+        log::debug!("VM {}: -> {}", ipaddr, state);
+        self.lifecycles.lock().unwrap().insert(ipaddr.to_string(), VmLifecycle::new(state));
+    }
+
+    /// Drops the state of a VM that never got a client entry, e.g. after its allocation failed.
+    pub fn forget_vm_state(&self, ipaddr: &str) {
+        if self.get_client(ipaddr).is_none() {
+            self.lifecycles.lock().unwrap().remove(ipaddr);
+        }
+    }
+
+    /// Ends a control operation on a VM: it is running or idle again if it holds a virt server.
+    pub fn settle(&self, ipaddr: &str) {
+        let state = match self.get_client(ipaddr) {
+            Some(client) if client.virt_server.is_some() => {
+                if *client.is_active.read().unwrap() { VmState::Running } else { VmState::Idle }
+            }
+            _ => VmState::Pending,
+        };
+        if self.vm_state(ipaddr) != state {
+            self.force_transition(ipaddr, state);
+        }
+    }
+
+    /// Extends the lease of a client's virt server by the lease TTL.
+    fn renew_lease(&self, ipaddr: &str) {
+        if let Some(ttl) = self.lease_ttl {
+            self.leases.lock().unwrap().insert(ipaddr.to_string(), Instant::now() + ttl);
+        }
+    }
+
+    /// Returns the time left until the lease of a client's virt server expires.
+    pub fn lease_remaining(&self, ipaddr: &str) -> Option<Duration> {
+        let leases = self.leases.lock().unwrap();
+        leases.get(ipaddr).map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Reclaims the virt servers of clients whose daemons stopped renewing their leases.
+    pub fn start_lease_reaper(&self) {
+        let ttl = match self.lease_ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        loop {
+            thread::sleep((ttl / 4).max(Duration::from_secs(1)));
+
+            let now = Instant::now();
+            let expired = self.leases.lock().unwrap().iter()
+                .filter(|(_, expires_at)| **expires_at <= now)
+                .map(|(ipaddr, _)| ipaddr.clone())
+                .collect::<Vec<String>>();
+            for ipaddr in expired {
+                self.reclaim_lease(&ipaddr);
+            }
+        }
+    }
+
+    fn reclaim_lease(&self, ipaddr: &str) {
+        let mut client = match self.get_client(ipaddr) {
+            Some(client) if client.virt_server.is_some() => client,
+            _ => {
+                self.leases.lock().unwrap().remove(ipaddr);
+                return;
+            }
+        };
+        let (virt_ip, rpc_id) = virt_server_key(&client.virt_server).unwrap();
+        log::warn!("Lease of client {} on virt server {}/{} expired", ipaddr, virt_ip, rpc_id);
+
+        // tried again on the next check
+        let _operation = match self.try_start_operation(ipaddr, "lease reclaim") {
+            Ok(operation) => operation,
+            Err(e) => {
+                log::info!("Not reclaiming virt server of client {} yet: {}", ipaddr, e);
+                return;
+            }
+        };
+        if let Err(e) = self.transition(ipaddr, VmState::Deallocating) {
+            log::info!("Not reclaiming virt server of client {} yet: {}", ipaddr, e);
+            return;
+        }
+
+        match self.server_nodes_manager.find_virt_server(&virt_ip, rpc_id) {
+            Ok(Some(_)) => {
+                if let Err(e) = self.server_nodes_manager.free_virt_server(&virt_ip, rpc_id) {
+                    log::error!("Error reclaiming virt server {}/{}: {}", virt_ip, rpc_id, e);
+                    self.settle(ipaddr);
+                    return;
+                }
+            }
+            Ok(None) => {}
+            // tried again once the server node is back
+            Err(e) => {
+                log::error!("Error reclaiming virt server {}/{}: {}", virt_ip, rpc_id, e);
+                self.settle(ipaddr);
+                return;
+            }
+        }
+
+        // a daemon that is still there finds out when it reconnects
+        client.stream.write().unwrap().take();
+        client.virt_server = None;
+        self.update_client(client);
+        self.settle(ipaddr);
+    }
+
+    /// Records that the VM's virt server was checkpointed and freed, in the journal as well so that a
+    /// manager taking over can still wake the VM up.
+    pub fn mark_hibernated(&self, ipaddr: &str, hibernated: HibernatedVm) {
+        self.force_transition(ipaddr, VmState::Hibernated);
+        if let Some(mut client) = self.get_client(ipaddr) {
+            client.virt_server = None;
+            self.update_client(client);
+        }
+        if let Some(journal) = self.journal {
+            journal.record(&JournalEntry::Hibernate {
+                client_ip: ipaddr.to_string(),
+                compute_units: hibernated.compute_units,
+                memory: hibernated.memory,
+                ckp_path: hibernated.ckp_path.clone(),
+            });
+        }
+        self.hibernated.lock().unwrap().insert(ipaddr.to_string(), hibernated);
+    }
+
+    pub fn hibernated_vm(&self, ipaddr: &str) -> Option<HibernatedVm> {
+        self.hibernated.lock().unwrap().get(ipaddr).cloned()
+    }
+
+    pub fn clear_hibernated(&self, ipaddr: &str) {
+        self.hibernated.lock().unwrap().remove(ipaddr);
+    }
+
+    /// Keeps assignments replayed from the journal until the server nodes report their virt servers, and
+    /// takes over the hibernated VMs so they can be woken up.
+    pub fn restore_journal(&self, state: JournalState) {
+        log::info!("Restoring {} virt server assignments and {} hibernated VMs from the journal", state.assignments.len(), state.hibernated.len());
+        *self.pending_assignments.lock().unwrap() = state.assignments;
+        for (ipaddr, hibernated) in state.hibernated {
+            self.set_vm_state(&ipaddr, VmState::Hibernated);
+            self.hibernated.lock().unwrap().insert(ipaddr, hibernated);
+        }
     }
 
     pub fn get_all_clients(&self) -> Vec<FlytClientNode> {
@@ -114,15 +396,25 @@ impl<'a> FlytClientManager<'a> {
     }
 
     pub fn stop_client(&self, ipaddr: &str) -> Result<(),String> {
+        self.pause_client_with(ipaddr, FlytApiCommand::RMGR_CLIENTD_PAUSE)
+    }
+
+    /// Pauses the vCUDA clients of a VM like `stop_client`, and has them ask for a wake up on their
+    /// next CUDA call.
+    pub fn hibernate_client(&self, ipaddr: &str) -> Result<(),String> {
+        self.pause_client_with(ipaddr, FlytApiCommand::RMGR_CLIENTD_HIBERNATE)
+    }
+
+    fn pause_client_with(&self, ipaddr: &str, command: &str) -> Result<(),String> {
         if self.exists(ipaddr) {
             let client = self.get_client(ipaddr).unwrap();
             if client.stream.read().unwrap().is_some() {
-                let writer_resp = { get_writer!(client).write_all(format!("{}\n", FlytApiCommand::RMGR_CLIENTD_PAUSE).as_bytes()) };
+                let writer_resp = { get_writer!(client).write_all(format!("{}\n", command).as_bytes()) };
                 match writer_resp {
                     Ok(_) => {
                         let response = match StreamUtils::read_response(get_reader!(client), 2) {
                             Ok(response) => {
-                                log::info!("Response from client {} for stop: {:?}", ipaddr, response);
+                                log::info!("Response from client {} for {}: {:?}", ipaddr, command, response);
                                 response
                             }
                             Err(e) => {
@@ -241,21 +533,25 @@ impl<'a> FlytClientManager<'a> {
         }
     }
 
-    pub fn start_flytclient_handler<'b>(&'b self, port: u16, scope: &'b thread::Scope<'b, '_>) {
+    /// Serves every client connection on its own thread, so a wake-up or allocation does not hold up
+    /// other clients' connects and lease renewals. Operations on the same VM still run one at a time.
+    pub fn start_flytclient_handler(&self, port: u16) {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_flytclient(stream, scope)
-                }
-                Err(e) => {
-                    log::error!("Error: {}", e);
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        s.spawn(move || self.handle_flytclient(stream));
+                    }
+                    Err(e) => {
+                        log::error!("Error: {}", e);
+                    }
                 }
             }
-        }
+        });
     }
 
-    fn handle_flytclient<'b>(&'b self, mut stream: TcpStream, scope: &'b thread::Scope<'b, '_>) {
+    fn handle_flytclient(&self, mut stream: TcpStream) {
         let client_ip = stream.peer_addr().unwrap().ip().to_string();
 
         let stream_clone = match stream.try_clone() {
@@ -282,6 +578,7 @@ impl<'a> FlytClientManager<'a> {
 
                 if self.exists(&client_ip) && self.get_client(&client_ip).unwrap().virt_server.is_some() {
                     self.set_client_status(&client_ip, true);
+                    self.renew_lease(&client_ip);
                     let client = self.get_client(&client_ip).unwrap();
                     let virt_server = client.virt_server.as_ref().unwrap().read().unwrap();
                     match stream.write_all(format!("200\n{},{}\n", virt_server.ipaddr, virt_server.rpc_id).as_bytes()) {
@@ -294,30 +591,84 @@ impl<'a> FlytClientManager<'a> {
                     }
                 }
                 else {
-                    let virt_server = self.server_nodes_manager.allocate_vm_resources(&client_ip);
-                    if virt_server.is_ok() {
-                        let virt_server = virt_server.unwrap();
-                        let client = FlytClientNode {
-                            ipaddr: client_ip.clone(),
-                            stream: Arc::new(RwLock::new(Some(StreamEnds{writer: stream, reader}))),
-                            virt_server: Some(virt_server.clone()),
-                            is_active: RwLock::new(true),
-                        };
-                        let client_stream_clone = client.stream.clone();
-                        let mut client_stream_clone_writer = client_stream_clone.write().unwrap();
-                        match client_stream_clone_writer.as_mut().unwrap().writer.write_all(format!("200\n{},{}\n", virt_server.read().unwrap().ipaddr, virt_server.read().unwrap().rpc_id).as_bytes()) {
-                            Ok(_) => {
-                                log::info!("Adding new client {}", client_ip);
-                                self.add_client(client);
-                            }
-                            Err(e) => {
-                                log::error!("Error writing to stream: {}", e);
+                    let _operation = match self.try_start_operation(&client_ip, "allocation") {
+                        Ok(operation) => operation,
+                        Err(e) => {
+                            let _ = stream.write_all(format!("409\n{}\n", e).as_bytes());
+                            return;
+                        }
+                    };
+                    // a restarted client daemon has no paused processes left to restore
+                    self.clear_hibernated(&client_ip);
+                    if let Err(e) = self.transition(&client_ip, VmState::Allocating) {
+                        let _ = stream.write_all(format!("409\n{}\n", e).as_bytes());
+                        return;
+                    }
+                    match self.server_nodes_manager.allocate_vm_resources(&client_ip) {
+                        Ok(virt_server) => {
+                            let client = FlytClientNode {
+                                ipaddr: client_ip.clone(),
+                                stream: Arc::new(RwLock::new(Some(StreamEnds{writer: stream, reader}))),
+                                virt_server: Some(virt_server.clone()),
+                                is_active: RwLock::new(true),
+                            };
+                            let client_stream_clone = client.stream.clone();
+                            let mut client_stream_clone_writer = client_stream_clone.write().unwrap();
+                            match client_stream_clone_writer.as_mut().unwrap().writer.write_all(format!("200\n{},{}\n", virt_server.read().unwrap().ipaddr, virt_server.read().unwrap().rpc_id).as_bytes()) {
+                                Ok(_) => {
+                                    log::info!("Adding new client {}", client_ip);
+                                    self.add_client(client);
+                                    self.settle(&client_ip);
+                                }
+                                Err(e) => {
+                                    log::error!("Error writing to stream: {}", e);
+                                    self.forget_vm_state(&client_ip);
+                                }
                             }
                         }
+                        Err(e) => {
+                            self.forget_vm_state(&client_ip);
+                            let _ = stream.write_all(format!("500\n{}\n", e).as_bytes());
+                        }
                     }
-                    else {
-                        let _ = stream.write_all(format!("500\n{}\n", virt_server.unwrap_err()).as_bytes());
+                }
+            },
+
+            FlytApiCommand::CLIENTD_RMGR_RECONNECT => {
+                log::info!("CLIENTD_RMGR_RECONNECT command received from client {}", client_ip);
+                self.handle_reconnect(client_ip, stream, reader);
+            },
+
+            FlytApiCommand::CLIENTD_RMGR_WAKE => {
+                log::info!("CLIENTD_RMGR_WAKE command received from client {}", client_ip);
+                let response = match self.start_operation(&client_ip, "wake-up")
+                    .and_then(|_operation| self.server_nodes_manager.wake_virt_server(self, &client_ip)) {
+                    Ok(virt_server) => {
+                        let virt_server = virt_server.read().unwrap();
+                        format!("200\n{},{}\n", virt_server.ipaddr, virt_server.rpc_id)
+                    }
+                    Err(e) => {
+                        log::error!("Error waking client VM {}: {}", client_ip, e);
+                        format!("500\n{}\n", e)
                     }
+                };
+                if let Err(e) = stream.write_all(response.as_bytes()) {
+                    log::error!("Error writing response to stream: {}", e);
+                }
+            },
+
+            FlytApiCommand::CLIENTD_RMGR_RENEW_LEASE => {
+                log::trace!("CLIENTD_RMGR_RENEW_LEASE command received from client {}", client_ip);
+                let response = if self.get_client(&client_ip).is_some_and(|client| client.virt_server.is_some()) {
+                    self.renew_lease(&client_ip);
+                    // a TTL of 0 means leases do not expire
+                    format!("200\n{}\n", self.lease_ttl.map(|ttl| ttl.as_secs()).unwrap_or(0))
+                }
+                else {
+                    "404\nNo virt server allocated\n".to_string()
+                };
+                if let Err(e) = stream.write_all(response.as_bytes()) {
+                    log::error!("Error writing response to stream: {}", e);
                 }
             },
 
@@ -325,12 +676,7 @@ impl<'a> FlytClientManager<'a> {
                 log::info!("CLIENTD_RMGR_ZERO_VCUDA_CLIENTS command received from client {}", client_ip);
                 if self.get_client_status(&client_ip) {
                     self.set_client_status(&client_ip, false);
-                    if let Some(dealloc_time) = get_virt_server_deallocate_time() {
-                        scope.spawn(move || {
-                            thread::sleep(std::time::Duration::from_secs(dealloc_time));
-                            let _ = self.deallocate_vm_resources(&client_ip);
-                        });
-                    }
+                    self.schedule_deallocation(client_ip);
                 }
                 match stream.write_all("200\nDone\n".as_bytes()) {
                     Ok(_) => {}
@@ -347,7 +693,326 @@ impl<'a> FlytClientManager<'a> {
         
     }
 
+    /// Starts the deallocation timer of an idle client, with the grace period from its VM spec if set.
+    fn schedule_deallocation(&self, client_ip: String) {
+        let vm_grace_period = self.server_nodes_manager.vm_resource_getter().get_vm_required_resources(&client_ip)
+            .and_then(|vm_resources| vm_resources.idle_grace_period);
+        if let Some(grace_period) = vm_grace_period.or_else(get_virt_server_deallocate_time) {
+            log::info!("Deallocating virt server of client {} in {}s unless it becomes active", client_ip, grace_period);
+            self.deallocation_timers.schedule(&client_ip, Duration::from_secs(grace_period));
+        }
+    }
+
+    /// Returns the clients whose virt servers are about to be deallocated and the time left for each.
+    pub fn pending_deallocations(&self) -> Vec<(String, Duration)> {
+        self.deallocation_timers.pending()
+    }
+
+    /// Deallocates virt servers whose clients stayed idle for the grace period. The timer thread hands
+    /// expired clients to a worker, so a slow server node does not delay the other timers.
+    pub fn start_deallocation_timers(&self) {
+        let (sender, receiver) = mpsc::channel::<String>();
+        thread::scope(|s| {
+            s.spawn(|| self.deallocation_timers.run(sender));
+
+            for client_ip in receiver {
+                self.deallocate_idle_client(&client_ip);
+            }
+        });
+    }
+
+    fn deallocate_idle_client(&self, client_ip: &str) {
+        let _operation = match self.try_start_operation(client_ip, "deallocation") {
+            Ok(operation) => operation,
+            Err(e) => {
+                // checked again once the grace period passed another time
+                log::info!("Deferring deallocation of client {}: {}", client_ip, e);
+                self.schedule_deallocation(client_ip.to_string());
+                return;
+            }
+        };
+        if let Err(e) = self.deallocate_vm_resources(client_ip) {
+            log::info!("Virt server of client {} not deallocated: {}", client_ip, e);
+        }
+    }
+
+    /// A client daemon that lost its resource manager registers the virt server it is using.
+    /// The client is kept on that virt server if its server node reported it, otherwise it gets the one
+    /// this manager assigned to it. Replies 404 if neither exists and 503 if its server node has not
+    /// connected yet.
+    fn handle_reconnect(&self, client_ip: String, mut stream: TcpStream, mut reader: BufReader<TcpStream>) {
+        let payload = match StreamUtils::read_line(&mut reader) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Error reading virt server from client {}: {}", client_ip, e);
+                return;
+            }
+        };
+
+        // format: virt_server_address,rpc_id,num_clients
+        let parts = payload.split(",").collect::<Vec<&str>>();
+        let rpc_id = parts.get(1).and_then(|s| s.parse::<u64>().ok());
+        let num_clients = parts.get(2).and_then(|s| s.parse::<u64>().ok());
+
+        let (virt_ip, rpc_id, num_clients) = match (rpc_id, num_clients) {
+            (Some(rpc_id), Some(num_clients)) => (parts[0].to_string(), rpc_id, num_clients),
+            _ => {
+                log::error!("Invalid reconnect from client {}: {}", client_ip, payload);
+                let _ = stream.write_all("400\nInvalid arguments\n".as_bytes());
+                return;
+            }
+        };
+
+        // the client daemon tries again with backoff
+        let _operation = match self.try_start_operation(&client_ip, "reconnect") {
+            Ok(operation) => operation,
+            Err(e) => {
+                let _ = stream.write_all(format!("409\n{}\n", e).as_bytes());
+                return;
+            }
+        };
+
+        // a hibernated VM has no virt server, its command stream is kept so that it can be woken up
+        if self.hibernated_vm(&client_ip).is_some() {
+            self.reconnect_hibernated(client_ip, &virt_ip, rpc_id, num_clients > 0, stream, reader);
+            return;
+        }
+
+        // the client's applications run on the virt server it reports, so it is kept whenever it still exists
+        let known = self.get_client(&client_ip).and_then(|client| client.virt_server.clone());
+        let virt_server = match (self.server_nodes_manager.find_virt_server(&virt_ip, rpc_id), known) {
+            (Ok(Some(virt_server)), known) => {
+                if let Some(holder) = self.virt_server_holder(&virt_server).filter(|holder| *holder != client_ip) {
+                    log::warn!("Client {} reconnected with virt server {}/{} held by client {}", client_ip, virt_ip, rpc_id, holder);
+                    let _ = stream.write_all("409\nVirt server is held by another client\n".as_bytes());
+                    return;
+                }
+                if !self.is_assigned(&client_ip, &virt_server, known.as_ref()) {
+                    log::warn!("Client {} reconnected with virt server {}/{} that was not assigned to it", client_ip, virt_ip, rpc_id);
+                    let _ = stream.write_all("409\nVirt server is not assigned to this client\n".as_bytes());
+                    return;
+                }
+                if let Some(known) = known.filter(|known| !Arc::ptr_eq(known, &virt_server)) {
+                    self.release_stale_virt_server(&client_ip, &known);
+                }
+                virt_server
+            }
+            (_, Some(known)) => known,
+            (Ok(None), None) => {
+                log::warn!("Client {} reconnected with unknown virt server {}/{}", client_ip, virt_ip, rpc_id);
+                let _ = stream.write_all("404\nVirt server not found\n".as_bytes());
+                return;
+            }
+            (Err(e), None) => {
+                let _ = stream.write_all(format!("503\n{}\n", e).as_bytes());
+                return;
+            }
+        };
+
+        // a resumed session supersedes what the journal recorded for the client
+        self.pending_assignments.lock().unwrap().remove(&client_ip);
+
+        let response = {
+            let virt_server = virt_server.read().unwrap();
+            format!("200\n{},{}\n", virt_server.ipaddr, virt_server.rpc_id)
+        };
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            log::error!("Error writing to stream: {}", e);
+            return;
+        }
+
+        let is_active = num_clients > 0;
+        self.renew_lease(&client_ip);
+        if self.exists(&client_ip) {
+            let mut client = self.get_client(&client_ip).unwrap();
+            client.virt_server = Some(virt_server);
+            self.update_client(client);
+            self.update_stream(&client_ip, stream, reader);
+            self.set_client_status(&client_ip, is_active);
+        } else {
+            log::info!("Adding reconnected client {}", client_ip);
+            self.add_client(FlytClientNode {
+                ipaddr: client_ip.clone(),
+                stream: Arc::new(RwLock::new(Some(StreamEnds{writer: stream, reader}))),
+                virt_server: Some(virt_server),
+                is_active: RwLock::new(is_active),
+            });
+        }
+
+        // a VM being worked on keeps its state
+        let state = if is_active { VmState::Running } else { VmState::Idle };
+        if matches!(self.vm_state(&client_ip), VmState::Pending | VmState::Deallocating) {
+            self.force_transition(&client_ip, state);
+        }
+
+        if !is_active {
+            self.schedule_deallocation(client_ip);
+        }
+    }
+
+    /// Acknowledges the virt server a hibernated VM names, which no longer exists, and keeps the VM's command
+    /// stream for waking it up.
+    fn reconnect_hibernated(&self, client_ip: String, virt_ip: &str, rpc_id: u64, is_active: bool, mut stream: TcpStream, reader: BufReader<TcpStream>) {
+        if let Err(e) = stream.write_all(format!("200\n{},{}\n", virt_ip, rpc_id).as_bytes()) {
+            log::error!("Error writing to stream: {}", e);
+            return;
+        }
+
+        log::info!("Hibernated client {} reconnected", client_ip);
+        if self.exists(&client_ip) {
+            self.update_stream(&client_ip, stream, reader);
+            self.set_client_status(&client_ip, is_active);
+        } else {
+            self.add_client(FlytClientNode {
+                ipaddr: client_ip,
+                stream: Arc::new(RwLock::new(Some(StreamEnds{writer: stream, reader}))),
+                virt_server: None,
+                is_active: RwLock::new(is_active),
+            });
+        }
+    }
+
+    /// Whether `virt_server` was assigned to `client_ip`: it is the client's known virt server or the journal
+    /// replayed at startup assigned it to the client. Without a journal a restarted manager has no record of
+    /// assignments, so any virt server no other client holds is accepted.
+    fn is_assigned(&self, client_ip: &str, virt_server: &Arc<RwLock<VirtServer>>, known: Option<&Arc<RwLock<VirtServer>>>) -> bool {
+        if known.is_some_and(|known| Arc::ptr_eq(known, virt_server)) {
+            return true;
+        }
+        let key = virt_server_key(&Some(virt_server.clone()));
+        if self.pending_assignments.lock().unwrap().get(client_ip).is_some_and(|assigned| Some(assigned) == key.as_ref()) {
+            return true;
+        }
+        self.journal.is_none()
+    }
+
+    /// The client whose virt server is `virt_server`, if any.
+    fn virt_server_holder(&self, virt_server: &Arc<RwLock<VirtServer>>) -> Option<String> {
+        let clients = self.clients.lock().unwrap();
+        clients.values()
+            .find(|client| client.virt_server.as_ref().is_some_and(|held| Arc::ptr_eq(held, virt_server)))
+            .map(|client| client.ipaddr.clone())
+    }
+
+    /// Frees a virt server recorded for a client that resumed its session on another one, e.g. after
+    /// a migration whose last step did not reach the client.
+    fn release_stale_virt_server(&self, client_ip: &str, virt_server: &Arc<RwLock<VirtServer>>) {
+        let (virt_ip, rpc_id) = {
+            let virt_server = virt_server.read().unwrap();
+            (virt_server.ipaddr.clone(), virt_server.rpc_id)
+        };
+        log::warn!("Client {} resumed on another virt server, freeing {}/{}", client_ip, virt_ip, rpc_id);
+        if let Err(e) = self.server_nodes_manager.free_virt_server(&virt_ip, rpc_id) {
+            log::error!("Error freeing virt server {}/{}: {}", virt_ip, rpc_id, e);
+        }
+    }
+
+    /// Detaches a virt server that exited on its server node from the client using it. The client
+    /// gets a new virt server the next time it connects.
+    pub fn virt_server_exited(&self, server_ip: &str, rpc_id: u64) {
+        let uses_virt_server = |client: &FlytClientNode| {
+            client.virt_server.as_ref().is_some_and(|virt_server| {
+                let virt_server = virt_server.read().unwrap();
+                virt_server.ipaddr == server_ip && virt_server.rpc_id == rpc_id
+            })
+        };
+
+        let client_ip = match self.get_all_clients().into_iter().find(|client| uses_virt_server(client)) {
+            Some(client) => client.ipaddr,
+            None => {
+                log::info!("No client was using virt server {}/{}", server_ip, rpc_id);
+                return;
+            }
+        };
+
+        let _operation = match self.start_operation(&client_ip, "virt server exit") {
+            Ok(operation) => operation,
+            Err(e) => {
+                log::error!("Error detaching virt server {}/{} from client {}: {}", server_ip, rpc_id, client_ip, e);
+                return;
+            }
+        };
+
+        // the operation that ran before may have moved the client elsewhere
+        let mut client = match self.get_client(&client_ip).filter(|client| uses_virt_server(client)) {
+            Some(client) => client,
+            None => return,
+        };
+
+        log::warn!("Virt server of client {} exited", client.ipaddr);
+
+        if client.stream.read().unwrap().is_some() {
+            let res = get_writer!(client).write_all(format!("{}\n", FlytApiCommand::RMGR_CLIENTD_DEALLOC_VIRT_SERVER).as_bytes())
+                .and_then(|_| StreamUtils::read_response(get_reader!(client), 2));
+            match res {
+                Ok(response) if response[0] == "200" => {}
+                Ok(response) => log::error!("Client {} did not release its virt server: {:?}", client.ipaddr, response),
+                Err(e) => log::error!("Error notifying client {}: {}", client.ipaddr, e),
+            }
+        }
+
+        client.virt_server = None;
+        self.force_transition(&client.ipaddr, VmState::Pending);
+        self.update_client(client);
+    }
+
+    /// Gives a virt server reported by a reconnecting server node back to the client the journal
+    /// assigned it to. The client's stream is set once its daemon reconnects.
+    pub fn virt_server_adopted(&self, server_ip: &str, rpc_id: u64) {
+        let is_assigned = |(virt_ip, id): &(String, u64)| virt_ip == server_ip && *id == rpc_id;
+        let client_ip = match self.pending_assignments.lock().unwrap().iter()
+            .find(|(_, assignment)| is_assigned(assignment))
+            .map(|(client_ip, _)| client_ip.clone()) {
+            Some(client_ip) => client_ip,
+            None => return,
+        };
+
+        let _operation = match self.start_operation(&client_ip, "adoption") {
+            Ok(operation) => operation,
+            Err(e) => {
+                log::error!("Error restoring virt server {}/{} of client {}: {}", server_ip, rpc_id, client_ip, e);
+                return;
+            }
+        };
+
+        {
+            let mut pending = self.pending_assignments.lock().unwrap();
+            if !pending.get(&client_ip).is_some_and(is_assigned) {
+                return;
+            }
+            pending.remove(&client_ip);
+        }
+
+        // the client daemon reconnected first and already told us
+        if self.get_client(&client_ip).is_some_and(|client| client.virt_server.is_some()) {
+            return;
+        }
+
+        let virt_server = match self.server_nodes_manager.find_virt_server(server_ip, rpc_id) {
+            Ok(Some(virt_server)) => virt_server,
+            _ => return,
+        };
+
+        log::info!("Restored virt server {}/{} of client {}", server_ip, rpc_id, client_ip);
+        let mut client = self.get_client(&client_ip).unwrap_or(FlytClientNode {
+            ipaddr: client_ip.clone(),
+            stream: Arc::new(RwLock::new(None)),
+            virt_server: None,
+            is_active: RwLock::new(true),
+        });
+        client.virt_server = Some(virt_server);
+        self.update_client(client);
+        self.settle(&client_ip);
+    }
+
     fn deallocate_vm_resources(&self, ipaddr: &str) -> Result<(),String> {
+        self.transition(ipaddr, VmState::Deallocating)?;
+        let res = self.release_vm_resources(ipaddr);
+        self.settle(ipaddr);
+        res
+    }
+
+    fn release_vm_resources(&self, ipaddr: &str) -> Result<(),String> {
         log::info!("Deallocating virt server for client: {}", ipaddr);
         let mut client = self.get_client(ipaddr).ok_or("Client not found".to_string())?;
         if client.virt_server.is_none() {
@@ -1,6 +1,7 @@
-use std::{fs, io::BufReader, os::unix::net::{UnixListener, UnixStream}, path::Path};
+use std::{fs, io::BufReader, thread, os::unix::net::{UnixListener, UnixStream}, path::Path, time::{SystemTime, UNIX_EPOCH}};
 
 use crate::{client_handler::FlytClientManager, common::{api_commands::FrontEndCommand, utils::StreamUtils}, servernode_handler::ServerNodesManager};
+use crate::lifecycle::{VmLifecycle, VmState};
 
 
 #[derive(PartialEq, Debug)]
@@ -10,6 +11,10 @@ enum ChangeConfigFor {
     Both,
 }
 
+fn seconds_since(time: SystemTime) -> u64 {
+    time.elapsed().map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
 pub struct FrontendHandler<'a> {
     client_mgr: &'a FlytClientManager<'a>,
     server_nodes_manager: &'a ServerNodesManager<'a>,
@@ -33,20 +38,24 @@ impl <'a> FrontendHandler<'a> {
 
         log::info!("Frontend handler listening on {}", socket_path);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_request(stream);
-                }
-                Err(e) => {
-                    log::error!("Error: {}", e);
-                    break;
+        // every request gets its own thread, so listing is not held up by a migration or resize
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        s.spawn(move || self.handle_request(stream));
+                    }
+                    Err(e) => {
+                        log::error!("Error: {}", e);
+                        break;
+                    }
                 }
             }
-        }
+        });
     }
 
-    fn handle_request(&self, stream: UnixStream) {
+    /// Serves one command of the line protocol used by `flytctl`.
+    pub fn handle_request(&self, stream: UnixStream) {
         let reader_clone = match stream.try_clone() {
             Ok(stream) => stream,
             Err(e) => {
@@ -89,6 +98,18 @@ impl <'a> FrontendHandler<'a> {
             FrontEndCommand::MIGRATE_VIRT_SERVER_AUTO => {
                 self.migrate_vm_auto(stream, reader);
             }
+            FrontEndCommand::TOP => {
+                self.top(stream);
+            }
+            FrontEndCommand::VIRT_SERVER_LOGS => {
+                self.virt_server_logs(stream, reader);
+            }
+            FrontEndCommand::RESCAN_GPUS => {
+                self.rescan_gpus(stream, reader);
+            }
+            FrontEndCommand::LIST_PENDING_DEALLOCATIONS => {
+                self.list_pending_deallocations(stream);
+            }
             _ => {
                 log::error!("Invalid command: {}", command);
             }
@@ -143,6 +164,21 @@ impl <'a> FrontendHandler<'a> {
             }
         };
 
+        let _operation = match self.client_mgr.start_operation(ipaddr, "migration") {
+            Ok(operation) => operation,
+            Err(e) => {
+                let _ = StreamUtils::write_all(&mut stream, format!("409\n{}\n", e));
+                return;
+            }
+        };
+
+        // a VM another operation is working on must not be resumed below
+        let state = self.client_mgr.vm_state(ipaddr);
+        if !state.is_settled() {
+            let _ = StreamUtils::write_all(&mut stream, format!("409\nVM {} is {}\n", ipaddr, state));
+            return;
+        }
+
         log::info!("Migrating VM: {} to server: {} with gpu_id: {}", ipaddr, new_server_ip, new_server_gpu_id);
         let res = self.server_nodes_manager.migrate_virt_server(
             self.client_mgr, 
@@ -152,17 +188,19 @@ impl <'a> FrontendHandler<'a> {
             new_server_compute_units,
             new_server_memory);
         
-        if res.is_err() {
-            log::error!("Error migrating VM: {}", res.clone().unwrap_err());
-            
-            // resume the client if stopped
-            let _ = self.client_mgr.resume_client(ipaddr);
+        match res {
+            Err(e) => {
+                log::error!("Error migrating VM: {}", e);
+                
+                // resume the client if stopped
+                let _ = self.client_mgr.resume_client(ipaddr);
 
-            let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", res.unwrap_err()));
-        }
-        else {
-            log::info!("VM migrated successfully");
-            let _ = StreamUtils::write_all(&mut stream, "200\nVM migrated successfully\n".to_string());
+                let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", e));
+            }
+            Ok(_) => {
+                log::info!("VM migrated successfully");
+                let _ = StreamUtils::write_all(&mut stream, "200\nVM migrated successfully\n".to_string());
+            }
         }
     
     }
@@ -206,6 +244,21 @@ impl <'a> FrontendHandler<'a> {
             }
         };
 
+        let _operation = match self.client_mgr.start_operation(ipaddr, "migration") {
+            Ok(operation) => operation,
+            Err(e) => {
+                let _ = StreamUtils::write_all(&mut stream, format!("409\n{}\n", e));
+                return;
+            }
+        };
+
+        // a VM another operation is working on must not be resumed below
+        let state = self.client_mgr.vm_state(ipaddr);
+        if !state.is_settled() {
+            let _ = StreamUtils::write_all(&mut stream, format!("409\nVM {} is {}\n", ipaddr, state));
+            return;
+        }
+
         log::info!("Migrating VM: {}", ipaddr);
         let res = self.server_nodes_manager.migrate_virt_server_auto(
             self.client_mgr, 
@@ -213,46 +266,75 @@ impl <'a> FrontendHandler<'a> {
             new_server_compute_units,
             new_server_memory);
         
-        if res.is_err() {
-            log::error!("Error migrating VM: {}", res.clone().unwrap_err());
-            
-            // resume the client if stopped
-            let _ = self.client_mgr.resume_client(ipaddr);
+        match res {
+            Err(e) => {
+                log::error!("Error migrating VM: {}", e);
+                
+                // resume the client if stopped
+                let _ = self.client_mgr.resume_client(ipaddr);
 
-            let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", res.unwrap_err()));
-        }
-        else {
-            log::info!("VM migrated successfully to server: {}", res.unwrap().read().unwrap().ipaddr );
-            let _ = StreamUtils::write_all(&mut stream, "200\nVM migrated successfully\n".to_string());
+                let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", e));
+            }
+            Ok(virt_server) => {
+                log::info!("VM migrated successfully to server: {}", virt_server.read().unwrap().ipaddr);
+                let _ = StreamUtils::write_all(&mut stream, "200\nVM migrated successfully\n".to_string());
+            }
         }
     
     }
 
     fn list_vms(&self, mut stream: UnixStream){
         let vms = self.client_mgr.get_all_clients();
-        let mut response = String::new();
-        response.push_str(format!("200\n{}\n", vms.len()).as_str());
+        let mut lifecycles = self.client_mgr.all_vm_lifecycles();
+        let mut rows = Vec::new();
         for vm in vms {
-            // format: vmip,servnode_ip,servnode_rpcid,sm_cores,memory,isactive
+            let lease = match self.client_mgr.lease_remaining(&vm.ipaddr) {
+                Some(remaining) => format!("{}s", remaining.as_secs()),
+                None => String::new(),
+            };
+            let lifecycle = lifecycles.remove(&vm.ipaddr).unwrap_or(VmLifecycle::new(VmState::Pending));
+            // format: vmip,servnode_ip,servnode_rpcid,sm_cores,memory,isactive,lease_expires_in,state,time_in_state
             if let Some(virt_server) = vm.virt_server {
                 let virt_server = virt_server.read().unwrap();
-                response.push_str(&format!("{},{},{},{},{},{}\n",
+                rows.push(format!("{},{},{},{},{},{},{},{},{}s\n",
                     vm.ipaddr,
                     virt_server.ipaddr,
                     virt_server.rpc_id,
                     virt_server.compute_units,
                     virt_server.memory,
-                    *vm.is_active.read().unwrap()
+                    *vm.is_active.read().unwrap(),
+                    lease,
+                    lifecycle.state,
+                    seconds_since(lifecycle.since)
                 ));
             }
             else {
-                response.push_str(&format!("{},,,,,{}\n",
+                rows.push(format!("{},,,,,{},,{},{}s\n",
                     vm.ipaddr,
-                    *vm.is_active.read().unwrap()
+                    *vm.is_active.read().unwrap(),
+                    lifecycle.state,
+                    seconds_since(lifecycle.since)
                 ));
             }
             
         }
+        // VMs being allocated have no client entry yet
+        for (vm_ip, lifecycle) in lifecycles {
+            rows.push(format!("{},,,,,false,,{},{}s\n", vm_ip, lifecycle.state, seconds_since(lifecycle.since)));
+        }
+
+        let mut response = format!("200\n{}\n", rows.len());
+        response.extend(rows);
+        let _ = StreamUtils::write_all(&mut stream, response);
+    }
+
+    fn list_pending_deallocations(&self, mut stream: UnixStream) {
+        let pending = self.client_mgr.pending_deallocations();
+        let mut response = format!("200\n{}\n", pending.len());
+        for (vm_ip, remaining) in pending {
+            // format: vmip,seconds_left
+            response.push_str(&format!("{},{}\n", vm_ip, remaining.as_secs()));
+        }
         let _ = StreamUtils::write_all(&mut stream, response);
     }
 
@@ -261,19 +343,147 @@ impl <'a> FrontendHandler<'a> {
         let mut response = String::new();
         response.push_str(format!("200\n{}\n", server_nodes.len()).as_str());
         for server_node in server_nodes {
-            // format: ipaddr,num_vgpus
-            response.push_str(&format!("{},{}\n", server_node.ipaddr, server_node.gpus.len()));
+            // format: ipaddr,num_vgpus,flavor+flavor
+            let flavors = server_node.flavors.iter().map(|flavor| flavor.name.as_str()).collect::<Vec<&str>>().join("+");
+            response.push_str(&format!("{},{},{}\n", server_node.ipaddr, server_node.gpus.len(), flavors));
 
             for gpu in server_node.gpus.iter() {
-                // format: gpuid,name,memory,allocated_memory,compute_units,allocated_compute_units
+                // format: gpuid,name,memory,allocated_memory,compute_units,allocated_compute_units,isolation,parent_gpu_id,health,mps_status
                 let gpu = gpu.read().unwrap();
-                response.push_str(&format!("{},{},{},{},{},{}\n",
+                let isolation = if gpu.exclusive { format!("{} (dedicated)", gpu.isolation.as_str()) } else { gpu.isolation.as_str().to_string() };
+                response.push_str(&format!("{},{},{},{},{},{},{},{},{},{}\n",
                     gpu.gpu_id,
                     gpu.name,
                     gpu.memory,
                     gpu.allocated_memory,
                     gpu.compute_units,
-                    gpu.allocated_compute_units
+                    gpu.allocated_compute_units,
+                    isolation,
+                    gpu.parent_gpu_id,
+                    gpu.health.as_str(),
+                    gpu.mps.as_str()
+                ));
+            }
+        }
+        let _ = StreamUtils::write_all(&mut stream, response);
+    }
+
+    fn rescan_gpus(&self, mut stream: UnixStream, mut reader: BufReader<UnixStream>) {
+        let server_ip = match StreamUtils::read_line(&mut reader) {
+            Ok(server_ip) => server_ip,
+            Err(e) => {
+                log::error!("Error reading request params: {}", e);
+                return;
+            }
+        };
+
+        match self.server_nodes_manager.rescan_server_node_gpus(&server_ip) {
+            Ok(_) => {
+                let _ = StreamUtils::write_all(&mut stream, "200\nDone\n".to_string());
+            }
+            Err(e) => {
+                let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", e.replace('\n', " ")));
+            }
+        }
+    }
+
+    fn virt_server_logs(&self, mut stream: UnixStream, mut reader: BufReader<UnixStream>) {
+        let request_params = match StreamUtils::read_line(&mut reader) {
+            Ok(params) => params,
+            Err(e) => {
+                log::error!("Error reading request params: {}", e);
+                return;
+            }
+        };
+
+        // format: vm_ip,offset
+        let parts: Vec<&str> = request_params.split(',').collect();
+        let offset = parts.get(1).and_then(|s| s.parse::<u64>().ok());
+        if parts.len() != 2 || offset.is_none() {
+            log::error!("Invalid arguments for logs command: {:?}", parts);
+            let _ = StreamUtils::write_all(&mut stream, "400\nInvalid arguments\n".to_string());
+            return;
+        }
+
+        let virt_server = self.client_mgr.get_client(parts[0]).and_then(|client| client.virt_server);
+        let (snode_ip, rpc_id) = match virt_server {
+            Some(virt_server) => {
+                let virt_server = virt_server.read().unwrap();
+                (virt_server.ipaddr.clone(), virt_server.rpc_id)
+            }
+            None => {
+                let _ = StreamUtils::write_all(&mut stream, "404\nNo virt server allocated for VM\n".to_string());
+                return;
+            }
+        };
+
+        match self.server_nodes_manager.get_virt_server_logs(&snode_ip, rpc_id, offset.unwrap()) {
+            Ok((offset, lines)) => {
+                // format: offset,num_lines followed by the lines
+                let mut response = format!("200\n{},{}\n", offset, lines.len());
+                for line in lines {
+                    response.push_str(&line);
+                    response.push('\n');
+                }
+                let _ = StreamUtils::write_all(&mut stream, response);
+            }
+            Err(e) => {
+                let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", e.replace('\n', " ")));
+            }
+        }
+    }
+
+    fn top(&self, mut stream: UnixStream) {
+        let server_nodes = self.server_nodes_manager.get_all_server_nodes();
+        let telemetry = self.server_nodes_manager.telemetry();
+        let clients = self.client_mgr.get_all_clients();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        let mut response = String::new();
+        response.push_str(format!("200\n{}\n", server_nodes.len()).as_str());
+        for server_node in server_nodes {
+            let sample = match telemetry.latest(&server_node.ipaddr) {
+                Some(sample) => sample,
+                None => {
+                    response.push_str(&format!("{},0,0,\n", server_node.ipaddr));
+                    continue;
+                }
+            };
+
+            // format: ipaddr,num_gpus,num_virt_servers,sample_age
+            response.push_str(&format!("{},{},{},{}\n", server_node.ipaddr, sample.gpus.len(), sample.virt_servers.len(), now.saturating_sub(sample.timestamp)));
+
+            for gpu in sample.gpus.iter() {
+                // format: gpu_id,utilization,avg_utilization,memory_used,memory_total,power
+                let avg_utilization = telemetry.average_utilization(&server_node.ipaddr, gpu.gpu_id).unwrap_or(gpu.utilization);
+                response.push_str(&format!("{},{},{},{},{},{}\n",
+                    gpu.gpu_id,
+                    gpu.utilization,
+                    avg_utilization,
+                    gpu.memory_used,
+                    gpu.memory_total,
+                    gpu.power
+                ));
+            }
+
+            for virt_server in sample.virt_servers.iter() {
+                let vm_ip = clients.iter().find(|client| {
+                    client.virt_server.as_ref().is_some_and(|vs| {
+                        let vs = vs.read().unwrap();
+                        vs.ipaddr == server_node.ipaddr && vs.rpc_id == virt_server.rpc_id
+                    })
+                }).map(|client| client.ipaddr.clone()).unwrap_or_default();
+
+                // format: rpc_id,vm_ip,gpu_id,memory_used,sm_utilization,host_cpu_usage,host_memory,pids
+                response.push_str(&format!("{},{},{},{},{},{},{},{}\n",
+                    virt_server.rpc_id,
+                    vm_ip,
+                    virt_server.gpu_id,
+                    virt_server.memory_used,
+                    virt_server.sm_utilization,
+                    virt_server.host_cpu_usage,
+                    virt_server.host_memory,
+                    virt_server.pids
                 ));
             }
         }
@@ -322,6 +532,14 @@ impl <'a> FrontendHandler<'a> {
 
         log::info!("Changing resource for VM: {}, new resource: {} for {:?}", ipaddr, new_resource, change_for);
 
+        let _operation = match self.client_mgr.start_operation(ipaddr, "resize") {
+            Ok(operation) => operation,
+            Err(e) => {
+                let _ = StreamUtils::write_all(&mut stream, format!("409\n{}\n", e));
+                return;
+            }
+        };
+
         let client = self.client_mgr.get_client(ipaddr);
         if client.is_none() {
             log::error!("Client VM {} not found", ipaddr);
@@ -337,6 +555,11 @@ impl <'a> FrontendHandler<'a> {
             return;
         }
 
+        if let Err(e) = self.client_mgr.transition(ipaddr, VmState::Resizing) {
+            let _ = StreamUtils::write_all(&mut stream, format!("409\n{}\n", e));
+            return;
+        }
+
         let (virt_server_ip, virt_server_rpc_id, cur_compute, cur_mem) = {
             let virt_server = client.virt_server.as_ref().unwrap().read().unwrap();
             (virt_server.ipaddr.clone(), virt_server.rpc_id, virt_server.compute_units, virt_server.memory)
@@ -350,14 +573,17 @@ impl <'a> FrontendHandler<'a> {
                 self.server_nodes_manager.change_resource_configurations(&virt_server_ip, virt_server_rpc_id, new_resource as u32, mem_new)
             }
         };
+        self.client_mgr.settle(ipaddr);
 
-        if ret.is_ok() {
-            log::info!("Resource updated successfully");
-            let _ = StreamUtils::write_all(&mut stream, "200\nResource updated successfully\n".to_string());
-        }
-        else {
-            log::error!("Error updating resource: {:?}", ret);
-            let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", ret.unwrap_err()));
+        match ret {
+            Ok(_) => {
+                log::info!("Resource updated successfully");
+                let _ = StreamUtils::write_all(&mut stream, "200\nResource updated successfully\n".to_string());
+            }
+            Err(e) => {
+                log::error!("Error updating resource: {:?}", e);
+                let _ = StreamUtils::write_all(&mut stream, format!("500\n{}\n", e));
+            }
         }
 
     }
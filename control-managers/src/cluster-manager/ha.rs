@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::hibernation::HibernatedVm;
+
+/// Settings of `[ha]`, present when managers run as an active/standby pair.
+#[derive(Debug, Clone)]
+pub struct HaConfig {
+    /// Identifies this manager in the lease
+    pub node_id: String,
+    pub lease_path: String,
+    pub journal_path: String,
+    /// Time after its last renewal at which a standby may take the lease over
+    pub lease_ttl: Duration,
+    pub renew_period: Duration,
+    /// Largest difference between the clocks of the managers, the leader stops serving this much earlier
+    pub clock_skew: Duration,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or(0)
+}
+
+/// Holds an exclusive flock on a file until dropped.
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl<'a> FileLock<'a> {
+    fn lock(file: &'a File) -> Result<Self, String> {
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+        if ret != 0 {
+            return Err(format!("Error locking lease: {}", std::io::Error::last_os_error()));
+        }
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Leader lease in a file on storage shared by the managers, holding `<node_id>,<expires_at_ms>`.
+/// Reads and updates are serialised with flock, so two managers never both see the lease as theirs.
+/// The expiry is wall clock time, so the clocks of the managers must be kept in sync (e.g. with NTP)
+/// to within the `clock_skew` passed to `keep_renewing`.
+pub struct FileLease {
+    path: String,
+    node_id: String,
+    ttl: Duration,
+    /// When the last successful acquire or renewal started
+    renewed_at: Mutex<Option<Instant>>,
+}
+
+impl FileLease {
+    pub fn new(path: &str, node_id: &str, ttl: Duration) -> Self {
+        FileLease {
+            path: path.to_string(),
+            node_id: node_id.to_string(),
+            ttl,
+            renewed_at: Mutex::new(None),
+        }
+    }
+
+    /// Takes the lease if it is free or expired, or renews it if this manager holds it.
+    /// Returns false while another manager holds an unexpired lease.
+    pub fn try_acquire(&self) -> Result<bool, String> {
+        // taken before the expiry is computed, so the lease is never thought to last longer than it does
+        let started_at = Instant::now();
+        let acquired = self.update(|holder, expires_at, now| {
+            if holder != self.node_id && expires_at > now {
+                return None;
+            }
+            Some(now + self.ttl.as_millis() as u64)
+        })?;
+        if acquired {
+            *self.renewed_at.lock().unwrap() = Some(started_at);
+        }
+        Ok(acquired)
+    }
+
+    /// Gives the lease up, so a standby does not have to wait for it to expire.
+    pub fn release(&self) -> Result<(), String> {
+        self.update(|holder, _, now| {
+            if holder != self.node_id {
+                return None;
+            }
+            Some(now)
+        }).map(|_| ())
+    }
+
+    /// Runs `next_expiry` on the current holder and expiry under the lock, and writes this
+    /// manager as the holder with the returned expiry.
+    fn update<F: Fn(&str, u64, u64) -> Option<u64>>(&self, next_expiry: F) -> Result<bool, String> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)
+            .map_err(|e| format!("Error opening lease {}: {}", self.path, e))?;
+        let _lock = FileLock::lock(&file)?;
+        let mut file = &file;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(|e| format!("Error reading lease: {}", e))?;
+        let (holder, expires_at) = contents.trim().split_once(",")
+            .map(|(holder, expires_at)| (holder.to_string(), expires_at.parse::<u64>().unwrap_or(0)))
+            .unwrap_or_default();
+
+        let expires_at = match next_expiry(&holder, expires_at, now_millis()) {
+            Some(expires_at) => expires_at,
+            None => return Ok(false),
+        };
+
+        file.set_len(0).and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(format!("{},{}\n", self.node_id, expires_at).as_bytes()))
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("Error writing lease: {}", e))?;
+        Ok(true)
+    }
+
+    /// Blocks until this manager holds the lease, trying every `retry_period`.
+    pub fn wait_for_leadership(&self, retry_period: Duration) {
+        let mut standby_logged = false;
+        loop {
+            match self.try_acquire() {
+                Ok(true) => return,
+                Ok(false) => {
+                    if !standby_logged {
+                        log::info!("Another cluster manager holds the lease, running as standby");
+                        standby_logged = true;
+                    }
+                }
+                Err(e) => log::error!("{}", e),
+            }
+            thread::sleep(retry_period);
+        }
+    }
+
+    /// How long after its last renewal the leader may keep serving: it only notices a failed renewal up to
+    /// `renew_period` late, and a standby whose clock is `clock_skew` ahead sees the lease expire that much early.
+    fn serve_period(&self, renew_period: Duration, clock_skew: Duration) -> Duration {
+        self.ttl.saturating_sub(renew_period).saturating_sub(clock_skew)
+    }
+
+    /// Renews the lease every `renew_period`. Returns once it was taken over or could not be renewed within
+    /// `serve_period`, before a standby can see it expire, after which this manager must stop serving.
+    pub fn keep_renewing(&self, renew_period: Duration, clock_skew: Duration) {
+        let serve_period = self.serve_period(renew_period, clock_skew);
+        loop {
+            thread::sleep(renew_period);
+            match self.try_acquire() {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::error!("Lease was taken over by another cluster manager");
+                    return;
+                }
+                Err(e) => log::error!("Error renewing lease: {}", e),
+            }
+
+            let renewed_at = *self.renewed_at.lock().unwrap();
+            if renewed_at.is_none_or(|renewed_at| renewed_at.elapsed() >= serve_period) {
+                log::error!("Lease was not renewed within {:?}", serve_period);
+                return;
+            }
+        }
+    }
+}
+
+/// A change of the virt server assigned to a client VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JournalEntry {
+    Assign { client_ip: String, virt_ip: String, rpc_id: u64 },
+    Release { client_ip: String },
+    /// The VM's virt server was checkpointed to `ckp_path` and freed
+    Hibernate { client_ip: String, compute_units: u32, memory: u64, ckp_path: String },
+}
+
+impl JournalEntry {
+    pub fn to_line(&self) -> String {
+        match self {
+            JournalEntry::Assign { client_ip, virt_ip, rpc_id } => format!("assign,{},{},{}", client_ip, virt_ip, rpc_id),
+            JournalEntry::Release { client_ip } => format!("release,{}", client_ip),
+            // the path goes last, it may hold commas
+            JournalEntry::Hibernate { client_ip, compute_units, memory, ckp_path } => format!("hibernate,{},{},{},{}", client_ip, compute_units, memory, ckp_path),
+        }
+    }
+}
+
+impl FromStr for JournalEntry {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid journal entry: {}", value);
+        let fields = value.splitn(5, ",").collect::<Vec<&str>>();
+        match fields.as_slice() {
+            ["assign", client_ip, virt_ip, rpc_id] => Ok(JournalEntry::Assign {
+                client_ip: client_ip.to_string(),
+                virt_ip: virt_ip.to_string(),
+                rpc_id: rpc_id.parse::<u64>().map_err(|_| invalid())?,
+            }),
+            ["release", client_ip] => Ok(JournalEntry::Release { client_ip: client_ip.to_string() }),
+            ["hibernate", client_ip, compute_units, memory, ckp_path] if !ckp_path.is_empty() => Ok(JournalEntry::Hibernate {
+                client_ip: client_ip.to_string(),
+                compute_units: compute_units.parse::<u32>().map_err(|_| invalid())?,
+                memory: memory.parse::<u64>().map_err(|_| invalid())?,
+                ckp_path: ckp_path.to_string(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The client VMs as left by the journal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JournalState {
+    /// client_ip -> (virt_ip, rpc_id)
+    pub assignments: HashMap<String, (String, u64)>,
+    pub hibernated: HashMap<String, HibernatedVm>,
+}
+
+impl JournalState {
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Assign { client_ip, virt_ip, rpc_id } => {
+                self.hibernated.remove(&client_ip);
+                self.assignments.insert(client_ip, (virt_ip, rpc_id));
+            }
+            JournalEntry::Release { client_ip } => {
+                self.assignments.remove(&client_ip);
+                self.hibernated.remove(&client_ip);
+            }
+            JournalEntry::Hibernate { client_ip, compute_units, memory, ckp_path } => {
+                self.assignments.remove(&client_ip);
+                self.hibernated.insert(client_ip, HibernatedVm { ckp_path, compute_units, memory });
+            }
+        }
+    }
+
+    fn entries(&self) -> Vec<JournalEntry> {
+        let assigned = self.assignments.iter().map(|(client_ip, (virt_ip, rpc_id))| {
+            JournalEntry::Assign { client_ip: client_ip.clone(), virt_ip: virt_ip.clone(), rpc_id: *rpc_id }
+        });
+        let hibernated = self.hibernated.iter().map(|(client_ip, hibernated)| JournalEntry::Hibernate {
+            client_ip: client_ip.clone(),
+            compute_units: hibernated.compute_units,
+            memory: hibernated.memory,
+            ckp_path: hibernated.ckp_path.clone(),
+        });
+        assigned.chain(hibernated).collect()
+    }
+}
+
+/// Client to virt server assignments and hibernated VMs, appended to by the leader and replayed by a standby
+/// when it takes over. Server nodes report their virt servers themselves, the journal tells which VM uses them.
+pub struct Journal {
+    path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    pub fn new(path: &str) -> Self {
+        Journal {
+            path: path.to_string(),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, entry: &JournalEntry) {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(opened) => *file = Some(opened),
+                Err(e) => {
+                    log::error!("Error opening journal {}: {}", self.path, e);
+                    return;
+                }
+            }
+        }
+
+        let res = file.as_mut().unwrap().write_all(format!("{}\n", entry.to_line()).as_bytes())
+            .and_then(|_| file.as_ref().unwrap().sync_data());
+        if let Err(e) = res {
+            log::error!("Error writing journal {}: {}", self.path, e);
+            // reopened on the next entry
+            *file = None;
+        }
+    }
+
+    /// Returns the assignments and hibernated VMs as left by the journal.
+    pub fn replay(&self) -> Result<JournalState, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(JournalState::default()),
+            Err(e) => return Err(format!("Error opening journal {}: {}", self.path, e)),
+        };
+
+        let mut state = JournalState::default();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Error reading journal {}: {}", self.path, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse::<JournalEntry>() {
+                Ok(entry) => state.apply(entry),
+                // a torn last line from a leader that died mid write
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        Ok(state)
+    }
+
+    /// Rewrites the journal to hold only `state`.
+    pub fn compact(&self, state: &JournalState) -> Result<(), String> {
+        let mut file = self.file.lock().unwrap();
+        let tmp_path = format!("{}.tmp", self.path);
+
+        let mut contents = String::new();
+        for entry in state.entries() {
+            contents.push_str(&format!("{}\n", entry.to_line()));
+        }
+
+        File::create(&tmp_path).and_then(|mut tmp| tmp.write_all(contents.as_bytes()).and_then(|_| tmp.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Error compacting journal {}: {}", self.path, e))?;
+
+        *file = None;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flyt-ha-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_file_lease() {
+        let dir = test_dir("lease");
+        let path = dir.join("lease").to_string_lossy().to_string();
+        let lease_a = FileLease::new(&path, "rmgr-a", Duration::from_secs(10));
+        let lease_b = FileLease::new(&path, "rmgr-b", Duration::from_secs(10));
+
+        assert!(lease_a.try_acquire().unwrap());
+        assert!(!lease_b.try_acquire().unwrap());
+        assert!(lease_a.try_acquire().unwrap());
+        assert!(fs::read_to_string(&path).unwrap().starts_with("rmgr-a,"));
+
+        // only the holder can release
+        lease_b.release().unwrap();
+        assert!(!lease_b.try_acquire().unwrap());
+
+        lease_a.release().unwrap();
+        assert!(lease_b.try_acquire().unwrap());
+        assert!(!lease_a.try_acquire().unwrap());
+
+        // an expired lease is taken over
+        let lease_c = FileLease::new(&path, "rmgr-c", Duration::ZERO);
+        fs::write(&path, format!("rmgr-b,{}\n", now_millis())).unwrap();
+        assert!(lease_c.try_acquire().unwrap());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_keep_renewing() {
+        let dir = test_dir("renew");
+        let path = dir.join("lease").to_string_lossy().to_string();
+
+        let lease = FileLease::new(&path, "rmgr-a", Duration::from_secs(10));
+        assert_eq!(lease.serve_period(Duration::from_secs(3), Duration::from_secs(1)), Duration::from_secs(6));
+        assert_eq!(lease.serve_period(Duration::from_secs(8), Duration::from_secs(3)), Duration::ZERO);
+
+        // returns once another manager took the lease over
+        let leader = FileLease::new(&path, "rmgr-a", Duration::from_millis(20));
+        let standby = FileLease::new(&path, "rmgr-b", Duration::from_secs(10));
+        assert!(leader.try_acquire().unwrap());
+        thread::sleep(Duration::from_millis(30));
+        assert!(standby.try_acquire().unwrap());
+        leader.keep_renewing(Duration::from_millis(1), Duration::ZERO);
+
+        // returns once the lease could not be renewed in time
+        let unreachable = FileLease::new(&dir.join("missing").join("lease").to_string_lossy(), "rmgr-a", Duration::from_millis(50));
+        let started_at = Instant::now();
+        unreachable.keep_renewing(Duration::from_millis(5), Duration::from_millis(10));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let assign = JournalEntry::Assign { client_ip: "10.0.0.5".to_string(), virt_ip: "10.0.1.2".to_string(), rpc_id: 7 };
+        assert_eq!(assign.to_line(), "assign,10.0.0.5,10.0.1.2,7");
+        assert_eq!(assign.to_line().parse::<JournalEntry>().unwrap(), assign);
+
+        let release = JournalEntry::Release { client_ip: "10.0.0.5".to_string() };
+        assert_eq!("release,10.0.0.5".parse::<JournalEntry>().unwrap(), release);
+
+        assert!("assign,10.0.0.5,10.0.1.2,x".parse::<JournalEntry>().is_err());
+        assert!("assign,10.0.0.5,10.0.1.".parse::<JournalEntry>().is_err());
+        assert!("evict,10.0.0.5".parse::<JournalEntry>().is_err());
+
+        let hibernate = JournalEntry::Hibernate { client_ip: "10.0.0.5".to_string(), compute_units: 8, memory: 1024, ckp_path: "/ckp/vm,5".to_string() };
+        assert_eq!(hibernate.to_line(), "hibernate,10.0.0.5,8,1024,/ckp/vm,5");
+        assert_eq!(hibernate.to_line().parse::<JournalEntry>().unwrap(), hibernate);
+        assert!("hibernate,10.0.0.5,8,1024,".parse::<JournalEntry>().is_err());
+        assert!("hibernate,10.0.0.5,8,/ckp/vm".parse::<JournalEntry>().is_err());
+    }
+
+    #[test]
+    fn test_journal_replay_and_compact() {
+        let dir = test_dir("journal");
+        let path = dir.join("journal").to_string_lossy().to_string();
+        let journal = Journal::new(&path);
+
+        assert_eq!(journal.replay().unwrap(), JournalState::default());
+
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.5".to_string(), virt_ip: "10.0.1.2".to_string(), rpc_id: 7 });
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.6".to_string(), virt_ip: "10.0.1.2".to_string(), rpc_id: 8 });
+        journal.record(&JournalEntry::Release { client_ip: "10.0.0.5".to_string() });
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.6".to_string(), virt_ip: "10.0.1.3".to_string(), rpc_id: 1 });
+
+        // hibernated, then woken up
+        journal.record(&JournalEntry::Hibernate { client_ip: "10.0.0.8".to_string(), compute_units: 8, memory: 1024, ckp_path: "/ckp/8".to_string() });
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.8".to_string(), virt_ip: "10.0.1.3".to_string(), rpc_id: 2 });
+        journal.record(&JournalEntry::Release { client_ip: "10.0.0.8".to_string() });
+
+        // freed on hibernation and still hibernated
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.9".to_string(), virt_ip: "10.0.1.2".to_string(), rpc_id: 9 });
+        journal.record(&JournalEntry::Release { client_ip: "10.0.0.9".to_string() });
+        journal.record(&JournalEntry::Hibernate { client_ip: "10.0.0.9".to_string(), compute_units: 4, memory: 512, ckp_path: "/ckp/9".to_string() });
+
+        // a torn last line from a leader that died mid write
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"assign,10.0.0.7,10.0").unwrap();
+
+        let state = journal.replay().unwrap();
+        let expected = JournalState {
+            assignments: HashMap::from([("10.0.0.6".to_string(), ("10.0.1.3".to_string(), 1))]),
+            hibernated: HashMap::from([("10.0.0.9".to_string(), HibernatedVm { ckp_path: "/ckp/9".to_string(), compute_units: 4, memory: 512 })]),
+        };
+        assert_eq!(state, expected);
+
+        journal.compact(&state).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "assign,10.0.0.6,10.0.1.3,1\nhibernate,10.0.0.9,4,512,/ckp/9\n");
+        assert!(!dir.join("journal.tmp").exists());
+        assert_eq!(journal.replay().unwrap(), expected);
+
+        // entries after a compaction go to the new file
+        journal.record(&JournalEntry::Release { client_ip: "10.0.0.6".to_string() });
+        journal.record(&JournalEntry::Assign { client_ip: "10.0.0.9".to_string(), virt_ip: "10.0.1.2".to_string(), rpc_id: 10 });
+        let state = journal.replay().unwrap();
+        assert_eq!(state.assignments, HashMap::from([("10.0.0.9".to_string(), ("10.0.1.2".to_string(), 10))]));
+        assert!(state.hibernated.is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
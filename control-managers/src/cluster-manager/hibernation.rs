@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client_handler::FlytClientManager;
+use crate::servernode_handler::ServerNodesManager;
+use crate::telemetry::TelemetrySample;
+
+/// What is needed to restore a VM whose virt server was checkpointed and freed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HibernatedVm {
+    pub ckp_path: String,
+    pub compute_units: u32,
+    pub memory: u64,
+}
+
+/// Hibernates VMs whose virt servers have not used their SMs for `idle_period`, according to the
+/// telemetry of their server nodes.
+pub struct HibernationMonitor<'a> {
+    client_mgr: &'a FlytClientManager<'a>,
+    server_nodes_manager: &'a ServerNodesManager<'a>,
+    idle_period: Duration,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Whether virt server `rpc_id` used its SMs in the samples received after `since`, or `None` if none
+/// of them reported it.
+fn was_busy(history: &[TelemetrySample], since: u64, rpc_id: u64) -> Option<bool> {
+    let mut reports = history.iter()
+        .filter(|sample| sample.timestamp > since)
+        .flat_map(|sample| sample.virt_servers.iter())
+        .filter(|virt_server| virt_server.rpc_id == rpc_id)
+        .peekable();
+    reports.peek()?;
+    Some(reports.any(|virt_server| virt_server.sm_utilization > 0))
+}
+
+impl<'a> HibernationMonitor<'a> {
+
+    pub fn new(client_mgr: &'a FlytClientManager<'a>, server_nodes_manager: &'a ServerNodesManager<'a>, idle_period: Duration) -> Self {
+        HibernationMonitor {
+            client_mgr,
+            server_nodes_manager,
+            idle_period,
+        }
+    }
+
+    pub fn start(&self) {
+        let check_period = (self.idle_period / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        // client ip -> seconds since the epoch at which its virt server was last seen busy
+        let mut idle_since = HashMap::new();
+        let mut last_check = now_secs();
+
+        loop {
+            thread::sleep(check_period);
+            let now = now_secs();
+
+            for client in self.client_mgr.get_all_clients() {
+                let (server_ip, rpc_id) = match client.virt_server.as_ref() {
+                    Some(virt_server) => {
+                        let virt_server = virt_server.read().unwrap();
+                        (virt_server.ipaddr.clone(), virt_server.rpc_id)
+                    }
+                    None => {
+                        idle_since.remove(&client.ipaddr);
+                        continue;
+                    }
+                };
+
+                match was_busy(&self.server_nodes_manager.telemetry().history(&server_ip), last_check, rpc_id) {
+                    Some(true) => {
+                        idle_since.insert(client.ipaddr.clone(), now);
+                        continue;
+                    }
+                    Some(false) => {}
+                    // without telemetry the VM is not known to be idle, the idle period starts over
+                    None => {
+                        idle_since.remove(&client.ipaddr);
+                        continue;
+                    }
+                }
+
+                let since = *idle_since.entry(client.ipaddr.clone()).or_insert(now);
+                if now - since < self.idle_period.as_secs() {
+                    continue;
+                }
+
+                // considered again after another idle period
+                idle_since.remove(&client.ipaddr);
+                let _operation = match self.client_mgr.try_start_operation(&client.ipaddr, "hibernation") {
+                    Ok(operation) => operation,
+                    Err(e) => {
+                        log::info!("Not hibernating client VM {}: {}", client.ipaddr, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = self.server_nodes_manager.hibernate_virt_server(self.client_mgr, &client.ipaddr) {
+                    log::error!("Error hibernating client VM {}: {}", client.ipaddr, e);
+                }
+            }
+
+            last_check = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::VirtServerTelemetry;
+
+    fn sample(timestamp: u64, virt_servers: &[(u64, u32)]) -> TelemetrySample {
+        TelemetrySample {
+            timestamp,
+            gpus: Vec::new(),
+            virt_servers: virt_servers.iter().map(|(rpc_id, sm_utilization)| VirtServerTelemetry {
+                rpc_id: *rpc_id,
+                gpu_id: 0,
+                memory_used: 0,
+                sm_utilization: *sm_utilization,
+                host_cpu_usage: 0,
+                host_memory: 0,
+                pids: 0,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_was_busy() {
+        let history = vec![
+            sample(100, &[(1, 50), (2, 0)]),
+            sample(110, &[(1, 0), (2, 0)]),
+            sample(120, &[(1, 0), (2, 30)]),
+        ];
+
+        assert_eq!(was_busy(&history, 90, 1), Some(true));
+        // samples before `since` do not count
+        assert_eq!(was_busy(&history, 100, 1), Some(false));
+        assert_eq!(was_busy(&history, 110, 2), Some(true));
+        assert_eq!(was_busy(&history, 100, 2), Some(true));
+
+        // not reported at all, or no samples in the window
+        assert_eq!(was_busy(&history, 90, 3), None);
+        assert_eq!(was_busy(&history, 120, 1), None);
+        assert_eq!(was_busy(&[], 0, 1), None);
+    }
+}
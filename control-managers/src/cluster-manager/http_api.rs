@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+
+use crate::common::api_commands::FrontEndCommand;
+use crate::common::utils::StreamUtils;
+use crate::frontend_handler::FrontendHandler;
+
+/// Settings of `[http-api]`, present when the JSON API is enabled.
+#[derive(Debug, Clone)]
+pub struct HttpApiConfig {
+    pub address: String,
+    /// Accepted as `Authorization: Bearer <token>`
+    pub tokens: Vec<String>,
+}
+
+/// Requests only carry a few parameters
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Request line and headers together
+const MAX_HEADER_SIZE: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+/// Time a client has to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Connections served at once, further ones are answered with 503
+const MAX_CONNECTIONS: usize = 32;
+
+/// A failed request: the HTTP status and the message returned as `{"error": ...}`.
+type ApiError = (u16, String);
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// What a request asks for, with its parameters checked.
+#[derive(Debug, PartialEq)]
+enum Endpoint<'r> {
+    ListVms,
+    VirtServerLogs { vm_ip: &'r str, offset: u64 },
+    ChangeResources { vm_ip: &'r str, body: Value },
+    Migrate { vm_ip: &'r str, body: Value },
+    ListServerNodes,
+    RescanGpus { server_ip: &'r str },
+    ListVirtServers,
+    Top,
+    ListPendingDeallocations,
+}
+
+/// Reads from a connection until a deadline, however slowly the client sends.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "request not received in time"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// One of the `MAX_CONNECTIONS` connections being served, freed when dropped.
+struct ConnectionSlot<'a> {
+    connections: &'a AtomicUsize,
+}
+
+impl<'a> ConnectionSlot<'a> {
+    fn try_acquire(connections: &'a AtomicUsize) -> Option<Self> {
+        connections.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < MAX_CONNECTIONS).then_some(count + 1)).ok()?;
+        Some(ConnectionSlot { connections })
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// JSON API over HTTP for the portal and automation. Every request is run as the matching
+/// `FrontEndCommand` by the frontend handler, so it behaves exactly like the same `flytctl` command.
+pub struct HttpApi<'a> {
+    frontend: &'a FrontendHandler<'a>,
+    config: HttpApiConfig,
+    connections: AtomicUsize,
+}
+
+impl<'a> HttpApi<'a> {
+    pub fn new(frontend: &'a FrontendHandler<'a>, config: HttpApiConfig) -> Self {
+        HttpApi {
+            frontend,
+            config,
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn start_listening(&self) {
+        if self.config.tokens.is_empty() {
+            log::error!("HTTP API is enabled without tokens, not starting it");
+            return;
+        }
+
+        let listener = match TcpListener::bind(&self.config.address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Error binding HTTP API to {}: {}", self.config.address, e);
+                return;
+            }
+        };
+
+        log::info!("HTTP API listening on {}", self.config.address);
+
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => match ConnectionSlot::try_acquire(&self.connections) {
+                        Some(slot) => {
+                            s.spawn(move || {
+                                self.handle_connection(stream);
+                                drop(slot);
+                            });
+                        }
+                        None => {
+                            log::warn!("HTTP API: {} connections open, rejecting another", MAX_CONNECTIONS);
+                            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                            let _ = write_response(&mut stream, 503, &json!({ "error": "Too many connections" }));
+                        }
+                    },
+                    Err(e) => {
+                        log::error!("Error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) {
+        let reader = DeadlineReader {
+            stream: &stream,
+            deadline: Instant::now() + REQUEST_TIMEOUT,
+        };
+
+        let reply = read_request(reader).and_then(|request| {
+            if !self.is_authorized(&request) {
+                return Err((401, "Missing or invalid API token".to_string()));
+            }
+            log::info!("HTTP API: {} {}", request.method, request.path);
+            self.route(&request)
+        });
+
+        let (status, body) = match reply {
+            Ok(body) => (200, body),
+            Err((status, message)) => (status, json!({ "error": message })),
+        };
+        if let Err(e) = write_response(&mut stream, status, &body) {
+            log::error!("Error writing HTTP response: {}", e);
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let token = request.headers.get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim());
+        token.is_some_and(|token| self.config.tokens.iter().any(|known| tokens_equal(known, token)))
+    }
+
+    fn route(&self, request: &Request) -> Result<Value, ApiError> {
+        match endpoint(request)? {
+            Endpoint::ListVms => self.list_vms(),
+            Endpoint::VirtServerLogs { vm_ip, offset } => self.virt_server_logs(vm_ip, offset),
+            Endpoint::ChangeResources { vm_ip, body } => self.change_resources(vm_ip, &body),
+            Endpoint::Migrate { vm_ip, body } => self.migrate(vm_ip, &body),
+            Endpoint::ListServerNodes => self.list_server_nodes(),
+            Endpoint::RescanGpus { server_ip } => self.rescan_gpus(server_ip),
+            Endpoint::ListVirtServers => self.list_virt_servers(),
+            Endpoint::Top => self.top(),
+            Endpoint::ListPendingDeallocations => self.list_pending_deallocations(),
+        }
+    }
+
+    /// Sends `request` to the frontend handler and passes the reply after a 200 status to `read_reply`.
+    /// Other statuses are returned as errors with the message of the reply.
+    fn frontend_request<T, F>(&self, request: String, read_reply: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut BufReader<UnixStream>) -> Result<T, String>,
+    {
+        let internal_error = |e: std::io::Error| (500, format!("Error talking to the frontend handler: {}", e));
+        let (mut stream, frontend_stream) = UnixStream::pair().map_err(internal_error)?;
+
+        thread::scope(|s| {
+            s.spawn(|| self.frontend.handle_request(frontend_stream));
+
+            StreamUtils::write_all(&mut stream, request).map_err(internal_error)?;
+            let mut reader = BufReader::new(stream);
+
+            let status = StreamUtils::read_line(&mut reader).map_err(internal_error)?;
+            if status != "200" {
+                let message = StreamUtils::read_line(&mut reader).unwrap_or_default();
+                let status = status.parse::<u16>().ok().filter(|status| (400..600).contains(status)).unwrap_or(500);
+                return Err((status, message));
+            }
+            read_reply(&mut reader).map_err(|e| (500, e))
+        })
+    }
+
+    /// Runs a command whose reply is a single message line.
+    fn frontend_command(&self, request: String) -> Result<Value, ApiError> {
+        self.frontend_request(request, |reader| {
+            let message = read_line(reader)?;
+            Ok(json!({ "message": message }))
+        })
+    }
+
+    /// Runs a command that replies with a count and that many rows of fields.
+    fn frontend_list(&self, command: &str, fields: &[&str]) -> Result<Value, ApiError> {
+        self.frontend_request(format!("{}\n", command), |reader| {
+            let count = read_count(reader)?;
+            let mut rows = Vec::new();
+            for _ in 0..count {
+                rows.push(to_object(fields, &read_line(reader)?));
+            }
+            Ok(Value::Array(rows))
+        })
+    }
+
+    fn list_vms(&self) -> Result<Value, ApiError> {
+        self.frontend_list(FrontEndCommand::LIST_VMS, &["vm_ip", "server_ip", "rpc_id", "sm_cores", "memory", "is_active",
+            "lease_expires_in_seconds", "state", "in_state_seconds"])
+    }
+
+    fn list_virt_servers(&self) -> Result<Value, ApiError> {
+        self.frontend_list(FrontEndCommand::LIST_VIRT_SERVERS, &["server_ip", "rpc_id", "gpu_id", "sm_cores", "memory"])
+    }
+
+    fn list_pending_deallocations(&self) -> Result<Value, ApiError> {
+        self.frontend_list(FrontEndCommand::LIST_PENDING_DEALLOCATIONS, &["vm_ip", "deallocated_in_seconds"])
+    }
+
+    fn list_server_nodes(&self) -> Result<Value, ApiError> {
+        self.frontend_request(format!("{}\n", FrontEndCommand::LIST_SERVER_NODES), |reader| {
+            let count = read_count(reader)?;
+            let mut server_nodes = Vec::new();
+            for _ in 0..count {
+                // the flavors are joined with '+'
+                let mut server_node = to_object(&["server_ip", "num_gpus", "flavors"], &read_line(reader)?);
+                let flavors = server_node["flavors"].as_str().unwrap_or_default().split('+')
+                    .filter(|flavor| !flavor.is_empty())
+                    .map(|flavor| Value::String(flavor.to_string()))
+                    .collect::<Vec<Value>>();
+                server_node["flavors"] = Value::Array(flavors);
+
+                let num_gpus = server_node["num_gpus"].as_u64().unwrap_or(0);
+                let mut gpus = Vec::new();
+                for _ in 0..num_gpus {
+                    gpus.push(to_object(&["gpu_id", "name", "memory", "allocated_memory", "sm_cores", "allocated_sm_cores",
+                        "isolation", "parent_gpu_id", "health", "mps_status"], &read_line(reader)?));
+                }
+                server_node["gpus"] = Value::Array(gpus);
+                server_nodes.push(server_node);
+            }
+            Ok(Value::Array(server_nodes))
+        })
+    }
+
+    fn top(&self) -> Result<Value, ApiError> {
+        self.frontend_request(format!("{}\n", FrontEndCommand::TOP), |reader| {
+            let count = read_count(reader)?;
+            let mut server_nodes = Vec::new();
+            for _ in 0..count {
+                let mut server_node = to_object(&["server_ip", "num_gpus", "num_virt_servers", "sample_age_seconds"], &read_line(reader)?);
+
+                let num_gpus = server_node["num_gpus"].as_u64().unwrap_or(0);
+                let mut gpus = Vec::new();
+                for _ in 0..num_gpus {
+                    gpus.push(to_object(&["gpu_id", "utilization", "avg_utilization", "memory_used", "memory_total", "power"],
+                        &read_line(reader)?));
+                }
+
+                let num_virt_servers = server_node["num_virt_servers"].as_u64().unwrap_or(0);
+                let mut virt_servers = Vec::new();
+                for _ in 0..num_virt_servers {
+                    virt_servers.push(to_object(&["rpc_id", "vm_ip", "gpu_id", "memory_used", "sm_utilization", "host_cpu_usage",
+                        "host_memory", "pids"], &read_line(reader)?));
+                }
+
+                server_node["gpus"] = Value::Array(gpus);
+                server_node["virt_servers"] = Value::Array(virt_servers);
+                server_nodes.push(server_node);
+            }
+            Ok(Value::Array(server_nodes))
+        })
+    }
+
+    fn virt_server_logs(&self, vm_ip: &str, offset: u64) -> Result<Value, ApiError> {
+        self.frontend_request(format!("{}\n{},{}\n", FrontEndCommand::VIRT_SERVER_LOGS, vm_ip, offset), |reader| {
+            // format: offset,num_lines followed by the lines
+            let header = to_object(&["offset", "num_lines"], &read_line(reader)?);
+            let num_lines = header["num_lines"].as_u64().ok_or("Invalid logs reply".to_string())?;
+            let mut lines = Vec::new();
+            for _ in 0..num_lines {
+                lines.push(Value::String(read_line(reader)?));
+            }
+            Ok(json!({ "offset": header["offset"], "lines": lines }))
+        })
+    }
+
+    fn rescan_gpus(&self, server_ip: &str) -> Result<Value, ApiError> {
+        self.frontend_command(format!("{}\n{}\n", FrontEndCommand::RESCAN_GPUS, server_ip))
+    }
+
+    /// Body: `{"sm_cores": <cores>, "memory": <bytes>}`, either may be left out.
+    fn change_resources(&self, vm_ip: &str, body: &Value) -> Result<Value, ApiError> {
+        let sm_cores = optional_u64(body, "sm_cores")?;
+        let memory = optional_u64(body, "memory")?;
+        let request = match (sm_cores, memory) {
+            (Some(sm_cores), Some(memory)) => format!("{}\n{},{},{}\n", FrontEndCommand::CHANGE_SM_CORES_AND_MEMORY, vm_ip, sm_cores, memory),
+            (Some(sm_cores), None) => format!("{}\n{},{}\n", FrontEndCommand::CHANGE_SM_CORES, vm_ip, sm_cores),
+            (None, Some(memory)) => format!("{}\n{},{}\n", FrontEndCommand::CHANGE_MEMORY, vm_ip, memory),
+            (None, None) => return Err((400, "sm_cores or memory is required".to_string())),
+        };
+        self.frontend_command(request)
+    }
+
+    /// Body: `{"sm_cores": <cores>, "memory": <bytes>}`, with `"server_ip"` and `"gpu_id"` to pick the
+    /// target GPU instead of letting the cluster manager choose one.
+    fn migrate(&self, vm_ip: &str, body: &Value) -> Result<Value, ApiError> {
+        let sm_cores = optional_u64(body, "sm_cores")?.ok_or((400, "sm_cores is required".to_string()))?;
+        let memory = optional_u64(body, "memory")?.ok_or((400, "memory is required".to_string()))?;
+        let server_ip = match body.get("server_ip") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(server_ip)) => Some(param(server_ip)?),
+            Some(_) => return Err((400, "server_ip must be a string".to_string())),
+        };
+
+        let request = match (server_ip, optional_u64(body, "gpu_id")?) {
+            (Some(server_ip), Some(gpu_id)) => format!("{}\n{},{},{},{},{}\n", FrontEndCommand::MIGRATE_VIRT_SERVER, vm_ip, server_ip, gpu_id, sm_cores, memory),
+            (None, None) => format!("{}\n{},{},{}\n", FrontEndCommand::MIGRATE_VIRT_SERVER_AUTO, vm_ip, sm_cores, memory),
+            _ => return Err((400, "server_ip and gpu_id must be given together".to_string())),
+        };
+        self.frontend_command(request)
+    }
+}
+
+fn endpoint(request: &Request) -> Result<Endpoint<'_>, ApiError> {
+    let segments = request.path.trim_matches('/').split('/').collect::<Vec<&str>>();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["v1", "vms"]) => Ok(Endpoint::ListVms),
+        ("GET", ["v1", "vms", vm_ip, "logs"]) => {
+            let offset = match request.query.get("offset") {
+                Some(offset) => offset.parse::<u64>().map_err(|_| (400, "Invalid offset".to_string()))?,
+                None => 0,
+            };
+            Ok(Endpoint::VirtServerLogs { vm_ip: param(vm_ip)?, offset })
+        }
+        ("POST", ["v1", "vms", vm_ip, "resources"]) => Ok(Endpoint::ChangeResources { vm_ip: param(vm_ip)?, body: parse_body(&request.body)? }),
+        ("POST", ["v1", "vms", vm_ip, "migrate"]) => Ok(Endpoint::Migrate { vm_ip: param(vm_ip)?, body: parse_body(&request.body)? }),
+        ("GET", ["v1", "server-nodes"]) => Ok(Endpoint::ListServerNodes),
+        ("POST", ["v1", "server-nodes", server_ip, "rescan-gpus"]) => Ok(Endpoint::RescanGpus { server_ip: param(server_ip)? }),
+        ("GET", ["v1", "virt-servers"]) => Ok(Endpoint::ListVirtServers),
+        ("GET", ["v1", "top"]) => Ok(Endpoint::Top),
+        ("GET", ["v1", "pending-deallocations"]) => Ok(Endpoint::ListPendingDeallocations),
+        _ => Err((404, format!("No such endpoint: {} {}", request.method, request.path))),
+    }
+}
+
+/// Compares tokens without returning early on the first differing byte.
+fn tokens_equal(known: &str, given: &str) -> bool {
+    known.len() == given.len() && known.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks a path or body parameter that is passed on in a frontend command.
+fn param(value: &str) -> Result<&str, ApiError> {
+    if value.is_empty() || value.contains([',', '\n', '\r']) {
+        return Err((400, format!("Invalid parameter: {:?}", value)));
+    }
+    Ok(value)
+}
+
+fn optional_u64(body: &Value, name: &str) -> Result<Option<u64>, ApiError> {
+    match body.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or((400, format!("{} must be a non-negative integer", name))),
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<Value, ApiError> {
+    let body = serde_json::from_slice::<Value>(body).map_err(|e| (400, format!("Invalid JSON body: {}", e)))?;
+    if !body.is_object() {
+        return Err((400, "Request body must be a JSON object".to_string()));
+    }
+    Ok(body)
+}
+
+fn read_line(reader: &mut BufReader<UnixStream>) -> Result<String, String> {
+    StreamUtils::read_line(reader).map_err(|e| format!("Error reading reply: {}", e))
+}
+
+fn read_count(reader: &mut BufReader<UnixStream>) -> Result<u64, String> {
+    let count = read_line(reader)?;
+    count.parse::<u64>().map_err(|_| format!("Invalid count in reply: {}", count))
+}
+
+/// Turns a comma separated row of a frontend reply into an object with the given field names.
+/// Numbers and booleans keep their type, empty fields become null and fields named `*_seconds`
+/// lose the unit suffix the listings print.
+fn to_object(names: &[&str], row: &str) -> Value {
+    let mut object = Map::new();
+    for (name, field) in names.iter().zip(row.split(',')) {
+        let field = if name.ends_with("_seconds") { field.trim_end_matches('s') } else { field };
+        let value = if field.is_empty() {
+            Value::Null
+        } else if let Ok(number) = field.parse::<u64>() {
+            Value::from(number)
+        } else if let Some(number) = field.parse::<f64>().ok().filter(|number| number.is_finite()) {
+            Value::from(number)
+        } else if let Ok(flag) = field.parse::<bool>() {
+            Value::Bool(flag)
+        } else {
+            Value::String(field.to_string())
+        };
+        object.insert(name.to_string(), value);
+    }
+    Value::Object(object)
+}
+
+/// Status for a request that could not be read.
+fn read_error(context: &str, e: io::Error) -> ApiError {
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => (408, format!("{}: {}", context, e)),
+        _ => (400, format!("{}: {}", context, e)),
+    }
+}
+
+/// Reads one line of the request line and headers, which may not go past `MAX_HEADER_SIZE`.
+fn read_header_line<R: Read>(reader: &mut BufReader<io::Take<R>>, context: &str) -> Result<String, ApiError> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| read_error(context, e))?;
+    if !line.ends_with('\n') {
+        if reader.get_ref().limit() == 0 {
+            return Err((431, "Request headers too large".to_string()));
+        }
+        return Err((400, format!("{}: connection closed", context)));
+    }
+    Ok(line)
+}
+
+fn read_request<R: Read>(stream: R) -> Result<Request, ApiError> {
+    let bad_request = |message: String| (400, message);
+    let mut reader = BufReader::new(stream.take(MAX_HEADER_SIZE));
+
+    let request_line = read_header_line(&mut reader, "Error reading request")?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(bad_request("Invalid request line".to_string())),
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_header_line(&mut reader, "Error reading headers")?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(bad_request("Too many headers".to_string()));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length = match headers.get("content-length") {
+        Some(length) => length.parse::<usize>().map_err(|_| bad_request("Invalid Content-Length".to_string()))?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err((413, "Request body too large".to_string()));
+    }
+    // the body may already be partly buffered, the rest is left to read
+    reader.get_mut().set_limit(content_length as u64);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| read_error("Error reading body", e))?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+    let query = query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<HashMap<String, String>>();
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body,
+    })
+}
+
+fn write_response(stream: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    let authenticate = if status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+    stream.write_all(format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status, reason, body.len(), authenticate, body).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body);
+        read_request(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /v1/vms/10.0.0.2/resources?offset=5&x HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 15\r\n\r\n{\"sm_cores\": 4}";
+        let request = read_request(raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/vms/10.0.0.2/resources");
+        assert_eq!(request.query.get("offset").map(String::as_str), Some("5"));
+        assert_eq!(request.query.len(), 1);
+        assert_eq!(request.headers.get("authorization").map(String::as_str), Some("Bearer secret"));
+        assert_eq!(request.body, b"{\"sm_cores\": 4}");
+    }
+
+    #[test]
+    fn test_read_request_limits() {
+        let status = |raw: &[u8]| read_request(raw).err().unwrap().0;
+
+        assert_eq!(status(b"GET\r\n\r\n"), 400);
+        // connection closed before the end of the headers
+        assert_eq!(status(b"GET /v1/vms HTTP/1.1\r\nHost: flyt"), 400);
+        assert_eq!(status(b"GET /v1/vms HTTP/1.1\r\nContent-Length: x\r\n\r\n"), 400);
+        assert_eq!(status(format!("GET /v1/vms HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1).as_bytes()), 413);
+        assert_eq!(status(b"POST /v1/top HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"), 400);
+
+        let long_header = format!("GET /v1/vms HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE as usize));
+        assert_eq!(status(long_header.as_bytes()), 431);
+
+        let headers = (0..=MAX_HEADERS).map(|i| format!("X-{}: a\r\n", i)).collect::<String>();
+        let many_headers = format!("GET /v1/vms HTTP/1.1\r\n{}\r\n", headers);
+        assert_eq!(status(many_headers.as_bytes()), 400);
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(endpoint(&request("GET", "/v1/vms", "")), Ok(Endpoint::ListVms));
+        assert_eq!(endpoint(&request("GET", "/v1/vms/10.0.0.2/logs?offset=7", "")),
+            Ok(Endpoint::VirtServerLogs { vm_ip: "10.0.0.2", offset: 7 }));
+        assert_eq!(endpoint(&request("POST", "/v1/vms/10.0.0.2/migrate", "{\"sm_cores\": 4}")),
+            Ok(Endpoint::Migrate { vm_ip: "10.0.0.2", body: json!({ "sm_cores": 4 }) }));
+        assert_eq!(endpoint(&request("POST", "/v1/server-nodes/10.0.1.5/rescan-gpus", "")),
+            Ok(Endpoint::RescanGpus { server_ip: "10.0.1.5" }));
+
+        let status = |method: &str, target: &str, body: &str| endpoint(&request(method, target, body)).err().unwrap().0;
+        assert_eq!(status("POST", "/v1/vms", ""), 404);
+        assert_eq!(status("GET", "/v2/vms", ""), 404);
+        assert_eq!(status("GET", "/v1/vms/10.0.0.2/logs?offset=-1", ""), 400);
+        assert_eq!(status("POST", "/v1/vms/10.0.0.2,1/resources", "{}"), 400);
+        assert_eq!(status("POST", "/v1/vms/10.0.0.2/resources", "[4]"), 400);
+        assert_eq!(status("POST", "/v1/vms/10.0.0.2/resources", "{"), 400);
+    }
+
+    #[test]
+    fn test_to_object() {
+        let object = to_object(&["vm_ip", "rpc_id", "is_active", "load", "lease_expires_in_seconds", "state", "in_state_seconds"],
+            "10.0.0.2,3,true,0.5,,running,42s");
+        assert_eq!(object, json!({
+            "vm_ip": "10.0.0.2",
+            "rpc_id": 3,
+            "is_active": true,
+            "load": 0.5,
+            "lease_expires_in_seconds": null,
+            "state": "running",
+            "in_state_seconds": 42,
+        }));
+
+        // missing fields are left out, extra ones dropped
+        assert_eq!(to_object(&["a", "b"], "1"), json!({ "a": 1 }));
+        assert_eq!(to_object(&["a"], "x,y"), json!({ "a": "x" }));
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let connections = AtomicUsize::new(0);
+        let mut slots = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::try_acquire(&connections).unwrap()).collect::<Vec<_>>();
+        assert!(ConnectionSlot::try_acquire(&connections).is_none());
+
+        slots.pop();
+        assert!(ConnectionSlot::try_acquire(&connections).is_some());
+        drop(slots);
+        assert_eq!(connections.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_tokens_equal() {
+        assert!(tokens_equal("secret", "secret"));
+        assert!(!tokens_equal("secret", "secreT"));
+        assert!(!tokens_equal("secret", "secret2"));
+        assert!(!tokens_equal("secret", ""));
+    }
+}
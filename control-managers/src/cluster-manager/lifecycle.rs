@@ -0,0 +1,121 @@
+use std::fmt;
+use std::time::SystemTime;
+
+/// Where a client VM is in its lifecycle, as seen by the cluster manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// Known, but holds no virt server
+    Pending,
+    /// A virt server is being created for the VM
+    Allocating,
+    /// Holds a virt server and has vCUDA clients
+    Running,
+    /// Holds a virt server without vCUDA clients
+    Idle,
+    /// Its vCUDA clients are paused, e.g. while it is being hibernated
+    Paused,
+    Migrating,
+    Resizing,
+    /// Its virt server was checkpointed and freed, it is restored on its next CUDA call
+    Hibernated,
+    Deallocating,
+}
+
+impl VmState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VmState::Pending => "pending",
+            VmState::Allocating => "allocating",
+            VmState::Running => "running",
+            VmState::Idle => "idle",
+            VmState::Paused => "paused",
+            VmState::Migrating => "migrating",
+            VmState::Resizing => "resizing",
+            VmState::Hibernated => "hibernated",
+            VmState::Deallocating => "deallocating",
+        }
+    }
+
+    /// Whether the VM holds a virt server that no control operation is working on.
+    pub fn is_settled(&self) -> bool {
+        matches!(self, VmState::Running | VmState::Idle)
+    }
+
+    pub fn can_become(&self, next: VmState) -> bool {
+        use VmState::*;
+        matches!((*self, next),
+            (Pending, Allocating | Running | Idle)
+            | (Allocating, Running | Idle | Pending)
+            // a failed wake-up
+            | (Allocating, Hibernated)
+            | (Running, Idle) | (Idle, Running)
+            | (Running | Idle, Paused | Migrating | Resizing | Deallocating)
+            | (Paused, Running | Idle | Hibernated)
+            | (Migrating | Resizing, Running | Idle)
+            | (Hibernated, Allocating | Pending)
+            // a failed deallocation keeps the virt server
+            | (Deallocating, Pending | Running | Idle)
+            // the virt server exited or was lost with its server node
+            | (Running | Idle | Paused | Migrating | Resizing, Pending))
+    }
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The state of a VM and when it was entered.
+#[derive(Debug, Clone, Copy)]
+pub struct VmLifecycle {
+    pub state: VmState,
+    pub since: SystemTime,
+}
+
+impl VmLifecycle {
+    pub fn new(state: VmState) -> Self {
+        VmLifecycle {
+            state,
+            since: SystemTime::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_become() {
+        use VmState::*;
+        assert!(Pending.can_become(Allocating));
+        assert!(Allocating.can_become(Running));
+        assert!(Allocating.can_become(Hibernated));
+        assert!(Running.can_become(Idle));
+        assert!(Idle.can_become(Migrating));
+        assert!(Resizing.can_become(Running));
+        assert!(Paused.can_become(Hibernated));
+        assert!(Hibernated.can_become(Allocating));
+        assert!(Deallocating.can_become(Idle));
+        assert!(Migrating.can_become(Pending));
+
+        assert!(!Pending.can_become(Pending));
+        assert!(!Running.can_become(Running));
+        assert!(!Pending.can_become(Migrating));
+        assert!(!Migrating.can_become(Resizing));
+        assert!(!Resizing.can_become(Deallocating));
+        assert!(!Running.can_become(Hibernated));
+        assert!(!Hibernated.can_become(Running));
+        assert!(!Deallocating.can_become(Allocating));
+    }
+
+    #[test]
+    fn test_is_settled() {
+        assert!(VmState::Running.is_settled());
+        assert!(VmState::Idle.is_settled());
+        assert!(!VmState::Pending.is_settled());
+        assert!(!VmState::Migrating.is_settled());
+        assert!(!VmState::Hibernated.is_settled());
+    }
+}
@@ -5,12 +5,25 @@ mod servernode_handler;
 mod client_handler;
 mod cli_frontend;
 mod frontend_handler;
+mod node_events;
+mod telemetry;
+mod ha;
+mod timers;
+mod hibernation;
+mod http_api;
+mod lifecycle;
+mod operations;
 #[path = "../common/mod.rs"]
 mod common;
 
+use std::sync::mpsc;
 use std::thread;
 
 use frontend_handler::FrontendHandler;
+use hibernation::HibernationMonitor;
+use http_api::HttpApi;
+use ha::{FileLease, Journal};
+use node_events::NodeEventHandler;
 use servernode_handler::ServerNodesManager;
 
 fn main() {
@@ -19,24 +32,84 @@ fn main() {
 
     let ( servernode_port, client_port) = bookkeeping::get_ports();
 
+    // a standby waits here, daemons fail over to it once it listens
+    let ha_config = bookkeeping::get_ha_config();
+    let lease = ha_config.as_ref().map(|ha| FileLease::new(&ha.lease_path, &ha.node_id, ha.lease_ttl));
+    let journal = ha_config.as_ref().map(|ha| Journal::new(&ha.journal_path));
+    if let (Some(ha), Some(lease)) = (ha_config.as_ref(), lease.as_ref()) {
+        lease.wait_for_leadership(ha.renew_period);
+        log::info!("Cluster manager {} holds the lease", ha.node_id);
+    }
+
     let vm_resource_getter = bookkeeping::VMResourcesGetter::new();
 
     let server_nodes_manager = ServerNodesManager::new(&vm_resource_getter);
-    let client_handler = client_handler::FlytClientManager::new(&server_nodes_manager);
+    let mut client_handler = client_handler::FlytClientManager::new(&server_nodes_manager);
+    if let Some(journal) = journal.as_ref() {
+        let state = journal.replay().unwrap_or_else(|e| {
+            log::error!("{}", e);
+            Default::default()
+        });
+        if let Err(e) = journal.compact(&state) {
+            log::error!("{}", e);
+        }
+        client_handler = client_handler.with_journal(journal);
+        client_handler.restore_journal(state);
+    }
     let frontend_handler = FrontendHandler::new(&client_handler, &server_nodes_manager);
+    let http_api = bookkeeping::get_http_api_config().map(|config| HttpApi::new(&frontend_handler, config));
+    let node_event_handler = NodeEventHandler::new(&client_handler, &server_nodes_manager);
+    let hibernation_monitor = bookkeeping::get_hibernation_idle_period()
+        .map(|idle_period| HibernationMonitor::new(&client_handler, &server_nodes_manager, idle_period));
+
+    let (event_sender, event_receiver) = mpsc::channel();
+    server_nodes_manager.set_event_sender(event_sender);
 
     thread::scope(|s| {
+        if let (Some(ha), Some(lease)) = (ha_config.as_ref(), lease.as_ref()) {
+            s.spawn(|| {
+                lease.keep_renewing(ha.renew_period, ha.clock_skew);
+                // the standby may already serve nodes and clients
+                log::error!("Lost the lease, exiting");
+                std::process::exit(1);
+            });
+        }
+
         s.spawn(|| {
             server_nodes_manager.start_servernode_handler(servernode_port);
         });
 
         s.spawn(|| {
-            client_handler.start_flytclient_handler(client_port, s);
+            client_handler.start_flytclient_handler(client_port);
         });
 
         s.spawn(|| {
             frontend_handler.start_listening(crate::cli_frontend::get_stream_path().as_str());
         });
+
+        if let Some(http_api) = http_api.as_ref() {
+            s.spawn(|| {
+                http_api.start_listening();
+            });
+        }
+
+        s.spawn(|| {
+            node_event_handler.start(event_receiver);
+        });
+
+        s.spawn(|| {
+            client_handler.start_lease_reaper();
+        });
+
+        s.spawn(|| {
+            client_handler.start_deallocation_timers();
+        });
+
+        if let Some(hibernation_monitor) = hibernation_monitor.as_ref() {
+            s.spawn(|| {
+                hibernation_monitor.start();
+            });
+        }
     });
 
 }
\ No newline at end of file
@@ -0,0 +1,90 @@
+use std::sync::mpsc::Receiver;
+
+use crate::client_handler::FlytClientManager;
+use crate::servernode_handler::ServerNodesManager;
+
+/// Events reported by server nodes that require action across clients.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    GpuUnhealthy { server_ip: String, gpu_id: u64 },
+    /// A virt server exited and was not restarted by its server node
+    VirtServerExited { server_ip: String, rpc_id: u64 },
+    /// A server node reported a virt server this manager did not know about
+    VirtServerAdopted { server_ip: String, rpc_id: u64 },
+}
+
+pub struct NodeEventHandler<'a> {
+    client_mgr: &'a FlytClientManager<'a>,
+    server_nodes_manager: &'a ServerNodesManager<'a>,
+}
+
+impl<'a> NodeEventHandler<'a> {
+
+    pub fn new(client_mgr: &'a FlytClientManager<'a>, server_nodes_manager: &'a ServerNodesManager<'a>) -> Self {
+        NodeEventHandler {
+            client_mgr,
+            server_nodes_manager,
+        }
+    }
+
+    pub fn start(&self, receiver: Receiver<NodeEvent>) {
+        for event in receiver {
+            match event {
+                NodeEvent::GpuUnhealthy { server_ip, gpu_id } => {
+                    self.evacuate_gpu(&server_ip, gpu_id);
+                }
+                NodeEvent::VirtServerExited { server_ip, rpc_id } => {
+                    self.client_mgr.virt_server_exited(&server_ip, rpc_id);
+                }
+                NodeEvent::VirtServerAdopted { server_ip, rpc_id } => {
+                    self.client_mgr.virt_server_adopted(&server_ip, rpc_id);
+                }
+            }
+        }
+    }
+
+    fn evacuate_gpu(&self, server_ip: &String, gpu_id: u64) {
+        log::info!("Evacuating virt servers from GPU {} on server node {}", gpu_id, server_ip);
+
+        for client in self.client_mgr.get_all_clients() {
+            let (compute_units, memory) = match client.virt_server.as_ref() {
+                Some(virt_server) => {
+                    let virt_server = virt_server.read().unwrap();
+                    if virt_server.ipaddr != *server_ip || virt_server.gpu.read().unwrap().gpu_id != gpu_id {
+                        continue;
+                    }
+                    (virt_server.compute_units, virt_server.memory)
+                }
+                None => continue,
+            };
+
+            let _operation = match self.client_mgr.start_operation(&client.ipaddr, "evacuation") {
+                Ok(operation) => operation,
+                Err(e) => {
+                    log::error!("Error evacuating client VM {}: {}", client.ipaddr, e);
+                    continue;
+                }
+            };
+
+            // the operation that ran before may have moved the VM already
+            let still_on_gpu = self.client_mgr.get_client(&client.ipaddr)
+                .and_then(|client| client.virt_server)
+                .is_some_and(|virt_server| {
+                    let virt_server = virt_server.read().unwrap();
+                    virt_server.ipaddr == *server_ip && virt_server.gpu.read().unwrap().gpu_id == gpu_id
+                });
+            if !still_on_gpu {
+                continue;
+            }
+
+            match self.server_nodes_manager.migrate_virt_server_auto(self.client_mgr, &client.ipaddr, compute_units, memory) {
+                Ok(_) => {
+                    log::info!("Client VM {} evacuated from GPU {} on server node {}", client.ipaddr, gpu_id, server_ip);
+                }
+                Err(e) => {
+                    log::error!("Error evacuating client VM {}: {}", client.ipaddr, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Control operations running on VMs, at most one per VM. Operations on different VMs do not wait
+/// for each other.
+pub struct VmOperations {
+    /// VM IP -> name of the operation running on it
+    running: Mutex<HashMap<String, &'static str>>,
+    finished: Condvar,
+}
+
+/// An operation running on a VM, the next one may start once this is dropped.
+pub struct VmOperation<'a> {
+    operations: &'a VmOperations,
+    vm_ip: String,
+}
+
+impl VmOperations {
+    pub fn new() -> Self {
+        VmOperations {
+            running: Mutex::new(HashMap::new()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Starts `operation` on a VM, failing right away if another one is running on it.
+    pub fn try_start(&self, vm_ip: &str, operation: &'static str) -> Result<VmOperation<'_>, String> {
+        self.start(vm_ip, operation, Duration::ZERO)
+    }
+
+    /// Starts `operation` on a VM once the operation running on it finished, waiting at most `timeout`.
+    pub fn start(&self, vm_ip: &str, operation: &'static str, timeout: Duration) -> Result<VmOperation<'_>, String> {
+        let deadline = Instant::now() + timeout;
+        let mut running = self.running.lock().unwrap();
+        while let Some(current) = running.get(vm_ip).copied() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("VM {} is busy: {} in progress", vm_ip, current));
+            }
+            running = self.finished.wait_timeout(running, deadline - now).unwrap().0;
+        }
+
+        log::debug!("Starting {} of VM {}", operation, vm_ip);
+        running.insert(vm_ip.to_string(), operation);
+        Ok(VmOperation {
+            operations: self,
+            vm_ip: vm_ip.to_string(),
+        })
+    }
+}
+
+impl Drop for VmOperation<'_> {
+    fn drop(&mut self) {
+        self.operations.running.lock().unwrap().remove(&self.vm_ip);
+        self.operations.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_try_start_busy() {
+        let operations = VmOperations::new();
+        let migration = operations.try_start("10.0.0.2", "migration").unwrap();
+        assert_eq!(operations.try_start("10.0.0.2", "resize").err().unwrap(), "VM 10.0.0.2 is busy: migration in progress");
+        // other VMs do not wait
+        assert!(operations.try_start("10.0.0.3", "resize").is_ok());
+        drop(migration);
+        assert!(operations.try_start("10.0.0.2", "resize").is_ok());
+    }
+
+    #[test]
+    fn test_queue_timeout() {
+        let operations = VmOperations::new();
+        let _migration = operations.try_start("10.0.0.2", "migration").unwrap();
+        let start = Instant::now();
+        assert!(operations.start("10.0.0.2", "resize", Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_release_on_drop() {
+        let operations = VmOperations::new();
+        let migration = operations.try_start("10.0.0.2", "migration").unwrap();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| operations.start("10.0.0.2", "resize", Duration::from_secs(5)).map(|_| ()));
+            thread::sleep(Duration::from_millis(50));
+            drop(migration);
+            assert!(waiter.join().unwrap().is_ok());
+        });
+        assert!(operations.running.lock().unwrap().is_empty());
+    }
+}
@@ -4,30 +4,27 @@ use log::info;
 
 use crate::bookkeeping::*;
 use crate::client_handler::FlytClientManager;
+use crate::hibernation::HibernatedVm;
+use crate::lifecycle::VmState;
 use crate::common::api_commands::FlytApiCommand;
-use crate::common::types::StreamEnds;
+use crate::common::types::{GpuHealth, HostLimits, IsolationLevel, MpsStatus, RestartPolicy, StreamEnds, VirtServerFlavor};
+use crate::node_events::NodeEvent;
+use crate::telemetry::{TelemetrySample, TelemetryStore};
 use crate::common::utils::StreamUtils;
 
 use std::collections::HashMap;
 use std::{fs, thread};
-use std::io::{ BufReader, Write };
+use std::io::{ BufRead, BufReader, ErrorKind, Write };
 use std::net::{TcpListener, TcpStream};
 use std::path::Path;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-
-macro_rules! get_reader {
-    ($server_node: expr) => {
-        $server_node.stream.write().unwrap().reader
-    };
-}
-
-macro_rules! get_writer {
-    ($server_node: expr) => {
-        $server_node.stream.write().unwrap().writer
-    };
-}
-
+/// Time a server node has to send its first command before it is taken for a legacy node daemon
+const NODE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time a server node has to send the rest of an event
+const NODE_EVENT_TIMEOUT: Duration = Duration::from_secs(30);
 
 macro_rules! stream_write {
     ($stream: expr, $data: expr) => {
@@ -68,6 +65,8 @@ macro_rules! stream_read_response {
 pub struct ServerNodesManager<'a> {
     server_nodes: Mutex<HashMap<String, ServerNode>>,
     vm_resource_getter: &'a VMResourcesGetter,
+    event_sender: Mutex<Option<Sender<NodeEvent>>>,
+    telemetry: TelemetryStore,
 }
 
 impl<'a> ServerNodesManager<'a> {
@@ -76,9 +75,24 @@ impl<'a> ServerNodesManager<'a> {
         ServerNodesManager {
             server_nodes: Mutex::new(HashMap::new()),
             vm_resource_getter: resource_getter,
+            event_sender: Mutex::new(None),
+            telemetry: TelemetryStore::new(get_telemetry_history_length()),
         }
     }
 
+    pub fn telemetry(&self) -> &TelemetryStore {
+        &self.telemetry
+    }
+
+    pub fn vm_resource_getter(&self) -> &VMResourcesGetter {
+        self.vm_resource_getter
+    }
+
+    /// Node events that need the client manager are forwarded to `sender`, see `NodeEventHandler`.
+    pub fn set_event_sender(&self, sender: Sender<NodeEvent>) {
+        self.event_sender.lock().unwrap().replace(sender);
+    }
+
     pub fn add_server_node(&self, server_node: ServerNode) {
         let mut server_nodes = self.server_nodes.lock().unwrap();
         server_nodes.insert(server_node.ipaddr.clone(), server_node);
@@ -111,16 +125,19 @@ impl<'a> ServerNodesManager<'a> {
     pub fn start_servernode_handler(&self, port : u16) {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
         log::info!("Server node handler started on port: {}", port);
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_servernode(stream)
-                }
-                Err(e) => {
-                    log::error!("Error accepting connection: {}", e)
+        // every connection gets its own thread, so a slow registration does not hold up node events
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        s.spawn(move || self.handle_servernode(stream));
+                    }
+                    Err(e) => {
+                        log::error!("Error accepting connection: {}", e)
+                    }
                 }
             }
-        }
+        });
     }
 
     fn handle_servernode(&self, stream: TcpStream) {
@@ -140,20 +157,61 @@ impl<'a> ServerNodesManager<'a> {
             }
         };
 
-        let reader = BufReader::new(reader_clone);
+        let mut reader = BufReader::new(reader_clone);
+
+        // node daemons older than SNODE_RMGR_CONNECT send nothing and wait for the GPU request
+        let _ = stream.set_read_timeout(Some(NODE_HANDSHAKE_TIMEOUT));
+        let command = match StreamUtils::read_line(&mut reader) {
+            Ok(command) => command,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                log::info!("Server node {} sent no command, registering it as a legacy node daemon", server_ip);
+                FlytApiCommand::SNODE_RMGR_CONNECT.to_string()
+            }
+            Err(e) => {
+                log::error!("Error reading command from server node {}: {}", server_ip, e);
+                return;
+            }
+        };
+
+        // events are a single line, registration keeps the stream for requests without a timeout
+        let read_timeout = if command == FlytApiCommand::SNODE_RMGR_CONNECT { None } else { Some(NODE_EVENT_TIMEOUT) };
+        if let Err(e) = stream.set_read_timeout(read_timeout) {
+            log::error!("Error setting read timeout for server node {}: {}", server_ip, e);
+            return;
+        }
+
+        match command.as_str() {
+            FlytApiCommand::SNODE_RMGR_CONNECT => {}
+            FlytApiCommand::SNODE_RMGR_GPU_HEALTH_CHANGED => {
+                self.handle_gpu_health_changed(&server_ip, stream, reader);
+                return;
+            }
+            FlytApiCommand::SNODE_RMGR_TELEMETRY => {
+                self.handle_telemetry(&server_ip, stream, reader);
+                return;
+            }
+            FlytApiCommand::SNODE_RMGR_VIRT_SERVER_EXITED => {
+                self.handle_virt_server_exited(&server_ip, stream, reader);
+                return;
+            }
+            FlytApiCommand::SNODE_RMGR_GPU_INVENTORY => {
+                self.handle_gpu_inventory(&server_ip, stream, reader);
+                return;
+            }
+            _ => {
+                log::error!("Unknown command from server node {}: {}", server_ip, command);
+                return;
+            }
+        }
     
         log::info!("Server node connected: {}", server_ip);
 
         if self.exists(&server_ip) {
             log::info!("Server node already exists: {}", server_ip);
             
-            let mut server_node = self.get_server_node(&server_ip).unwrap();
-            server_node.stream.write().unwrap().writer = stream;
-            server_node.stream.write().unwrap().reader = reader;
-            server_node.virt_servers = Vec::new();
-            server_node.gpus = Vec::new();
-
-            self.update_server_node(server_node);
+            // GPUs and virt servers are refreshed below, keeping the entries clients refer to
+            let server_node = self.get_server_node(&server_ip).unwrap();
+            *server_node.stream.write().unwrap() = StreamEnds{writer: stream, reader};
         }
         else {
             
@@ -162,16 +220,303 @@ impl<'a> ServerNodesManager<'a> {
                 gpus: Vec::new(),
                 stream: Arc::new(RwLock::new(StreamEnds{writer: stream, reader })),
                 virt_servers: Vec::new(),
+                flavors: Vec::new(),
             };
         
             self.add_server_node(server_node);
         }
 
         let _ = self.update_server_node_gpus(&server_ip);
+        if let Err(e) = self.update_server_node_flavors(&server_ip) {
+            // every node daemon runs `program-path` as the default flavor
+            log::error!("Error getting virt server flavors of server node {}: {}, assuming it offers the default flavor", server_ip, e);
+            if let Some(mut server_node) = self.get_server_node(&server_ip) {
+                server_node.flavors = vec![VirtServerFlavor::default()];
+                self.update_server_node(server_node);
+            }
+        }
+        if let Err(e) = self.update_server_node_virt_servers(&server_ip) {
+            log::error!("Error getting virt servers of server node {}: {}", server_ip, e);
+        }
         
     }
 
+    fn handle_gpu_health_changed(&self, server_ip: &String, mut stream: TcpStream, mut reader: BufReader<TcpStream>) {
+        let payload = match StreamUtils::read_line(&mut reader) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Error reading gpu health from server node {}: {}", server_ip, e);
+                return;
+            }
+        };
+
+        // format: gpu_id,health,reason
+        let parts = payload.splitn(3, ",").collect::<Vec<&str>>();
+        let gpu_id = parts.first().and_then(|s| s.parse::<u64>().ok());
+        let health = parts.get(1).and_then(|s| s.parse::<GpuHealth>().ok());
+
+        let (gpu_id, health) = match (gpu_id, health) {
+            (Some(gpu_id), Some(health)) => (gpu_id, health),
+            _ => {
+                log::error!("Invalid gpu health event from server node {}: {}", server_ip, payload);
+                let _ = stream.write_all("400\nInvalid arguments\n".as_bytes());
+                return;
+            }
+        };
+        let reason = parts.get(2).unwrap_or(&"");
+
+        let gpu = self.get_server_node(server_ip)
+            .and_then(|server_node| server_node.gpus.iter().find(|gpu| gpu.read().unwrap().gpu_id == gpu_id).cloned());
+
+        let gpu = match gpu {
+            Some(gpu) => gpu,
+            None => {
+                log::error!("GPU {} not found on server node {}", gpu_id, server_ip);
+                let _ = stream.write_all("400\nGPU not found\n".as_bytes());
+                return;
+            }
+        };
+
+        log::warn!("GPU {} on server node {} is {} {}", gpu_id, server_ip, health.as_str(), reason);
+        gpu.write().unwrap().health = health;
+
+        if let Err(e) = stream.write_all("200\nDone\n".as_bytes()) {
+            log::error!("Error writing to stream: {}", e);
+        }
+
+        if health == GpuHealth::Unhealthy && get_gpu_health_evacuate() {
+            if let Some(sender) = self.event_sender.lock().unwrap().as_ref() {
+                let _ = sender.send(NodeEvent::GpuUnhealthy { server_ip: server_ip.clone(), gpu_id });
+            }
+        }
+    }
+
+    fn handle_virt_server_exited(&self, server_ip: &String, mut stream: TcpStream, mut reader: BufReader<TcpStream>) {
+        let payload = match StreamUtils::read_line(&mut reader) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Error reading virt server exit from server node {}: {}", server_ip, e);
+                return;
+            }
+        };
+
+        // format: rpc_id,status,restarted
+        let parts = payload.split(",").collect::<Vec<&str>>();
+        let rpc_id = parts.first().and_then(|s| s.parse::<u64>().ok());
+        let restarted = parts.get(2).and_then(|s| s.parse::<bool>().ok());
+
+        let (rpc_id, restarted) = match (rpc_id, restarted) {
+            (Some(rpc_id), Some(restarted)) => (rpc_id, restarted),
+            _ => {
+                log::error!("Invalid virt server exit event from server node {}: {}", server_ip, payload);
+                let _ = stream.write_all("400\nInvalid arguments\n".as_bytes());
+                return;
+            }
+        };
+
+        let _ = stream.write_all("200\nDone\n".as_bytes());
+
+        if restarted {
+            log::warn!("Virt server {}/{} exited with {} and was restarted", server_ip, rpc_id, parts[1]);
+            return;
+        }
+
+        log::warn!("Virt server {}/{} exited with {}", server_ip, rpc_id, parts[1]);
+
+        if let Err(e) = self.release_virt_server(server_ip, rpc_id) {
+            log::error!("Error releasing virt server {}/{}: {}", server_ip, rpc_id, e);
+        }
+
+        if let Some(sender) = self.event_sender.lock().unwrap().as_ref() {
+            let _ = sender.send(NodeEvent::VirtServerExited { server_ip: server_ip.clone(), rpc_id });
+        }
+    }
+
+    fn handle_telemetry(&self, server_ip: &String, mut stream: TcpStream, mut reader: BufReader<TcpStream>) {
+        match TelemetrySample::read_from(&mut reader) {
+            Ok(sample) => {
+                self.telemetry.record(server_ip, sample);
+                let _ = stream.write_all("200\nDone\n".as_bytes());
+            }
+            Err(e) => {
+                log::error!("Error reading telemetry from server node {}: {}", server_ip, e);
+                let _ = stream.write_all(format!("400\n{}\n", e).as_bytes());
+            }
+        }
+    }
+
+    fn handle_gpu_inventory(&self, server_ip: &String, mut stream: TcpStream, mut reader: BufReader<TcpStream>) {
+        let gpus = match read_gpu_info(&mut reader) {
+            Ok(gpus) => gpus,
+            Err(e) => {
+                log::error!("Invalid gpu inventory from server node {}: {}", server_ip, e);
+                let _ = stream.write_all(format!("400\n{}\n", e).as_bytes());
+                return;
+            }
+        };
+
+        log::info!("GPU inventory of server node {} changed", server_ip);
+        self.merge_server_node_gpus(server_ip, gpus);
+        let _ = stream.write_all("200\nDone\n".as_bytes());
+    }
+
+    fn update_server_node_flavors(&self, server_node_ip: &String) -> Result<(),String> {
+        let mut server_node = self.get_server_node(server_node_ip).ok_or("Server node not found")?;
+
+        let mut flavors = Vec::new();
+        {
+            let mut stream = server_node.stream.write().unwrap();
+            stream_write!(stream.writer, format!("{}\n", FlytApiCommand::RMGR_SNODE_SEND_FLAVORS));
+
+            let status = stream_read_line!(stream.reader);
+            let num_flavors_str = stream_read_line!(stream.reader);
+            if status != "200" {
+                return Err(format!("Server node {}: {}", server_node_ip, num_flavors_str));
+            }
+            let num_flavors = num_flavors_str.parse::<u64>().map_err(|_| format!("Invalid number of flavors: {}", num_flavors_str))?;
+
+            for _ in 0..num_flavors {
+                let flavor_str = stream_read_line!(stream.reader);
+                match flavor_str.parse::<VirtServerFlavor>() {
+                    Ok(flavor) => flavors.push(flavor),
+                    Err(e) => log::error!("Server node {}: {}", server_node_ip, e),
+                }
+            }
+        }
+
+        if flavors.is_empty() {
+            return Err("no valid virt server flavor".to_string());
+        }
+
+        log::info!("Server node {} offers virt server flavors: {:?}", server_node_ip, flavors.iter().map(|flavor| flavor.name.as_str()).collect::<Vec<&str>>());
+        server_node.flavors = flavors;
+        self.update_server_node(server_node);
+        Ok(())
+    }
+
+    /// Takes over the virt servers a server node reports as running, after it reconnected or when this
+    /// resource manager replaced another one. GPU allocations are recomputed from them, and clients of
+    /// virt servers that are gone are told so.
+    fn update_server_node_virt_servers(&self, server_node_ip: &String) -> Result<(),String> {
+        let mut server_node = self.get_server_node(server_node_ip).ok_or("Server node not found")?;
+
+        let mut reported = Vec::new();
+        {
+            let mut stream = server_node.stream.write().unwrap();
+            stream_write!(stream.writer, format!("{}\n", FlytApiCommand::RMGR_SNODE_SEND_VIRT_SERVERS));
+
+            let status = stream_read_line!(stream.reader);
+            let num_virt_servers_str = stream_read_line!(stream.reader);
+            if status != "200" {
+                return Err(format!("Server node {}: {}", server_node_ip, num_virt_servers_str));
+            }
+            let num_virt_servers = num_virt_servers_str.parse::<u64>().map_err(|_| format!("Invalid number of virt servers: {}", num_virt_servers_str))?;
+
+            for _ in 0..num_virt_servers {
+                let virt_server_str = stream_read_line!(stream.reader);
+                match parse_virt_server_info(&virt_server_str) {
+                    Some(virt_server) => reported.push(virt_server),
+                    None => log::error!("Invalid virt server from server node {}: {}", server_node_ip, virt_server_str),
+                }
+            }
+        }
+
+        for gpu in server_node.gpus.iter() {
+            let mut gpu_write = gpu.write().unwrap();
+            gpu_write.allocated_compute_units = 0;
+            gpu_write.allocated_memory = 0;
+        }
+
+        let mut virt_servers = Vec::new();
+        let mut adopted = Vec::new();
+        for (rpc_id, gpu_id, compute_units, memory) in reported {
+            let gpu = match server_node.gpus.iter().find(|gpu| gpu.read().unwrap().gpu_id == gpu_id) {
+                Some(gpu) => gpu.clone(),
+                None => {
+                    log::warn!("Virt server {}/{} runs on unknown GPU {}", server_node_ip, rpc_id, gpu_id);
+                    continue;
+                }
+            };
+
+            {
+                let mut gpu_write = gpu.write().unwrap();
+                gpu_write.allocated_compute_units += compute_units;
+                gpu_write.allocated_memory += memory;
+            }
+
+            // clients that used the virt server before keep their reference to it
+            let virt_server = match server_node.virt_servers.iter().find(|virt_server| virt_server.read().unwrap().rpc_id == rpc_id) {
+                Some(existing) => {
+                    {
+                        let mut existing_write = existing.write().unwrap();
+                        existing_write.compute_units = compute_units;
+                        existing_write.memory = memory;
+                        existing_write.gpu = gpu;
+                    }
+                    existing.clone()
+                }
+                None => {
+                    log::info!("Adopting virt server {}/{} on GPU {}", server_node_ip, rpc_id, gpu_id);
+                    adopted.push(rpc_id);
+                    // a MIG slice is held as a dedicated unit, as if the cluster manager had allocated it
+                    {
+                        let mut gpu_write = gpu.write().unwrap();
+                        if gpu_write.isolation == IsolationLevel::Mig {
+                            gpu_write.exclusive = true;
+                        }
+                    }
+                    Arc::new(RwLock::new(VirtServer {
+                        ipaddr: server_node.ipaddr.clone(),
+                        compute_units,
+                        memory,
+                        rpc_id,
+                        gpu,
+                    }))
+                }
+            };
+            virt_servers.push(virt_server);
+        }
+
+        for gpu in server_node.gpus.iter() {
+            let gpu_id = gpu.read().unwrap().gpu_id;
+            if !virt_servers.iter().any(|virt_server| virt_server.read().unwrap().gpu.read().unwrap().gpu_id == gpu_id) {
+                gpu.write().unwrap().exclusive = false;
+            }
+        }
+
+        let gone = server_node.virt_servers.iter()
+            .map(|virt_server| virt_server.read().unwrap().rpc_id)
+            .filter(|rpc_id| !virt_servers.iter().any(|virt_server| virt_server.read().unwrap().rpc_id == *rpc_id))
+            .collect::<Vec<u64>>();
+
+        log::info!("Server node {} runs {} virt servers", server_node_ip, virt_servers.len());
+        server_node.virt_servers = virt_servers;
+        self.update_server_node(server_node);
+
+        for rpc_id in gone {
+            log::warn!("Virt server {}/{} is no longer running", server_node_ip, rpc_id);
+            if let Some(sender) = self.event_sender.lock().unwrap().as_ref() {
+                let _ = sender.send(NodeEvent::VirtServerExited { server_ip: server_node_ip.clone(), rpc_id });
+            }
+        }
+        for rpc_id in adopted {
+            if let Some(sender) = self.event_sender.lock().unwrap().as_ref() {
+                let _ = sender.send(NodeEvent::VirtServerAdopted { server_ip: server_node_ip.clone(), rpc_id });
+            }
+        }
+        Ok(())
+    }
+
     fn update_server_node_gpus(&self, server_node_ip: &String ) -> Result<(),String> {
+        self.fetch_server_node_gpus(server_node_ip, FlytApiCommand::RMGR_SNODE_SEND_GPU_INFO)
+    }
+
+    /// Asks the server node to rescan its GPUs and merges the result.
+    pub fn rescan_server_node_gpus(&self, server_node_ip: &String) -> Result<(),String> {
+        self.fetch_server_node_gpus(server_node_ip, FlytApiCommand::RMGR_SNODE_RESCAN_GPUS)
+    }
+
+    fn fetch_server_node_gpus(&self, server_node_ip: &String, command: &str) -> Result<(),String> {
 
         log::info!("Getting GPU details for servernode: {}", server_node_ip);
 
@@ -180,53 +525,84 @@ impl<'a> ServerNodesManager<'a> {
             return Err("Server node not found".to_string());
         }
 
-        let mut server_node = self.get_server_node(server_node_ip).unwrap();
+        let server_node = self.get_server_node(server_node_ip).unwrap();
 
-        stream_write!(get_writer!(server_node), format!("{}\n", FlytApiCommand::RMGR_SNODE_SEND_GPU_INFO));
+        let gpus = {
+            let mut stream = server_node.stream.write().unwrap();
+            stream_write!(stream.writer, format!("{}\n", command));
 
-        let status = stream_read_line!(get_reader!(server_node));
+            let status = stream_read_line!(stream.reader);
 
-        if status != "200" {
-            let err_msg = stream_read_line!(get_reader!(server_node));
-            log::error!("RMGR_SNODE_SEND_GPU_INFO, Status: {}\n{}", status, err_msg);
-            return Err(format!("RMGR_SNODE_SEND_GPU_INFO, Status: {}\n{}", status, err_msg));
-        }
-        
-        let num_gpus_str = stream_read_line!(get_reader!(server_node));
-        
-        let num_gpus = num_gpus_str.parse::<u64>().unwrap();
+            if status != "200" {
+                let err_msg = stream_read_line!(stream.reader);
+                log::error!("{}, Status: {}, {}", command, status, err_msg);
+                return Err(format!("Server node {}: {}", server_node_ip, err_msg));
+            }
+
+            read_gpu_info(&mut stream.reader)?
+        };
+
+        self.merge_server_node_gpus(server_node_ip, gpus);
+        log::info!("Server node gpus updated: {}", server_node_ip);
+        Ok(())
+    }
+
+    /// Replaces the GPU list of a server node with `reported`. GPUs that are still present keep their `Arc`,
+    /// so virt servers and allocations that refer to them stay attached.
+    fn merge_server_node_gpus(&self, server_node_ip: &String, reported: Vec<GPU>) {
+        let mut server_node = match self.get_server_node(server_node_ip) {
+            Some(server_node) => server_node,
+            None => return,
+        };
 
         let mut gpus = Vec::new();
+        for gpu in reported {
+            match server_node.gpus.iter().find(|existing| existing.read().unwrap().gpu_id == gpu.gpu_id) {
+                Some(existing) => {
+                    {
+                        let mut existing_write = existing.write().unwrap();
+                        if existing_write.memory != gpu.memory || existing_write.compute_units != gpu.compute_units {
+                            log::info!("GPU {} on server node {} changed from {} SMs, {} bytes to {} SMs, {} bytes",
+                                gpu.gpu_id, server_node_ip, existing_write.compute_units, existing_write.memory, gpu.compute_units, gpu.memory);
+                        }
+                        existing_write.name = gpu.name;
+                        existing_write.memory = gpu.memory;
+                        existing_write.compute_units = gpu.compute_units;
+                        existing_write.compute_power = gpu.compute_power;
+                        existing_write.isolation = gpu.isolation;
+                        existing_write.parent_gpu_id = gpu.parent_gpu_id;
+                        existing_write.health = gpu.health;
+                        existing_write.mps = gpu.mps;
+                    }
+                    gpus.push(existing.clone());
+                }
+                None => {
+                    log::info!("GPU {} added on server node {}", gpu.gpu_id, server_node_ip);
+                    gpus.push(Arc::new(RwLock::new(gpu)));
+                }
+            }
+        }
 
-        for _ in 0..num_gpus {
-            let gpu_info_str = stream_read_line!(get_reader!(server_node));
-            let gpu_info = gpu_info_str.split(",").collect::<Vec<&str>>();
-            // gpu_id, name, memory, compute_units, compute_power
-            let gpu = Arc::new(RwLock::new (GPU {
-                gpu_id: gpu_info[0].parse::<u64>().unwrap(),
-                name: gpu_info[1].to_string(),
-                memory: gpu_info[2].parse::<u64>().unwrap(),
-                compute_units: gpu_info[3].parse::<u32>().unwrap(),
-                compute_power: gpu_info[4].parse::<u64>().unwrap(),
-                ..Default::default()
-            }));
-            gpus.push(gpu);
+        for removed in server_node.gpus.iter().filter(|existing| !gpus.iter().any(|gpu| Arc::ptr_eq(gpu, existing))) {
+            let removed = removed.read().unwrap();
+            if removed.allocated_compute_units > 0 || removed.allocated_memory > 0 {
+                log::warn!("GPU {} removed from server node {} while virt servers were allocated on it", removed.gpu_id, server_node_ip);
+            } else {
+                log::info!("GPU {} removed from server node {}", removed.gpu_id, server_node_ip);
+            }
         }
 
         server_node.gpus = gpus;
         self.update_server_node(server_node);
-        log::info!("Server node gpus updated: {}", server_node_ip);
-        Ok(())
     }
 
     fn get_free_gpu(&self, required_resources: &VMResources) -> Option<(String, u64)> {
         let host_server_node = self.get_server_node(&required_resources.host_ip);
         
-        if host_server_node.is_some() {
-            let host_server_node = host_server_node.unwrap();
+        if let Some(host_server_node) = host_server_node {
             let gpu_id = check_resource_availability(&host_server_node, required_resources);
-            if gpu_id.is_some() {
-                return Some((host_server_node.ipaddr.clone(), gpu_id.unwrap()));
+            if let Some(gpu_id) = gpu_id {
+                return Some((host_server_node.ipaddr.clone(), gpu_id));
             }
         }
 
@@ -236,8 +612,8 @@ impl<'a> ServerNodesManager<'a> {
         
         for server_node in server_nodes {
             let gpu_id = check_resource_availability(&server_node, required_resources);
-            if gpu_id.is_some() {
-                return Some((server_node.ipaddr.clone(), gpu_id.unwrap()));
+            if let Some(gpu_id) = gpu_id {
+                return Some((server_node.ipaddr.clone(), gpu_id));
             }
         }
 
@@ -267,20 +643,23 @@ impl<'a> ServerNodesManager<'a> {
 
         let (target_server_ip, target_gpu_id) = target_gpu.unwrap();
         
-        let virt_server = self.create_virt_server(&target_server_ip, target_gpu_id, vm_required_resources.compute_units, vm_required_resources.memory, false);
+        let exclusive = vm_required_resources.placement() == PlacementPolicy::MigSlice;
+        let virt_server = self.create_virt_server(&target_server_ip, target_gpu_id, vm_required_resources.compute_units, vm_required_resources.memory, vm_required_resources.restart_policy(), vm_required_resources.host_limits(), vm_required_resources.flavor(), false, exclusive);
 
         if virt_server.is_err() {
             log::error!("Error creating virt server for client: {}", client_ip);
-            return Err("Error creating virt server".to_string());
+            return Err(format!("Error creating virt server: {}", virt_server.err().unwrap()));
         }
 
-        let virt_server = virt_server.unwrap();
-
-        Ok(virt_server)
+        Ok(virt_server.unwrap())
 
     }
 
-    pub fn create_virt_server(&self, snode_ip: &String, gpu_id: u64, compute_units: u32, memory: u64, allow_overprovision: bool) -> Result<Arc<RwLock<VirtServer>>,String> {
+    /// Allocates a virt server on GPU `gpu_id` of a server node. The GPU's resources are reserved before the
+    /// server node is asked, so concurrent allocations cannot both take the last of them, and `exclusive`
+    /// dedicates the GPU, a MIG slice, to this virt server.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_virt_server(&self, snode_ip: &String, gpu_id: u64, compute_units: u32, memory: u64, restart_policy: RestartPolicy, host_limits: HostLimits, flavor: &str, allow_overprovision: bool, exclusive: bool) -> Result<Arc<RwLock<VirtServer>>,String> {
         
         let server_node = self.get_server_node(&snode_ip);
 
@@ -298,30 +677,79 @@ impl<'a> ServerNodesManager<'a> {
             return Err("GPU not found".to_string());
         }
 
-        let target_gpu = target_gpu.unwrap();
+        let target_gpu = target_gpu.unwrap().clone();
+
+        if !server_node.flavors.iter().any(|offered| offered.name == flavor) {
+            log::error!("Server node {} does not offer virt server flavor {}", snode_ip, flavor);
+            return Err(format!("Server node does not offer virt server flavor {}", flavor));
+        }
 
         {
-            let gpu_read = target_gpu.read().unwrap();
+            let mut gpu_write = target_gpu.write().unwrap();
 
-            if !allow_overprovision && (compute_units > (gpu_read.compute_units - gpu_read.allocated_compute_units) 
-                                        || memory > (gpu_read.memory - gpu_read.allocated_memory)) 
+            if gpu_write.exclusive {
+                log::error!("GPU {} is a MIG slice dedicated to another VM", gpu_id);
+                return Err("GPU is dedicated to another VM".to_string());
+            }
+
+            if !allow_overprovision && (compute_units > (gpu_write.compute_units.saturating_sub(gpu_write.allocated_compute_units)) 
+                                        || memory > (gpu_write.memory.saturating_sub(gpu_write.allocated_memory))) 
             {
                 log::error!("Not enough resources to allocate compute_units: {}, memory: {}", compute_units, memory);
-                log::error!("Available compute_units: {}, memory: {}", gpu_read.compute_units - gpu_read.allocated_compute_units, gpu_read.memory - gpu_read.allocated_memory);
+                log::error!("Available compute_units: {}, memory: {}", gpu_write.compute_units.saturating_sub(gpu_write.allocated_compute_units), gpu_write.memory.saturating_sub(gpu_write.allocated_memory));
                 return Err("Not enough resources to allocate".to_string());
             }
+
+            gpu_write.allocated_compute_units += compute_units;
+            gpu_write.allocated_memory += memory;
+            if exclusive {
+                log::info!("Reserving MIG slice {} on {}", gpu_id, snode_ip);
+                gpu_write.exclusive = true;
+            }
         }
 
-        stream_write!(get_writer!(server_node), format!("{}\n{},{},{}\n", FlytApiCommand::RMGR_SNODE_ALLOC_VIRT_SERVER, gpu_id, compute_units, memory));
+        let release_reservation = || {
+            let mut gpu_write = target_gpu.write().unwrap();
+            gpu_write.allocated_compute_units = gpu_write.allocated_compute_units.saturating_sub(compute_units);
+            gpu_write.allocated_memory = gpu_write.allocated_memory.saturating_sub(memory);
+            if exclusive {
+                gpu_write.exclusive = false;
+            }
+        };
 
-        let response = stream_read_response!(get_reader!(server_node), 2);
+        // a virt server that timed out during startup has been stopped again, so allocating anew is safe
+        let response = match self.request_with_retry(&server_node.ipaddr, &server_node.stream, format!("{}\n{},{},{},{},{},{}\n", FlytApiCommand::RMGR_SNODE_ALLOC_VIRT_SERVER, gpu_id, compute_units, memory, restart_policy.as_str(), host_limits.to_arg(), flavor)) {
+            Ok(response) => response,
+            Err(e) => {
+                release_reservation();
+                return Err(e);
+            }
+        };
 
         if response[0] != "200" {
-            log::error!("RMGR_SNODE_ALLOC_VIRT_SERVER, Status: {}\n{}", response[0], response[1]);
-            return Err(format!("RMGR_SNODE_ALLOC_VIRT_SERVER, Status: {}\n{}", response[0], response[1]));
+            log::error!("RMGR_SNODE_ALLOC_VIRT_SERVER, Status: {}, {}", response[0], response[1]);
+            release_reservation();
+            return Err(format!("Server node {}: {}", snode_ip, response[1]));
         }
 
-        let virt_server_rpc_id = response[1].parse::<u64>().unwrap();
+        // format: rpc_id[,applied_sm_cores,applied_memory,cuda_version]
+        let fields = response[1].split(",").collect::<Vec<&str>>();
+        let virt_server_rpc_id = match fields[0].parse::<u64>() {
+            Ok(rpc_id) => rpc_id,
+            Err(_) => {
+                release_reservation();
+                return Err(format!("Invalid virt server reply: {}", response[1]));
+            }
+        };
+
+        if fields.len() >= 4 {
+            let applied_compute_units = fields[1].parse::<u32>().unwrap_or(compute_units);
+            let applied_memory = fields[2].parse::<u64>().unwrap_or(memory);
+            if applied_compute_units != compute_units || applied_memory != memory {
+                log::warn!("Virt server {} on {} applied compute_units: {}, memory: {} instead of {}, {}", virt_server_rpc_id, snode_ip, applied_compute_units, applied_memory, compute_units, memory);
+            }
+            log::info!("Virt server {} on {} uses CUDA runtime {}", virt_server_rpc_id, snode_ip, fields[3]);
+        }
 
         let virt_server = Arc::new(RwLock::new(VirtServer {
             ipaddr: server_node.ipaddr.clone(),
@@ -332,18 +760,44 @@ impl<'a> ServerNodesManager<'a> {
         }));
 
         server_node.virt_servers.push(virt_server.clone());
-
-        {
-            let mut gpu_write = target_gpu.write().unwrap();
-            gpu_write.allocated_compute_units += compute_units;
-            gpu_write.allocated_memory += memory;
-        }
-
         self.update_server_node(server_node);
 
         Ok(virt_server)
     }
 
+    /// Sends `request` to a server node and reads the two line response.
+    fn request(&self, stream: &RwLock<StreamEnds<TcpStream>>, request: String) -> Result<Vec<String>,String> {
+        // held until the response is read, so concurrent requests to the node are not interleaved
+        let mut stream = stream.write().unwrap();
+        stream_write!(stream.writer, request);
+        Ok(stream_read_response!(stream.reader, 2))
+    }
+
+    /// Like `request`, for requests that can safely be repeated: allocations and resizes. A 504 means the virt
+    /// server did not answer in time and the operation may still finish; it is sent again up to
+    /// `get_timeout_retries()` times.
+    fn request_with_retry(&self, snode_ip: &str, stream: &RwLock<StreamEnds<TcpStream>>, request: String) -> Result<Vec<String>,String> {
+        let retries = get_timeout_retries();
+        let mut attempt = 0;
+
+        loop {
+            let response = self.request(stream, request.clone())?;
+
+            if response[0] != "504" || attempt >= retries {
+                return Ok(response);
+            }
+
+            attempt += 1;
+            log::warn!("Server node {} timed out: {}, retrying ({} of {})", snode_ip, response[1], attempt, retries);
+        }
+    }
+
+    /// Looks up virt server `rpc_id` of a server node. Fails if the server node is not connected.
+    pub fn find_virt_server(&self, snode_ip: &str, rpc_id: u64) -> Result<Option<Arc<RwLock<VirtServer>>>,String> {
+        let server_node = self.get_server_node(&snode_ip.to_string()).ok_or(format!("Server node {} is not connected", snode_ip))?;
+        Ok(server_node.virt_servers.iter().find(|virt_server| virt_server.read().unwrap().rpc_id == rpc_id).cloned())
+    }
+
     pub fn checkpoint(&self, virt_ip: &String, rpc_id: u64, ckp_path: &String) -> Result<(),String> {
         let server_node = self.get_server_node(&virt_ip);
 
@@ -361,18 +815,48 @@ impl<'a> ServerNodesManager<'a> {
             return Err("Virt server not found".to_string());
         }
 
-        stream_write!(get_writer!(server_node), format!("{}\n{}\n{}\n", FlytApiCommand::RMGR_SNODE_CHECKPOINT, rpc_id, ckp_path));
-
-        let response = stream_read_response!(get_reader!(server_node), 2);
+        // a checkpoint that timed out may still be writing to ckp_path, the migration is rolled back instead of repeating it
+        let response = self.request(&server_node.stream, format!("{}\n{}\n{}\n", FlytApiCommand::RMGR_SNODE_CHECKPOINT, rpc_id, ckp_path))?;
 
         if response[0] != "200" {
-            log::error!("RMGR_SNODE_CHECKPOINT, Status: {}\n{}", response[0], response[1]);
-            return Err(format!("RMGR_SNODE_CHECKPOINT, Status: {}\n{}", response[0], response[1]));
+            log::error!("RMGR_SNODE_CHECKPOINT, Status: {}, {}", response[0], response[1]);
+            return Err(format!("Server node {}: {}", virt_ip, response[1]));
         }
 
         Ok(())
     }
 
+    /// Fetches the log lines of a virt server starting at `offset`, see `RMGR_SNODE_VIRT_SERVER_LOGS`.
+    pub fn get_virt_server_logs(&self, snode_ip: &String, rpc_id: u64, offset: u64) -> Result<(u64, Vec<String>),String> {
+        let server_node = self.get_server_node(snode_ip);
+
+        if server_node.is_none() {
+            log::error!("Server node not found: {}", snode_ip);
+            return Err("Server node not found".to_string());
+        }
+
+        let server_node = server_node.unwrap();
+
+        let mut stream = server_node.stream.write().unwrap();
+        stream_write!(stream.writer, format!("{}\n{},{}\n", FlytApiCommand::RMGR_SNODE_VIRT_SERVER_LOGS, rpc_id, offset));
+
+        let response = stream_read_response!(stream.reader, 2);
+
+        if response[0] != "200" {
+            log::error!("RMGR_SNODE_VIRT_SERVER_LOGS, Status: {}\n{}", response[0], response[1]);
+            return Err(format!("RMGR_SNODE_VIRT_SERVER_LOGS, Status: {}\n{}", response[0], response[1]));
+        }
+
+        // format: offset,num_lines
+        let parts = response[1].split(",").collect::<Vec<&str>>();
+        let next_offset = parts.first().and_then(|s| s.parse::<u64>().ok()).ok_or("Invalid response from server node".to_string())?;
+        let num_lines = parts.get(1).and_then(|s| s.parse::<u16>().ok()).ok_or("Invalid response from server node".to_string())?;
+
+        let lines = stream_read_response!(stream.reader, num_lines);
+
+        Ok((next_offset, lines))
+    }
+
     pub fn restore_state(&self, snode_ip: &String, rpc_id: u64, ckp_path: &String) -> Result<(),String> {
         
         let server_node = self.get_server_node(&snode_ip);
@@ -384,19 +868,25 @@ impl<'a> ServerNodesManager<'a> {
 
         let server_node = server_node.unwrap();
 
-        stream_write!(get_writer!(server_node), format!("{}\n{}\n{}\n", FlytApiCommand::RMGR_SNODE_RESTORE, rpc_id, ckp_path));
-
-        let response = stream_read_response!(get_reader!(server_node), 2);
+        // not repeated after a timeout, the caller frees the new virt server and rolls back
+        let response = self.request(&server_node.stream, format!("{}\n{}\n{}\n", FlytApiCommand::RMGR_SNODE_RESTORE, rpc_id, ckp_path))?;
 
         if response[0] != "200" {
-            log::error!("RMGR_SNODE_RESTORE, Status: {}\n{}", response[0], response[1]);
-            return Err(format!("RMGR_SNODE_RESTORE, Status: {}\n{}", response[0], response[1]));
+            log::error!("RMGR_SNODE_RESTORE, Status: {}, {}", response[0], response[1]);
+            return Err(format!("Server node {}: {}", snode_ip, response[1]));
         }
 
         Ok(())
     }
 
     pub fn migrate_virt_server(&self, client_mgr: &FlytClientManager, client_ip: &String, target_snode_id: &String, target_gpu_id: u64, new_sm_cores: u32, new_mem: u64) -> Result<Arc<RwLock<VirtServer>>,String> {
+        client_mgr.transition(client_ip, VmState::Migrating)?;
+        let res = self.move_virt_server(client_mgr, client_ip, target_snode_id, target_gpu_id, new_sm_cores, new_mem);
+        client_mgr.settle(client_ip);
+        res
+    }
+
+    fn move_virt_server(&self, client_mgr: &FlytClientManager, client_ip: &String, target_snode_id: &String, target_gpu_id: u64, new_sm_cores: u32, new_mem: u64) -> Result<Arc<RwLock<VirtServer>>,String> {
         
         log::info!("Migrating virt server: {} to server node: {}", client_ip, target_snode_id);
 
@@ -410,35 +900,15 @@ impl<'a> ServerNodesManager<'a> {
             }
         }
 
-        let ckp_base_path = get_ckp_base_path();
-
-        if ckp_base_path.is_none() {
-            log::error!("Checkpoint base path not found");
-            return Err("Checkpoint base path not found".to_string());
-        }
-
-        let ckp_base_path = ckp_base_path.unwrap();
-        let ckp_path = format!("{}/{}", ckp_base_path, client_ip);
-
-        log::debug!("Checkpoint path: {}", ckp_path);
-
-        // if path exists, remove it
-        if Path::new(&ckp_path).exists() {
-            let res = fs::remove_dir_all(&ckp_path);
-            if res.is_err() {
-                log::error!("Error removing path: {}", res.err().unwrap());
-                return Err("Error clearing checkpoint path".to_string());
-            }
-        }
-
-        // create new path
-        if fs::create_dir_all(&ckp_path).is_err() {
-            log::error!("Error creating path: {}", ckp_path);
-            return Err(format!("Error creating path: {}", ckp_path));
-        }
+        let ckp_path = prepare_ckp_path(client_ip)?;
 
         let snode_ip = client_mgr.get_client(client_ip).unwrap().virt_server.as_ref().unwrap().read().unwrap().ipaddr.clone();
         let rpc_id = client_mgr.get_client(client_ip).unwrap().virt_server.as_ref().unwrap().read().unwrap().rpc_id;
+        let vm_resources = self.vm_resource_getter.get_vm_required_resources(client_ip);
+        let restart_policy = vm_resources.as_ref().map(|rsc| rsc.restart_policy()).unwrap_or(RestartPolicy::Never);
+        let host_limits = vm_resources.as_ref().map(|rsc| rsc.host_limits()).unwrap_or_default();
+        let flavor = vm_resources.as_ref().map(|rsc| rsc.flavor()).unwrap_or(VirtServerFlavor::DEFAULT);
+        let exclusive = vm_resources.as_ref().is_some_and(|rsc| rsc.placement() == PlacementPolicy::MigSlice);
 
 
         let vserver = thread::scope( |s| {
@@ -446,17 +916,19 @@ impl<'a> ServerNodesManager<'a> {
             let checkpoint_thread = s.spawn( || {
                 let res = self.checkpoint(&snode_ip, rpc_id, &ckp_path);
                 if res.is_err() {
-                    log::error!("Error checkpointing virt server: {}", res.err().unwrap());
-                    return Err(format!("Error checkpointing virt server"));
+                    let e = res.err().unwrap();
+                    log::error!("Error checkpointing virt server: {}", e);
+                    return Err(e);
                 }
                 Ok(())
             });
 
             let create_vserver_thread = s.spawn(|| {
-                let res = self.create_virt_server(target_snode_id, target_gpu_id, new_sm_cores, new_mem, true);
+                let res = self.create_virt_server(target_snode_id, target_gpu_id, new_sm_cores, new_mem, restart_policy, host_limits, flavor, true, exclusive);
                 if res.is_err() {
-                    log::error!("Error allocating and restoring virt server: {}", res.err().unwrap());
-                    return Err(format!("Error allocating and restoring virt server"));
+                    let e = res.err().unwrap();
+                    log::error!("Error allocating and restoring virt server: {}", e);
+                    return Err(format!("Error allocating and restoring virt server: {}", e));
                 }
                 Ok(res.unwrap())
             });
@@ -465,7 +937,7 @@ impl<'a> ServerNodesManager<'a> {
                 Ok(res) => res,
                 Err(e) => {
                     log::error!("Error creating virt server: {:?}", e);
-                    return Err(format!("Error creating virt server"));
+                    return Err("Error creating virt server".to_string());
                 }
             };
 
@@ -482,13 +954,7 @@ impl<'a> ServerNodesManager<'a> {
 
             let ckp_err_handler = |e: String| {
                 log::error!("Error checkpointing virt server: {:?}", e);
-
-                // free the allocated resources
-                let res = self.free_virt_server(target_snode_id, vserver.read().unwrap().rpc_id);
-                
-                if res.is_err() {
-                    log::error!("Error freeing virt server: {}", res.err().unwrap());
-                }
+                self.rollback_migration(client_mgr, client_ip, target_snode_id, &vserver);
             };
 
 
@@ -500,16 +966,18 @@ impl<'a> ServerNodesManager<'a> {
             let checkpoint_thread_res = checkpoint_thread_res.unwrap();
 
             if checkpoint_thread_res.is_err() {
-                ckp_err_handler(checkpoint_thread_res.err().unwrap());
-                return Err("Error checkpointing virt server".to_string());
+                let e = checkpoint_thread_res.err().unwrap();
+                ckp_err_handler(e.clone());
+                return Err(format!("Error checkpointing virt server: {}", e));
             }
 
             Ok(vserver)
         });
 
         if vserver.is_err() {
-            log::error!("Error creating/checkpoint virt server: {}", vserver.err().unwrap());
-            return Err(format!("Error creating/checkpoint virt server"));
+            let e = vserver.err().unwrap();
+            log::error!("Error creating/checkpoint virt server: {}", e);
+            return Err(e);
         }
         
         let vserver = vserver.unwrap();
@@ -520,8 +988,10 @@ impl<'a> ServerNodesManager<'a> {
         // restore the state
         let res = self.restore_state(&new_server_ip, new_rpc_id, &ckp_path);
         if res.is_err() {
-            log::error!("Error restoring virt server: {}", res.err().unwrap());
-            return Err(format!("Error restoring virt server"));
+            let e = res.err().unwrap();
+            log::error!("Error restoring virt server: {}", e);
+            self.rollback_migration(client_mgr, client_ip, &new_server_ip, &vserver);
+            return Err(format!("Error restoring virt server: {}", e));
         }
 
         
@@ -529,14 +999,14 @@ impl<'a> ServerNodesManager<'a> {
         
         if res.is_err() {
             log::error!("Error changing virt server: {}", res.err().unwrap());
-            return Err(format!("Error changing virt server for client"));
+            return Err("Error changing virt server for client".to_string());
         }
 
         let res = client_mgr.resume_client(&client_ip);
         
         if res.is_err() {
             log::error!("Error resuming client VM: {}", res.err().unwrap());
-            return Err(format!("Error resuming client VM"));
+            return Err("Error resuming client VM".to_string());
         }
 
         log::info!("VM migrated successfully");
@@ -551,6 +1021,133 @@ impl<'a> ServerNodesManager<'a> {
     
     }
 
+    /// Checkpoints the virt server of an idle VM and frees it, keeping the VM paused until it makes
+    /// its next CUDA call, see `wake_virt_server`.
+    pub fn hibernate_virt_server(&self, client_mgr: &FlytClientManager, client_ip: &String) -> Result<(),String> {
+        let virt_server = client_mgr.get_client(client_ip).and_then(|client| client.virt_server)
+            .ok_or(format!("Client VM {} has no virt server", client_ip))?;
+        let (snode_ip, rpc_id, compute_units, memory) = {
+            let virt_server = virt_server.read().unwrap();
+            (virt_server.ipaddr.clone(), virt_server.rpc_id, virt_server.compute_units, virt_server.memory)
+        };
+
+        log::info!("Hibernating client VM {} on virt server {}/{}", client_ip, snode_ip, rpc_id);
+
+        client_mgr.transition(client_ip, VmState::Paused)?;
+
+        let ckp_path = match prepare_ckp_path(client_ip) {
+            Ok(ckp_path) => ckp_path,
+            Err(e) => {
+                client_mgr.settle(client_ip);
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = client_mgr.hibernate_client(client_ip) {
+            client_mgr.settle(client_ip);
+            return Err(format!("Error pausing client VM {}: {}", client_ip, e));
+        }
+
+        if let Err(e) = self.checkpoint(&snode_ip, rpc_id, &ckp_path) {
+            log::error!("Error checkpointing virt server of client VM {}: {}", client_ip, e);
+            if let Err(e) = client_mgr.resume_client(client_ip) {
+                log::error!("Error resuming client VM {}: {}", client_ip, e);
+            }
+            client_mgr.settle(client_ip);
+            return Err(format!("Error checkpointing virt server: {}", e));
+        }
+
+        // the VM keeps its virt server unless it was freed
+        if let Err(e) = self.free_virt_server(&snode_ip, rpc_id) {
+            log::error!("Error freeing virt server {}/{}: {}", snode_ip, rpc_id, e);
+            if let Err(e) = client_mgr.resume_client(client_ip) {
+                log::error!("Error resuming client VM {}: {}", client_ip, e);
+            }
+            client_mgr.settle(client_ip);
+            return Err(format!("Error freeing virt server: {}", e));
+        }
+
+        client_mgr.mark_hibernated(client_ip, HibernatedVm { ckp_path, compute_units, memory });
+
+        log::info!("Client VM {} hibernated", client_ip);
+        Ok(())
+    }
+
+    /// Restores a hibernated VM onto any GPU with room for it and resumes it.
+    pub fn wake_virt_server(&self, client_mgr: &FlytClientManager, client_ip: &String) -> Result<Arc<RwLock<VirtServer>>,String> {
+        let hibernated = match client_mgr.hibernated_vm(client_ip) {
+            Some(hibernated) => hibernated,
+            // woken up by an earlier request, whose resume may have failed
+            None => {
+                let virt_server = client_mgr.get_client(client_ip).and_then(|client| client.virt_server)
+                    .ok_or(format!("Client VM {} is not hibernated", client_ip))?;
+                client_mgr.resume_client(client_ip)?;
+                return Ok(virt_server);
+            }
+        };
+
+        log::info!("Waking client VM {}", client_ip);
+
+        client_mgr.transition(client_ip, VmState::Allocating)?;
+        let res = self.restore_hibernated(client_mgr, client_ip, hibernated);
+        match res {
+            // tried again on the VM's next CUDA call
+            Err(_) if client_mgr.hibernated_vm(client_ip).is_some() => client_mgr.force_transition(client_ip, VmState::Hibernated),
+            _ => client_mgr.settle(client_ip),
+        }
+        res
+    }
+
+    fn restore_hibernated(&self, client_mgr: &FlytClientManager, client_ip: &String, hibernated: HibernatedVm) -> Result<Arc<RwLock<VirtServer>>,String> {
+        let mut vm_required_resources = self.vm_resource_getter.get_vm_required_resources(client_ip)
+            .ok_or("VM resources not found".to_string())?;
+        vm_required_resources.compute_units = hibernated.compute_units;
+        vm_required_resources.memory = hibernated.memory;
+
+        let (target_server_ip, target_gpu_id) = self.get_free_gpu(&vm_required_resources).ok_or("No free GPU found".to_string())?;
+
+        let exclusive = vm_required_resources.placement() == PlacementPolicy::MigSlice;
+        let vserver = self.create_virt_server(&target_server_ip, target_gpu_id, hibernated.compute_units, hibernated.memory,
+            vm_required_resources.restart_policy(), vm_required_resources.host_limits(), vm_required_resources.flavor(), false, exclusive)?;
+
+        let rpc_id = vserver.read().unwrap().rpc_id;
+        if let Err(e) = self.restore_state(&target_server_ip, rpc_id, &hibernated.ckp_path) {
+            log::error!("Error restoring virt server of client VM {}: {}", client_ip, e);
+            if let Err(e) = self.free_virt_server(&target_server_ip, rpc_id) {
+                log::error!("Error freeing virt server: {}", e);
+            }
+            return Err(format!("Error restoring virt server: {}", e));
+        }
+
+        if let Err(e) = client_mgr.change_virt_server(client_ip, &vserver) {
+            log::error!("Error changing virt server of client VM {}: {}", client_ip, e);
+            if let Err(e) = self.free_virt_server(&target_server_ip, rpc_id) {
+                log::error!("Error freeing virt server: {}", e);
+            }
+            return Err(format!("Error changing virt server for client: {}", e));
+        }
+        // the VM holds its new virt server now, a failed resume is sent again on the next wake-up
+        client_mgr.clear_hibernated(client_ip);
+        client_mgr.resume_client(client_ip)?;
+
+        log::info!("Client VM {} woken up on virt server {}/{}", client_ip, target_server_ip, rpc_id);
+        Ok(vserver)
+    }
+
+    /// Frees the virt server a failed migration allocated and lets the client VM continue on its old one.
+    fn rollback_migration(&self, client_mgr: &FlytClientManager, client_ip: &String, target_snode_ip: &String, vserver: &Arc<RwLock<VirtServer>>) {
+        let res = self.free_virt_server(target_snode_ip, vserver.read().unwrap().rpc_id);
+
+        if res.is_err() {
+            log::error!("Error freeing virt server: {}", res.err().unwrap());
+        }
+
+        match client_mgr.resume_client(client_ip) {
+            Ok(_) => log::info!("Client VM {} resumed on its old virt server", client_ip),
+            Err(e) => log::error!("Error resuming client VM {}: {}", client_ip, e),
+        }
+    }
+
     pub fn migrate_virt_server_auto(&self, client_mgr: &FlytClientManager, client_ip: &String, new_sm_cores: u32, new_mem: u64) -> Result<Arc<RwLock<VirtServer>>,String> {
         let vm_required_resources = self.vm_resource_getter.get_vm_required_resources(client_ip);
         
@@ -588,7 +1185,7 @@ impl<'a> ServerNodesManager<'a> {
             return Err("Server node not found".to_string());
         }
 
-        let mut server_node = server_node.unwrap();
+        let server_node = server_node.unwrap();
 
         let target_vserver = server_node.virt_servers.iter().find(|virt_server| virt_server.read().unwrap().rpc_id == rpc_id);
 
@@ -598,9 +1195,11 @@ impl<'a> ServerNodesManager<'a> {
         }
 
         log::trace!("Sending dealloc command to server node: {}/{}", virt_ip, rpc_id);
-        stream_write!(get_writer!(server_node), format!("{}\n{}\n", FlytApiCommand::RMGR_SNODE_DEALLOC_VIRT_SERVER, rpc_id));
-        
-        let response = stream_read_response!(get_reader!(server_node), 2);
+        let response = {
+            let mut stream = server_node.stream.write().unwrap();
+            stream_write!(stream.writer, format!("{}\n{}\n", FlytApiCommand::RMGR_SNODE_DEALLOC_VIRT_SERVER, rpc_id));
+            stream_read_response!(stream.reader, 2)
+        };
 
         log::trace!("Response from server node {} for deallocate: {:?}", virt_ip, response);
 
@@ -609,14 +1208,27 @@ impl<'a> ServerNodesManager<'a> {
             return Err(format!("RMGR_SNODE_DEALLOC_VIRT_SERVER, Status: {}\n{}", response[0], response[1]));
         }
 
-        let target_vserver = target_vserver.unwrap();
+        // the node reports how the virt server was stopped: graceful, sigterm or sigkill
+        info!("Virt server {}/{} stopped: {}", virt_ip, rpc_id, response[1]);
+
+        self.release_virt_server(virt_ip, rpc_id)
+    }
+
+    /// Returns the resources of a virt server that no longer exists on its server node to the pool.
+    pub fn release_virt_server(&self, virt_ip: &String, rpc_id: u64) -> Result<(),String> {
+        let mut server_node = self.get_server_node(virt_ip).ok_or("Server node not found".to_string())?;
+
+        let target_vserver = server_node.virt_servers.iter()
+            .find(|virt_server| virt_server.read().unwrap().rpc_id == rpc_id)
+            .ok_or("Virt server not found".to_string())?;
 
         {
             let target_vserver_lock_guard = target_vserver.read().unwrap();
             let mut gpu_write_lock_guard = target_vserver_lock_guard.gpu.write().unwrap();
 
-            gpu_write_lock_guard.allocated_compute_units -= target_vserver_lock_guard.compute_units;
-            gpu_write_lock_guard.allocated_memory -= target_vserver_lock_guard.memory;
+            gpu_write_lock_guard.allocated_compute_units = gpu_write_lock_guard.allocated_compute_units.saturating_sub(target_vserver_lock_guard.compute_units);
+            gpu_write_lock_guard.allocated_memory = gpu_write_lock_guard.allocated_memory.saturating_sub(target_vserver_lock_guard.memory);
+            gpu_write_lock_guard.exclusive = false;
         }
 
         server_node.virt_servers.retain(|virt_server| virt_server.read().unwrap().rpc_id != rpc_id);
@@ -643,38 +1255,56 @@ impl<'a> ServerNodesManager<'a> {
             return Err("Virt server not found".to_string());
         }
 
-        let target_vserver = target_vserver.unwrap();
-        let mut target_vserver_write_guard = target_vserver.write().unwrap();
-
-        let tgpu = target_vserver_write_guard.gpu.clone();
-        let mut gpu = tgpu.write().unwrap();
-
-        let compute_units_diff = compute_units - target_vserver_write_guard.compute_units;
-        let memory_diff = memory - target_vserver_write_guard.memory;
+        let target_vserver = target_vserver.unwrap().clone();
+        let (tgpu, current_compute_units, current_memory) = {
+            let target_vserver = target_vserver.read().unwrap();
+            (target_vserver.gpu.clone(), target_vserver.compute_units, target_vserver.memory)
+        };
 
-        if compute_units_diff + gpu.allocated_compute_units > gpu.compute_units|| memory_diff + gpu.allocated_memory > gpu.memory {
-            log::error!("Not enough resources to allocate compute_units: {}, memory: {}", compute_units, memory);
-            log::error!("Available compute_units: {}, memory: {}", gpu.compute_units - gpu.allocated_compute_units, gpu.memory - gpu.allocated_memory);
-            log::error!("Current compute_units: {}, memory: {}", target_vserver_write_guard.compute_units, target_vserver_write_guard.memory);
-            return Err("Not enough resources to allocate".to_string());
+        // growth is reserved before the server node is asked and released again if it refuses,
+        // so placement and listings are not held up by the request
+        let compute_units_growth = compute_units.saturating_sub(current_compute_units);
+        let memory_growth = memory.saturating_sub(current_memory);
+        {
+            let mut gpu = tgpu.write().unwrap();
+            if compute_units_growth + gpu.allocated_compute_units > gpu.compute_units || memory_growth + gpu.allocated_memory > gpu.memory {
+                log::error!("Not enough resources to allocate compute_units: {}, memory: {}", compute_units, memory);
+                log::error!("Available compute_units: {}, memory: {}", gpu.compute_units.saturating_sub(gpu.allocated_compute_units), gpu.memory.saturating_sub(gpu.allocated_memory));
+                log::error!("Current compute_units: {}, memory: {}", current_compute_units, current_memory);
+                return Err("Not enough resources to allocate".to_string());
+            }
+            gpu.allocated_compute_units += compute_units_growth;
+            gpu.allocated_memory += memory_growth;
         }
 
         // call the server node
 
-        stream_write!(get_writer!(server_node), format!("{}\n{},{},{}\n", FlytApiCommand::RMGR_SNODE_CHANGE_RESOURCES, rpc_id, compute_units, memory));
-
-        let response = stream_read_response!(get_reader!(server_node), 2);
+        let response = self.request_with_retry(&server_node.ipaddr, &server_node.stream, format!("{}\n{},{},{}\n", FlytApiCommand::RMGR_SNODE_CHANGE_RESOURCES, rpc_id, compute_units, memory))
+            .and_then(|response| {
+                if response[0] != "200" {
+                    log::error!("RMGR_SNODE_CHANGE_RESOURCES, Status: {}\n{}", response[0], response[1]);
+                    return Err(format!("RMGR_SNODE_CHANGE_RESOURCES, Status: {}\n{}", response[0], response[1]));
+                }
+                Ok(())
+            });
 
-        if response[0] != "200" {
-            log::error!("RMGR_SNODE_CHANGE_RESOURCES, Status: {}\n{}", response[0], response[1]);
-            return Err(format!("RMGR_SNODE_CHANGE_RESOURCES, Status: {}\n{}", response[0], response[1]));
+        if let Err(e) = response {
+            let mut gpu = tgpu.write().unwrap();
+            gpu.allocated_compute_units = gpu.allocated_compute_units.saturating_sub(compute_units_growth);
+            gpu.allocated_memory = gpu.allocated_memory.saturating_sub(memory_growth);
+            return Err(e);
         }
 
-        gpu.allocated_compute_units += compute_units_diff;
-        gpu.allocated_memory += memory_diff;
+        {
+            let mut target_vserver = target_vserver.write().unwrap();
+            target_vserver.compute_units = compute_units;
+            target_vserver.memory = memory;
+        }
 
-        target_vserver_write_guard.compute_units = compute_units;
-        target_vserver_write_guard.memory = memory;
+        // a shrunk virt server frees what it no longer uses
+        let mut gpu = tgpu.write().unwrap();
+        gpu.allocated_compute_units = gpu.allocated_compute_units.saturating_sub(current_compute_units.saturating_sub(compute_units));
+        gpu.allocated_memory = gpu.allocated_memory.saturating_sub(current_memory.saturating_sub(memory));
 
         Ok(())
     
@@ -684,11 +1314,99 @@ impl<'a> ServerNodesManager<'a> {
 }
 
 
+/// Returns an empty checkpoint directory for the virt server of `client_ip`.
+fn prepare_ckp_path(client_ip: &String) -> Result<String,String> {
+    let ckp_base_path = get_ckp_base_path();
+
+    if ckp_base_path.is_none() {
+        log::error!("Checkpoint base path not found");
+        return Err("Checkpoint base path not found".to_string());
+    }
+
+    let ckp_base_path = ckp_base_path.unwrap();
+    let ckp_path = format!("{}/{}", ckp_base_path, client_ip);
+
+    log::debug!("Checkpoint path: {}", ckp_path);
+
+    // if path exists, remove it
+    if Path::new(&ckp_path).exists() {
+        let res = fs::remove_dir_all(&ckp_path);
+        if res.is_err() {
+            log::error!("Error removing path: {}", res.err().unwrap());
+            return Err("Error clearing checkpoint path".to_string());
+        }
+    }
+
+    // create new path
+    if fs::create_dir_all(&ckp_path).is_err() {
+        log::error!("Error creating path: {}", ckp_path);
+        return Err(format!("Error creating path: {}", ckp_path));
+    }
+
+    Ok(ckp_path)
+}
+
+/// Parses `rpc_id,gpu_id,sm_cores,memory,flavor` as sent for `RMGR_SNODE_SEND_VIRT_SERVERS`.
+fn parse_virt_server_info(virt_server_str: &str) -> Option<(u64, u64, u32, u64)> {
+    let fields = virt_server_str.split(",").collect::<Vec<&str>>();
+    if fields.len() < 4 {
+        return None;
+    }
+    Some((fields[0].parse().ok()?, fields[1].parse().ok()?, fields[2].parse().ok()?, fields[3].parse().ok()?))
+}
+
+/// Reads `num_gpus` followed by one line per GPU unit, as sent by a server node.
+fn read_gpu_info<T: BufRead>(reader: &mut T) -> Result<Vec<GPU>,String> {
+    let num_gpus_str = StreamUtils::read_line(reader).map_err(|e| e.to_string())?;
+    let num_gpus = num_gpus_str.parse::<u64>().map_err(|_| format!("Invalid number of gpus: {}", num_gpus_str))?;
+
+    let mut gpus = Vec::new();
+    for _ in 0..num_gpus {
+        let gpu_info_str = StreamUtils::read_line(reader).map_err(|e| e.to_string())?;
+        gpus.push(parse_gpu_info(&gpu_info_str).ok_or(format!("Invalid gpu info: {}", gpu_info_str))?);
+    }
+    Ok(gpus)
+}
+
+fn parse_gpu_info(gpu_info_str: &str) -> Option<GPU> {
+    let gpu_info = gpu_info_str.split(",").collect::<Vec<&str>>();
+    // gpu_id, name, memory, compute_units, compute_power, max_clock, isolation, parent_gpu_id, health, mps_status
+    let gpu_id = gpu_info.first()?.parse::<u64>().ok()?;
+    Some(GPU {
+        gpu_id,
+        name: gpu_info.get(1)?.to_string(),
+        memory: gpu_info.get(2)?.parse::<u64>().ok()?,
+        compute_units: gpu_info.get(3)?.parse::<u32>().ok()?,
+        compute_power: gpu_info.get(4)?.parse::<u64>().ok()?,
+        isolation: gpu_info.get(6).and_then(|s| s.parse::<IsolationLevel>().ok()).unwrap_or(IsolationLevel::Mps),
+        parent_gpu_id: gpu_info.get(7).and_then(|s| s.parse::<u64>().ok()).unwrap_or(gpu_id),
+        health: gpu_info.get(8).and_then(|s| s.parse::<GpuHealth>().ok()).unwrap_or(GpuHealth::Healthy),
+        mps: gpu_info.get(9).and_then(|s| s.parse::<MpsStatus>().ok()).unwrap_or(MpsStatus::Unmanaged),
+        ..Default::default()
+    })
+}
+
 fn check_resource_availability(server_node: &ServerNode, vm_resources: &VMResources) -> Option<u64> {
+    if !server_node.flavors.iter().any(|flavor| flavor.name == vm_resources.flavor()) {
+        return None;
+    }
+    let placement = vm_resources.placement();
     for gpu in server_node.gpus.iter() {
         let gpu_read = gpu.read().unwrap();
-        let remain_compute_units = gpu_read.compute_units - gpu_read.allocated_compute_units;
-        let remain_memory = gpu_read.memory - gpu_read.allocated_memory;
+        if gpu_read.exclusive || gpu_read.health == GpuHealth::Unhealthy || gpu_read.mps == MpsStatus::Unhealthy {
+            continue;
+        }
+        let placement_allowed = match placement {
+            PlacementPolicy::Mps => true,
+            PlacementPolicy::MigMps => gpu_read.isolation == IsolationLevel::Mig,
+            PlacementPolicy::MigSlice => gpu_read.isolation == IsolationLevel::Mig
+                && gpu_read.allocated_compute_units == 0 && gpu_read.allocated_memory == 0,
+        };
+        if !placement_allowed {
+            continue;
+        }
+        let remain_compute_units = gpu_read.compute_units.saturating_sub(gpu_read.allocated_compute_units);
+        let remain_memory = gpu_read.memory.saturating_sub(gpu_read.allocated_memory);
         if remain_memory >= vm_resources.memory && remain_compute_units >= vm_resources.compute_units {
             return Some(gpu_read.gpu_id);
         }
@@ -0,0 +1,145 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::BufRead;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::utils::StreamUtils;
+
+#[derive(Debug, Clone)]
+pub struct GpuTelemetry {
+    pub gpu_id: u64,
+    pub utilization: u32,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    /// Milliwatts
+    pub power: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtServerTelemetry {
+    pub rpc_id: u64,
+    pub gpu_id: u64,
+    pub memory_used: u64,
+    pub sm_utilization: u32,
+    /// Microseconds of host CPU time, 0 when the virt server is not in a cgroup
+    pub host_cpu_usage: u64,
+    pub host_memory: u64,
+    pub pids: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetrySample {
+    /// Seconds since the epoch at which the sample was received
+    pub timestamp: u64,
+    pub gpus: Vec<GpuTelemetry>,
+    pub virt_servers: Vec<VirtServerTelemetry>,
+}
+
+fn parse_field<T: std::str::FromStr>(fields: &[&str], index: usize, line: &str) -> Result<T, String> {
+    fields.get(index)
+        .and_then(|field| field.parse::<T>().ok())
+        .ok_or(format!("Invalid telemetry line: {}", line))
+}
+
+impl TelemetrySample {
+
+    /// Reads a telemetry report as sent by a server node with `SNODE_RMGR_TELEMETRY`.
+    pub fn read_from<T: BufRead>(reader: &mut T) -> Result<Self, String> {
+        let header = StreamUtils::read_line(reader).map_err(|e| e.to_string())?;
+        let counts = header.split(",").collect::<Vec<&str>>();
+        let num_gpus = parse_field::<usize>(&counts, 0, &header)?;
+        let num_virt_servers = parse_field::<usize>(&counts, 1, &header)?;
+
+        let mut gpus = Vec::new();
+        for _ in 0..num_gpus {
+            // format: gpu_id,utilization,memory_used,memory_total,power
+            let line = StreamUtils::read_line(reader).map_err(|e| e.to_string())?;
+            let fields = line.split(",").collect::<Vec<&str>>();
+            gpus.push(GpuTelemetry {
+                gpu_id: parse_field(&fields, 0, &line)?,
+                utilization: parse_field(&fields, 1, &line)?,
+                memory_used: parse_field(&fields, 2, &line)?,
+                memory_total: parse_field(&fields, 3, &line)?,
+                power: parse_field(&fields, 4, &line)?,
+            });
+        }
+
+        let mut virt_servers = Vec::new();
+        for _ in 0..num_virt_servers {
+            // format: rpc_id,gpu_id,memory_used,sm_utilization[,host_cpu_usage,host_memory,pids]
+            let line = StreamUtils::read_line(reader).map_err(|e| e.to_string())?;
+            let fields = line.split(",").collect::<Vec<&str>>();
+            virt_servers.push(VirtServerTelemetry {
+                rpc_id: parse_field(&fields, 0, &line)?,
+                gpu_id: parse_field(&fields, 1, &line)?,
+                memory_used: parse_field(&fields, 2, &line)?,
+                sm_utilization: parse_field(&fields, 3, &line)?,
+                host_cpu_usage: parse_field(&fields, 4, &line).unwrap_or(0),
+                host_memory: parse_field(&fields, 5, &line).unwrap_or(0),
+                pids: parse_field(&fields, 6, &line).unwrap_or(0),
+            });
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        Ok(TelemetrySample {
+            timestamp,
+            gpus,
+            virt_servers,
+        })
+    }
+}
+
+/// Keeps the last `history_length` telemetry samples of every server node.
+pub struct TelemetryStore {
+    history_length: usize,
+    samples: Mutex<HashMap<String, VecDeque<TelemetrySample>>>,
+}
+
+impl TelemetryStore {
+
+    pub fn new(history_length: usize) -> Self {
+        TelemetryStore {
+            history_length: history_length.max(1),
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, server_ip: &str, sample: TelemetrySample) {
+        let mut samples = self.samples.lock().unwrap();
+        let history = samples.entry(server_ip.to_string()).or_default();
+        history.push_back(sample);
+        while history.len() > self.history_length {
+            history.pop_front();
+        }
+    }
+
+    pub fn latest(&self, server_ip: &str) -> Option<TelemetrySample> {
+        self.samples.lock().unwrap().get(server_ip)?.back().cloned()
+    }
+
+    /// Oldest sample first
+    pub fn history(&self, server_ip: &str) -> Vec<TelemetrySample> {
+        match self.samples.lock().unwrap().get(server_ip) {
+            Some(history) => history.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Mean utilization of `gpu_id` over the stored history
+    pub fn average_utilization(&self, server_ip: &str, gpu_id: u64) -> Option<u32> {
+        let samples = self.samples.lock().unwrap();
+        let utilizations = samples.get(server_ip)?.iter()
+            .filter_map(|sample| sample.gpus.iter().find(|gpu| gpu.gpu_id == gpu_id))
+            .map(|gpu| gpu.utilization as u64)
+            .collect::<Vec<u64>>();
+        if utilizations.is_empty() {
+            return None;
+        }
+        Some((utilizations.iter().sum::<u64>() / utilizations.len() as u64) as u32)
+    }
+
+    pub fn remove(&self, server_ip: &str) {
+        self.samples.lock().unwrap().remove(server_ip);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// One-shot timers keyed by VM IP, all served by a single thread running `run`.
+pub struct TimerService {
+    deadlines: Mutex<HashMap<String, Instant>>,
+    /// Wakes `run` when a timer is added, so a shorter one is not missed
+    changed: Condvar,
+}
+
+impl TimerService {
+    pub fn new() -> Self {
+        TimerService {
+            deadlines: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Starts a timer for `key`, replacing a pending one.
+    pub fn schedule(&self, key: &str, delay: Duration) {
+        self.deadlines.lock().unwrap().insert(key.to_string(), Instant::now() + delay);
+        self.changed.notify_all();
+    }
+
+    /// Returns whether a pending timer was cancelled.
+    pub fn cancel(&self, key: &str) -> bool {
+        self.deadlines.lock().unwrap().remove(key).is_some()
+    }
+
+    /// Returns the pending timers and the time left on each, soonest first.
+    pub fn pending(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut pending = self.deadlines.lock().unwrap().iter()
+            .map(|(key, deadline)| (key.clone(), deadline.saturating_duration_since(now)))
+            .collect::<Vec<(String, Duration)>>();
+        pending.sort_by_key(|(_, remaining)| *remaining);
+        pending
+    }
+
+    /// Sends the key of every timer to `expired` once it expires, soonest first. The work is left to
+    /// the receiver, so a slow one does not hold up other timers. Returns once the receiver is gone.
+    pub fn run(&self, expired: Sender<String>) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut due = deadlines.iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(key, deadline)| (key.clone(), *deadline))
+                .collect::<Vec<(String, Instant)>>();
+            due.sort_by_key(|(_, deadline)| *deadline);
+
+            for (key, _) in due {
+                deadlines.remove(&key);
+                if expired.send(key).is_err() {
+                    return;
+                }
+            }
+
+            deadlines = match deadlines.values().min().copied() {
+                Some(next) => self.changed.wait_timeout(deadlines, next.saturating_duration_since(now)).unwrap().0,
+                None => self.changed.wait(deadlines).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn start() -> (Arc<TimerService>, Receiver<String>) {
+        let timers = Arc::new(TimerService::new());
+        let (sender, receiver) = mpsc::channel();
+        let runner = timers.clone();
+        // ends with the test process, or once the receiver is dropped and a timer fires
+        thread::spawn(move || runner.run(sender));
+        (timers, receiver)
+    }
+
+    #[test]
+    fn test_schedule() {
+        let (timers, expired) = start();
+        timers.schedule("10.0.0.5", Duration::from_millis(20));
+
+        assert_eq!(timers.pending().len(), 1);
+        assert_eq!(expired.recv_timeout(Duration::from_secs(5)).unwrap(), "10.0.0.5");
+        assert!(timers.pending().is_empty());
+        assert!(!timers.cancel("10.0.0.5"));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (timers, expired) = start();
+        timers.schedule("10.0.0.5", Duration::from_millis(50));
+        timers.schedule("10.0.0.6", Duration::from_millis(100));
+
+        assert!(timers.cancel("10.0.0.5"));
+        assert!(!timers.cancel("10.0.0.7"));
+        assert_eq!(expired.recv_timeout(Duration::from_secs(5)).unwrap(), "10.0.0.6");
+        assert!(expired.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_replace() {
+        let (timers, expired) = start();
+        timers.schedule("10.0.0.5", Duration::from_secs(60));
+        timers.schedule("10.0.0.5", Duration::from_millis(20));
+
+        // the shorter timer is not missed while `run` waits for the longer one
+        assert_eq!(timers.pending().len(), 1);
+        assert_eq!(expired.recv_timeout(Duration::from_secs(5)).unwrap(), "10.0.0.5");
+
+        timers.schedule("10.0.0.5", Duration::from_millis(20));
+        timers.schedule("10.0.0.5", Duration::from_secs(60));
+        assert!(expired.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(timers.pending()[0].1 > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_fire_order() {
+        let timers = TimerService::new();
+        timers.schedule("10.0.0.7", Duration::from_millis(30));
+        timers.schedule("10.0.0.5", Duration::from_millis(10));
+        timers.schedule("10.0.0.6", Duration::from_millis(20));
+        timers.schedule("10.0.0.8", Duration::from_secs(60));
+
+        let pending = timers.pending().into_iter().map(|(key, _)| key).collect::<Vec<String>>();
+        assert_eq!(pending, vec!["10.0.0.5", "10.0.0.6", "10.0.0.7", "10.0.0.8"]);
+
+        // all three have expired once `run` starts, they are still sent soonest first
+        thread::sleep(Duration::from_millis(40));
+        let (sender, expired) = mpsc::channel();
+        let timers = Arc::new(timers);
+        let runner = timers.clone();
+        thread::spawn(move || runner.run(sender));
+
+        let fired = (0..3).map(|_| expired.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<String>>();
+        assert_eq!(fired, vec!["10.0.0.5", "10.0.0.6", "10.0.0.7"]);
+        assert_eq!(timers.pending().len(), 1);
+    }
+}
@@ -4,13 +4,18 @@ pub struct FlytApiCommand;
 
 impl FlytApiCommand {
     pub const CLIENTD_VCUDA_PAUSE: &'static str = "CLIENTD_VCUDA_PAUSE";
+    pub const CLIENTD_VCUDA_HIBERNATE: &'static str = "CLIENTD_VCUDA_HIBERNATE";
     pub const CLIENTD_VCUDA_CHANGE_VIRT_SERVER: &'static str = "CLIENTD_VCUDA_CHANGE_VIRT_SERVER";
     pub const CLIENTD_VCUDA_RESUME: &'static str = "CLIENTD_VCUDA_RESUME";
     pub const PING: &'static str = "PING";
     pub const CLIENTD_RMGR_CONNECT: &'static str = "CLIENTD_RMGR_CONNECT";
+    pub const CLIENTD_RMGR_RECONNECT: &'static str = "CLIENTD_RMGR_RECONNECT";
     pub const RMGR_CLIENTD_PAUSE: &'static str = "RMGR_CLIENTD_PAUSE";
+    pub const RMGR_CLIENTD_HIBERNATE: &'static str = "RMGR_CLIENTD_HIBERNATE";
+    pub const CLIENTD_RMGR_WAKE: &'static str = "CLIENTD_RMGR_WAKE";
     pub const RMGR_CLIENTD_RESUME: &'static str = "RMGR_CLIENTD_RESUME";
     pub const RMGR_CLIENTD_CHANGE_VIRT_SERVER: &'static str = "RMGR_CLIENTD_CHANGE_VIRT_SERVER";
+    pub const CLIENTD_RMGR_RENEW_LEASE: &'static str = "CLIENTD_RMGR_RENEW_LEASE";
     pub const CLIENTD_RMGR_ZERO_VCUDA_CLIENTS: &'static str = "CLIENTD_RMGR_ZERO_VCUDA_CLIENTS";
     pub const RMGR_CLIENTD_DEALLOC_VIRT_SERVER: &'static str = "RMGR_CLIENTD_DEALLOC_VIRT_SERVER";
     pub const RMGR_SNODE_DEALLOC_VIRT_SERVER: &'static str = "RMGR_SNODE_DEALLOC_VIRT_SERVER";
@@ -20,8 +25,18 @@ impl FlytApiCommand {
     pub const SNODE_VIRTS_CHANGE_RESOURCES: &'static str = "SNODE_VIRTS_CHANGE_RESOURCES";
     pub const SNODE_VIRTS_CHECKPOINT: &'static str = "SNODE_VIRTS_CHECKPOINT";
     pub const SNODE_VIRTS_RESTORE: &'static str = "SNODE_VIRTS_RESTORE";
+    pub const SNODE_VIRTS_SHUTDOWN: &'static str = "SNODE_VIRTS_SHUTDOWN";
     pub const RMGR_SNODE_CHECKPOINT: &'static str = "RMGR_SNODE_CHECKPOINT";
     pub const RMGR_SNODE_RESTORE: &'static str = "RMGR_SNODE_RESTORE";
+    pub const RMGR_SNODE_VIRT_SERVER_LOGS: &'static str = "RMGR_SNODE_VIRT_SERVER_LOGS";
+    pub const RMGR_SNODE_RESCAN_GPUS: &'static str = "RMGR_SNODE_RESCAN_GPUS";
+    pub const RMGR_SNODE_SEND_FLAVORS: &'static str = "RMGR_SNODE_SEND_FLAVORS";
+    pub const RMGR_SNODE_SEND_VIRT_SERVERS: &'static str = "RMGR_SNODE_SEND_VIRT_SERVERS";
+    pub const SNODE_RMGR_CONNECT: &'static str = "SNODE_RMGR_CONNECT";
+    pub const SNODE_RMGR_GPU_HEALTH_CHANGED: &'static str = "SNODE_RMGR_GPU_HEALTH_CHANGED";
+    pub const SNODE_RMGR_TELEMETRY: &'static str = "SNODE_RMGR_TELEMETRY";
+    pub const SNODE_RMGR_VIRT_SERVER_EXITED: &'static str = "SNODE_RMGR_VIRT_SERVER_EXITED";
+    pub const SNODE_RMGR_GPU_INVENTORY: &'static str = "SNODE_RMGR_GPU_INVENTORY";
 }
 
 pub struct FrontEndCommand;
@@ -35,4 +50,8 @@ impl FrontEndCommand {
     pub const CHANGE_SM_CORES_AND_MEMORY: &'static str = "CHANGE_SM_CORES_AND_MEMORY";
     pub const MIGRATE_VIRT_SERVER: &'static str = "MIGRATE_VIRT_SERVER";
     pub const MIGRATE_VIRT_SERVER_AUTO: &'static str = "MIGRATE_VIRT_SERVER_AUTO";
+    pub const TOP: &'static str = "TOP";
+    pub const VIRT_SERVER_LOGS: &'static str = "VIRT_SERVER_LOGS";
+    pub const RESCAN_GPUS: &'static str = "RESCAN_GPUS";
+    pub const LIST_PENDING_DEALLOCATIONS: &'static str = "LIST_PENDING_DEALLOCATIONS";
 }
\ No newline at end of file
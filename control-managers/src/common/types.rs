@@ -1,4 +1,6 @@
 use std::io::{BufReader, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
 
 
 
@@ -19,6 +21,274 @@ impl Default for MqueueClientControlCommand {
 }
 
 
+/// How a GPU unit reported by a server node is isolated from its neighbours.
+/// `Mps` units are whole GPUs shared through MPS, `Mig` units are MIG slices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    Mps,
+    Mig,
+}
+
+impl IsolationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IsolationLevel::Mps => "mps",
+            IsolationLevel::Mig => "mig",
+        }
+    }
+}
+
+impl FromStr for IsolationLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mps" => Ok(IsolationLevel::Mps),
+            "mig" => Ok(IsolationLevel::Mig),
+            _ => Err(format!("Unknown isolation level: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuHealth {
+    Healthy,
+    /// Usable, but throttled or reporting recoverable errors
+    Degraded,
+    /// Must not receive new virt servers
+    Unhealthy,
+}
+
+impl GpuHealth {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GpuHealth::Healthy => "healthy",
+            GpuHealth::Degraded => "degraded",
+            GpuHealth::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+impl FromStr for GpuHealth {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "healthy" => Ok(GpuHealth::Healthy),
+            "degraded" => Ok(GpuHealth::Degraded),
+            "unhealthy" => Ok(GpuHealth::Unhealthy),
+            _ => Err(format!("Unknown gpu health: {}", value)),
+        }
+    }
+}
+
+/// State of the MPS control daemon serving a GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpsStatus {
+    Running,
+    /// Not responding and could not be restarted, the GPU must not receive new virt servers
+    Unhealthy,
+    /// MPS is managed outside of the node daemon
+    Unmanaged,
+}
+
+impl MpsStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MpsStatus::Running => "running",
+            MpsStatus::Unhealthy => "unhealthy",
+            MpsStatus::Unmanaged => "unmanaged",
+        }
+    }
+}
+
+impl FromStr for MpsStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "running" => Ok(MpsStatus::Running),
+            "unhealthy" => Ok(MpsStatus::Unhealthy),
+            "unmanaged" => Ok(MpsStatus::Unmanaged),
+            _ => Err(format!("Unknown mps status: {}", value)),
+        }
+    }
+}
+
+/// What the node daemon does when a virt server exits without being deallocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart only if the virt server exited with an error or was killed by a signal
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestartPolicy::Never => "never",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!("Unknown restart policy: {}", value)),
+        }
+    }
+}
+
+/// Host resources a virt server may use, enforced by the node daemon with cgroup v2. `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostLimits {
+    /// Thousandths of a CPU
+    pub cpu_millis: Option<u32>,
+    /// Bytes
+    pub memory: Option<u64>,
+    pub pids: Option<u32>,
+}
+
+impl HostLimits {
+    /// Formats the limits as `cpu_millis:memory:pids`, with `max` for unlimited
+    pub fn to_arg(self) -> String {
+        fn field<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or("max".to_string())
+        }
+        format!("{}:{}:{}", field(self.cpu_millis), field(self.memory), field(self.pids))
+    }
+}
+
+impl FromStr for HostLimits {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        fn field<T: FromStr>(value: &str, field: Option<&str>) -> Result<Option<T>, String> {
+            match field {
+                Some("max") => Ok(None),
+                Some(field) => field.parse::<T>().map(Some).map_err(|_| format!("Invalid host limits: {}", value)),
+                None => Err(format!("Invalid host limits: {}", value)),
+            }
+        }
+        let fields = value.split(":").collect::<Vec<&str>>();
+        if fields.len() != 3 {
+            return Err(format!("Invalid host limits: {}", value));
+        }
+        Ok(HostLimits {
+            cpu_millis: field(value, fields.first().copied())?,
+            memory: field(value, fields.get(1).copied())?,
+            pids: field(value, fields.get(2).copied())?,
+        })
+    }
+}
+
+/// A virt server build offered by a server node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtServerFlavor {
+    pub name: String,
+    /// CUDA runtime the build targets, as returned by `cudaRuntimeGetVersion`, 0 if unknown
+    pub cuda_version: u32,
+    /// e.g. "ib" for builds with InfiniBand support
+    pub features: Vec<String>,
+}
+
+impl VirtServerFlavor {
+    /// Flavor of the `program-path` virt server, used by VMs that do not ask for one
+    pub const DEFAULT: &'static str = "default";
+
+    /// Formats the flavor as `name,cuda_version,feature+feature`
+    pub fn to_arg(&self) -> String {
+        format!("{},{},{}", self.name, self.cuda_version, self.features.join("+"))
+    }
+}
+
+impl Default for VirtServerFlavor {
+    fn default() -> Self {
+        VirtServerFlavor {
+            name: VirtServerFlavor::DEFAULT.to_string(),
+            cuda_version: 0,
+            features: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for VirtServerFlavor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let fields = value.split(",").collect::<Vec<&str>>();
+        if fields.len() != 3 || fields[0].is_empty() {
+            return Err(format!("Invalid virt server flavor: {}", value));
+        }
+        Ok(VirtServerFlavor {
+            name: fields[0].to_string(),
+            cuda_version: fields[1].parse::<u32>().map_err(|_| format!("Invalid virt server flavor: {}", value))?,
+            features: fields[2].split("+").filter(|feature| !feature.is_empty()).map(|feature| feature.to_string()).collect(),
+        })
+    }
+}
+
+/// One resource manager a daemon can connect to, written as `address:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManagerEndpoint {
+    pub address: String,
+    pub port: u16,
+}
+
+impl FromStr for ManagerEndpoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, port) = value.rsplit_once(":").ok_or(format!("Invalid resource manager endpoint: {}", value))?;
+        if address.is_empty() {
+            return Err(format!("Invalid resource manager endpoint: {}", value));
+        }
+        Ok(ManagerEndpoint {
+            address: address.to_string(),
+            port: port.parse::<u16>().map_err(|_| format!("Invalid resource manager endpoint: {}", value))?,
+        })
+    }
+}
+
+impl std::fmt::Display for ManagerEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+/// Delay between reconnect attempts, doubled after every failed attempt up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max: max.max(min), current: min }
+    }
+
+    /// The delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
 #[derive(Debug)]
 pub struct StreamEnds <T: Read + Write> {
     pub reader: BufReader<T>,
@@ -26,15 +296,19 @@ pub struct StreamEnds <T: Read + Write> {
 } 
 
 impl MqueueClientControlCommand {
+    /// Longest command or data that fits, the last byte is kept for the terminating NUL
+    pub const MAX_FIELD_LEN: usize = 63;
+
+    /// Builds a command, truncating `command` and `data` to `MAX_FIELD_LEN` bytes.
     pub fn new(command: &str, data: &str) -> Self {
         let mut command_bytes = [0u8; 64];
         let mut data_bytes = [0u8; 64];
 
-        for (i, byte) in command.as_bytes().iter().enumerate() {
+        for (i, byte) in command.as_bytes().iter().take(Self::MAX_FIELD_LEN).enumerate() {
             command_bytes[i] = *byte;
         }
 
-        for (i, byte) in data.as_bytes().iter().enumerate() {
+        for (i, byte) in data.as_bytes().iter().take(Self::MAX_FIELD_LEN).enumerate() {
             data_bytes[i] = *byte;
         }
 
@@ -44,6 +318,14 @@ impl MqueueClientControlCommand {
         }
     }
 
+    /// Like `new`, but fails instead of truncating.
+    pub fn try_new(command: &str, data: &str) -> Result<Self, String> {
+        if command.len() > Self::MAX_FIELD_LEN || data.len() > Self::MAX_FIELD_LEN {
+            return Err(format!("{} does not fit in a message queue command, data is {} bytes", command, data.len()));
+        }
+        Ok(Self::new(command, data))
+    }
+
     pub fn as_bytes(&self) -> [u8; 128] {
         let mut bytes = [0u8; 128];
         bytes[..64].copy_from_slice(&self.command);
@@ -90,4 +372,153 @@ impl MqueueClientControlCommand {
 
 }
 
+/// Largest frame accepted on a control channel
+pub const MAX_CONTROL_FRAME_LEN: usize = 64 * 1024;
+
+/// Writes `payload` prefixed with its length as a big endian u32.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    if payload.len() > MAX_CONTROL_FRAME_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("frame of {} bytes is too long", payload.len())));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_CONTROL_FRAME_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("frame of {} bytes is too long", len)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// A command from the node daemon to a virt server, framed as `<command>\n<data>`.
+/// Unlike `MqueueClientControlCommand` the data has no length limit short of `MAX_CONTROL_FRAME_LEN`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub command: String,
+    pub data: String,
+}
+
+impl ControlMessage {
+    pub fn new(command: &str, data: &str) -> Self {
+        ControlMessage {
+            command: command.to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{}\n{}", self.command, self.data).into_bytes()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, String> {
+        let (command, data) = split_frame(payload)?;
+        Ok(ControlMessage { command, data })
+    }
+}
+
+/// A virt server's answer to a `ControlMessage` or its startup report, framed as `<status>\n<body>`.
+/// `status` is "200" on success, the body carries the result or the reason of a failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlReply {
+    pub status: String,
+    pub body: String,
+}
+
+impl ControlReply {
+    pub fn is_ok(&self) -> bool {
+        self.status == "200"
+    }
+
+    /// The reply as an error message, for replies that are not ok
+    pub fn error(&self) -> String {
+        match self.body.is_empty() {
+            true => format!("status {}", self.status),
+            false => format!("{}: {}", self.status, self.body),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{}\n{}", self.status, self.body).into_bytes()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, String> {
+        let (status, body) = split_frame(payload)?;
+        Ok(ControlReply { status, body })
+    }
+}
+
+fn split_frame(payload: &[u8]) -> Result<(String, String), String> {
+    let payload = std::str::from_utf8(payload).map_err(|_| "Control frame is not valid UTF-8".to_string())?;
+    match payload.split_once('\n') {
+        Some((head, rest)) => Ok((head.to_string(), rest.to_string())),
+        None => Ok((payload.to_string(), String::new())),
+    }
+}
+
+/// Failure of an operation on a virt server. Timeouts are kept apart from other failures
+/// because the operation may still complete and is worth retrying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    Timeout(String),
+    Failed(String),
+}
+
+impl ControlError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ControlError::Timeout(_))
+    }
+
+    /// Status reported to the resource manager, 504 lets it retry or roll back a timed out operation
+    pub fn status(&self) -> &'static str {
+        match self {
+            ControlError::Timeout(_) => "504",
+            ControlError::Failed(_) => "500",
+        }
+    }
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::Timeout(e) | ControlError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for ControlError {
+    fn from(e: String) -> Self {
+        ControlError::Failed(e)
+    }
+}
 
+impl From<&str> for ControlError {
+    fn from(e: &str) -> Self {
+        ControlError::Failed(e.to_string())
+    }
+}
+
+impl From<ControlError> for String {
+    fn from(e: ControlError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Channel between the node daemon and one virt server.
+pub trait ControlTransport: Send + Sync {
+    /// Environment the virt server process needs to reach this channel
+    fn env(&self) -> Vec<(String, String)>;
+
+    /// Waits up to `timeout` for the report a virt server sends once it has initialised, `None` on timeout.
+    fn recv_startup(&self, timeout: Duration) -> Result<Option<ControlReply>, String>;
+
+    /// Sends `message` and waits up to `timeout` for the reply, `ControlError::Timeout` if none arrives.
+    fn request(&self, message: &ControlMessage, timeout: Duration) -> Result<ControlReply, ControlError>;
+}
@@ -1,11 +1,12 @@
 use std::fs::File;
 use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
 use std::time::Duration;
 use ipc_rs::MessageQueue;
 use toml::Table;
 
 use crate::common::api_commands::FlytApiCommand;
-use crate::common::types::MqueueClientControlCommand;
+use crate::common::types::{Backoff, ManagerEndpoint, MqueueClientControlCommand};
 
 pub struct Utils;
 pub struct StreamUtils;
@@ -37,6 +38,49 @@ impl Utils {
         contents.parse::<Table>().unwrap()
     }
 
+    /// Resource managers from `[resource-manager]`, in the order they are tried: the `endpoints` list
+    /// of `address:port` strings if present, otherwise the single `address` and `port`.
+    pub fn load_manager_endpoints(config: &Table) -> Vec<ManagerEndpoint> {
+        let resource_manager = &config["resource-manager"];
+
+        if let Some(endpoints) = resource_manager.get("endpoints").and_then(|v| v.as_array()) {
+            return endpoints.iter().filter_map(|endpoint| endpoint.as_str()).filter_map(|endpoint| {
+                endpoint.parse::<ManagerEndpoint>().map_err(|e| log::error!("{}", e)).ok()
+            }).collect();
+        }
+
+        vec![ManagerEndpoint {
+            address: resource_manager["address"].as_str().unwrap().to_string(),
+            port: resource_manager["port"].as_integer().unwrap() as u16,
+        }]
+    }
+
+    /// `reconnect-min-delay` and `reconnect-max-delay` of `[resource-manager]`, in seconds.
+    pub fn load_reconnect_backoff(config: &Table) -> Backoff {
+        let get_secs = |key: &str, default: u64| -> Duration {
+            let secs = config.get("resource-manager").and_then(|v| v.get(key)).and_then(|v| v.as_integer()).unwrap_or(default as i64);
+            Duration::from_secs(secs.max(1) as u64)
+        };
+        Backoff::new(get_secs("reconnect-min-delay", 1), get_secs("reconnect-max-delay", 30))
+    }
+
+    /// Connects to the first endpoint that accepts, starting at `first` and wrapping around.
+    /// Returns the stream and the index of the endpoint.
+    pub fn connect_any(endpoints: &[ManagerEndpoint], first: usize) -> Result<(TcpStream, usize), String> {
+        let mut last_error = "No resource manager endpoints configured".to_string();
+        for offset in 0..endpoints.len() {
+            let index = (first + offset) % endpoints.len();
+            match TcpStream::connect(format!("{}:{}", endpoints[index].address, endpoints[index].port)) {
+                Ok(stream) => return Ok((stream, index)),
+                Err(e) => {
+                    log::warn!("Error connecting to resource manager {}: {}", endpoints[index], e);
+                    last_error = format!("{}: {}", endpoints[index], e);
+                }
+            }
+        }
+        Err(last_error)
+    }
+
 }
 
 impl StreamUtils {
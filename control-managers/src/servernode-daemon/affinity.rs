@@ -0,0 +1,152 @@
+use std::{fs, os::unix::process::CommandExt, path::Path, process::Command, str::FromStr};
+use nix::{sched::{self, CpuSet}, unistd::Pid};
+
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Memory policy for `set_mempolicy`, from linux/mempolicy.h
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// How virt servers are placed relative to the NUMA node of their GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityMode {
+    Off,
+    /// Run only on the CPUs local to the GPU
+    Cpus,
+    /// Run on the local CPUs and prefer memory of the local NUMA node
+    Numa,
+}
+
+impl FromStr for AffinityMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(AffinityMode::Off),
+            "cpus" => Ok(AffinityMode::Cpus),
+            "numa" => Ok(AffinityMode::Numa),
+            _ => Err(format!("Unknown affinity mode: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuTopology {
+    /// None on machines with a single NUMA node
+    pub numa_node: Option<u32>,
+    pub cpus: Vec<usize>,
+}
+
+/// NVML reports `00000000:3B:00.0`, sysfs names the device `0000:3b:00.0`
+fn sysfs_device_name(pci_bus_id: &str) -> String {
+    let bus_id = pci_bus_id.to_lowercase();
+    match bus_id.split_once(":") {
+        Some((domain, rest)) if domain.len() > 4 => format!("{}:{}", &domain[domain.len() - 4..], rest),
+        _ => bus_id,
+    }
+}
+
+/// Parses a kernel cpu list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(",").filter(|range| !range.is_empty()) {
+        match range.split_once("-") {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) {
+                    cpus.extend(start..=end);
+                }
+            }
+            None => {
+                if let Ok(cpu) = range.parse::<usize>() {
+                    cpus.push(cpu);
+                }
+            }
+        }
+    }
+    cpus
+}
+
+/// Reads the NUMA node and local CPUs of the GPU at `pci_bus_id` from sysfs below `sysfs_root`.
+pub fn read_topology(sysfs_root: &Path, pci_bus_id: &str) -> Option<GpuTopology> {
+    if pci_bus_id.is_empty() {
+        return None;
+    }
+
+    let device = sysfs_root.join(sysfs_device_name(pci_bus_id));
+    let numa_node = fs::read_to_string(device.join("numa_node")).ok()?.trim().parse::<i32>().ok()?;
+    let cpus = parse_cpu_list(&fs::read_to_string(device.join("local_cpulist")).ok()?);
+
+    Some(GpuTopology {
+        numa_node: if numa_node < 0 { None } else { Some(numa_node as u32) },
+        cpus,
+    })
+}
+
+/// Restricts the process spawned by `command` to the CPUs, and with `AffinityMode::Numa` the memory, local to the GPU at `pci_bus_id`.
+pub fn apply(mode: AffinityMode, pci_bus_id: &str, command: &mut Command) {
+    if mode == AffinityMode::Off {
+        return;
+    }
+
+    let topology = match read_topology(Path::new(SYSFS_PCI_DEVICES), pci_bus_id) {
+        Some(topology) => topology,
+        None => {
+            log::warn!("No NUMA topology for GPU {:?}, not setting affinity", pci_bus_id);
+            return;
+        }
+    };
+    log::debug!("GPU {} is on NUMA node {:?} with CPUs {:?}", pci_bus_id, topology.numa_node, topology.cpus);
+
+    let mut cpu_set = CpuSet::new();
+    for cpu in topology.cpus.iter() {
+        if cpu_set.set(*cpu).is_err() {
+            log::warn!("CPU {} is out of range for the affinity mask", cpu);
+        }
+    }
+
+    // one bit per node, for up to 1024 nodes
+    let mut node_mask = [0 as libc::c_ulong; 16];
+    let node_bits = libc::c_ulong::BITS as usize;
+    let numa_node = match mode {
+        AffinityMode::Numa => topology.numa_node.filter(|node| (*node as usize) < node_mask.len() * node_bits),
+        _ => None,
+    };
+    if let Some(node) = numa_node {
+        node_mask[node as usize / node_bits] |= 1 << (node as usize % node_bits);
+    }
+
+    unsafe {
+        command.pre_exec(move || {
+            sched::sched_setaffinity(Pid::from_raw(0), &cpu_set)?;
+            if numa_node.is_some() {
+                let ret = libc::syscall(libc::SYS_set_mempolicy, MPOL_PREFERRED, node_mask.as_ptr(), (node_mask.len() * node_bits) as libc::c_ulong);
+                if ret != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_topology() {
+        let sysfs_root = std::env::temp_dir().join(format!("flyt-affinity-test-{}", std::process::id()));
+        let device = sysfs_root.join("0000:3b:00.0");
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("numa_node"), "1\n").unwrap();
+        fs::write(device.join("local_cpulist"), "16-19,48\n").unwrap();
+
+        let topology = read_topology(&sysfs_root, "00000000:3B:00.0").unwrap();
+        assert_eq!(topology, GpuTopology { numa_node: Some(1), cpus: vec![16, 17, 18, 19, 48] });
+
+        fs::write(device.join("numa_node"), "-1\n").unwrap();
+        assert_eq!(read_topology(&sysfs_root, "0000:3b:00.0").unwrap().numa_node, None);
+        assert!(read_topology(&sysfs_root, "").is_none());
+
+        let _ = fs::remove_dir_all(&sysfs_root);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{ffi::CString, fs, os::{fd::BorrowedFd, unix::{ffi::OsStrExt, process::CommandExt}}, path::PathBuf, process::Command};
+use nix::{fcntl::{self, OFlag}, sys::stat::Mode, unistd::{self, Gid, Uid}};
+use crate::common::types::HostLimits;
+
+/// cgroup v2 scheduling period for `cpu.max`, in microseconds
+const CPU_PERIOD: u64 = 100000;
+
+#[derive(Debug, Clone)]
+pub struct CgroupConfig {
+    pub enabled: bool,
+    /// Parent group of the per virt server groups, must be on a cgroup v2 hierarchy
+    pub root: String,
+    /// Unprivileged user and group the virt servers run as, the node daemon's own when unset
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        CgroupConfig {
+            enabled: false,
+            root: "/sys/fs/cgroup/flyt".to_string(),
+            uid: None,
+            gid: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CgroupUsage {
+    /// Microseconds of CPU time
+    pub cpu_usage: u64,
+    /// Bytes
+    pub memory: u64,
+    pub pids: u64,
+}
+
+/// Places every virt server in its own cgroup `<root>/virt-server-<rpc_id>`.
+pub struct CgroupManager {
+    config: CgroupConfig,
+}
+
+impl CgroupManager {
+
+    pub fn new(config: CgroupConfig) -> Self {
+        let manager = CgroupManager { config };
+        if manager.config.enabled {
+            if let Err(e) = manager.init() {
+                log::error!("Error setting up cgroup {}: {}", manager.config.root, e);
+            }
+        }
+        manager
+    }
+
+    fn init(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.config.root).map_err(|e| e.to_string())?;
+        fs::write(PathBuf::from(&self.config.root).join("cgroup.subtree_control"), "+cpu +memory +pids")
+            .map_err(|e| format!("Error enabling controllers: {}", e))
+    }
+
+    fn path(&self, rpc_id: u64) -> PathBuf {
+        PathBuf::from(&self.config.root).join(format!("virt-server-{}", rpc_id))
+    }
+
+    /// Creates the cgroup of `rpc_id` with `limits`, or updates the limits if it exists.
+    pub fn create(&self, rpc_id: u64, limits: &HostLimits) -> Result<(), String> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let path = self.path(rpc_id);
+        fs::create_dir_all(&path).map_err(|e| format!("Error creating cgroup {:?}: {}", path, e))?;
+
+        let cpu_max = match limits.cpu_millis {
+            Some(cpu_millis) => format!("{} {}", cpu_millis as u64 * CPU_PERIOD / 1000, CPU_PERIOD),
+            None => format!("max {}", CPU_PERIOD),
+        };
+        let memory_max = limits.memory.map(|m| m.to_string()).unwrap_or("max".to_string());
+        let pids_max = limits.pids.map(|p| p.to_string()).unwrap_or("max".to_string());
+
+        for (file, value) in [("cpu.max", cpu_max), ("memory.max", memory_max), ("pids.max", pids_max)] {
+            fs::write(path.join(file), &value).map_err(|e| format!("Error writing {} to {:?}: {}", value, path.join(file), e))?;
+        }
+        Ok(())
+    }
+
+    /// Makes the process spawned by `command` join the cgroup of `rpc_id` and drop to the configured user before it runs.
+    pub fn attach(&self, rpc_id: u64, command: &mut Command) -> Result<(), String> {
+        if !self.config.enabled && self.config.uid.is_none() {
+            return Ok(());
+        }
+
+        // prepared up front, nothing may allocate between fork and exec
+        let procs_path = match self.config.enabled {
+            true => Some(CString::new(self.path(rpc_id).join("cgroup.procs").as_os_str().as_bytes()).map_err(|e| e.to_string())?),
+            false => None,
+        };
+        let uid = self.config.uid.map(Uid::from_raw);
+        let gid = self.config.gid.or(self.config.uid).map(Gid::from_raw);
+
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs_path) = procs_path.as_ref() {
+                    let fd = fcntl::open(procs_path.as_c_str(), OFlag::O_WRONLY, Mode::empty())?;
+                    let res = unistd::write(BorrowedFd::borrow_raw(fd), b"0");
+                    let _ = unistd::close(fd);
+                    res?;
+                }
+                if let Some(gid) = gid {
+                    unistd::setgroups(&[gid])?;
+                    unistd::setgid(gid)?;
+                }
+                if let Some(uid) = uid {
+                    unistd::setuid(uid)?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn usage(&self, rpc_id: u64) -> Option<CgroupUsage> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let path = self.path(rpc_id);
+        let read_u64 = |file: &str| -> Option<u64> {
+            fs::read_to_string(path.join(file)).ok()?.trim().parse::<u64>().ok()
+        };
+
+        // cpu.stat lines are "<key> <value>"
+        let cpu_usage = fs::read_to_string(path.join("cpu.stat")).ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("usage_usec ")?.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+
+        Some(CgroupUsage {
+            cpu_usage,
+            memory: read_u64("memory.current").unwrap_or(0),
+            pids: read_u64("pids.current").unwrap_or(0),
+        })
+    }
+
+    /// Removes the cgroup of `rpc_id`, its processes must have exited.
+    pub fn remove(&self, rpc_id: u64) {
+        if !self.config.enabled {
+            return;
+        }
+        let path = self.path(rpc_id);
+        match fs::remove_dir(&path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("Error removing cgroup {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_and_usage() {
+        // a plain directory stands in for the cgroup hierarchy
+        let root = std::env::temp_dir().join(format!("flyt-cgroup-test-{}", std::process::id()));
+        let manager = CgroupManager::new(CgroupConfig {
+            enabled: true,
+            root: root.to_string_lossy().to_string(),
+            uid: None,
+            gid: None,
+        });
+
+        let limits = "1500:1073741824:max".parse::<HostLimits>().unwrap();
+        manager.create(3, &limits).unwrap();
+
+        let path = root.join("virt-server-3");
+        assert_eq!(fs::read_to_string(path.join("cpu.max")).unwrap(), "150000 100000");
+        assert_eq!(fs::read_to_string(path.join("memory.max")).unwrap(), "1073741824");
+        assert_eq!(fs::read_to_string(path.join("pids.max")).unwrap(), "max");
+
+        fs::write(path.join("cpu.stat"), "usage_usec 4200\nuser_usec 4000\n").unwrap();
+        fs::write(path.join("memory.current"), "2048\n").unwrap();
+        fs::write(path.join("pids.current"), "7\n").unwrap();
+        assert_eq!(manager.usage(3), Some(CgroupUsage { cpu_usage: 4200, memory: 2048, pids: 7 }));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
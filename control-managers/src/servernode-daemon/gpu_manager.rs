@@ -1,10 +1,27 @@
 
-use nvml_wrapper::{enum_wrappers::device::Clock, Nvml};
+use std::{collections::HashMap, ffi::CStr, sync::{Arc, Mutex, OnceLock}, thread};
+
+use nvml_wrapper::{bitmasks::{device::ThrottleReasons, event::EventTypes}, enum_wrappers::device::{Clock, EccCounter, MemoryError, TemperatureSensor}, enums::{device::UsedGpuMemory, event::XidError}, error::NvmlError, Device, Nvml};
+use nvml_wrapper_sys::bindings::{nvmlDeviceAttributes_t, nvmlDevice_t, nvmlReturn_enum_NVML_SUCCESS, NvmlLib, NVML_DEVICE_MIG_ENABLE, NVML_DEVICE_UUID_V2_BUFFER_SIZE};
+use toml::Table;
+
+use crate::common::types::{GpuHealth, IsolationLevel};
+
+/// Loaded at runtime like nvml-wrapper does, so binaries still start on hosts without a driver
+const NVML_LIB_PATH: &str = "libnvidia-ml.so";
+
+/// MIG slice ids start above any physical device index
+const MIG_SLICE_ID_BASE: u32 = 1024;
+
+/// XID errors that point at a hardware or driver fault rather than at a misbehaving application
+const FATAL_XID_ERRORS: [u64; 11] = [48, 62, 63, 64, 74, 79, 92, 94, 95, 119, 120];
 
 extern "C" {
     fn get_gpu_cores(device_id: u32) -> i32;
 }
 
+/// An allocatable GPU unit. This is either a whole GPU shared through MPS or a single MIG slice
+/// of a GPU; `parent_gpu_id` always names the physical device.
 #[derive(Debug,Clone)]
 pub struct GPU {
     pub name: String,
@@ -13,40 +30,601 @@ pub struct GPU {
     pub total_cores: u32,
     pub max_clock: u32,
     pub gpu_id: u32,
+    pub parent_gpu_id: u32,
+    pub isolation: IsolationLevel,
+    /// Value of `CUDA_VISIBLE_DEVICES` that exposes exactly this unit to a virt server
+    pub visible_device: String,
+    /// PCI address of the physical device as reported by NVML, empty if unknown
+    pub pci_bus_id: String,
     pub virt_servers: Vec<u32>
 }
 
+#[derive(Debug, Clone)]
+pub struct MigSlice {
+    pub uuid: String,
+    pub sm_cores: u32,
+    pub memory: u64,
+    pub gpu_instance_id: u32,
+    pub compute_instance_id: u32,
+}
+
+impl MigSlice {
+    /// Derived from the parent device and the GPU and compute instance, so a slice keeps its id across
+    /// rescans while its instances exist. GPU instance ids stay below 16 and compute instance ids below 8.
+    fn unit_id(&self, parent_gpu_id: u32) -> u32 {
+        MIG_SLICE_ID_BASE + parent_gpu_id * 256 + self.gpu_instance_id * 16 + self.compute_instance_id
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GpuHealthSample {
+    pub temperature: u32,
+    pub uncorrected_ecc_errors: u64,
+    pub throttled: bool,
+    /// Critical XID errors seen since the previous sample
+    pub xid_errors: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthThresholds {
+    pub max_temperature: u32,
+    pub max_uncorrected_ecc_errors: u64,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        HealthThresholds {
+            max_temperature: 90,
+            max_uncorrected_ecc_errors: 0,
+        }
+    }
+}
+
+impl GpuHealthSample {
+    pub fn evaluate(&self, thresholds: &HealthThresholds) -> (GpuHealth, String) {
+        if let Some(xid) = self.xid_errors.iter().find(|xid| FATAL_XID_ERRORS.contains(xid)) {
+            return (GpuHealth::Unhealthy, format!("XID error {}", xid));
+        }
+        if self.uncorrected_ecc_errors > thresholds.max_uncorrected_ecc_errors {
+            return (GpuHealth::Unhealthy, format!("{} uncorrected ECC errors", self.uncorrected_ecc_errors));
+        }
+        if self.temperature >= thresholds.max_temperature {
+            return (GpuHealth::Unhealthy, format!("temperature {}C", self.temperature));
+        }
+        if let Some(xid) = self.xid_errors.last() {
+            return (GpuHealth::Degraded, format!("XID error {}", xid));
+        }
+        if self.throttled {
+            return (GpuHealth::Degraded, "clocks throttled".to_string());
+        }
+        (GpuHealth::Healthy, String::new())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GpuHealthChange {
+    pub gpu_id: u32,
+    pub health: GpuHealth,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessUsage {
+    pub pid: u32,
+    pub memory_used: u64,
+    pub sm_utilization: u32,
+}
+
+/// Point in time usage of a physical GPU
+#[derive(Debug, Clone, Default)]
+pub struct GpuTelemetry {
+    pub gpu_id: u32,
+    /// Percent of time a kernel was running over the last sample period
+    pub utilization: u32,
+    pub memory_used: u64,
+    pub memory_total: u64,
+    /// Milliwatts
+    pub power: u32,
+    pub processes: Vec<ProcessUsage>,
+}
+
+pub trait GpuBackend: Send + Sync {
+    fn get_all_gpus(&self) -> Option<Vec<GPU>>;
+
+    /// Health of the physical GPU backing `gpu`
+    fn sample_health(&self, gpu: &GPU) -> Option<GpuHealthSample>;
+
+    fn sample_telemetry(&self, parent_gpu_id: u32) -> Option<GpuTelemetry>;
+}
+
+pub struct NvmlBackend {
+    xid_errors: Arc<Mutex<HashMap<u32, Vec<u64>>>>
+}
+
+impl NvmlBackend {
+    pub fn new() -> NvmlBackend {
+        let xid_errors = Arc::new(Mutex::new(HashMap::new()));
+        let watcher_errors = xid_errors.clone();
+        thread::spawn(move || watch_xid_errors(watcher_errors));
+        NvmlBackend {
+            xid_errors
+        }
+    }
+}
+
+impl GpuBackend for NvmlBackend {
+    fn get_all_gpus(&self) -> Option<Vec<GPU>> {
+        get_all_gpus()
+    }
+
+    fn sample_health(&self, gpu: &GPU) -> Option<GpuHealthSample> {
+        let nvml = Nvml::init().ok()?;
+        let device = nvml.device_by_index(gpu.parent_gpu_id).ok()?;
+
+        let temperature = device.temperature(TemperatureSensor::Gpu).ok()?;
+        // ECC is not supported on every GPU
+        let uncorrected_ecc_errors = device.total_ecc_errors(MemoryError::Uncorrected, EccCounter::Volatile).unwrap_or(0);
+        let throttled = device.current_throttle_reasons()
+            .map(|reasons| reasons.intersects(ThrottleReasons::HW_SLOWDOWN | ThrottleReasons::HW_THERMAL_SLOWDOWN | ThrottleReasons::SW_THERMAL_SLOWDOWN))
+            .unwrap_or(false);
+        let xid_errors = self.xid_errors.lock().unwrap().remove(&gpu.parent_gpu_id).unwrap_or_default();
+
+        Some(GpuHealthSample {
+            temperature,
+            uncorrected_ecc_errors,
+            throttled,
+            xid_errors,
+        })
+    }
+
+    fn sample_telemetry(&self, parent_gpu_id: u32) -> Option<GpuTelemetry> {
+        let nvml = Nvml::init().ok()?;
+        let device = nvml.device_by_index(parent_gpu_id).ok()?;
+
+        let memory_info = device.memory_info().ok()?;
+        let utilization = device.utilization_rates().map(|u| u.gpu).unwrap_or(0);
+        let power = device.power_usage().unwrap_or(0);
+
+        // the driver keeps a buffer of samples, only the newest one per process is of interest
+        let mut sm_utilization: HashMap<u32, (u64, u32)> = HashMap::new();
+        for sample in device.process_utilization_stats(None).unwrap_or_default() {
+            let entry = sm_utilization.entry(sample.pid).or_insert((sample.timestamp, sample.sm_util));
+            if sample.timestamp > entry.0 {
+                *entry = (sample.timestamp, sample.sm_util);
+            }
+        }
+
+        let processes = device.running_compute_processes().unwrap_or_default().iter().map(|process| {
+            ProcessUsage {
+                pid: process.pid,
+                memory_used: match process.used_gpu_memory {
+                    UsedGpuMemory::Used(used) => used,
+                    UsedGpuMemory::Unavailable => 0,
+                },
+                sm_utilization: sm_utilization.get(&process.pid).map(|(_, util)| *util).unwrap_or(0),
+            }
+        }).collect();
+
+        Some(GpuTelemetry {
+            gpu_id: parent_gpu_id,
+            utilization,
+            memory_used: memory_info.used,
+            memory_total: memory_info.total,
+            power,
+            processes,
+        })
+    }
+}
+
+fn watch_xid_errors(xid_errors: Arc<Mutex<HashMap<u32, Vec<u64>>>>) {
+    let nvml = match Nvml::init() {
+        Ok(nvml) => nvml,
+        Err(e) => {
+            log::error!("Error initializing NVML for XID monitoring: {}", e);
+            return;
+        }
+    };
+
+    let mut event_set = match nvml.create_event_set() {
+        Ok(event_set) => event_set,
+        Err(e) => {
+            log::error!("Error creating NVML event set: {}", e);
+            return;
+        }
+    };
+
+    let num_devices = nvml.device_count().unwrap_or(0);
+    for i in 0..num_devices {
+        let device = match nvml.device_by_index(i) {
+            Ok(device) => device,
+            Err(_) => continue,
+        };
+        event_set = match device.register_events(EventTypes::CRITICAL_XID_ERROR, event_set) {
+            Ok(event_set) => event_set,
+            Err(e) => {
+                log::error!("Error registering XID events for GPU {}: {:?}", i, e);
+                return;
+            }
+        };
+    }
+
+    loop {
+        match event_set.wait(5000) {
+            Ok(event) => {
+                let gpu_id = match event.device.index() {
+                    Ok(index) => index,
+                    Err(_) => continue,
+                };
+                if let Some(XidError::Value(xid)) = event.event_data {
+                    log::warn!("XID error {} on GPU {}", xid, gpu_id);
+                    xid_errors.lock().unwrap().entry(gpu_id).or_default().push(xid);
+                }
+            }
+            Err(NvmlError::Timeout) => {}
+            Err(e) => {
+                log::error!("Error waiting for XID events: {}", e);
+                thread::sleep(std::time::Duration::from_secs(5));
+            }
+        }
+    }
+}
+
+/// Reports a fixed, optionally MIG-partitioned, set of GPUs. Lets the node daemon and the cluster
+/// manager be exercised on machines without NVIDIA hardware.
+pub struct MockGpuBackend {
+    devices: Vec<(GPU, Vec<MigSlice>)>,
+    health: Mutex<HashMap<u32, GpuHealthSample>>
+}
+
+impl MockGpuBackend {
+    pub fn new() -> MockGpuBackend {
+        MockGpuBackend {
+            devices: Vec::new(),
+            health: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Reports `sample` for the physical GPU `gpu_id` instead of a healthy GPU. Like the NVML
+    /// event watcher, its XID errors are reported by the first sample only.
+    pub fn with_health(self, gpu_id: u32, sample: GpuHealthSample) -> MockGpuBackend {
+        self.health.lock().unwrap().insert(gpu_id, sample);
+        self
+    }
+
+    pub fn with_gpu(self, name: &str, memory: u64, sm_cores: u32) -> MockGpuBackend {
+        self.with_mig_gpu(name, memory, sm_cores, &[])
+    }
+
+    /// Adds a physical GPU partitioned into MIG slices given as `(sm_cores, memory)` pairs.
+    pub fn with_mig_gpu(mut self, name: &str, memory: u64, sm_cores: u32, slices: &[(u32, u64)]) -> MockGpuBackend {
+        let gpu_id = self.devices.len() as u32;
+        let slices = slices.iter().enumerate().map(|(i, (slice_sm_cores, slice_memory))| MigSlice {
+            uuid: format!("MIG-mock-{}-{}", gpu_id, i),
+            sm_cores: *slice_sm_cores,
+            memory: *slice_memory,
+            gpu_instance_id: i as u32,
+            compute_instance_id: 0,
+        }).collect();
+
+        self.devices.push((GPU {
+            name: name.to_string(),
+            memory,
+            sm_cores,
+            total_cores: sm_cores * 64,
+            max_clock: 1500,
+            gpu_id,
+            parent_gpu_id: gpu_id,
+            isolation: IsolationLevel::Mps,
+            visible_device: gpu_id.to_string(),
+            pci_bus_id: String::new(),
+            virt_servers: Vec::new()
+        }, slices));
+        self
+    }
+
+    /// Builds the mock from `[[gpu.mock-devices]]` entries. Memory values are in MB.
+    pub fn from_config(config: &Table) -> Option<MockGpuBackend> {
+        let devices = config.get("gpu")?.get("mock-devices")?.as_array()?;
+        let mut backend = MockGpuBackend::new();
+
+        for device in devices {
+            let name = device.get("name")?.as_str()?;
+            let memory = device.get("memory")?.as_integer()? as u64 * 1024 * 1024;
+            let sm_cores = device.get("sm-cores")?.as_integer()? as u32;
+
+            let mut slices = Vec::new();
+            if let Some(mig_slices) = device.get("mig-slices").and_then(|s| s.as_array()) {
+                for slice in mig_slices {
+                    let slice = slice.as_array()?;
+                    let slice_sm_cores = slice.first()?.as_integer()? as u32;
+                    let slice_memory = slice.get(1)?.as_integer()? as u64 * 1024 * 1024;
+                    slices.push((slice_sm_cores, slice_memory));
+                }
+            }
+
+            backend = backend.with_mig_gpu(name, memory, sm_cores, &slices);
+        }
+
+        Some(backend)
+    }
+}
+
+impl GpuBackend for MockGpuBackend {
+    fn get_all_gpus(&self) -> Option<Vec<GPU>> {
+        Some(expand_mig_slices(self.devices.clone()))
+    }
+
+    fn sample_health(&self, gpu: &GPU) -> Option<GpuHealthSample> {
+        let mut health = self.health.lock().unwrap();
+        Some(health.get_mut(&gpu.parent_gpu_id).map(|sample| GpuHealthSample {
+            xid_errors: std::mem::take(&mut sample.xid_errors),
+            ..sample.clone()
+        }).unwrap_or_default())
+    }
+
+    fn sample_telemetry(&self, parent_gpu_id: u32) -> Option<GpuTelemetry> {
+        let (gpu, _) = self.devices.get(parent_gpu_id as usize)?;
+        Some(GpuTelemetry {
+            gpu_id: parent_gpu_id,
+            memory_total: gpu.memory,
+            ..Default::default()
+        })
+    }
+}
+
+/// GPU units that appeared, disappeared or changed capacity between two scans
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InventoryChanges {
+    pub added: Vec<u32>,
+    pub removed: Vec<u32>,
+    pub changed: Vec<u32>,
+}
+
+impl InventoryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
 pub struct GPUManager {
-    gpu_list: Option<Vec<GPU>>
+    backend: Box<dyn GpuBackend>,
+    gpu_list: Mutex<Option<Vec<GPU>>>,
+    /// Bytes of every GPU unit kept back from virt servers
+    memory_reserve: u64,
+    health: Mutex<HashMap<u32, GpuHealth>>,
+    /// Fatal XID errors per physical GPU, kept until the daemon restarts
+    xid_history: Mutex<HashMap<u32, Vec<u64>>>
 }
 
 impl GPUManager {
     pub fn new() -> GPUManager {
+        GPUManager::with_backend(Box::new(NvmlBackend::new()))
+    }
+
+    pub fn with_backend(backend: Box<dyn GpuBackend>) -> GPUManager {
         GPUManager {
-            gpu_list: None
+            backend,
+            gpu_list: Mutex::new(None),
+            memory_reserve: 0,
+            health: Mutex::new(HashMap::new()),
+            xid_history: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn with_memory_reserve(mut self, memory_reserve: u64) -> GPUManager {
+        self.memory_reserve = memory_reserve;
+        self
+    }
+
+    fn scan(&self) -> Option<Vec<GPU>> {
+        let mut gpus = self.backend.get_all_gpus()?;
+        for gpu in gpus.iter_mut() {
+            gpu.memory = gpu.memory.saturating_sub(self.memory_reserve);
         }
+        Some(gpus)
     }
 
-    pub fn get_all_gpus(&mut self) -> Option<Vec<GPU>> {
-        if self.gpu_list.is_none() {
-            self.gpu_list = get_all_gpus();
+    pub fn get_all_gpus(&self) -> Option<Vec<GPU>> {
+        let mut gpu_list = self.gpu_list.lock().unwrap();
+        if gpu_list.is_none() {
+            *gpu_list = self.scan();
         }
-        self.gpu_list.clone()
+        gpu_list.clone()
+    }
+
+    /// Queries the backend again and replaces the cached GPU list.
+    /// Keeps the cached list and returns None if the backend fails.
+    pub fn rescan(&self) -> Option<InventoryChanges> {
+        let gpus = self.scan()?;
+        let mut gpu_list = self.gpu_list.lock().unwrap();
+        let previous = gpu_list.take().unwrap_or_default();
+
+        let mut changes = InventoryChanges::default();
+        for gpu in gpus.iter() {
+            match previous.iter().find(|old| old.gpu_id == gpu.gpu_id) {
+                None => changes.added.push(gpu.gpu_id),
+                Some(old) if old.memory != gpu.memory || old.sm_cores != gpu.sm_cores || old.visible_device != gpu.visible_device => {
+                    changes.changed.push(gpu.gpu_id)
+                }
+                Some(_) => {}
+            }
+        }
+        changes.removed = previous.iter()
+            .filter(|old| !gpus.iter().any(|gpu| gpu.gpu_id == old.gpu_id))
+            .map(|old| old.gpu_id)
+            .collect();
+
+        if !changes.is_empty() {
+            log::info!("GPU inventory changed: {:?}", changes);
+        }
+        *gpu_list = Some(gpus);
+        Some(changes)
+    }
+
+    pub fn get_gpu(&self, gpu_id: u32) -> Option<GPU> {
+        self.get_all_gpus()?.into_iter().find(|gpu| gpu.gpu_id == gpu_id)
+    }
+
+    /// Samples every physical GPU once, MIG slices are reported through their parent.
+    pub fn get_telemetry(&self) -> Vec<GpuTelemetry> {
+        let mut parent_gpu_ids = match self.get_all_gpus() {
+            Some(gpus) => gpus.iter().map(|gpu| gpu.parent_gpu_id).collect::<Vec<u32>>(),
+            None => return Vec::new(),
+        };
+        parent_gpu_ids.sort();
+        parent_gpu_ids.dedup();
+
+        parent_gpu_ids.into_iter().filter_map(|gpu_id| self.backend.sample_telemetry(gpu_id)).collect()
     }
 
-    pub fn get_gpu(&mut self, gpu_id: u32) -> Option<GPU> {
-        let gpus = self.get_all_gpus()?;
+    pub fn get_health(&self, gpu_id: u32) -> GpuHealth {
+        self.health.lock().unwrap().get(&gpu_id).copied().unwrap_or(GpuHealth::Healthy)
+    }
+
+    /// Samples every GPU and returns the units whose health changed since the last check.
+    pub fn check_health(&self, thresholds: &HealthThresholds) -> Vec<GpuHealthChange> {
+        let gpus = match self.get_all_gpus() {
+            Some(gpus) => gpus,
+            None => return Vec::new(),
+        };
+
+        let mut samples: HashMap<u32, Option<GpuHealthSample>> = HashMap::new();
+        let mut changes = Vec::new();
+
         for gpu in gpus {
-            if gpu.gpu_id == gpu_id {
-                return Some(gpu);
+            let sample = samples.entry(gpu.parent_gpu_id).or_insert_with(|| {
+                let mut sample = self.backend.sample_health(&gpu)?;
+                let mut xid_history = self.xid_history.lock().unwrap();
+                let history = xid_history.entry(gpu.parent_gpu_id).or_default();
+                // other XIDs degrade the GPU only for the sample they were seen in
+                for xid in sample.xid_errors.iter().filter(|xid| FATAL_XID_ERRORS.contains(xid)) {
+                    if !history.contains(xid) {
+                        history.push(*xid);
+                    }
+                }
+                let recent = std::mem::replace(&mut sample.xid_errors, history.clone());
+                sample.xid_errors.extend(recent.into_iter().filter(|xid| !FATAL_XID_ERRORS.contains(xid)));
+                Some(sample)
+            });
+
+            let (health, reason) = match sample {
+                Some(sample) => sample.evaluate(thresholds),
+                None => (GpuHealth::Unhealthy, "unable to query GPU".to_string()),
+            };
+
+            let previous = self.health.lock().unwrap().insert(gpu.gpu_id, health).unwrap_or(GpuHealth::Healthy);
+            if previous != health {
+                log::warn!("GPU {} health changed from {} to {} {}", gpu.gpu_id, previous.as_str(), health.as_str(), reason);
+                changes.push(GpuHealthChange {
+                    gpu_id: gpu.gpu_id,
+                    health,
+                    reason,
+                });
+            }
+        }
+
+        changes
+    }
+}
+
+/// Turns physical GPUs into allocatable units. GPUs without MIG slices keep their device index as id,
+/// MIG slices get ids from `MIG_SLICE_ID_BASE` on, see `MigSlice::unit_id`.
+fn expand_mig_slices(physical_gpus: Vec<(GPU, Vec<MigSlice>)>) -> Vec<GPU> {
+    let mut units = Vec::new();
+
+    for (gpu, slices) in physical_gpus {
+        if slices.is_empty() {
+            units.push(gpu);
+            continue;
+        }
+
+        let parent_sm_cores = if gpu.sm_cores > 0 { gpu.sm_cores } else { slices.iter().map(|s| s.sm_cores).sum() };
+
+        for slice in slices {
+            let total_cores = if parent_sm_cores > 0 {
+                (gpu.total_cores as u64 * slice.sm_cores as u64 / parent_sm_cores as u64) as u32
+            } else {
+                0
+            };
+
+            units.push(GPU {
+                name: format!("{} MIG", gpu.name),
+                memory: slice.memory,
+                sm_cores: slice.sm_cores,
+                total_cores,
+                max_clock: gpu.max_clock,
+                gpu_id: slice.unit_id(gpu.gpu_id),
+                parent_gpu_id: gpu.gpu_id,
+                isolation: IsolationLevel::Mig,
+                visible_device: slice.uuid,
+                pci_bus_id: gpu.pci_bus_id.clone(),
+                virt_servers: Vec::new()
+            });
+        }
+    }
+
+    units
+}
+
+/// nvml-wrapper has no MIG calls, they go through a second handle on the library it loaded.
+fn nvml_lib() -> Option<&'static NvmlLib> {
+    static NVML_LIB: OnceLock<Option<NvmlLib>> = OnceLock::new();
+    NVML_LIB.get_or_init(|| unsafe { NvmlLib::new(NVML_LIB_PATH) }.map_err(|e| log::error!("Error loading {}: {}", NVML_LIB_PATH, e)).ok())
+        .as_ref()
+}
+
+/// The MIG devices of a physical GPU, none if MIG is disabled or not supported.
+fn get_mig_slices(device: &Device, device_id: u32) -> Vec<MigSlice> {
+    let lib = match nvml_lib() {
+        Some(lib) => lib,
+        None => return Vec::new(),
+    };
+    let is_success = |ret| ret == nvmlReturn_enum_NVML_SUCCESS;
+
+    unsafe {
+        let handle = device.handle();
+        let (mut current_mode, mut pending_mode, mut max_count) = (0, 0, 0);
+        if !is_success(lib.nvmlDeviceGetMigMode(handle, &mut current_mode, &mut pending_mode)) || current_mode != NVML_DEVICE_MIG_ENABLE {
+            return Vec::new();
+        }
+        if !is_success(lib.nvmlDeviceGetMaxMigDeviceCount(handle, &mut max_count)) {
+            log::error!("Error getting MIG instances for GPU {}", device_id);
+            return Vec::new();
+        }
+
+        let mut slices = Vec::new();
+        for i in 0..max_count {
+            let mut mig_device: nvmlDevice_t = std::ptr::null_mut();
+            // unpopulated slots return NVML_ERROR_NOT_FOUND
+            if !is_success(lib.nvmlDeviceGetMigDeviceHandleByIndex(handle, i, &mut mig_device)) {
+                continue;
             }
+
+            let mut uuid = [0; NVML_DEVICE_UUID_V2_BUFFER_SIZE as usize];
+            let mut attributes = std::mem::zeroed::<nvmlDeviceAttributes_t>();
+            let (mut gpu_instance_id, mut compute_instance_id) = (0, 0);
+            if !is_success(lib.nvmlDeviceGetUUID(mig_device, uuid.as_mut_ptr(), uuid.len() as u32))
+                || !is_success(lib.nvmlDeviceGetAttributes_v2(mig_device, &mut attributes))
+                || !is_success(lib.nvmlDeviceGetGpuInstanceId(mig_device, &mut gpu_instance_id))
+                || !is_success(lib.nvmlDeviceGetComputeInstanceId(mig_device, &mut compute_instance_id)) {
+                continue;
+            }
+
+            log::debug!("GPU {}: MIG instance gi {} ci {}", device_id, gpu_instance_id, compute_instance_id);
+            slices.push(MigSlice {
+                uuid: CStr::from_ptr(uuid.as_ptr()).to_string_lossy().to_string(),
+                sm_cores: attributes.multiprocessorCount,
+                memory: attributes.memorySizeMB * 1024 * 1024,
+                gpu_instance_id,
+                compute_instance_id,
+            });
         }
-        None
+        slices
     }
 }
 
 pub fn get_all_gpus() -> Option<Vec<GPU>> {
-    
+
     let nvml = Nvml::init().ok()?;
     let num_devices = nvml.device_count().ok()?;
 
@@ -55,29 +633,36 @@ pub fn get_all_gpus() -> Option<Vec<GPU>> {
     for i in 0..num_devices {
         let device = nvml.device_by_index(i).ok()?;
         let name = device.name().ok()?;
-        let memory = device.memory_info().ok()?.free;
+        let memory = device.memory_info().ok()?.total;
         let max_clock = device.max_clock_info(Clock::SM).ok()?;
+        let mig_slices = get_mig_slices(&device, i);
         let sm_cores = unsafe { get_gpu_cores(i) };
         let total_cores = device.num_cores().ok()?;
+        let pci_bus_id = device.pci_info().map(|info| info.bus_id).unwrap_or_default();
 
-        if sm_cores == -1 {
+        // CUDA only enumerates the slices of a MIG enabled GPU, so the core count lookup may fail for it
+        if sm_cores == -1 && mig_slices.is_empty() {
             log::error!("Error getting SM cores for GPU {}", i);
             continue;
-        } 
+        }
 
         let gpu_id = i;
 
-        gpus.push(GPU {
+        gpus.push((GPU {
             name: name,
             memory: memory,
-            sm_cores: sm_cores as u32,
+            sm_cores: sm_cores.max(0) as u32,
             total_cores: total_cores,
             max_clock: max_clock,
             gpu_id: gpu_id,
+            parent_gpu_id: gpu_id,
+            isolation: IsolationLevel::Mps,
+            visible_device: gpu_id.to_string(),
+            pci_bus_id,
             virt_servers: Vec::new()
-        });
+        }, mig_slices));
     }
-    Some(gpus)
+    Some(expand_mig_slices(gpus))
 }
 
 
@@ -92,4 +677,145 @@ mod tests {
         assert!(gpus.is_some());
         println!("{:?}", gpus.unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mock_mig_slices() {
+        let gb = 1024 * 1024 * 1024;
+        let backend = MockGpuBackend::new()
+            .with_gpu("Mock A", 16 * gb, 80)
+            .with_mig_gpu("Mock B", 40 * gb, 98, &[(42, 20 * gb), (14, 5 * gb)]);
+        let gpu_manager = GPUManager::with_backend(Box::new(backend));
+
+        let gpus = gpu_manager.get_all_gpus().unwrap();
+        assert_eq!(gpus.len(), 3);
+
+        assert_eq!(gpus[0].gpu_id, 0);
+        assert_eq!(gpus[0].isolation, IsolationLevel::Mps);
+        assert_eq!(gpus[0].visible_device, "0");
+
+        let slice = gpu_manager.get_gpu(MIG_SLICE_ID_BASE + 256 + 16).unwrap();
+        assert_eq!(slice.isolation, IsolationLevel::Mig);
+        assert_eq!(slice.parent_gpu_id, 1);
+        assert_eq!(slice.sm_cores, 14);
+        assert_eq!(slice.memory, 5 * gb);
+        assert_eq!(slice.visible_device, "MIG-mock-1-1");
+        assert!(gpu_manager.get_gpu(1).is_none());
+    }
+
+    #[test]
+    fn test_mig_slice_ids() {
+        let mut devices = MockGpuBackend::new()
+            .with_mig_gpu("Mock A", 1024, 20, &[(10, 512), (10, 512)])
+            .with_gpu("Mock B", 1024, 20)
+            .devices;
+        // device 1 could not be queried
+        devices[1].0.gpu_id = 2;
+
+        let gpu_ids = |devices: Vec<(GPU, Vec<MigSlice>)>| {
+            let mut gpu_ids = expand_mig_slices(devices).iter().map(|gpu| gpu.gpu_id).collect::<Vec<_>>();
+            gpu_ids.sort();
+            gpu_ids
+        };
+        assert_eq!(gpu_ids(devices.clone()), vec![2, MIG_SLICE_ID_BASE, MIG_SLICE_ID_BASE + 16]);
+
+        // slices keep their ids when devices appear or other slices go away
+        let (mut gpu, _) = devices[1].clone();
+        gpu.gpu_id = 5;
+        devices.push((gpu, Vec::new()));
+        devices[0].1.remove(0);
+        assert_eq!(gpu_ids(devices), vec![2, 5, MIG_SLICE_ID_BASE + 16]);
+    }
+
+    #[test]
+    fn test_mock_from_config() {
+        let config = r#"
+            [[gpu.mock-devices]]
+            name = "Mock A"
+            memory = 1024
+            sm-cores = 20
+            mig-slices = [[10, 512], [10, 512]]
+        "#.parse::<Table>().unwrap();
+
+        let backend = MockGpuBackend::from_config(&config).unwrap();
+        let gpus = backend.get_all_gpus().unwrap();
+        assert_eq!(gpus.len(), 2);
+        assert!(gpus.iter().all(|gpu| gpu.isolation == IsolationLevel::Mig && gpu.memory == 512 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_health_changes() {
+        let backend = MockGpuBackend::new()
+            .with_gpu("Mock A", 1024, 20)
+            .with_mig_gpu("Mock B", 1024, 20, &[(10, 512), (10, 512)])
+            .with_health(1, GpuHealthSample { xid_errors: vec![79], ..Default::default() });
+        let gpu_manager = GPUManager::with_backend(Box::new(backend));
+
+        let changes = gpu_manager.check_health(&HealthThresholds::default());
+        let mut unhealthy = changes.iter().map(|change| change.gpu_id).collect::<Vec<_>>();
+        unhealthy.sort();
+        assert_eq!(unhealthy, vec![MIG_SLICE_ID_BASE + 256, MIG_SLICE_ID_BASE + 256 + 16]);
+        assert!(changes.iter().all(|change| change.health == GpuHealth::Unhealthy));
+        assert_eq!(gpu_manager.get_health(0), GpuHealth::Healthy);
+
+        // fatal XIDs stick even though the backend reports them only once
+        assert!(gpu_manager.check_health(&HealthThresholds::default()).is_empty());
+        assert_eq!(gpu_manager.get_health(MIG_SLICE_ID_BASE + 256 + 16), GpuHealth::Unhealthy);
+    }
+
+    #[test]
+    fn test_non_fatal_xids_expire() {
+        let backend = MockGpuBackend::new()
+            .with_gpu("Mock A", 1024, 20)
+            .with_health(0, GpuHealthSample { xid_errors: vec![13], ..Default::default() });
+        let gpu_manager = GPUManager::with_backend(Box::new(backend));
+
+        gpu_manager.check_health(&HealthThresholds::default());
+        assert_eq!(gpu_manager.get_health(0), GpuHealth::Degraded);
+
+        gpu_manager.check_health(&HealthThresholds::default());
+        assert_eq!(gpu_manager.get_health(0), GpuHealth::Healthy);
+    }
+
+    /// Lets a test change the devices after they were handed to a `GPUManager`
+    struct SharedBackend(Arc<Mutex<MockGpuBackend>>);
+
+    impl GpuBackend for SharedBackend {
+        fn get_all_gpus(&self) -> Option<Vec<GPU>> {
+            self.0.lock().unwrap().get_all_gpus()
+        }
+
+        fn sample_health(&self, gpu: &GPU) -> Option<GpuHealthSample> {
+            self.0.lock().unwrap().sample_health(gpu)
+        }
+
+        fn sample_telemetry(&self, parent_gpu_id: u32) -> Option<GpuTelemetry> {
+            self.0.lock().unwrap().sample_telemetry(parent_gpu_id)
+        }
+    }
+
+    #[test]
+    fn test_rescan() {
+        let backend = Arc::new(Mutex::new(MockGpuBackend::new().with_gpu("Mock A", 1024, 20)));
+        let gpu_manager = GPUManager::with_backend(Box::new(SharedBackend(backend.clone()))).with_memory_reserve(24);
+        assert_eq!(gpu_manager.get_gpu(0).unwrap().memory, 1000);
+
+        *backend.lock().unwrap() = MockGpuBackend::new().with_gpu("Mock A", 2048, 20).with_gpu("Mock B", 1024, 10);
+        let changes = gpu_manager.rescan().unwrap();
+        assert_eq!(changes, InventoryChanges { added: vec![1], removed: vec![], changed: vec![0] });
+        assert_eq!(gpu_manager.get_gpu(0).unwrap().memory, 2024);
+
+        *backend.lock().unwrap() = MockGpuBackend::new().with_gpu("Mock A", 2048, 20);
+        assert_eq!(gpu_manager.rescan().unwrap().removed, vec![1]);
+        assert!(gpu_manager.rescan().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_health_evaluation() {
+        let thresholds = HealthThresholds::default();
+        assert_eq!(GpuHealthSample::default().evaluate(&thresholds).0, GpuHealth::Healthy);
+        assert_eq!(GpuHealthSample { throttled: true, ..Default::default() }.evaluate(&thresholds).0, GpuHealth::Degraded);
+        assert_eq!(GpuHealthSample { xid_errors: vec![13], ..Default::default() }.evaluate(&thresholds).0, GpuHealth::Degraded);
+        assert_eq!(GpuHealthSample { uncorrected_ecc_errors: 1, ..Default::default() }.evaluate(&thresholds).0, GpuHealth::Unhealthy);
+        assert_eq!(GpuHealthSample { temperature: 95, ..Default::default() }.evaluate(&thresholds).0, GpuHealth::Unhealthy);
+    }
+}
@@ -1,15 +1,26 @@
 #![allow(dead_code)]
 
-use std::{sync::Arc, thread};
+use std::{sync::Arc, thread, time::Duration};
 
-use common::config::SNODE_CONFIG_PATH;
-use gpu_manager::GPUManager;
+use affinity::AffinityMode;
+use cgroups::{CgroupConfig, CgroupManager};
+use common::{api_commands::FlytApiCommand, config::SNODE_CONFIG_PATH};
+use common::types::{Backoff, GpuHealth, ManagerEndpoint, VirtServerFlavor};
+use gpu_manager::{GPUManager, HealthThresholds, MockGpuBackend};
+use mps_manager::{MpsConfig, MpsManager};
 use resource_manager_handler::ResourceManagerHandler;
-use virt_server_manager::VirtServerManager;
+use virt_server_logs::LogConfig;
+use virt_server_manager::{OperationTimeout, OperationTimeouts, VirtServerManager, VirtServerProgram};
+use virt_server_transport::TransportKind;
 
 mod resource_manager_handler;
 mod gpu_manager;
 mod virt_server_manager;
+mod virt_server_logs;
+mod virt_server_transport;
+mod mps_manager;
+mod cgroups;
+mod affinity;
 #[path = "../common/mod.rs"]
 mod common;
 
@@ -19,39 +30,324 @@ fn get_mqueue_path() -> String {
     config["ipc"]["mqueue-path"].as_str().unwrap().to_string()
 }
 
-fn get_virt_server_program_path() -> String {
+/// The `program-path` virt server as the default flavor, followed by the `[[virt-server.flavors]]`.
+/// Flavors use the `[virt-server]` transport unless they set their own.
+fn get_virt_server_programs() -> Vec<VirtServerProgram> {
     let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
-    config["virt-server"]["program-path"].as_str().unwrap().to_string()
+    let virt_server = &config["virt-server"];
+    let get_transport = |table: &toml::Value, default: TransportKind| -> TransportKind {
+        match table.get("transport").and_then(|v| v.as_str()).map(|v| v.parse::<TransportKind>()) {
+            Some(Ok(transport)) => transport,
+            Some(Err(e)) => {
+                log::error!("{}, using {:?}", e, default);
+                default
+            }
+            None => default,
+        }
+    };
+    let default_transport = get_transport(virt_server, TransportKind::Mqueue);
+    let get_flavor = |table: &toml::Value, name: &str| -> VirtServerFlavor {
+        VirtServerFlavor {
+            name: name.to_string(),
+            cuda_version: table.get("cuda-version").and_then(|v| v.as_integer()).unwrap_or(0) as u32,
+            features: table.get("features").and_then(|v| v.as_array())
+                .map(|features| features.iter().filter_map(|feature| feature.as_str()).map(|feature| feature.to_string()).collect())
+                .unwrap_or_default(),
+        }
+    };
+
+    let mut programs = vec![VirtServerProgram {
+        flavor: get_flavor(virt_server, VirtServerFlavor::DEFAULT),
+        path: virt_server["program-path"].as_str().unwrap().to_string(),
+        transport: default_transport,
+    }];
+
+    for flavor in virt_server.get("flavors").and_then(|v| v.as_array()).into_iter().flatten() {
+        let name = flavor.get("name").and_then(|v| v.as_str()).unwrap_or("");
+        let path = flavor.get("program-path").and_then(|v| v.as_str());
+        // names are sent in comma separated lists to the resource manager
+        if name.is_empty() || name.contains([',', '+']) || path.is_none() {
+            log::error!("Ignoring virt server flavor {:?}, it needs a name without ',' or '+' and a program-path", name);
+            continue;
+        }
+        if programs.iter().any(|program| program.flavor.name == name) {
+            log::error!("Ignoring duplicate virt server flavor {}", name);
+            continue;
+        }
+        programs.push(VirtServerProgram {
+            flavor: get_flavor(flavor, name),
+            path: path.unwrap().to_string(),
+            transport: get_transport(flavor, default_transport),
+        });
+    }
+    programs
+}
+
+fn get_log_config() -> LogConfig {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let defaults = LogConfig::default();
+    let virt_server = config.get("virt-server");
+
+    LogConfig {
+        log_dir: virt_server.and_then(|v| v.get("log-dir")).and_then(|v| v.as_str()).map(|v| v.to_string()).unwrap_or(defaults.log_dir),
+        max_size: virt_server.and_then(|v| v.get("log-max-size")).and_then(|v| v.as_integer()).map(|v| v as u64 * 1024 * 1024).unwrap_or(defaults.max_size),
+        max_files: virt_server.and_then(|v| v.get("log-max-files")).and_then(|v| v.as_integer()).map(|v| v as u32).unwrap_or(defaults.max_files),
+    }
+}
+
+fn get_drain_timeout() -> Duration {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let drain_timeout = config.get("virt-server").and_then(|v| v.get("drain-timeout")).and_then(|v| v.as_integer()).unwrap_or(10);
+    Duration::from_secs(drain_timeout as u64)
+}
+
+fn get_pool_size() -> usize {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let pool_size = config.get("virt-server").and_then(|v| v.get("pool-size")).and_then(|v| v.as_integer()).unwrap_or(0);
+    pool_size.max(0) as usize
+}
+
+/// `[virt-server.timeouts]`, in seconds. `<operation>-per-gb` is added for every GB of GPU memory of the virt server.
+fn get_operation_timeouts() -> OperationTimeouts {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let timeouts = config.get("virt-server").and_then(|v| v.get("timeouts"));
+    let get_secs = |key: &str| -> Option<Duration> {
+        let secs = timeouts?.get(key)?;
+        secs.as_float().or(secs.as_integer().map(|v| v as f64)).filter(|v| *v >= 0.0).map(Duration::from_secs_f64)
+    };
+    let get_timeout = |operation: &str, default: OperationTimeout| -> OperationTimeout {
+        OperationTimeout {
+            base: get_secs(operation).unwrap_or(default.base),
+            per_gb: get_secs(&format!("{}-per-gb", operation)).unwrap_or(default.per_gb),
+        }
+    };
+
+    let defaults = OperationTimeouts::default();
+    OperationTimeouts {
+        startup: get_timeout("startup", defaults.startup),
+        checkpoint: get_timeout("checkpoint", defaults.checkpoint),
+        restore: get_timeout("restore", defaults.restore),
+        resize: get_timeout("resize", defaults.resize),
+        shutdown: get_timeout("shutdown", defaults.shutdown),
+    }
+}
+
+fn get_socket_dir() -> String {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    config.get("virt-server").and_then(|v| v.get("socket-dir")).and_then(|v| v.as_str()).unwrap_or("/tmp/flyt-virt-server-sockets").to_string()
+}
+
+fn get_mps_config() -> (Duration, MpsConfig) {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let defaults = MpsConfig::default();
+    let mps = config.get("mps");
+    let get_str = |key: &str| -> Option<String> {
+        Some(mps?.get(key)?.as_str()?.to_string())
+    };
+
+    let check_period = mps.and_then(|v| v.get("check-period")).and_then(|v| v.as_integer()).unwrap_or(10);
+    let mps_config = MpsConfig {
+        managed: mps.and_then(|v| v.get("managed")).and_then(|v| v.as_bool()).unwrap_or(defaults.managed),
+        control_path: get_str("control-path").unwrap_or(defaults.control_path),
+        pipe_dir: get_str("pipe-dir").unwrap_or(defaults.pipe_dir),
+        log_dir: get_str("log-dir").unwrap_or(defaults.log_dir),
+        start_timeout: defaults.start_timeout,
+    };
+    (Duration::from_secs(check_period as u64), mps_config)
+}
+
+fn get_cgroup_config() -> CgroupConfig {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let defaults = CgroupConfig::default();
+    let cgroups = config.get("cgroups");
+    let get_id = |key: &str| -> Option<u32> {
+        Some(cgroups?.get(key)?.as_integer()? as u32)
+    };
+
+    CgroupConfig {
+        enabled: cgroups.and_then(|v| v.get("enabled")).and_then(|v| v.as_bool()).unwrap_or(defaults.enabled),
+        root: cgroups.and_then(|v| v.get("root")).and_then(|v| v.as_str()).map(|v| v.to_string()).unwrap_or(defaults.root),
+        uid: get_id("uid"),
+        gid: get_id("gid"),
+    }
+}
+
+fn get_affinity_mode() -> AffinityMode {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let mode = config.get("affinity").and_then(|v| v.get("mode")).and_then(|v| v.as_str()).unwrap_or("off");
+    mode.parse::<AffinityMode>().unwrap_or_else(|e| {
+        log::error!("{}, not setting virt server affinity", e);
+        AffinityMode::Off
+    })
+}
+
+fn get_resource_mgr_endpoints() -> (Vec<ManagerEndpoint>, Backoff) {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    (common::utils::Utils::load_manager_endpoints(&config), common::utils::Utils::load_reconnect_backoff(&config))
+}
+
+fn get_gpu_manager() -> GPUManager {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let backend = config.get("gpu").and_then(|gpu| gpu.get("backend")).and_then(|backend| backend.as_str()).unwrap_or("nvml");
+    let memory_reserve = config.get("gpu").and_then(|gpu| gpu.get("memory-reserve")).and_then(|v| v.as_integer()).unwrap_or(0) as u64 * 1024 * 1024;
+
+    let gpu_manager = match backend {
+        "mock" => {
+            log::warn!("Using mock GPU backend");
+            GPUManager::with_backend(Box::new(MockGpuBackend::from_config(&config).expect("Invalid gpu.mock-devices configuration")))
+        }
+        _ => GPUManager::new()
+    };
+    gpu_manager.with_memory_reserve(memory_reserve)
+}
+
+fn get_rescan_period() -> Option<Duration> {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let period = config.get("gpu").and_then(|gpu| gpu.get("rescan-period")).and_then(|v| v.as_integer()).unwrap_or(60);
+    if period <= 0 {
+        return None;
+    }
+    Some(Duration::from_secs(period as u64))
+}
+
+fn get_health_config() -> (Duration, HealthThresholds) {
+    let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
+    let defaults = HealthThresholds::default();
+    let get_integer = |key: &str| -> Option<i64> {
+        config.get("gpu-health")?.get(key)?.as_integer()
+    };
+
+    let poll_period = Duration::from_secs(get_integer("poll-period").unwrap_or(10) as u64);
+    let thresholds = HealthThresholds {
+        max_temperature: get_integer("max-temperature").map(|t| t as u32).unwrap_or(defaults.max_temperature),
+        max_uncorrected_ecc_errors: get_integer("max-uncorrected-ecc-errors").map(|e| e as u64).unwrap_or(defaults.max_uncorrected_ecc_errors),
+    };
+    (poll_period, thresholds)
 }
 
-fn get_resource_mgr_address() -> (String, u16) {
+fn get_telemetry_period() -> Option<Duration> {
     let config = common::utils::Utils::load_config_file(SNODE_CONFIG_PATH);
-    (config["resource-manager"]["address"].as_str().unwrap().to_string(), config["resource-manager"]["port"].as_integer().unwrap() as u16)
+    let period = config.get("telemetry")?.get("period")?.as_integer()?;
+    if period <= 0 {
+        return None;
+    }
+    Some(Duration::from_secs(period as u64))
 }
 
 fn main() {
 
     env_logger::init();
 
-    let gpu_manager = GPUManager::new();
+    let gpu_manager = Arc::new(get_gpu_manager());
+
+    let (mps_check_period, mps_config) = get_mps_config();
+    let mps_manager = Arc::new(MpsManager::new(mps_config));
+    mps_manager.start(&gpu_manager.get_all_gpus().unwrap_or_default());
 
-    let virt_server_manager = Arc::new(VirtServerManager::new(&get_mqueue_path(), get_virt_server_program_path()));
-    let mut resource_manager_handler = ResourceManagerHandler::new(virt_server_manager.clone(), gpu_manager);
-    let (address, port) = get_resource_mgr_address();
+    let virt_server_manager = Arc::new(VirtServerManager::new(&get_mqueue_path(), get_virt_server_programs(), get_log_config(), get_drain_timeout(), mps_manager.clone(), CgroupManager::new(get_cgroup_config()), get_affinity_mode()).with_pool_size(get_pool_size()).with_socket_dir(get_socket_dir()).with_timeouts(get_operation_timeouts()));
+    let resource_manager_handler = ResourceManagerHandler::new(virt_server_manager.clone(), gpu_manager.clone(), mps_manager.clone());
+    let (endpoints, mut backoff) = get_resource_mgr_endpoints();
+    let (health_poll_period, health_thresholds) = get_health_config();
+    let telemetry_period = get_telemetry_period();
+    let rescan_period = get_rescan_period();
 
     thread::scope(|s| {
         s.spawn(|| {
+            loop {
+                for change in gpu_manager.check_health(&health_thresholds) {
+                    let payload = format!("{},{},{}", change.gpu_id, change.health.as_str(), change.reason);
+                    if let Err(e) = resource_manager_handler.send_event(FlytApiCommand::SNODE_RMGR_GPU_HEALTH_CHANGED, &payload) {
+                        log::error!("Error reporting health of GPU {}: {}", change.gpu_id, e);
+                    }
+                }
+                thread::sleep(health_poll_period);
+            }
+        });
+
+        s.spawn(|| {
+            loop {
+                thread::sleep(mps_check_period);
+                let changes = mps_manager.check();
+                for (gpu_id, status) in changes.iter() {
+                    log::warn!("MPS control daemon of GPU {} is {}", gpu_id, status.as_str());
+                }
+                // the resource manager stops placing VMs on GPUs whose MPS control daemon is unhealthy
+                if !changes.is_empty() {
+                    if let Err(e) = resource_manager_handler.report_gpu_inventory() {
+                        log::error!("Error reporting MPS status: {}", e);
+                    }
+                }
+            }
+        });
+
+        s.spawn(|| {
+            loop {
+                for exit in virt_server_manager.reap_exited_virt_servers() {
+                    // format: rpc_id,status,restarted
+                    let payload = format!("{},{},{}", exit.rpc_id, exit.status, exit.restarted);
+                    if let Err(e) = resource_manager_handler.send_event(FlytApiCommand::SNODE_RMGR_VIRT_SERVER_EXITED, &payload) {
+                        log::error!("Error reporting exit of virt server {}: {}", exit.rpc_id, e);
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        s.spawn(|| {
+            loop {
+                let gpus = gpu_manager.get_all_gpus().unwrap_or_default().into_iter()
+                    .filter(|gpu| gpu_manager.get_health(gpu.gpu_id) != GpuHealth::Unhealthy)
+                    .collect::<Vec<_>>();
+                if virt_server_manager.fill_pool(&gpus) {
+                    if let Err(e) = resource_manager_handler.report_gpu_inventory() {
+                        log::debug!("Error reporting pool memory: {}", e);
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        if let Some(telemetry_period) = telemetry_period {
+            let resource_manager_handler = &resource_manager_handler;
+            s.spawn(move || {
+                loop {
+                    thread::sleep(telemetry_period);
+                    if let Err(e) = resource_manager_handler.report_telemetry() {
+                        log::debug!("Error reporting telemetry: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(rescan_period) = rescan_period {
+            let resource_manager_handler = &resource_manager_handler;
+            s.spawn(move || {
+                loop {
+                    thread::sleep(rescan_period);
+                    if let Err(e) = resource_manager_handler.refresh_gpu_inventory() {
+                        log::error!("Error refreshing GPU inventory: {}", e);
+                    }
+                }
+            });
+        }
 
+        let resource_manager_handler = &resource_manager_handler;
+        s.spawn(move || {
+            // the resource manager that accepted last is tried first after a disconnect
+            let mut first = 0;
             loop {
-                match resource_manager_handler.connect(&address, port) {
-                    Ok(_) => {
-                        log::info!("Connected to resource manager");
+                match resource_manager_handler.connect(&endpoints, first) {
+                    Ok(index) => {
+                        log::info!("Connected to resource manager {}", endpoints[index]);
+                        first = index;
+                        backoff.reset();
                         resource_manager_handler.incomming_message_handler();
                     },
                     Err(e) => {
+                        let delay = backoff.next_delay();
                         log::error!("Error connecting to resource manager: {}", e);
-                        log::error!("Retrying in 5 seconds");
-                        thread::sleep(std::time::Duration::from_secs(5));
+                        log::error!("Retrying in {:?}", delay);
+                        thread::sleep(delay);
                     }
                 }
             }
@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fs, io::Write, path::PathBuf, process::{Command, Stdio}, sync::Mutex, thread, time::{Duration, Instant}};
+use crate::common::types::MpsStatus;
+use crate::gpu_manager::GPU;
+
+#[derive(Debug, Clone)]
+pub struct MpsConfig {
+    /// When false, MPS is expected to be started by the operator
+    pub managed: bool,
+    pub control_path: String,
+    /// Every GPU gets its own `<pipe_dir>/<gpu_id>` and `<log_dir>/<gpu_id>`
+    pub pipe_dir: String,
+    pub log_dir: String,
+    /// Time a freshly started control daemon gets to answer
+    pub start_timeout: Duration,
+}
+
+impl Default for MpsConfig {
+    fn default() -> Self {
+        MpsConfig {
+            managed: false,
+            control_path: "nvidia-cuda-mps-control".to_string(),
+            pipe_dir: "/tmp/flyt-mps/pipe".to_string(),
+            log_dir: "/tmp/flyt-mps/log".to_string(),
+            start_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MpsDaemon {
+    gpu_id: u32,
+    visible_device: String,
+    pipe_dir: PathBuf,
+    log_dir: PathBuf,
+    status: MpsStatus,
+}
+
+/// Runs one `nvidia-cuda-mps-control` daemon per GPU unit, each with its own pipe and log directory.
+pub struct MpsManager {
+    config: MpsConfig,
+    daemons: Mutex<HashMap<u32, MpsDaemon>>,
+}
+
+impl MpsManager {
+
+    pub fn new(config: MpsConfig) -> Self {
+        MpsManager {
+            config,
+            daemons: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a control daemon for every GPU in `gpus` that does not have one yet.
+    /// A daemon that is already answering on the GPU's pipe directory is adopted.
+    pub fn start(&self, gpus: &[GPU]) {
+        if !self.config.managed {
+            return;
+        }
+
+        for gpu in gpus {
+            if self.daemons.lock().unwrap().contains_key(&gpu.gpu_id) {
+                continue;
+            }
+
+            let mut daemon = MpsDaemon {
+                gpu_id: gpu.gpu_id,
+                visible_device: gpu.visible_device.clone(),
+                pipe_dir: PathBuf::from(&self.config.pipe_dir).join(gpu.gpu_id.to_string()),
+                log_dir: PathBuf::from(&self.config.log_dir).join(gpu.gpu_id.to_string()),
+                status: MpsStatus::Unhealthy,
+            };
+
+            daemon.status = match self.start_daemon(&daemon) {
+                Ok(_) => {
+                    log::info!("MPS control daemon running for GPU {}", gpu.gpu_id);
+                    MpsStatus::Running
+                }
+                Err(e) => {
+                    log::error!("Error starting MPS control daemon for GPU {}: {}", gpu.gpu_id, e);
+                    MpsStatus::Unhealthy
+                }
+            };
+
+            self.daemons.lock().unwrap().insert(gpu.gpu_id, daemon);
+        }
+    }
+
+    /// Checks every control daemon and restarts the ones that stopped answering.
+    /// Returns the GPUs whose MPS status changed.
+    pub fn check(&self) -> Vec<(u32, MpsStatus)> {
+        // checked on a copy, a restart must not block allocations on other GPUs
+        let daemons = self.daemons.lock().unwrap().values().cloned().collect::<Vec<MpsDaemon>>();
+
+        let mut changes = Vec::new();
+        for daemon in daemons {
+            let status = if self.is_responding(&daemon) {
+                MpsStatus::Running
+            } else {
+                log::warn!("MPS control daemon for GPU {} is not responding, restarting it", daemon.gpu_id);
+                self.stop_daemon(&daemon);
+                match self.start_daemon(&daemon) {
+                    Ok(_) => MpsStatus::Running,
+                    Err(e) => {
+                        log::error!("Error restarting MPS control daemon for GPU {}: {}", daemon.gpu_id, e);
+                        MpsStatus::Unhealthy
+                    }
+                }
+            };
+
+            if status != daemon.status {
+                changes.push((daemon.gpu_id, status));
+                if let Some(daemon) = self.daemons.lock().unwrap().get_mut(&daemon.gpu_id) {
+                    daemon.status = status;
+                }
+            }
+        }
+        changes
+    }
+
+    pub fn get_status(&self, gpu_id: u32) -> MpsStatus {
+        if !self.config.managed {
+            return MpsStatus::Unmanaged;
+        }
+        self.daemons.lock().unwrap().get(&gpu_id).map(|daemon| daemon.status).unwrap_or(MpsStatus::Unhealthy)
+    }
+
+    /// Environment a virt server on `gpu_id` needs to reach the GPU's control daemon
+    pub fn virt_server_env(&self, gpu_id: u32) -> Vec<(String, String)> {
+        match self.daemons.lock().unwrap().get(&gpu_id) {
+            Some(daemon) => vec![
+                ("CUDA_MPS_PIPE_DIRECTORY".to_string(), daemon.pipe_dir.to_string_lossy().to_string()),
+                ("CUDA_MPS_LOG_DIRECTORY".to_string(), daemon.log_dir.to_string_lossy().to_string()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    pub fn stop_all(&self) {
+        for daemon in self.daemons.lock().unwrap().values() {
+            self.stop_daemon(daemon);
+        }
+    }
+
+    fn start_daemon(&self, daemon: &MpsDaemon) -> Result<(), String> {
+        if self.is_responding(daemon) {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&daemon.pipe_dir).map_err(|e| format!("Error creating MPS pipe directory {:?}: {}", daemon.pipe_dir, e))?;
+        fs::create_dir_all(&daemon.log_dir).map_err(|e| format!("Error creating MPS log directory {:?}: {}", daemon.log_dir, e))?;
+
+        let status = Command::new(&self.config.control_path)
+            .arg("-d")
+            .env("CUDA_VISIBLE_DEVICES", &daemon.visible_device)
+            .env("CUDA_MPS_PIPE_DIRECTORY", &daemon.pipe_dir)
+            .env("CUDA_MPS_LOG_DIRECTORY", &daemon.log_dir)
+            .status()
+            .map_err(|e| format!("Error running {}: {}", self.config.control_path, e))?;
+
+        if !status.success() {
+            return Err(format!("{} -d exited with {}", self.config.control_path, status));
+        }
+
+        let deadline = Instant::now() + self.config.start_timeout;
+        while Instant::now() < deadline {
+            if self.is_responding(daemon) {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(format!("control daemon did not respond within {:?}", self.config.start_timeout))
+    }
+
+    fn stop_daemon(&self, daemon: &MpsDaemon) {
+        if let Err(e) = self.control(daemon, "quit") {
+            log::debug!("Error stopping MPS control daemon for GPU {}: {}", daemon.gpu_id, e);
+        }
+    }
+
+    fn is_responding(&self, daemon: &MpsDaemon) -> bool {
+        self.control(daemon, "get_server_list").is_ok()
+    }
+
+    /// Sends `command` to the control daemon of `daemon` and returns its answer.
+    fn control(&self, daemon: &MpsDaemon, command: &str) -> Result<String, String> {
+        let mut child = Command::new(&self.config.control_path)
+            .env("CUDA_MPS_PIPE_DIRECTORY", &daemon.pipe_dir)
+            .env("CUDA_MPS_LOG_DIRECTORY", &daemon.log_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Error running {}: {}", self.config.control_path, e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // the control client may exit before reading when no daemon is running
+            let _ = writeln!(stdin, "{}", command);
+        }
+
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", command, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::common::types::IsolationLevel;
+
+    /// Stands in for nvidia-cuda-mps-control, a pid file in the pipe directory marks a running daemon
+    const STUB_CONTROL: &str = r#"#!/bin/sh
+pid="$CUDA_MPS_PIPE_DIRECTORY/control.pid"
+if [ "$1" = "-d" ]; then
+    [ -f "$CUDA_MPS_PIPE_DIRECTORY/broken" ] && exit 1
+    touch "$pid"
+    exit 0
+fi
+[ -f "$pid" ] || exit 1
+read cmd
+[ "$cmd" = "quit" ] && rm -f "$pid"
+exit 0
+"#;
+
+    fn test_gpu(gpu_id: u32) -> GPU {
+        GPU {
+            name: "Mock GPU".to_string(),
+            memory: 1024,
+            sm_cores: 10,
+            total_cores: 10,
+            max_clock: 1000,
+            gpu_id,
+            parent_gpu_id: gpu_id,
+            isolation: IsolationLevel::Mps,
+            visible_device: gpu_id.to_string(),
+            pci_bus_id: String::new(),
+            virt_servers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_start_and_restart() {
+        let dir = std::env::temp_dir().join(format!("flyt-mps-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let control_path = dir.join("mps-control");
+        fs::write(&control_path, STUB_CONTROL).unwrap();
+        fs::set_permissions(&control_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = MpsManager::new(MpsConfig {
+            managed: true,
+            control_path: control_path.to_string_lossy().to_string(),
+            pipe_dir: dir.join("pipe").to_string_lossy().to_string(),
+            log_dir: dir.join("log").to_string_lossy().to_string(),
+            start_timeout: Duration::from_secs(1),
+        });
+
+        manager.start(&[test_gpu(0), test_gpu(1)]);
+        assert_eq!(manager.get_status(0), MpsStatus::Running);
+        assert_eq!(manager.get_status(1), MpsStatus::Running);
+        assert_eq!(manager.get_status(2), MpsStatus::Unhealthy);
+        assert!(manager.virt_server_env(1).contains(&("CUDA_MPS_PIPE_DIRECTORY".to_string(), dir.join("pipe/1").to_string_lossy().to_string())));
+
+        // a daemon that died is restarted
+        fs::remove_file(dir.join("pipe/0/control.pid")).unwrap();
+        assert!(manager.check().is_empty());
+        assert_eq!(manager.get_status(0), MpsStatus::Running);
+
+        // a daemon that cannot be restarted marks its GPU unhealthy
+        fs::remove_file(dir.join("pipe/1/control.pid")).unwrap();
+        fs::write(dir.join("pipe/1/broken"), "").unwrap();
+        assert_eq!(manager.check(), vec![(1, MpsStatus::Unhealthy)]);
+
+        manager.stop_all();
+        assert!(!dir.join("pipe/0/control.pid").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,5 +1,5 @@
-use std::{io::{BufRead, BufReader, Write}, net::TcpStream, sync::{Arc, RwLock}};
-use crate::{common::api_commands::FlytApiCommand, gpu_manager::GPUManager, virt_server_manager::VirtServerManager, common::utils::StreamUtils};
+use std::{collections::HashMap, io::{BufRead, BufReader, Write}, net::TcpStream, sync::{Arc, RwLock}};
+use crate::{common::{api_commands::FlytApiCommand, types::{GpuHealth, HostLimits, ManagerEndpoint, MpsStatus, RestartPolicy, VirtServerFlavor}}, gpu_manager::GPUManager, mps_manager::MpsManager, virt_server_manager::VirtServerManager, common::utils::{StreamUtils, Utils}};
 
 macro_rules! stream_clone {
     ($stream:expr) => {
@@ -39,35 +39,120 @@ macro_rules! stream_read_line {
 
 pub struct ResourceManagerHandler {
     resource_manager_stream: RwLock<Option<TcpStream>>,
+    resource_manager_address: RwLock<Option<(String, u16)>>,
     virt_server_manager: Arc<VirtServerManager>,
-    gpu_manager: GPUManager
+    gpu_manager: Arc<GPUManager>,
+    mps_manager: Arc<MpsManager>,
 }
 
 impl ResourceManagerHandler {
 
-    pub fn new( virt_server_manager: Arc<VirtServerManager>, gpu_manager: GPUManager ) -> ResourceManagerHandler {
+    pub fn new( virt_server_manager: Arc<VirtServerManager>, gpu_manager: Arc<GPUManager>, mps_manager: Arc<MpsManager> ) -> ResourceManagerHandler {
         ResourceManagerHandler {
             resource_manager_stream: RwLock::new(None),
+            resource_manager_address: RwLock::new(None),
             virt_server_manager,
-            gpu_manager
+            gpu_manager,
+            mps_manager,
         }
     }
 
-    pub fn connect(&self, address: &str, port: u16) -> Result<(),String> {
-        let stream = TcpStream::connect(format!("{}:{}", address, port));
-        match stream {
-            Ok(stream) => {
-                self.resource_manager_stream.write().unwrap().replace(stream);
-                Ok(())
-            }
-            Err(error) => {
-                log::error!("Error connecting to server: {}", error);
-                Err(error.to_string())
-            }
+    /// Registers with the first resource manager in `endpoints` that accepts, starting at `first`, and
+    /// returns its index. The resource manager then asks for the GPUs, flavors and running virt servers.
+    pub fn connect(&self, endpoints: &[ManagerEndpoint], first: usize) -> Result<usize,String> {
+        let (mut stream, index) = Utils::connect_any(endpoints, first)?;
+        stream.write_all(format!("{}\n", FlytApiCommand::SNODE_RMGR_CONNECT).as_bytes()).map_err(|e| e.to_string())?;
+        self.resource_manager_stream.write().unwrap().replace(stream);
+        self.resource_manager_address.write().unwrap().replace((endpoints[index].address.clone(), endpoints[index].port));
+        Ok(index)
+    }
+
+    /// Sends an unsolicited event to the resource manager over a short lived connection,
+    /// so that it does not interleave with the request/response traffic of the main stream.
+    pub fn send_event(&self, command: &str, payload: &str) -> Result<(),String> {
+        let (address, port) = self.resource_manager_address.read().unwrap().clone().ok_or("Not connected to resource manager".to_string())?;
+
+        let mut stream = TcpStream::connect(format!("{}:{}", address, port)).map_err(|e| e.to_string())?;
+        stream.write_all(format!("{}\n{}\n", command, payload).as_bytes()).map_err(|e| e.to_string())?;
+
+        let mut reader = BufReader::new(stream);
+        let response = StreamUtils::read_response(&mut reader, 2).map_err(|e| e.to_string())?;
+        if response[0] != "200" {
+            return Err(format!("{}, Status: {}\n{}", command, response[0], response[1]));
+        }
+        Ok(())
+    }
+
+    /// Lists the GPU units as `num_gpus` followed by one line per unit, without a trailing newline.
+    /// The memory held by pooled virt servers is not offered.
+    fn gpu_info(&self) -> Option<String> {
+        let gpus = self.gpu_manager.get_all_gpus()?;
+        let pool_memory = self.pool_memory();
+        let mut info = gpus.len().to_string();
+        for gpu in gpus {
+            // format: gpu_id,name,memory,sm_cores,total_cores,max_clock,isolation,parent_gpu_id,health,mps_status
+            let health = self.gpu_manager.get_health(gpu.gpu_id);
+            let mps_status = self.mps_manager.get_status(gpu.gpu_id);
+            let memory = gpu.memory.saturating_sub(pool_memory.get(&gpu.gpu_id).copied().unwrap_or(0));
+            info.push_str(&format!("\n{},{},{},{},{},{},{},{},{},{}", gpu.gpu_id, gpu.name, memory, gpu.sm_cores, gpu.total_cores, gpu.max_clock, gpu.isolation.as_str(), gpu.parent_gpu_id, health.as_str(), mps_status.as_str()));
+        }
+        Some(info)
+    }
+
+    /// GPU memory used by the pooled virt servers per gpu_id
+    fn pool_memory(&self) -> HashMap<u32, u64> {
+        let mut pool_memory = HashMap::new();
+        let pooled = self.virt_server_manager.get_pooled_process_ids();
+        if pooled.is_empty() {
+            return pool_memory;
+        }
+
+        let telemetry = self.gpu_manager.get_telemetry();
+        for (gpu_id, pid) in pooled {
+            let usage = telemetry.iter().flat_map(|gpu| gpu.processes.iter()).find(|process| process.pid == pid);
+            *pool_memory.entry(gpu_id).or_insert(0) += usage.map(|usage| usage.memory_used).unwrap_or(0);
+        }
+        pool_memory
+    }
+
+    /// Rescans the GPUs and pushes the new inventory to the resource manager if it changed.
+    pub fn refresh_gpu_inventory(&self) -> Result<(),String> {
+        let changes = self.gpu_manager.rescan().ok_or("Unable to get gpu information")?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.mps_manager.start(&self.gpu_manager.get_all_gpus().unwrap_or_default());
+        self.report_gpu_inventory()
+    }
+
+    /// Sends the current GPU inventory, including the MPS status of every GPU, to the resource manager.
+    pub fn report_gpu_inventory(&self) -> Result<(),String> {
+        let gpu_info = self.gpu_info().ok_or("Unable to get gpu information")?;
+        self.send_event(FlytApiCommand::SNODE_RMGR_GPU_INVENTORY, &gpu_info)
+    }
+
+    pub fn report_telemetry(&self) -> Result<(),String> {
+        let telemetry = self.gpu_manager.get_telemetry();
+        let virt_servers = self.virt_server_manager.get_process_ids();
+
+        // format: num_gpus,num_virt_servers
+        // gpu_id,utilization,memory_used,memory_total,power       (per physical GPU)
+        // rpc_id,gpu_id,memory_used,sm_utilization,host_cpu_usage,host_memory,pids   (per virt server)
+        let mut payload = format!("{},{}", telemetry.len(), virt_servers.len());
+        for gpu in telemetry.iter() {
+            payload.push_str(&format!("\n{},{},{},{},{}", gpu.gpu_id, gpu.utilization, gpu.memory_used, gpu.memory_total, gpu.power));
+        }
+        for (rpc_id, gpu_id, pid) in virt_servers {
+            let usage = telemetry.iter().flat_map(|gpu| gpu.processes.iter()).find(|process| process.pid == pid);
+            let (memory_used, sm_utilization) = usage.map(|usage| (usage.memory_used, usage.sm_utilization)).unwrap_or((0, 0));
+            let host_usage = self.virt_server_manager.get_host_usage(rpc_id).unwrap_or_default();
+            payload.push_str(&format!("\n{},{},{},{},{},{},{}", rpc_id, gpu_id, memory_used, sm_utilization, host_usage.cpu_usage, host_usage.memory, host_usage.pids));
         }
+
+        self.send_event(FlytApiCommand::SNODE_RMGR_TELEMETRY, &payload)
     }
 
-    pub fn incomming_message_handler(&mut self) {
+    pub fn incomming_message_handler(&self) {
         if self.resource_manager_stream.read().unwrap().is_none() {
             return;
         }
@@ -93,25 +178,52 @@ impl ResourceManagerHandler {
             }
 
             match buf.trim() {
-                FlytApiCommand::RMGR_SNODE_SEND_GPU_INFO => {
-                    log::info!("Got send gpu info command");
-                    let gpus = self.gpu_manager.get_all_gpus();
-                    match gpus {
-                        Some(gpus) => {
-                            stream_write!(writer, format!("200\n{}\n", gpus.len()));
-                            for gpu in gpus {
-                                let message = format!("{},{},{},{},{},{}\n", gpu.gpu_id, gpu.name, gpu.memory, gpu.sm_cores, gpu.total_cores, gpu.max_clock);
-                                stream_write!(writer, message);
+                FlytApiCommand::RMGR_SNODE_SEND_GPU_INFO | FlytApiCommand::RMGR_SNODE_RESCAN_GPUS => {
+                    log::info!("Got {} command", buf.trim());
+                    if buf.trim() == FlytApiCommand::RMGR_SNODE_RESCAN_GPUS {
+                        if let Some(changes) = self.gpu_manager.rescan() {
+                            if !changes.is_empty() {
+                                self.mps_manager.start(&self.gpu_manager.get_all_gpus().unwrap_or_default());
                             }
                         }
+                    }
+
+                    match self.gpu_info() {
+                        Some(gpu_info) => {
+                            stream_write!(writer, format!("200\n{}\n", gpu_info));
+                        }
                         None => {
-                            let message = format!("500\nUnable to get gpu information\n");
+                            let message = "500\nUnable to get gpu information\n".to_string();
                             stream_write!(writer, message);
                         }
                     }
                     
                 }
 
+                FlytApiCommand::RMGR_SNODE_SEND_FLAVORS => {
+                    log::info!("Got send flavors command");
+
+                    // format: num_flavors followed by name,cuda_version,features per flavor
+                    let flavors = self.virt_server_manager.flavors();
+                    let mut message = format!("200\n{}\n", flavors.len());
+                    for flavor in flavors {
+                        message.push_str(&format!("{}\n", flavor.to_arg()));
+                    }
+                    stream_write!(writer, message);
+                }
+
+                FlytApiCommand::RMGR_SNODE_SEND_VIRT_SERVERS => {
+                    log::info!("Got send virt servers command");
+
+                    // format: num_virt_servers followed by rpc_id,gpu_id,sm_cores,memory,flavor per virt server
+                    let virt_servers = self.virt_server_manager.list_virt_servers();
+                    let mut message = format!("200\n{}\n", virt_servers.len());
+                    for virt_server in virt_servers {
+                        message.push_str(&format!("{},{},{},{},{}\n", virt_server.rpc_id, virt_server.gpu_id, virt_server.num_sm_cores, virt_server.gpu_memory, virt_server.flavor));
+                    }
+                    stream_write!(writer, message);
+                }
+
                 FlytApiCommand::RMGR_SNODE_ALLOC_VIRT_SERVER => {
 
                     log::info!("Got allocate virt server command");
@@ -119,7 +231,8 @@ impl ResourceManagerHandler {
                     let args = stream_read_line!(reader);
                     let parts = args.split(",").collect::<Vec<&str>>();
 
-                    if parts.len() != 3 {
+                    // format: gpu_id,num_cores,memory[,restart_policy[,host_limits[,flavor]]]
+                    if parts.len() < 3 || parts.len() > 6 {
                         log::error!("Invalid number of arguments: {:?}", parts);
                         stream_write!(writer, "400\nInvalid number of arguments\n".to_string());
                         continue;
@@ -128,8 +241,11 @@ impl ResourceManagerHandler {
                     let gpu_id = parts[0].parse::<u32>();
                     let num_cores = parts[1].parse::<u32>();
                     let memory = parts[2].parse::<u64>();
+                    let restart_policy = parts.get(3).map(|p| p.parse::<RestartPolicy>()).unwrap_or(Ok(RestartPolicy::Never));
+                    let host_limits = parts.get(4).map(|p| p.parse::<HostLimits>()).unwrap_or(Ok(HostLimits::default()));
+                    let flavor = parts.get(5).copied().unwrap_or(VirtServerFlavor::DEFAULT);
 
-                    if gpu_id.is_err() || num_cores.is_err() || memory.is_err() {
+                    if gpu_id.is_err() || num_cores.is_err() || memory.is_err() || restart_policy.is_err() || host_limits.is_err() {
                         stream_write!(writer, "400\nInvalid arguments\n".to_string());
                         continue;
                     }
@@ -137,27 +253,45 @@ impl ResourceManagerHandler {
                     let gpu_id = gpu_id.unwrap();
                     let num_cores = num_cores.unwrap();
                     let memory = memory.unwrap();
+                    let restart_policy = restart_policy.unwrap();
+                    let host_limits = host_limits.unwrap();
+
+                    log::info!("Allocating virt server: gpu_id: {}, num_cores: {}, memory: {}, restart policy: {}, host limits: {}, flavor: {}", gpu_id, num_cores, memory, restart_policy.as_str(), host_limits.to_arg(), flavor);
+
+                    let gpu = match self.gpu_manager.get_gpu(gpu_id) {
+                        Some(gpu) => gpu,
+                        None => {
+                            log::error!("GPU not found: {}", gpu_id);
+                            stream_write!(writer, "400\nGPU not found\n".to_string());
+                            continue;
+                        }
+                    };
 
-                    log::info!("Allocating virt server: gpu_id: {}, num_cores: {}, memory: {}", gpu_id, num_cores, memory);
+                    if self.gpu_manager.get_health(gpu_id) == GpuHealth::Unhealthy {
+                        log::error!("Refusing to allocate on unhealthy GPU: {}", gpu_id);
+                        stream_write!(writer, "500\nGPU is unhealthy\n".to_string());
+                        continue;
+                    }
 
-                    if self.gpu_manager.get_gpu(gpu_id).is_none() {
-                        log::error!("GPU not found: {}", gpu_id);
-                        stream_write!(writer, "400\nGPU not found\n".to_string());
+                    if self.mps_manager.get_status(gpu_id) == MpsStatus::Unhealthy {
+                        log::error!("Refusing to allocate on GPU {} without a running MPS control daemon", gpu_id);
+                        stream_write!(writer, "500\nMPS control daemon is not running on GPU\n".to_string());
                         continue;
                     }
 
 
-                    let ret = self.virt_server_manager.create_virt_server(gpu_id, memory, num_cores);
+                    let ret = self.virt_server_manager.create_virt_server(&gpu, memory, num_cores, restart_policy, host_limits, flavor);
                     
                     match ret {
-                        Ok(rpc_id) => {
+                        Ok((rpc_id, startup_info)) => {
                             log::info!("Virt server created: {}", rpc_id);
-                            stream_write!(writer, format!("200\n{}\n", rpc_id));
+                            // format: rpc_id,applied_sm_cores,applied_memory,cuda_version
+                            stream_write!(writer, format!("200\n{},{},{},{}\n", rpc_id, startup_info.num_sm_cores, startup_info.gpu_memory, startup_info.cuda_version));
 
                         }
                         Err(e) => {
                             log::info!("Error creating virt server: {}", e);
-                            stream_write!(writer, format!("500\n{}\n", e));
+                            stream_write!(writer, format!("{}\n{}\n", e.status(), e));
                         }
                     }
                 
@@ -185,7 +319,7 @@ impl ResourceManagerHandler {
                         }
                         Err(e) => {
                             log::error!("Error checkpointing virt server: {}", e);
-                            stream_write!(writer, format!("500\n{}\n", e));
+                            stream_write!(writer, format!("{}\n{}\n", e.status(), e));
                         }
                     }
                 }
@@ -212,7 +346,7 @@ impl ResourceManagerHandler {
                         }
                         Err(e) => {
                             log::error!("Error restoring virt server: {}", e);
-                            stream_write!(writer, format!("500\n{}\n", e));
+                            stream_write!(writer, format!("{}\n{}\n", e.status(), e));
                         }
                     }
                 }
@@ -231,9 +365,9 @@ impl ResourceManagerHandler {
                     log::info!("Deallocating virt server: {}", rpc_id);
                     let ret = self.virt_server_manager.remove_virt_server(rpc_id);
                     match ret {
-                        Ok(_) => {
-                            log::info!("Virt server deallocated");
-                            stream_write!(writer, "200\nDone\n".to_string());
+                        Ok(shutdown_path) => {
+                            log::info!("Virt server deallocated ({})", shutdown_path.as_str());
+                            stream_write!(writer, format!("200\n{}\n", shutdown_path.as_str()));
                         }
                         Err(e) => {
                             log::error!("Error deallocating virt server: {}", e);
@@ -277,6 +411,35 @@ impl ResourceManagerHandler {
                         }
                         Err(e) => {
                             log::error!("Error changing resources: {}", e);
+                            stream_write!(writer, format!("{}\n{}\n", e.status(), e));
+                        }
+                    }
+                }
+
+                FlytApiCommand::RMGR_SNODE_VIRT_SERVER_LOGS => {
+                    let args = stream_read_line!(reader);
+                    let parts = args.split(",").collect::<Vec<&str>>();
+
+                    // format: rpc_id,offset
+                    let rpc_id = parts.first().and_then(|s| s.parse::<u64>().ok());
+                    let offset = parts.get(1).and_then(|s| s.parse::<u64>().ok());
+
+                    if rpc_id.is_none() || offset.is_none() {
+                        stream_write!(writer, "400\nInvalid arguments\n".to_string());
+                        continue;
+                    }
+
+                    match self.virt_server_manager.read_logs(rpc_id.unwrap(), offset.unwrap()) {
+                        Ok((offset, lines)) => {
+                            let mut message = format!("200\n{},{}\n", offset, lines.len());
+                            for line in lines {
+                                message.push_str(&line);
+                                message.push('\n');
+                            }
+                            stream_write!(writer, message);
+                        }
+                        Err(e) => {
+                            log::error!("Error reading virt server logs: {}", e);
                             stream_write!(writer, format!("500\n{}\n", e));
                         }
                     }
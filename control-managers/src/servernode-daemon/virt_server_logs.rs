@@ -0,0 +1,231 @@
+use std::{fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Read, Seek, SeekFrom, Write}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, process::Child, sync::{Arc, Mutex}, thread};
+
+/// Largest chunk returned by a single `read_log` call
+const MAX_READ_SIZE: u64 = 64 * 1024;
+
+/// Bits of a `read_log` offset holding the position in the file, the bits above hold the low bits
+/// of the file's inode. Together they stay below 2^53, so offsets survive as JSON numbers.
+const POSITION_BITS: u32 = 36;
+const INODE_BITS: u32 = 17;
+const POSITION_MASK: u64 = (1 << POSITION_BITS) - 1;
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub log_dir: String,
+    /// Size in bytes after which the log file is rotated
+    pub max_size: u64,
+    /// Number of rotated files kept besides the current one
+    pub max_files: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            log_dir: "/tmp/flyt-virt-server-logs".to_string(),
+            max_size: 10 * 1024 * 1024,
+            max_files: 3,
+        }
+    }
+}
+
+pub fn log_path(log_dir: &str, rpc_id: u64) -> PathBuf {
+    PathBuf::from(log_dir).join(format!("virt-server-{}.log", rpc_id))
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+/// Removes the log files left by an earlier virt server with the same rpc_id, e.g. from before the
+/// node daemon restarted and started counting rpc_ids from 1 again.
+pub fn clear_log(config: &LogConfig, rpc_id: u64) {
+    let path = log_path(&config.log_dir, rpc_id);
+    for index in 1..=config.max_files {
+        let _ = fs::remove_file(rotated_path(&path, index));
+    }
+    let _ = fs::remove_file(path);
+}
+
+/// Log file of a single virt server, rotated to `<name>.1`, `<name>.2`, ... once it grows past `max_size`.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingLog {
+
+    pub fn open(config: &LogConfig, rpc_id: u64) -> Result<Self, String> {
+        fs::create_dir_all(&config.log_dir).map_err(|e| format!("Error creating log directory {}: {}", config.log_dir, e))?;
+        let path = log_path(&config.log_dir, rpc_id);
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("Error opening log file {:?}: {}", path, e))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        Ok(RotatingLog {
+            path,
+            file,
+            size,
+            max_size: config.max_size,
+            max_files: config.max_files,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        if self.size + line.len() as u64 + 1 > self.max_size && self.size > 0 {
+            if let Err(e) = self.rotate() {
+                log::error!("Error rotating log file {:?}: {}", self.path, e);
+            }
+        }
+
+        match writeln!(self.file, "{}", line) {
+            Ok(_) => self.size += line.len() as u64 + 1,
+            Err(e) => log::error!("Error writing to log file {:?}: {}", self.path, e),
+        }
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        rotated_path(&self.path, index)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        // always start a new file, readers tell a rotation apart by the inode changing
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        }
+        else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Copies the stdout and stderr of `child` into `log` until the process closes them.
+pub fn capture_output(child: &mut Child, log: Arc<Mutex<RotatingLog>>) {
+    if let Some(stdout) = child.stdout.take() {
+        let log = log.clone();
+        thread::spawn(move || copy_lines(stdout, log));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        thread::spawn(move || copy_lines(stderr, log));
+    }
+}
+
+fn copy_lines<R: Read>(output: R, log: Arc<Mutex<RotatingLog>>) {
+    let mut reader = BufReader::new(output);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                log.lock().unwrap().write_line(line.trim_end_matches(['\n', '\r']));
+            }
+            Err(e) => {
+                log::error!("Error reading virt server output: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Reads the complete lines of the current log file of `rpc_id` that start at `offset`.
+/// Returns the offset to continue from, which also records the inode of the file read. An offset
+/// into a file that has been rotated away since starts over at 0 of the current file.
+pub fn read_log(log_dir: &str, rpc_id: u64, offset: u64) -> Result<(u64, Vec<String>), String> {
+    let path = log_path(log_dir, rpc_id);
+    let mut file = File::open(&path).map_err(|e| format!("Error opening log file {:?}: {}", path, e))?;
+    let metadata = file.metadata().map_err(|e| e.to_string())?;
+    let file_id = (metadata.ino() & ((1 << INODE_BITS) - 1)) << POSITION_BITS;
+
+    let position = if offset & !POSITION_MASK == file_id && offset & POSITION_MASK <= metadata.len() {
+        offset & POSITION_MASK
+    }
+    else {
+        0
+    };
+    file.seek(SeekFrom::Start(position)).map_err(|e| e.to_string())?;
+
+    let mut buf = Vec::new();
+    file.take(MAX_READ_SIZE).read_to_end(&mut buf).map_err(|e| e.to_string())?;
+
+    // leave a partially written last line for the next read
+    let end = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(pos) => pos + 1,
+        None => return Ok((file_id | position, Vec::new())),
+    };
+
+    let lines = String::from_utf8_lossy(&buf[..end]).lines().map(|line| line.to_string()).collect();
+    Ok((file_id | (position + end as u64), lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_and_read() {
+        let log_dir = std::env::temp_dir().join(format!("flyt-log-test-{}", std::process::id()));
+        let config = LogConfig {
+            log_dir: log_dir.to_string_lossy().to_string(),
+            max_size: 16,
+            max_files: 1,
+        };
+
+        let mut log = RotatingLog::open(&config, 7).unwrap();
+        log.write_line("first line");
+        log.write_line("second line");
+        log.write_line("third line");
+
+        let (offset, lines) = read_log(&config.log_dir, 7, 0).unwrap();
+        assert_eq!(lines, vec!["third line"]);
+        assert_eq!(offset & POSITION_MASK, 11);
+        assert!(log.rotated_path(1).exists());
+        assert!(!log.rotated_path(2).exists());
+
+        // the new file is longer than the offset into the rotated one
+        log.write_line("fourth line, much longer");
+        let (offset, lines) = read_log(&config.log_dir, 7, offset).unwrap();
+        assert_eq!(lines, vec!["fourth line, much longer"]);
+
+        let (_, lines) = read_log(&config.log_dir, 7, offset).unwrap();
+        assert!(lines.is_empty());
+
+        let _ = fs::remove_dir_all(&log_dir);
+    }
+
+    #[test]
+    fn test_clear_log() {
+        let log_dir = std::env::temp_dir().join(format!("flyt-log-clear-test-{}", std::process::id()));
+        let config = LogConfig {
+            log_dir: log_dir.to_string_lossy().to_string(),
+            max_size: 16,
+            max_files: 2,
+        };
+
+        let mut log = RotatingLog::open(&config, 3).unwrap();
+        log.write_line("earlier run");
+        log.write_line("earlier run");
+        assert!(log.rotated_path(1).exists());
+
+        clear_log(&config, 3);
+        assert!(!log.rotated_path(1).exists());
+
+        let mut log = RotatingLog::open(&config, 3).unwrap();
+        log.write_line("new run");
+        let (_, lines) = read_log(&config.log_dir, 3, 0).unwrap();
+        assert_eq!(lines, vec!["new run"]);
+
+        let _ = fs::remove_dir_all(&log_dir);
+    }
+}
@@ -1,32 +1,220 @@
-use std::{collections::HashMap, fs::File, path::Path, process::{Child, Command}, sync::{Arc, Mutex}, time::Duration};
+use std::{collections::HashMap, fs::File, os::unix::process::ExitStatusExt, path::Path, process::{Child, Command, ExitStatus, Stdio}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
 use ipc_rs::MessageQueue;
-use crate::common::{api_commands::FlytApiCommand, types::MqueueClientControlCommand, utils::Utils};
+use nix::{sys::signal::{self, Signal}, unistd::Pid};
+use crate::affinity::{self, AffinityMode};
+use crate::cgroups::{CgroupManager, CgroupUsage};
+use crate::common::{api_commands::FlytApiCommand, types::{Backoff, ControlError, ControlMessage, ControlReply, ControlTransport, HostLimits, IsolationLevel, MpsStatus, RestartPolicy, VirtServerFlavor}};
+use crate::gpu_manager::GPU;
+use crate::mps_manager::MpsManager;
+use crate::virt_server_logs::{self, LogConfig, RotatingLog};
+use crate::virt_server_transport::{MqueueTransport, SocketTransport, TransportKind};
 
 const PROJ_ID: i32 = 0x42;
 
+/// Restarts allowed per virt server before the supervisor gives up on it
+const MAX_RESTARTS: u32 = 3;
+
+/// Delays before starting a pooled virt server on a GPU again after it failed, doubled on every failure
+const POOL_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const POOL_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Time allowed for one kind of virt server operation: `base` plus `per_gb` for every GB of GPU memory of the virt server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OperationTimeout {
+    pub base: Duration,
+    pub per_gb: Duration,
+}
+
+impl OperationTimeout {
+    pub const fn fixed(secs: u64) -> Self {
+        OperationTimeout { base: Duration::from_secs(secs), per_gb: Duration::ZERO }
+    }
+
+    pub fn for_memory(&self, gpu_memory: u64) -> Duration {
+        self.base + self.per_gb.mul_f64(gpu_memory as f64 / (1024 * 1024 * 1024) as f64)
+    }
+}
+
+/// Timeouts of the requests the node daemon makes to its virt servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OperationTimeouts {
+    /// Until the virt server reports that it has initialised
+    pub startup: OperationTimeout,
+    pub checkpoint: OperationTimeout,
+    pub restore: OperationTimeout,
+    pub resize: OperationTimeout,
+    /// Until the virt server acknowledges a shutdown request, the drain timeout follows
+    pub shutdown: OperationTimeout,
+}
+
+impl Default for OperationTimeouts {
+    fn default() -> Self {
+        OperationTimeouts {
+            startup: OperationTimeout::fixed(5),
+            checkpoint: OperationTimeout { base: Duration::from_secs(5), per_gb: Duration::from_secs(1) },
+            restore: OperationTimeout { base: Duration::from_secs(60), per_gb: Duration::from_secs(1) },
+            resize: OperationTimeout::fixed(5),
+            shutdown: OperationTimeout::fixed(1),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct VirtServer {
     id: u64,
     gpu_id: u32,
+    gpu: GPU,
+    flavor: String,
     num_sm_cores: u32,
     gpu_memory: u64,
     process: Arc<Mutex<Child>>,
-    send_id: i64,
-    recv_id: i64,
+    /// Pid of `process`, readable while a shutdown holds the process
+    pid: u32,
+    transport: Arc<dyn ControlTransport>,
+    restart_policy: RestartPolicy,
+    host_limits: HostLimits,
+    restarts: u32,
+    /// Most recent checkpoint taken or restored, used when restarting after a crash
+    last_checkpoint: Option<String>,
+    state: VirtServerState,
+}
+
+/// Operations on a virt server fail unless it is running; the supervisor leaves it alone unless it is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VirtServerState {
+    Running,
+    /// Exited and being restarted by the supervisor
+    Restarting,
+    /// Being shut down on deallocation
+    Stopping,
+}
+
+impl VirtServerState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VirtServerState::Running => "running",
+            VirtServerState::Restarting => "restarting",
+            VirtServerState::Stopping => "stopping",
+        }
+    }
+}
+
+/// An allocated virt server as reported to the resource manager when it (re)connects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtServerInfo {
+    pub rpc_id: u64,
+    pub gpu_id: u32,
+    pub num_sm_cores: u32,
+    pub gpu_memory: u64,
+    pub flavor: String,
+}
+
+/// Limits actually applied by a virt server, as reported in its startup handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartupInfo {
+    pub num_sm_cores: u32,
+    pub gpu_memory: u64,
+    /// As returned by `cudaRuntimeGetVersion`, e.g. 12020. 0 for virt servers that report only a status
+    pub cuda_version: u32,
+}
+
+impl StartupInfo {
+    /// Parses the startup report of a virt server, `<sm_cores>,<memory>,<cuda_version>` on success or an error reason.
+    /// Virt servers that report only a status applied the requested limits.
+    fn parse(reply: &ControlReply, requested_sm_cores: u32, requested_memory: u64) -> Result<Self, String> {
+        if !reply.is_ok() {
+            return Err(reply.error());
+        }
+
+        if reply.body.is_empty() {
+            return Ok(StartupInfo {
+                num_sm_cores: requested_sm_cores,
+                gpu_memory: requested_memory,
+                cuda_version: 0,
+            });
+        }
+
+        let fields = reply.body.split(",").collect::<Vec<&str>>();
+        let field = |index: usize| fields.get(index).and_then(|field| field.parse::<u64>().ok()).ok_or(format!("Invalid startup reply from virt server: {}", reply.body));
+        Ok(StartupInfo {
+            num_sm_cores: field(0)? as u32,
+            gpu_memory: field(1)?,
+            cuda_version: field(2)? as u32,
+        })
+    }
+}
+
+/// A virt server flavor and the binary that runs it.
+#[derive(Debug, Clone)]
+pub struct VirtServerProgram {
+    pub flavor: VirtServerFlavor,
+    pub path: String,
+    pub transport: TransportKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtServerExit {
+    pub rpc_id: u64,
+    /// "exit:<code>" or "signal:<signal>"
+    pub status: String,
+    pub restarted: bool,
+}
+
+/// How a virt server was stopped on deallocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPath {
+    /// Acknowledged `SNODE_VIRTS_SHUTDOWN` and exited within the drain timeout
+    Graceful,
+    /// Exited on SIGTERM within the drain timeout
+    Terminated,
+    Killed,
+}
+
+impl ShutdownPath {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownPath::Graceful => "graceful",
+            ShutdownPath::Terminated => "sigterm",
+            ShutdownPath::Killed => "sigkill",
+        }
+    }
+}
+
+fn exit_status_str(status: &ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit:{}", code),
+        (None, Some(signal)) => format!("signal:{}", signal),
+        (None, None) => "unknown".to_string(),
+    }
 }
 
 pub struct VirtServerManager {
     counter: Mutex<u64>,
     virts_servers: Mutex<HashMap<u64,VirtServer>>,
-    message_queue: MessageQueue,
-    virt_server_program_path: String,
+    message_queue: Arc<MessageQueue>,
+    /// Directory of the control sockets of virt servers using `TransportKind::Socket`
+    socket_dir: String,
+    /// The first program is the default flavor, the only one kept in the pool
+    programs: Vec<VirtServerProgram>,
+    log_config: LogConfig,
+    /// Time a virt server gets to finish outstanding work after being asked to shut down
+    drain_timeout: Duration,
+    timeouts: OperationTimeouts,
+    mps_manager: Arc<MpsManager>,
+    cgroup_manager: CgroupManager,
+    affinity: AffinityMode,
+    /// Idle virt servers per gpu_id, started with the whole GPU and handed out on allocation
+    pool: Mutex<HashMap<u32, Vec<(VirtServer, StartupInfo)>>>,
+    pool_size: usize,
+    /// GPUs on which starting a pooled virt server failed, with the time of the next attempt
+    pool_retry: Mutex<HashMap<u32, (Backoff, Instant)>>,
 }
 
 
 
 impl VirtServerManager {
 
-    pub fn new(mqueue_path: &str, virt_server_program_path: String) -> VirtServerManager {
+    pub fn new(mqueue_path: &str, programs: Vec<VirtServerProgram>, log_config: LogConfig, drain_timeout: Duration, mps_manager: Arc<MpsManager>, cgroup_manager: CgroupManager, affinity: AffinityMode) -> VirtServerManager {
 
         if Path::new(mqueue_path).exists() == false {
             File::create(mqueue_path).unwrap();
@@ -39,15 +227,67 @@ impl VirtServerManager {
         VirtServerManager {
             counter: Mutex::new(0),
             virts_servers: Mutex::new(HashMap::new()),
-            message_queue: message_queue,
-            virt_server_program_path
+            message_queue: Arc::new(message_queue),
+            socket_dir: "/tmp/flyt-virt-server-sockets".to_string(),
+            programs,
+            log_config,
+            drain_timeout,
+            timeouts: OperationTimeouts::default(),
+            mps_manager,
+            cgroup_manager,
+            affinity,
+            pool: Mutex::new(HashMap::new()),
+            pool_size: 0,
+            pool_retry: Mutex::new(HashMap::new()),
         }
     }
 
-    fn get_virt_server(&self, rpc_id: u64) -> Option<VirtServer> {
+    /// Keeps `pool_size` initialised virt servers ready on every GPU, see `fill_pool`.
+    pub fn with_pool_size(mut self, pool_size: usize) -> VirtServerManager {
+        self.pool_size = pool_size;
+        self
+    }
+
+    pub fn with_socket_dir(mut self, socket_dir: String) -> VirtServerManager {
+        self.socket_dir = socket_dir;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: OperationTimeouts) -> VirtServerManager {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Flavors this node can start, the default one first
+    pub fn flavors(&self) -> Vec<VirtServerFlavor> {
+        self.programs.iter().map(|program| program.flavor.clone()).collect()
+    }
+
+    fn program(&self, flavor: &str) -> Result<&VirtServerProgram, String> {
+        self.programs.iter()
+            .find(|program| program.flavor.name == flavor)
+            .ok_or(format!("Virt server flavor {} is not available", flavor))
+    }
+
+    fn new_transport(&self, rpc_id: u64, kind: TransportKind) -> Result<Arc<dyn ControlTransport>, String> {
+        match kind {
+            TransportKind::Mqueue => Ok(Arc::new(MqueueTransport::new(self.message_queue.clone(), rpc_id))),
+            TransportKind::Socket => Ok(Arc::new(SocketTransport::new(&self.socket_dir, rpc_id)?)),
+        }
+    }
+
+    fn default_flavor(&self) -> &str {
+        self.programs.first().map(|program| program.flavor.name.as_str()).unwrap_or(VirtServerFlavor::DEFAULT)
+    }
+
+    /// Returns virt server `rpc_id` unless it is being restarted.
+    fn get_virt_server(&self, rpc_id: u64) -> Result<VirtServer, String> {
         let virt_servers = self.virts_servers.lock().unwrap();
-        let virt_server = virt_servers.get(&rpc_id)?;
-        Some(virt_server.clone())
+        let virt_server = virt_servers.get(&rpc_id).ok_or("Virt server not found")?;
+        if virt_server.state != VirtServerState::Running {
+            return Err(format!("Virt server {} is {}", rpc_id, virt_server.state.as_str()));
+        }
+        Ok(virt_server.clone())
     }
 
     fn update_virt_server(&self, rpc_id: u64, virt_server: VirtServer) {
@@ -56,149 +296,665 @@ impl VirtServerManager {
     }
 
 
-    pub fn create_virt_server(&self, gpu_id: u32, gpu_memory: u64, num_sm_cores: u32) -> Result<u64,String> {
-        log::debug!("create_virt_server: gpu_id: {}, gpu_memory: {}, num_sm_cores: {}", gpu_id, gpu_memory, num_sm_cores);
+    /// Starts a virt server and returns its rpc_id together with the limits it applied.
+    /// An idle virt server from the pool of the GPU is used when there is one.
+    pub fn create_virt_server(&self, gpu: &GPU, gpu_memory: u64, num_sm_cores: u32, restart_policy: RestartPolicy, host_limits: HostLimits, flavor: &str) -> Result<(u64, StartupInfo),ControlError> {
+        log::debug!("create_virt_server: gpu_id: {}, gpu_memory: {}, num_sm_cores: {}, flavor: {}", gpu.gpu_id, gpu_memory, num_sm_cores, flavor);
+
+        if flavor == self.default_flavor() {
+            if let Some(allocated) = self.take_from_pool(gpu.gpu_id, gpu_memory, num_sm_cores, restart_policy, host_limits) {
+                return Ok(allocated);
+            }
+        }
+
+        let (virt_server, startup_info) = self.start_virt_server(gpu, gpu_memory, num_sm_cores, restart_policy, host_limits, flavor)?;
+        let rpc_id = virt_server.id;
+        self.virts_servers.lock().unwrap().insert(rpc_id, virt_server);
+        
+        Ok((rpc_id, startup_info))
+    }
+
+    /// Hands out an idle virt server of `gpu_id` with the requested limits applied.
+    fn take_from_pool(&self, gpu_id: u32, gpu_memory: u64, num_sm_cores: u32, restart_policy: RestartPolicy, host_limits: HostLimits) -> Option<(u64, StartupInfo)> {
+        loop {
+            let (mut virt_server, startup_info) = self.pool.lock().unwrap().get_mut(&gpu_id)?.pop()?;
+            let rpc_id = virt_server.id;
+
+            let applied = self.cgroup_manager.create(rpc_id, &host_limits).map_err(ControlError::from)
+                .and_then(|_| self.send_resources(&virt_server, num_sm_cores, gpu_memory));
+            if let Err(e) = applied {
+                log::warn!("Error applying limits to pooled virt server {}: {}", rpc_id, e);
+                let _ = self.stop_virt_server(&virt_server);
+                self.cgroup_manager.remove(rpc_id);
+                continue;
+            }
+
+            log::info!("Allocated pooled virt server {} on GPU {}", rpc_id, gpu_id);
+            virt_server.num_sm_cores = num_sm_cores;
+            virt_server.gpu_memory = gpu_memory;
+            virt_server.restart_policy = restart_policy;
+            virt_server.host_limits = host_limits;
+            self.update_virt_server(rpc_id, virt_server);
+
+            return Some((rpc_id, StartupInfo {
+                num_sm_cores,
+                gpu_memory,
+                cuda_version: startup_info.cuda_version,
+            }));
+        }
+    }
+
+    /// Tops up the pool of every GPU in `gpus` to the pool size. A GPU on which a virt server failed
+    /// to start is retried with backoff. Pooled virt servers that exited, or whose GPU is no longer in
+    /// `gpus`, are dropped. Returns whether the pool changed.
+    pub fn fill_pool(&self, gpus: &[GPU]) -> bool {
+        let mut stale = Vec::new();
+        {
+            let mut pool = self.pool.lock().unwrap();
+            for (gpu_id, virt_servers) in pool.iter_mut() {
+                let gpu_present = gpus.iter().any(|gpu| gpu.gpu_id == *gpu_id);
+                virt_servers.retain(|(virt_server, _)| {
+                    let exited = !matches!(virt_server.process.lock().unwrap().try_wait(), Ok(None));
+                    if exited || !gpu_present {
+                        stale.push((virt_server.clone(), exited));
+                        return false;
+                    }
+                    true
+                });
+            }
+        }
+
+        let mut changed = !stale.is_empty();
+        for (virt_server, exited) in stale {
+            if exited {
+                log::warn!("Pooled virt server {} on GPU {} exited", virt_server.id, virt_server.gpu_id);
+            } else {
+                let _ = self.stop_virt_server(&virt_server);
+            }
+            self.cgroup_manager.remove(virt_server.id);
+        }
+
+        if self.pool_size == 0 {
+            return changed;
+        }
+
+        for gpu in gpus {
+            if self.mps_manager.get_status(gpu.gpu_id) == MpsStatus::Unhealthy {
+                continue;
+            }
+            if self.pool_retry.lock().unwrap().get(&gpu.gpu_id).is_some_and(|(_, retry_at)| Instant::now() < *retry_at) {
+                continue;
+            }
+
+            // started one at a time outside the lock, allocations keep taking from the pool meanwhile
+            while self.pool.lock().unwrap().get(&gpu.gpu_id).map(|virt_servers| virt_servers.len()).unwrap_or(0) < self.pool_size {
+                match self.start_virt_server(gpu, gpu.memory, gpu.sm_cores, RestartPolicy::Never, HostLimits::default(), self.default_flavor()) {
+                    Ok(pooled) => {
+                        log::debug!("Pooled virt server {} on GPU {}", pooled.0.id, gpu.gpu_id);
+                        self.pool.lock().unwrap().entry(gpu.gpu_id).or_default().push(pooled);
+                        self.pool_retry.lock().unwrap().remove(&gpu.gpu_id);
+                        changed = true;
+                    }
+                    Err(e) => {
+                        let mut pool_retry = self.pool_retry.lock().unwrap();
+                        let (backoff, retry_at) = pool_retry.entry(gpu.gpu_id)
+                            .or_insert((Backoff::new(POOL_RETRY_MIN_DELAY, POOL_RETRY_MAX_DELAY), Instant::now()));
+                        let delay = backoff.next_delay();
+                        *retry_at = Instant::now() + delay;
+                        log::error!("Error starting pooled virt server on GPU {}: {}, retrying in {:?}", gpu.gpu_id, e, delay);
+                        break;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// Returns (gpu_id, pid) of every pooled virt server.
+    pub fn get_pooled_process_ids(&self) -> Vec<(u32, u32)> {
+        let pool = self.pool.lock().unwrap();
+        pool.values().flatten().map(|(virt_server, _)| {
+            (virt_server.gpu_id, virt_server.pid)
+        }).collect()
+    }
+
+    fn start_virt_server(&self, gpu: &GPU, gpu_memory: u64, num_sm_cores: u32, restart_policy: RestartPolicy, host_limits: HostLimits, flavor: &str) -> Result<(VirtServer, StartupInfo),ControlError> {
+        let gpu_id = gpu.gpu_id;
+        let program = self.program(flavor)?;
         let rpc_id = {
             let mut counter = self.counter.lock().unwrap();
             *counter += 1;
             *counter
         };
+
+        // a restarted virt server appends to its log, a new one must not
+        virt_server_logs::clear_log(&self.log_config, rpc_id);
+        let (virt_server_process, transport, startup_info) = self.spawn_virt_server(rpc_id, gpu, gpu_memory, num_sm_cores, &host_limits, program)?;
+
+        log::info!("Virt server initialized with rpc_id: {}, {:?}", rpc_id, startup_info);
         
-        let send_id = rpc_id as i64;
-        let recv_id = send_id << 32;
+        let virt_server = VirtServer {
+            id: rpc_id,
+            gpu_id: gpu_id,
+            gpu: gpu.clone(),
+            flavor: flavor.to_string(),
+            num_sm_cores: startup_info.num_sm_cores,
+            gpu_memory: startup_info.gpu_memory,
+            pid: virt_server_process.id(),
+            process: Arc::new(Mutex::new(virt_server_process)),
+            transport,
+            restart_policy,
+            host_limits,
+            restarts: 0,
+            last_checkpoint: None,
+            state: VirtServerState::Running,
+        };
+
+        Ok((virt_server, startup_info))
+    }
+
+    /// Starts the virt server process for `rpc_id` and waits for it to report that it is ready.
+    /// Fails with the reason reported by the virt server, or with its last log line if it exits during startup.
+    fn spawn_virt_server(&self, rpc_id: u64, gpu: &GPU, gpu_memory: u64, num_sm_cores: u32, host_limits: &HostLimits, program: &VirtServerProgram) -> Result<(Child, Arc<dyn ControlTransport>, StartupInfo),ControlError> {
+        let gpu_id = gpu.gpu_id;
+
+        // a MIG slice is the only device visible to its virt server
+        let device_ordinal = match gpu.isolation {
+            IsolationLevel::Mps => gpu_id,
+            IsolationLevel::Mig => 0,
+        };
 
         log::info!("Starting virt server with rpc_id: {}", rpc_id);
 
-        let mut virt_server_process = Command::new(self.virt_server_program_path.as_str())
-            .env("CUDA_VISIBLE_DEVICES", gpu_id.to_string())
+        let log = RotatingLog::open(&self.log_config, rpc_id)?;
+
+        let transport = self.new_transport(rpc_id, program.transport)?;
+
+        self.cgroup_manager.create(rpc_id, host_limits)?;
+
+        let mut command = Command::new(&program.path);
+        command
+            .env("CUDA_VISIBLE_DEVICES", gpu.visible_device.as_str())
             .env("CUDA_MPS_ENABLE_PER_CTX_DEVICE_MULTIPROCESSOR_PARTITIONING", "1")
+            .envs(self.mps_manager.virt_server_env(gpu_id))
+            .envs(transport.env())
             .arg(rpc_id.to_string())
-            .arg(gpu_id.to_string())
+            .arg(device_ordinal.to_string())
             .arg(num_sm_cores.to_string())
             .arg(gpu_memory.to_string())
-            .spawn()
-            .map_err(|e| format!("Error starting virt server: {}", e))?;
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        self.cgroup_manager.attach(rpc_id, &mut command)?;
+        affinity::apply(self.affinity, &gpu.pci_bus_id, &mut command);
+
+        let mut virt_server_process = command.spawn().map_err(|e| {
+            self.cgroup_manager.remove(rpc_id);
+            format!("Error starting virt server: {}", e)
+        })?;
+
+        virt_server_logs::capture_output(&mut virt_server_process, Arc::new(Mutex::new(log)));
+
+        let startup_timeout = self.timeouts.startup.for_memory(gpu_memory);
+        let deadline = Instant::now() + startup_timeout;
+        let result = loop {
+            match transport.recv_startup(Duration::from_millis(200)) {
+                Ok(Some(reply)) => break StartupInfo::parse(&reply, num_sm_cores, gpu_memory).map_err(ControlError::Failed),
+                Ok(None) => {}
+                Err(e) => break Err(ControlError::Failed(e)),
+            }
+
+            match virt_server_process.try_wait() {
+                Ok(Some(status)) => {
+                    // give the output threads a moment to flush the last lines
+                    thread::sleep(Duration::from_millis(100));
+                    break Err(ControlError::Failed(format!("Virt server exited during startup ({}): {}", exit_status_str(&status), self.last_log_line(rpc_id))));
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Error checking virt server {}: {}", rpc_id, e),
+            }
+
+            if Instant::now() >= deadline {
+                break Err(ControlError::Timeout(format!("Virt server did not initialise within {:?}", startup_timeout)));
+            }
+        };
 
-        let response = self.message_queue.recv_type_timed(recv_id, Duration::from_secs(5));
-        
-        if response.is_err() {
-            log::error!("Error receiving message from virt server: {}, Killing it.", response.err().unwrap());
-            virt_server_process.kill().map_err(|e| format!("Error killing virt server: {}", e))?;
-            return Err("Error starting virt server".to_string());
+        match result {
+            Ok(startup_info) => {
+                log::debug!("Received startup reply from virt server: {:?}", startup_info);
+                Ok((virt_server_process, transport, startup_info))
+            }
+            Err(e) => {
+                log::error!("Error starting virt server {}: {}", rpc_id, e);
+                if let Ok(None) = virt_server_process.try_wait() {
+                    let _ = virt_server_process.kill();
+                }
+                let _ = virt_server_process.wait();
+                self.cgroup_manager.remove(rpc_id);
+                Err(e)
+            }
         }
+    }
 
-        log::debug!("Received message from virt server: {:?}", Utils::convert_bytes_to_u32(response.unwrap().as_slice()));
-
-        log::info!("Virt server initialized with rpc_id: {}", rpc_id);
-        
-        let virt_server = VirtServer {
-            id: rpc_id,
-            gpu_id: gpu_id,
-            num_sm_cores: num_sm_cores,
-            gpu_memory: gpu_memory,
-            process: Arc::new(Mutex::new(virt_server_process)),
-            send_id: send_id,
-            recv_id: recv_id,
-        };
-
-        self.virts_servers.lock().unwrap().insert(rpc_id, virt_server);
-        
-        Ok(rpc_id)
-        
+    fn last_log_line(&self, rpc_id: u64) -> String {
+        virt_server_logs::read_log(&self.log_config.log_dir, rpc_id, 0).ok()
+            .and_then(|(_, lines)| lines.into_iter().rev().find(|line| !line.trim().is_empty()))
+            .unwrap_or("no output".to_string())
     }
 
-    pub fn checkpoint_virt_server(&self, rpc_id: u64, ckp_path: &str) -> Result<(),String> {
+    pub fn checkpoint_virt_server(&self, rpc_id: u64, ckp_path: &str) -> Result<(),ControlError> {
         log::debug!("checkpoint_virt_server: rpc_id: {}, ckp_path: {}", rpc_id, ckp_path);
         
-        let virt_server = self.get_virt_server(rpc_id).ok_or("Virt server not found")?;
-
-        let mqueue_cmd = MqueueClientControlCommand::new(FlytApiCommand::SNODE_VIRTS_CHECKPOINT, ckp_path).as_bytes();
+        let virt_server = self.get_virt_server(rpc_id)?;
 
-        self.message_queue.send( &mqueue_cmd, virt_server.send_id).map_err(|e| format!("Error sending message to virt server: {}", e))?;
+        let message = ControlMessage::new(FlytApiCommand::SNODE_VIRTS_CHECKPOINT, ckp_path);
+        let reply = virt_server.transport.request(&message, self.timeouts.checkpoint.for_memory(virt_server.gpu_memory))?;
 
-        let recv_bytes = self.message_queue.recv_type_timed(virt_server.recv_id, Duration::from_secs(5)).map_err(|e| format!("Error receiving message from virt server: {}", e))?;
-
-        let recv_status = Utils::convert_bytes_to_u32(&recv_bytes).ok_or("Error converting bytes to u32")?;
-
-        if recv_status != 200 {
-            return Err("Error checkpointing virt server".to_string());
+        if !reply.is_ok() {
+            return Err(ControlError::Failed(format!("Error checkpointing virt server: {}", reply.error())));
         }
+
+        self.set_last_checkpoint(rpc_id, ckp_path);
         
         Ok(())
     }
 
-    pub fn restore_virt_server(&self, rpc_id: u64, ckp_path: &str) -> Result<(),String> {
+    pub fn restore_virt_server(&self, rpc_id: u64, ckp_path: &str) -> Result<(),ControlError> {
         log::debug!("restore_virt_server: rpc_id: {}, ckp_path: {}", rpc_id, ckp_path);
         
-        let virt_server = self.get_virt_server(rpc_id).ok_or("Virt server not found")?;
+        let virt_server = self.get_virt_server(rpc_id)?;
+        self.send_restore(&virt_server, ckp_path)?;
+        self.set_last_checkpoint(rpc_id, ckp_path);
+        
+        Ok(())
+    }
 
-        let mqueue_cmd = MqueueClientControlCommand::new(FlytApiCommand::SNODE_VIRTS_RESTORE, ckp_path).as_bytes();
+    fn send_restore(&self, virt_server: &VirtServer, ckp_path: &str) -> Result<(),ControlError> {
+        let message = ControlMessage::new(FlytApiCommand::SNODE_VIRTS_RESTORE, ckp_path);
+        let reply = virt_server.transport.request(&message, self.timeouts.restore.for_memory(virt_server.gpu_memory))?;
 
-        self.message_queue.send( &mqueue_cmd, virt_server.send_id).map_err(|e| format!("Error sending message to virt server: {}", e))?;
+        if !reply.is_ok() {
+            return Err(ControlError::Failed(format!("Error restoring virt server: {}", reply.error())));
+        }
+        Ok(())
+    }
 
-        let recv_bytes = self.message_queue.recv_type_timed(virt_server.recv_id, Duration::from_secs(60)).map_err(|e| format!("Error receiving message from virt server: {}", e))?;
+    fn set_last_checkpoint(&self, rpc_id: u64, ckp_path: &str) {
+        if let Some(virt_server) = self.virts_servers.lock().unwrap().get_mut(&rpc_id) {
+            virt_server.last_checkpoint = Some(ckp_path.to_string());
+        }
+    }
 
-        let recv_status = Utils::convert_bytes_to_u32(&recv_bytes).ok_or("Error converting bytes to u32")?;
+    /// Reaps virt servers that exited on their own and restarts them if their restart policy asks for it.
+    /// Restarted virt servers keep their rpc_id and are restored from their last checkpoint, if any. They stay
+    /// listed, marked as restarting, until the restart finished.
+    pub fn reap_exited_virt_servers(&self) -> Vec<VirtServerExit> {
+        let mut exited = Vec::new();
+        {
+            let mut virt_servers = self.virts_servers.lock().unwrap();
+            virt_servers.retain(|rpc_id, virt_server| {
+                if virt_server.state != VirtServerState::Running {
+                    return true;
+                }
+                match virt_server.process.lock().unwrap().try_wait() {
+                    Ok(Some(status)) => {
+                        log::warn!("Virt server {} exited with {}", rpc_id, status);
+                        let restart = match virt_server.restart_policy {
+                            RestartPolicy::Never => false,
+                            RestartPolicy::OnFailure => !status.success(),
+                            RestartPolicy::Always => true,
+                        };
+                        let restart = if restart && virt_server.restarts >= MAX_RESTARTS {
+                            log::error!("Virt server {} exceeded {} restarts, not restarting", rpc_id, MAX_RESTARTS);
+                            false
+                        } else {
+                            restart
+                        };
+                        if restart {
+                            virt_server.state = VirtServerState::Restarting;
+                        }
+                        exited.push((virt_server.clone(), status));
+                        restart
+                    }
+                    Ok(None) => true,
+                    Err(e) => {
+                        log::error!("Error checking virt server {}: {}", rpc_id, e);
+                        true
+                    }
+                }
+            });
+        }
 
-        if recv_status != 200 {
-            return Err("Error restoring virt server".to_string());
+        let mut exits = Vec::new();
+        for (virt_server, status) in exited {
+            let rpc_id = virt_server.id;
+
+            let restarted = if virt_server.state != VirtServerState::Restarting {
+                false
+            } else {
+                match self.restart_virt_server(virt_server) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        log::error!("Error restarting virt server {}: {}", rpc_id, e);
+                        self.virts_servers.lock().unwrap().remove(&rpc_id);
+                        false
+                    }
+                }
+            };
+
+            if !restarted {
+                self.cgroup_manager.remove(rpc_id);
+            }
+
+            exits.push(VirtServerExit {
+                rpc_id,
+                status: exit_status_str(&status),
+                restarted,
+            });
         }
-        
-        Ok(())
+        exits
     }
 
-    pub fn remove_virt_server(&self, rpc_id: u64) -> Result<(),String> {
-        let mut virt_servers = self.virts_servers.lock().unwrap();
-        let virt_server = virt_servers.get(&rpc_id).ok_or("Virt server not found")?;
-        
-        virt_server.process.lock().unwrap().kill().map_err(|e| format!("Error killing virt server: {}", e))?;
-        virt_servers.remove(&rpc_id);
-        
+    fn restart_virt_server(&self, mut virt_server: VirtServer) -> Result<(),String> {
+        let rpc_id = virt_server.id;
+        log::info!("Restarting virt server {} (restart {} of {})", rpc_id, virt_server.restarts + 1, MAX_RESTARTS);
+
+        let program = self.program(&virt_server.flavor)?;
+        let (process, transport, startup_info) = self.spawn_virt_server(rpc_id, &virt_server.gpu, virt_server.gpu_memory, virt_server.num_sm_cores, &virt_server.host_limits, program)?;
+        virt_server.pid = process.id();
+        virt_server.process = Arc::new(Mutex::new(process));
+        virt_server.transport = transport;
+        virt_server.num_sm_cores = startup_info.num_sm_cores;
+        virt_server.gpu_memory = startup_info.gpu_memory;
+        virt_server.restarts += 1;
+
+        if let Some(ckp_path) = virt_server.last_checkpoint.as_deref() {
+            if let Err(e) = self.send_restore(&virt_server, ckp_path) {
+                let _ = self.stop_virt_server(&virt_server);
+                return Err(format!("Error restoring checkpoint {}: {}", ckp_path, e));
+            }
+        }
+
+        virt_server.state = VirtServerState::Running;
+        self.update_virt_server(rpc_id, virt_server);
         Ok(())
     }
 
-    pub fn change_resources(&self, rpc_id: u64, new_num_sm_cores: u32, new_gpu_memory: u64) -> Result<(),String> {
+    /// Returns the log lines of virt server `rpc_id` from `offset` on and the offset of the next unread line.
+    /// Logs stay readable after the virt server has been removed.
+    pub fn read_logs(&self, rpc_id: u64, offset: u64) -> Result<(u64, Vec<String>),String> {
+        virt_server_logs::read_log(&self.log_config.log_dir, rpc_id, offset)
+    }
 
-        let mut virt_server = self.get_virt_server(rpc_id).ok_or("Virt server not found")?;
+    /// Describes every allocated virt server, pooled ones are not included.
+    pub fn list_virt_servers(&self) -> Vec<VirtServerInfo> {
+        let virt_servers = self.virts_servers.lock().unwrap();
+        virt_servers.values().map(|virt_server| VirtServerInfo {
+            rpc_id: virt_server.id,
+            gpu_id: virt_server.gpu_id,
+            num_sm_cores: virt_server.num_sm_cores,
+            gpu_memory: virt_server.gpu_memory,
+            flavor: virt_server.flavor.clone(),
+        }).collect()
+    }
 
-        let mqueue_cmd = MqueueClientControlCommand::new(FlytApiCommand::SNODE_VIRTS_CHANGE_RESOURCES, format!("{},{}", new_num_sm_cores, new_gpu_memory).as_str()).as_bytes();
+    /// Returns (rpc_id, gpu_id, pid) of every running virt server.
+    pub fn get_process_ids(&self) -> Vec<(u64, u32, u32)> {
+        let virt_servers = self.virts_servers.lock().unwrap();
+        virt_servers.values().filter(|virt_server| virt_server.state != VirtServerState::Restarting).map(|virt_server| {
+            (virt_server.id, virt_server.gpu_id, virt_server.pid)
+        }).collect()
+    }
 
-        self.message_queue.send( &mqueue_cmd, virt_server.send_id).map_err(|e| format!("Error sending message to virt server: {}", e))?;
+    /// Host CPU, memory and pids usage of virt server `rpc_id`, if it runs in a cgroup
+    pub fn get_host_usage(&self, rpc_id: u64) -> Option<CgroupUsage> {
+        self.cgroup_manager.usage(rpc_id)
+    }
 
-        let recv_bytes = self.message_queue.recv_type_timed(virt_server.recv_id, Duration::from_secs(5)).map_err(|e| format!("Error receiving message from virt server: {}", e))?;
+    /// Stops a virt server, asking it to shut down over the message queue first, then with SIGTERM,
+    /// and killing it only if it is still running after the drain timeout. The virt server is removed
+    /// once it stopped, it stays listed if it could not be stopped.
+    pub fn remove_virt_server(&self, rpc_id: u64) -> Result<ShutdownPath,String> {
+        // marked as stopping so that the supervisor does not mistake the shutdown for a crash
+        let virt_server = {
+            let mut virt_servers = self.virts_servers.lock().unwrap();
+            let virt_server = virt_servers.get_mut(&rpc_id).ok_or("Virt server not found")?;
+            if virt_server.state != VirtServerState::Running {
+                return Err(format!("Virt server {} is {}", rpc_id, virt_server.state.as_str()));
+            }
+            virt_server.state = VirtServerState::Stopping;
+            virt_server.clone()
+        };
 
-        let recv_status = Utils::convert_bytes_to_u32(&recv_bytes).ok_or("Error converting bytes to u32")?;
+        match self.stop_virt_server(&virt_server) {
+            Ok(shutdown_path) => {
+                self.virts_servers.lock().unwrap().remove(&rpc_id);
+                self.cgroup_manager.remove(rpc_id);
+                Ok(shutdown_path)
+            }
+            Err(e) => {
+                if let Some(virt_server) = self.virts_servers.lock().unwrap().get_mut(&rpc_id) {
+                    virt_server.state = VirtServerState::Running;
+                }
+                Err(e)
+            }
+        }
+    }
 
-        if recv_status != 200 {
-            return Err("Error changing num sm cores".to_string());
+    fn stop_virt_server(&self, virt_server: &VirtServer) -> Result<ShutdownPath,String> {
+        let rpc_id = virt_server.id;
+        let mut process = virt_server.process.lock().unwrap();
+
+        let message = ControlMessage::new(FlytApiCommand::SNODE_VIRTS_SHUTDOWN, "");
+        let acknowledged = virt_server.transport.request(&message, self.timeouts.shutdown.for_memory(virt_server.gpu_memory)).map(|reply| reply.is_ok()).unwrap_or(false);
+
+        if acknowledged && self.wait_for_exit(&mut process) {
+            return Ok(ShutdownPath::Graceful);
+        }
+
+        log::warn!("Virt server {} did not shut down on request, sending SIGTERM", rpc_id);
+        if signal::kill(Pid::from_raw(process.id() as i32), Signal::SIGTERM).is_ok() && self.wait_for_exit(&mut process) {
+            return Ok(ShutdownPath::Terminated);
+        }
+
+        log::warn!("Virt server {} still running after {:?}, killing it", rpc_id, self.drain_timeout);
+        process.kill().map_err(|e| format!("Error killing virt server: {}", e))?;
+        let _ = process.wait();
+        
+        Ok(ShutdownPath::Killed)
+    }
+
+    /// Waits up to the drain timeout for `process` to exit.
+    fn wait_for_exit(&self, process: &mut Child) -> bool {
+        let deadline = Instant::now() + self.drain_timeout;
+        while Instant::now() < deadline {
+            match process.try_wait() {
+                Ok(Some(_)) => return true,
+                Ok(None) => thread::sleep(Duration::from_millis(100)),
+                Err(_) => return false,
+            }
         }
+        false
+    }
+
+    pub fn change_resources(&self, rpc_id: u64, new_num_sm_cores: u32, new_gpu_memory: u64) -> Result<(),ControlError> {
+
+        let mut virt_server = self.get_virt_server(rpc_id)?;
+
+        self.send_resources(&virt_server, new_num_sm_cores, new_gpu_memory)?;
 
         virt_server.num_sm_cores = new_num_sm_cores;
+        virt_server.gpu_memory = new_gpu_memory;
         self.update_virt_server(rpc_id, virt_server);
         Ok(())
     }
+
+    fn send_resources(&self, virt_server: &VirtServer, num_sm_cores: u32, gpu_memory: u64) -> Result<(),ControlError> {
+        let message = ControlMessage::new(FlytApiCommand::SNODE_VIRTS_CHANGE_RESOURCES, &format!("{},{}", num_sm_cores, gpu_memory));
+        let reply = virt_server.transport.request(&message, self.timeouts.resize.for_memory(virt_server.gpu_memory.max(gpu_memory)))?;
+
+        if !reply.is_ok() {
+            return Err(ControlError::Failed(format!("Error changing num sm cores: {}", reply.error())));
+        }
+        Ok(())
+    }
     
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cgroups::CgroupConfig;
+    use crate::gpu_manager::{GPUManager, MockGpuBackend};
+    use crate::mps_manager::{MpsConfig, MpsManager};
 
     fn init() {
         let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Trace).try_init();
     }
 
+    /// Answers every request with `status`
+    struct FakeTransport {
+        status: &'static str,
+    }
+
+    impl ControlTransport for FakeTransport {
+        fn env(&self) -> Vec<(String, String)> {
+            Vec::new()
+        }
+
+        fn recv_startup(&self, _timeout: Duration) -> Result<Option<ControlReply>, String> {
+            Ok(None)
+        }
+
+        fn request(&self, _message: &ControlMessage, _timeout: Duration) -> Result<ControlReply, ControlError> {
+            Ok(ControlReply { status: self.status.to_string(), body: String::new() })
+        }
+    }
+
+    /// A manager whose virt server program does not exist, so every start fails
+    fn pool_manager(name: &str, pool_size: usize) -> VirtServerManager {
+        let dir = std::env::temp_dir().join(format!("flyt-pool-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+
+        let programs = vec![VirtServerProgram {
+            flavor: VirtServerFlavor::default(),
+            path: format!("{}/missing-virt-server", dir),
+            transport: TransportKind::Socket,
+        }];
+        let log_config = LogConfig { log_dir: dir.clone(), ..Default::default() };
+        let mps_manager = Arc::new(MpsManager::new(MpsConfig { managed: false, ..Default::default() }));
+        VirtServerManager::new(&format!("{}/queue", dir), programs, log_config, Duration::from_secs(2), mps_manager, CgroupManager::new(CgroupConfig::default()), AffinityMode::Off)
+            .with_pool_size(pool_size)
+            .with_socket_dir(dir)
+    }
+
+    fn pooled_virt_server(rpc_id: u64, gpu: &GPU, program: &str, status: &'static str) -> (VirtServer, StartupInfo) {
+        let process = Command::new(program).arg("30").spawn().unwrap();
+        let startup_info = StartupInfo { num_sm_cores: gpu.sm_cores, gpu_memory: gpu.memory, cuda_version: 12020 };
+        (VirtServer {
+            id: rpc_id,
+            gpu_id: gpu.gpu_id,
+            gpu: gpu.clone(),
+            flavor: VirtServerFlavor::DEFAULT.to_string(),
+            num_sm_cores: gpu.sm_cores,
+            gpu_memory: gpu.memory,
+            pid: process.id(),
+            process: Arc::new(Mutex::new(process)),
+            transport: Arc::new(FakeTransport { status }),
+            restart_policy: RestartPolicy::Never,
+            host_limits: HostLimits::default(),
+            restarts: 0,
+            last_checkpoint: None,
+            state: VirtServerState::Running,
+        }, startup_info)
+    }
+
+    fn mock_gpu() -> GPU {
+        GPUManager::with_backend(Box::new(MockGpuBackend::new().with_gpu("Mock A", 1024, 20))).get_gpu(0).unwrap()
+    }
+
+    #[test]
+    fn test_take_from_pool() {
+        let manager = pool_manager("take", 1);
+        let gpu = mock_gpu();
+        assert!(manager.take_from_pool(gpu.gpu_id, 512, 10, RestartPolicy::OnFailure, HostLimits::default()).is_none());
+
+        // the last pooled virt server is handed out first, one that rejects the limits is dropped
+        manager.pool.lock().unwrap().insert(gpu.gpu_id, vec![
+            pooled_virt_server(1, &gpu, "sleep", "200"),
+            pooled_virt_server(2, &gpu, "sleep", "500"),
+        ]);
+
+        let (rpc_id, startup_info) = manager.take_from_pool(gpu.gpu_id, 512, 10, RestartPolicy::OnFailure, HostLimits::default()).unwrap();
+        assert_eq!(rpc_id, 1);
+        assert_eq!(startup_info, StartupInfo { num_sm_cores: 10, gpu_memory: 512, cuda_version: 12020 });
+        assert_eq!(manager.list_virt_servers(), vec![VirtServerInfo { rpc_id: 1, gpu_id: 0, num_sm_cores: 10, gpu_memory: 512, flavor: VirtServerFlavor::DEFAULT.to_string() }]);
+        assert!(manager.pool.lock().unwrap().get(&gpu.gpu_id).unwrap().is_empty());
+
+        let _ = manager.get_virt_server(1).unwrap().process.lock().unwrap().kill();
+    }
+
+    #[test]
+    fn test_fill_pool() {
+        let manager = pool_manager("fill", 1);
+        let gpu = mock_gpu();
+
+        // pooled virt servers that exited or whose GPU is gone are dropped
+        manager.pool.lock().unwrap().insert(gpu.gpu_id, vec![pooled_virt_server(1, &gpu, "true", "200")]);
+        manager.pool.lock().unwrap().insert(7, vec![pooled_virt_server(2, &gpu, "sleep", "500")]);
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(manager.fill_pool(std::slice::from_ref(&gpu)));
+        assert!(manager.get_pooled_process_ids().is_empty());
+        assert_eq!(*manager.counter.lock().unwrap(), 1);
+
+        // the failed start is not retried before the backoff delay has passed
+        assert!(!manager.fill_pool(std::slice::from_ref(&gpu)));
+        assert_eq!(*manager.counter.lock().unwrap(), 1);
+
+        thread::sleep(POOL_RETRY_MIN_DELAY);
+        assert!(!manager.fill_pool(std::slice::from_ref(&gpu)));
+        assert_eq!(*manager.counter.lock().unwrap(), 2);
+    }
+
     #[test]
     fn test_create_vserver() {
         init();
         let program_path = "/home/ub-12-3/flyt/bin/cricket-rpc-server";
         let mqueue_path = "/tmp/flyt-servernode-queue";
 
-        let virt_server_manager = VirtServerManager::new(mqueue_path, program_path.to_string());
+        let gpu = GPUManager::new().get_gpu(0).unwrap();
+        let mps_manager = Arc::new(MpsManager::new(MpsConfig::default()));
+        mps_manager.start(std::slice::from_ref(&gpu));
+        let programs = vec![VirtServerProgram {
+            flavor: VirtServerFlavor::default(),
+            path: program_path.to_string(),
+            transport: TransportKind::Mqueue,
+        }];
+        let virt_server_manager = VirtServerManager::new(mqueue_path, programs, LogConfig::default(), Duration::from_secs(10), mps_manager, CgroupManager::new(CgroupConfig::default()), AffinityMode::Off);
         let gpu_mem = 1024u64 * 1024 * 1024; // 1GB
-        let rpc_id = virt_server_manager.create_virt_server(0, gpu_mem , 10);
+        let rpc_id = virt_server_manager.create_virt_server(&gpu, gpu_mem , 10, RestartPolicy::Never, HostLimits::default(), VirtServerFlavor::DEFAULT);
         assert!(rpc_id.is_ok());
     }
+
+    #[test]
+    fn test_operation_timeout() {
+        let checkpoint = OperationTimeout { base: Duration::from_secs(5), per_gb: Duration::from_secs(2) };
+        assert_eq!(checkpoint.for_memory(0), Duration::from_secs(5));
+        assert_eq!(checkpoint.for_memory(16 * 1024 * 1024 * 1024), Duration::from_secs(37));
+        assert_eq!(checkpoint.for_memory(512 * 1024 * 1024), Duration::from_secs(6));
+        assert_eq!(OperationTimeout::fixed(5).for_memory(80 * 1024 * 1024 * 1024), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_parse_startup_reply() {
+        let reply = |status: &str, body: &str| ControlReply { status: status.to_string(), body: body.to_string() };
+
+        assert_eq!(StartupInfo::parse(&reply("200", ""), 10, 1024).unwrap(), StartupInfo { num_sm_cores: 10, gpu_memory: 1024, cuda_version: 0 });
+        assert_eq!(StartupInfo::parse(&reply("200", "8,1024,12020"), 10, 1024).unwrap(), StartupInfo { num_sm_cores: 8, gpu_memory: 1024, cuda_version: 12020 });
+        assert_eq!(StartupInfo::parse(&reply("E_RESOURCES", "MPS is not running"), 10, 1024).unwrap_err(), "E_RESOURCES: MPS is not running");
+        assert_eq!(StartupInfo::parse(&reply("500", ""), 10, 1024).unwrap_err(), "status 500");
+    }
         
         
 }
\ No newline at end of file
@@ -0,0 +1,295 @@
+use std::{fs, io::ErrorKind, os::unix::net::{UnixListener, UnixStream}, path::PathBuf, str::FromStr, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use ipc_rs::MessageQueue;
+use crate::common::{types::{read_frame, write_frame, ControlError, ControlMessage, ControlReply, ControlTransport, MqueueClientControlCommand}, utils::Utils};
+
+/// Environment variable that tells a virt server where to connect its control socket
+const CONTROL_SOCKET_ENV: &str = "FLYT_CONTROL_SOCKET";
+
+/// How the node daemon talks to the virt servers of a flavor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// The shared SysV message queue, limited to 63 byte commands and data
+    Mqueue,
+    /// A Unix domain socket per virt server with length prefixed frames
+    Socket,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mqueue" => Ok(TransportKind::Mqueue),
+            "socket" => Ok(TransportKind::Socket),
+            _ => Err(format!("Unknown virt server transport: {}", value)),
+        }
+    }
+}
+
+/// Appends the sequence number of a request to its command on the control socket, the virt server echoes
+/// it with the status so replies to requests that already timed out can be told apart.
+fn tag_command(command: &str, seq: u32) -> String {
+    format!("{} {}", command, seq)
+}
+
+/// Splits `<status> <seq>` as sent by the virt server, startup reports carry no sequence number.
+fn untag_status(status: &str) -> (String, Option<u32>) {
+    match status.split_once(' ') {
+        Some((status, seq)) => (status.to_string(), seq.parse().ok()),
+        None => (status.to_string(), None),
+    }
+}
+
+/// Control channel over the node daemon's SysV message queue, the virt server finds it by its rpc_id.
+/// Keeps the untagged commands and 4 byte replies older virt servers understand, so a late reply to a
+/// request that timed out cannot be told apart; flavors that need that use the socket transport.
+pub struct MqueueTransport {
+    message_queue: Arc<MessageQueue>,
+    send_id: i64,
+    recv_id: i64,
+}
+
+impl MqueueTransport {
+    pub fn new(message_queue: Arc<MessageQueue>, rpc_id: u64) -> Self {
+        MqueueTransport {
+            message_queue,
+            send_id: rpc_id as i64,
+            recv_id: (rpc_id as i64) << 32,
+        }
+    }
+
+    /// Virt servers answer requests with a big endian u32 status and report startup with a 128 byte command.
+    fn reply_from_bytes(bytes: &[u8]) -> Result<ControlReply, String> {
+        if let Some(status) = Utils::convert_bytes_to_u32(bytes) {
+            return Ok(ControlReply { status: status.to_string(), body: String::new() });
+        }
+        let reply = MqueueClientControlCommand::try_from_bytes(bytes).ok_or(format!("Invalid reply of {} bytes from virt server", bytes.len()))?;
+        Ok(ControlReply { status: reply.command_str(), body: reply.data_str() })
+    }
+}
+
+impl ControlTransport for MqueueTransport {
+    fn env(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn recv_startup(&self, timeout: Duration) -> Result<Option<ControlReply>, String> {
+        match self.message_queue.recv_type_timed(self.recv_id, timeout) {
+            Ok(bytes) => Self::reply_from_bytes(&bytes).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn request(&self, message: &ControlMessage, timeout: Duration) -> Result<ControlReply, ControlError> {
+        let mqueue_cmd = MqueueClientControlCommand::try_new(&message.command, &message.data)?.as_bytes();
+
+        self.message_queue.send(&mqueue_cmd, self.send_id).map_err(|e| format!("Error sending message to virt server: {}", e))?;
+
+        // the queue only fails a timed receive when nothing arrived in time
+        let recv_bytes = self.message_queue.recv_type_timed(self.recv_id, timeout)
+            .map_err(|e| ControlError::Timeout(format!("No reply from virt server within {:?}: {}", timeout, e)))?;
+
+        Ok(Self::reply_from_bytes(&recv_bytes)?)
+    }
+}
+
+struct SocketConnection {
+    stream: UnixStream,
+    next_seq: u32,
+}
+
+/// Control channel over `<socket_dir>/virt-server-<rpc_id>.sock`. The node daemon listens, the virt server
+/// connects once it is up and sends its startup report as the first frame.
+pub struct SocketTransport {
+    path: PathBuf,
+    listener: UnixListener,
+    connection: Mutex<Option<SocketConnection>>,
+}
+
+impl SocketTransport {
+    pub fn new(socket_dir: &str, rpc_id: u64) -> Result<Self, String> {
+        fs::create_dir_all(socket_dir).map_err(|e| format!("Error creating socket directory {}: {}", socket_dir, e))?;
+
+        let path = PathBuf::from(socket_dir).join(format!("virt-server-{}.sock", rpc_id));
+        // left behind by a virt server with the same rpc_id
+        let _ = fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).map_err(|e| format!("Error binding {:?}: {}", path, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        Ok(SocketTransport {
+            path,
+            listener,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Reads replies until the one to request `seq`, replies to requests that timed out earlier are dropped.
+    fn read_reply(connection: &mut SocketConnection, seq: u32) -> std::io::Result<ControlReply> {
+        loop {
+            let payload = read_frame(&mut connection.stream)?;
+            let mut reply = ControlReply::decode(&payload).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            let (status, reply_seq) = untag_status(&reply.status);
+            if reply_seq != Some(seq) {
+                log::warn!("Dropping stale reply {:?} to request {:?}", reply, reply_seq);
+                continue;
+            }
+            reply.status = status;
+            return Ok(reply);
+        }
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+impl ControlTransport for SocketTransport {
+    fn env(&self) -> Vec<(String, String)> {
+        vec![(CONTROL_SOCKET_ENV.to_string(), self.path.to_string_lossy().to_string())]
+    }
+
+    fn recv_startup(&self, timeout: Duration) -> Result<Option<ControlReply>, String> {
+        let deadline = Instant::now() + timeout;
+        let mut connection = self.connection.lock().unwrap();
+
+        while connection.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+                    *connection = Some(SocketConnection { stream, next_seq: 0 });
+                }
+                Err(e) if is_timeout(&e) => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => return Err(format!("Error accepting virt server connection: {}", e)),
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+        let conn = connection.as_mut().unwrap();
+        conn.stream.set_read_timeout(Some(remaining)).map_err(|e| e.to_string())?;
+
+        match read_frame(&mut conn.stream) {
+            Ok(payload) => ControlReply::decode(&payload).map(Some),
+            Err(e) if is_timeout(&e) => Ok(None),
+            Err(e) => {
+                *connection = None;
+                Err(format!("Error receiving startup report from virt server: {}", e))
+            }
+        }
+    }
+
+    fn request(&self, message: &ControlMessage, timeout: Duration) -> Result<ControlReply, ControlError> {
+        let mut connection = self.connection.lock().unwrap();
+        let conn = connection.as_mut().ok_or("Virt server is not connected")?;
+
+        let seq = conn.next_seq;
+        conn.next_seq = conn.next_seq.wrapping_add(1);
+
+        let tagged = ControlMessage::new(&tag_command(&message.command, seq), &message.data);
+        conn.stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        let result = write_frame(&mut conn.stream, &tagged.encode()).and_then(|_| Self::read_reply(conn, seq));
+
+        match result {
+            Ok(reply) => Ok(reply),
+            Err(e) if is_timeout(&e) => Err(ControlError::Timeout(format!("No reply from virt server within {:?}", timeout))),
+            Err(e) => {
+                *connection = None;
+                Err(ControlError::Failed(format!("Error talking to virt server: {}", e)))
+            }
+        }
+    }
+}
+
+impl Drop for SocketTransport {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mqueue_reply() {
+        let status = MqueueTransport::reply_from_bytes(&500u32.to_be_bytes()).unwrap();
+        assert_eq!(status, ControlReply { status: "500".to_string(), body: String::new() });
+
+        let startup = MqueueClientControlCommand::new("200", "8,1024,12020").as_bytes();
+        assert_eq!(MqueueTransport::reply_from_bytes(&startup).unwrap(), ControlReply { status: "200".to_string(), body: "8,1024,12020".to_string() });
+
+        assert!(MqueueTransport::reply_from_bytes(&[0u8; 3]).is_err());
+        assert!(MqueueClientControlCommand::try_new("SNODE_VIRTS_CHECKPOINT", &"x".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_untag_status() {
+        assert_eq!(tag_command("SNODE_VIRTS_SHUTDOWN", 3), "SNODE_VIRTS_SHUTDOWN 3");
+        assert_eq!(untag_status("200 3"), ("200".to_string(), Some(3)));
+        assert_eq!(untag_status("200"), ("200".to_string(), None));
+    }
+
+    #[test]
+    fn test_socket_transport() {
+        let socket_dir = std::env::temp_dir().join(format!("flyt-transport-test-{}", std::process::id()));
+        let transport = SocketTransport::new(&socket_dir.to_string_lossy(), 7).unwrap();
+        let (_, path) = transport.env().pop().unwrap();
+
+        assert_eq!(transport.recv_startup(Duration::from_millis(50)).unwrap(), None);
+
+        let (late_tx, late_rx) = std::sync::mpsc::channel::<()>();
+
+        // the virt server answers with the sequence number the node daemon appended to the command
+        fn answer(stream: &mut UnixStream, message: &ControlMessage, status: &str, body: &str) {
+            let (_, seq) = message.command.rsplit_once(' ').unwrap();
+            write_frame(stream, &ControlReply { status: format!("{} {}", status, seq), body: body.to_string() }.encode()).unwrap();
+        }
+
+        // stands in for the virt server side of the channel
+        let virt_server = thread::spawn(move || {
+            let mut stream = UnixStream::connect(path).unwrap();
+            write_frame(&mut stream, &ControlReply { status: "200".to_string(), body: "8,1024,12020".to_string() }.encode()).unwrap();
+
+            let message = ControlMessage::decode(&read_frame(&mut stream).unwrap()).unwrap();
+            answer(&mut stream, &message, "200", &message.data.len().to_string());
+
+            // answers only after the node daemon gave up waiting
+            let late = ControlMessage::decode(&read_frame(&mut stream).unwrap()).unwrap();
+            late_rx.recv().unwrap();
+            answer(&mut stream, &late, "200", "late");
+
+            let message = ControlMessage::decode(&read_frame(&mut stream).unwrap()).unwrap();
+            answer(&mut stream, &message, "200", &message.command);
+        });
+
+        let startup = transport.recv_startup(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(startup.body, "8,1024,12020");
+
+        // longer than a message queue command could carry
+        let ckp_path = format!("/checkpoints/{}", "vm".repeat(100));
+        let reply = transport.request(&ControlMessage::new("SNODE_VIRTS_CHECKPOINT", &ckp_path), Duration::from_secs(5)).unwrap();
+        assert!(reply.is_ok());
+        assert_eq!(reply.body, ckp_path.len().to_string());
+
+        let timed_out = transport.request(&ControlMessage::new("SNODE_VIRTS_CHANGE_RESOURCES", "8,1024"), Duration::from_millis(50)).unwrap_err();
+        assert!(timed_out.is_timeout());
+        late_tx.send(()).unwrap();
+
+        // the late reply is skipped
+        let reply = transport.request(&ControlMessage::new("SNODE_VIRTS_SHUTDOWN", ""), Duration::from_secs(5)).unwrap();
+        assert_eq!(reply.status, "200");
+        assert_eq!(reply.body, "SNODE_VIRTS_SHUTDOWN 2");
+
+        virt_server.join().unwrap();
+        assert!(!transport.request(&ControlMessage::new("SNODE_VIRTS_SHUTDOWN", ""), Duration::from_secs(1)).unwrap_err().is_timeout());
+
+        drop(transport);
+        assert!(!socket_dir.join("virt-server-7.sock").exists());
+        let _ = fs::remove_dir_all(&socket_dir);
+    }
+}
@@ -9,6 +9,7 @@
 #include <errno.h>
 #include <fcntl.h>
 #include <sys/stat.h>
+#include <time.h>
 
 #include "cpu-common.h"
 #include "cpu-client-mgr-handler.h"
@@ -16,8 +17,13 @@
 #include "msg-handler.h"
 
 #define CLIENTD_MQUEUE_PATH "/tmp/flyt-client-mgr"
+/* message type of wake up requests, read by the client manager */
+#define CLIENTD_WAKE_REQUEST_TYPE 2
+/* seconds a call waits for a hibernated VM to wake before it requests the wake up again */
+#define WAKE_RETRY_SECS 30
 
 const char* CLIENTD_VCUDA_PAUSE = "CLIENTD_VCUDA_PAUSE";
+const char* CLIENTD_VCUDA_HIBERNATE = "CLIENTD_VCUDA_HIBERNATE";
 const char* CLIENTD_VCUDA_CHANGE_VIRT_SERVER = "CLIENTD_VCUDA_CHANGE_VIRT_SERVER";
 const char* CLIENTD_VCUDA_RESUME = "CLIENTD_VCUDA_RESUME";
 const char* PING = "PING";
@@ -26,6 +32,10 @@ const char* PING = "PING";
 
 static pthread_t handler_thread;
 static volatile uint8_t keep_handler_alive = 1;
+static int clientd_mqueue = -1;
+/* set while the virt server is checkpointed to disk and freed */
+static volatile int hibernated = 0;
+static volatile int wake_requested = 0;
 
 
 static void* cpu_client_mgr_handler(void* arg) {
@@ -56,7 +66,17 @@ static void* cpu_client_mgr_handler(void* arg) {
             resp.data = htonl(200);
             msgsnd(clientd_mqueue_id, &resp, sizeof(resp.data), 0);
         }
+        else if (strncmp(msg.msg.cmd, CLIENTD_VCUDA_HIBERNATE, 64) == 0) {
+            pthread_rwlock_wrlock(&access_sem);
+            wake_requested = 0;
+            hibernated = 1;
+            struct msgbuf_uint32 resp;
+            resp.mtype = send_type;
+            resp.data = htonl(200);
+            msgsnd(clientd_mqueue_id, &resp, sizeof(resp.data), 0);
+        }
         else if (strncmp(msg.msg.cmd, CLIENTD_VCUDA_RESUME, 64) == 0) {
+            hibernated = 0;
             resume_connection();
             pthread_rwlock_unlock(&access_sem);
             struct msgbuf_uint32 resp;
@@ -102,6 +122,44 @@ char* get_virt_server_info(int mqueue_id, uint64_t recv_type) {
 }
 
 
+static void request_wakeup(void) {
+    if (!hibernated || !__sync_bool_compare_and_swap(&wake_requested, 0, 1)) {
+        return;
+    }
+
+    LOGE(LOG_DEBUG, "CUDA call while hibernated, requesting wake up");
+    struct msgbuf_uint32 msg;
+    msg.mtype = CLIENTD_WAKE_REQUEST_TYPE;
+    msg.data = htonl(getpid());
+    if (msgsnd(clientd_mqueue, &msg, sizeof(msg.data), 0) == -1) {
+        LOGE(LOG_ERROR, "Error requesting wake up from client manager: %s", strerror(errno));
+        wake_requested = 0;
+    }
+}
+
+/*
+ * Takes the read lock, asking the client manager to wake a hibernated VM. The request is sent
+ * again every WAKE_RETRY_SECS while the VM stays hibernated, in case it was lost, e.g. to a
+ * restarted client manager.
+ */
+void wait_for_access(void) {
+    while (1) {
+        request_wakeup();
+
+        struct timespec deadline;
+        clock_gettime(CLOCK_REALTIME, &deadline);
+        deadline.tv_sec += WAKE_RETRY_SECS;
+        if (pthread_rwlock_timedrdlock(&access_sem, &deadline) == 0) {
+            return;
+        }
+
+        if (hibernated) {
+            LOGE(LOG_WARNING, "Still hibernated after %d seconds, requesting wake up again", WAKE_RETRY_SECS);
+            wake_requested = 0;
+        }
+    }
+}
+
 void init_handler_thread(int clientd_mqueue_id) {
     pthread_create(&handler_thread, NULL, cpu_client_mgr_handler, (void*)((long)clientd_mqueue_id));
 }
@@ -148,6 +206,7 @@ char* init_client_mgr() {
         return NULL;
     }
 
+    clientd_mqueue = clientd_mqueue_id;
     init_handler_thread(clientd_mqueue_id);
 
     return virt_server_info;
@@ -35,8 +35,13 @@ list kernel_infos;
 // semaphore
 pthread_rwlock_t access_sem;
 
+void wait_for_access(void); // in cpu-client-mgr-handler.c
+
+/* a call that finds the client manager holding the lock may have to wake a hibernated VM */
 #define FUNC_BEGIN \
-    pthread_rwlock_rdlock(&access_sem);
+    if (pthread_rwlock_tryrdlock(&access_sem) != 0) { \
+        wait_for_access(); \
+    }
 
 #define FUNC_END \
     pthread_rwlock_unlock(&access_sem);
@@ -7,6 +7,9 @@
 #include <errno.h>
 #include <sys/stat.h>
 #include <sys/types.h>
+#include <sys/socket.h>
+#include <sys/un.h>
+#include <arpa/inet.h>
 
 #include "msg-handler.h"
 #include "log.h"
@@ -14,25 +17,31 @@
 #include "cpu-server-resource-controller.h"
 #include "flyt-cr.h"
 #include <signal.h>
+#include <cuda_runtime_api.h>
 
 #define SNODE_MQUEUE_PATH "/tmp/flyt-servernode-queue"
 #define PROJ_ID 0x42
+#define CONTROL_SOCKET_ENV "FLYT_CONTROL_SOCKET"
+#define CONTROL_FRAME_MAX_LEN (64 * 1024)
 
 const char* SNODE_VIRTS_CHANGE_RESOURCES = "SNODE_VIRTS_CHANGE_RESOURCES";
 const char* SNODE_VIRTS_CHECKPOINT = "SNODE_VIRTS_CHECKPOINT";
 const char* SNODE_VIRTS_RESTORE = "SNODE_VIRTS_RESTORE";
+const char* SNODE_VIRTS_SHUTDOWN = "SNODE_VIRTS_SHUTDOWN";
 
 pthread_t handler_thread;
 
 static volatile uint64_t recv_type = 0;
 static volatile uint64_t send_type = 0;
 static volatile int snode_mqueue_id = -1;
+static volatile int control_fd = -1;
 
 
-static void resource_change_handler(const char* data, uint32_t *response) {
+static void resource_change_handler(const char* data, uint32_t *response, char *error, size_t error_len) {
     splitted_str *split_str = split_string(data, ",");
     if (split_str->size != 2) {
         LOGE(LOG_ERROR, "Invalid message received from client manager: %s", data);
+        snprintf(error, error_len, "Invalid resources: %s", data);
         *response = htonl(400);
         free_splitted_str(split_str);
         return;
@@ -46,6 +55,7 @@ static void resource_change_handler(const char* data, uint32_t *response) {
     // This will only set the new configuration if the new configuration is valid
     // Actual change will be done when the next cuda call is made
     if (set_new_config(new_num_sm_cores, new_mem) != 0) {
+        snprintf(error, error_len, "Unable to apply %u SM cores and %" PRIu64 " bytes of memory", new_num_sm_cores, new_mem);
         *response = htonl(400);
     }
 
@@ -54,6 +64,67 @@ static void resource_change_handler(const char* data, uint32_t *response) {
 }
 
 
+/*
+ * Runs a command from the node manager and returns its status code. On failure the
+ * reason is written to error, which is left empty otherwise. Sets *stop when the
+ * server should exit once the reply is sent.
+ */
+static uint32_t handle_command(const char *cmd, const char *data, int *stop, char *error, size_t error_len) {
+    LOGE(LOG_INFO, "Received message from node manager: %s", cmd);
+    *stop = 0;
+    error[0] = '\0';
+
+    if (strcmp(cmd, SNODE_VIRTS_CHANGE_RESOURCES) == 0) {
+        uint32_t rsp;
+        resource_change_handler(data, &rsp, error, error_len);
+        return ntohl(rsp);
+    }
+
+    if (strcmp(cmd, SNODE_VIRTS_CHECKPOINT) == 0) {
+        if (flyt_create_checkpoint(data) != 0) {
+            snprintf(error, error_len, "Checkpoint to %s failed", data);
+            return 500;
+        }
+        return 200;
+    }
+
+    if (strcmp(cmd, SNODE_VIRTS_RESTORE) == 0) {
+        if (flyt_restore_checkpoint(data) != 0) {
+            snprintf(error, error_len, "Restore from %s failed", data);
+            return 500;
+        }
+        return 200;
+    }
+
+    if (strcmp(cmd, SNODE_VIRTS_SHUTDOWN) == 0) {
+        *stop = 1;
+        return 200;
+    }
+
+    LOGE(LOG_ERROR, "Unknown message received from node manager: %s", cmd);
+    snprintf(error, error_len, "Unknown command %s", cmd);
+    return 400;
+}
+
+/*
+ * The node manager appends " <seq>" to every command on the control socket and drops replies that do not echo it,
+ * so a late reply to a timed out request is not taken for the answer to the next one.
+ * Strips the sequence number from cmd and returns it.
+ */
+static uint32_t split_seq(char *cmd) {
+    char *sep = strrchr(cmd, ' ');
+    if (sep == NULL) {
+        return 0;
+    }
+    *sep = '\0';
+    return (uint32_t)strtoul(sep + 1, NULL, 10);
+}
+
+static void shutdown_server() {
+    // same path as SIGINT: svc_run returns after the request in progress and the server cleans up
+    kill(getpid(), SIGINT);
+}
+
 static void *snode_msg_handler(void *arg) {
 
     struct msgbuf msg;
@@ -61,73 +132,212 @@ static void *snode_msg_handler(void *arg) {
     while (1) {
         if (msgrcv(snode_mqueue_id, &msg, sizeof(mqueue_msg), recv_type, 0) == -1) {
             LOGE(LOG_ERROR, "Error receiving message from node manager: %s", strerror(errno));
+            continue;
         }
 
-        if (strncmp(msg.msg.cmd, SNODE_VIRTS_CHANGE_RESOURCES, strlen(SNODE_VIRTS_CHANGE_RESOURCES)) == 0) {
-            LOGE(LOG_INFO, "Received message from node manager: %s", msg.msg.cmd);
-            
-            struct msgbuf_uint32 rsp;
-            resource_change_handler(msg.msg.data, &rsp.data);
-            
-            rsp.mtype = send_type;
-            if (msgsnd(snode_mqueue_id, &rsp, sizeof(uint32_t), 0) == -1) {
-                LOGE(LOG_ERROR, "Error sending response to node manager: %s", strerror(errno));
-            }
+        // the fields are not NUL terminated when they are used up to the last byte
+        char cmd[sizeof(msg.msg.cmd) + 1] = {0};
+        char data[sizeof(msg.msg.data) + 1] = {0};
+        memcpy(cmd, msg.msg.cmd, sizeof(msg.msg.cmd));
+        memcpy(data, msg.msg.data, sizeof(msg.msg.data));
+
+        // replies on the message queue carry only the status
+        int stop;
+        char error[256];
+        struct msgbuf_uint32 rsp;
+        rsp.mtype = send_type;
+        rsp.data = htonl(handle_command(cmd, data, &stop, error, sizeof(error)));
+
+        if (msgsnd(snode_mqueue_id, &rsp, sizeof(uint32_t), 0) == -1) {
+            LOGE(LOG_ERROR, "Error sending response to node manager: %s", strerror(errno));
+        }
 
-        } 
-        
-        else if (strncmp(msg.msg.cmd, SNODE_VIRTS_CHECKPOINT, strlen(SNODE_VIRTS_CHECKPOINT)) == 0) {
-            LOGE(LOG_INFO, "Received message from node manager: %s", msg.msg.cmd);
-            
-            struct msgbuf_uint32 rsp;
-            
-            int ret = flyt_create_checkpoint(msg.msg.data);
+        if (stop) {
+            shutdown_server();
+        }
+    }
+    return NULL;
+}
 
-            rsp.mtype = send_type;
-            rsp.data = ret == 0 ? htonl(200) : htonl(500);
+/*
+ * Frames on the control socket are a big endian uint32 length followed by the payload,
+ * "<command> <seq>\n<data>" from the node manager and "<status> <seq>\n<body>" back, startup
+ * reports are sent as "<status>\n<data>".
+ */
+static int read_full(int fd, void *buf, size_t len) {
+    size_t done = 0;
+    while (done < len) {
+        ssize_t n = read(fd, (char *)buf + done, len - done);
+        if (n == 0 || (n == -1 && errno != EINTR)) {
+            return -1;
+        }
+        if (n > 0) {
+            done += n;
+        }
+    }
+    return 0;
+}
 
-            if (msgsnd(snode_mqueue_id, &rsp, sizeof(uint32_t), 0) == -1) {
-                LOGE(LOG_ERROR, "Error sending response to node manager: %s", strerror(errno));
-            }
+static int write_full(int fd, const void *buf, size_t len) {
+    size_t done = 0;
+    while (done < len) {
+        ssize_t n = write(fd, (const char *)buf + done, len - done);
+        if (n == -1 && errno != EINTR) {
+            return -1;
+        }
+        if (n > 0) {
+            done += n;
         }
+    }
+    return 0;
+}
+
+static int send_frame(const char *status, const char *body) {
+    size_t len = strlen(status) + 1 + strlen(body);
+    if (len > CONTROL_FRAME_MAX_LEN) {
+        return -1;
+    }
 
-        else if (strncmp(msg.msg.cmd, SNODE_VIRTS_RESTORE, strlen(SNODE_VIRTS_RESTORE)) == 0) {
-            LOGE(LOG_INFO, "Received message from node manager: %s", msg.msg.cmd);
-            
-            struct msgbuf_uint32 rsp;
-            
-            int ret = flyt_restore_checkpoint(msg.msg.data);
+    char *payload = malloc(len + 1);
+    if (payload == NULL) {
+        return -1;
+    }
+    snprintf(payload, len + 1, "%s\n%s", status, body);
 
-            rsp.mtype = send_type;
-            rsp.data = ret == 0 ? htonl(200) : htonl(500);
+    uint32_t len_be = htonl((uint32_t)len);
+    int ret = write_full(control_fd, &len_be, sizeof(len_be)) == 0 && write_full(control_fd, payload, len) == 0 ? 0 : -1;
+    free(payload);
+    return ret;
+}
 
-            if (msgsnd(snode_mqueue_id, &rsp, sizeof(uint32_t), 0) == -1) {
-                LOGE(LOG_ERROR, "Error sending response to node manager: %s", strerror(errno));
-            }
+static void *control_socket_handler(void *arg) {
+
+    while (1) {
+        uint32_t len_be;
+        if (read_full(control_fd, &len_be, sizeof(len_be)) == -1) {
+            LOGE(LOG_ERROR, "Control socket to node manager closed");
+            return NULL;
+        }
+
+        uint32_t len = ntohl(len_be);
+        if (len > CONTROL_FRAME_MAX_LEN) {
+            LOGE(LOG_ERROR, "Control frame of %u bytes from node manager is too large", len);
+            return NULL;
+        }
+
+        char *payload = calloc(len + 1, 1);
+        if (payload == NULL || read_full(control_fd, payload, len) == -1) {
+            LOGE(LOG_ERROR, "Error receiving message from node manager: %s", strerror(errno));
+            free(payload);
+            return NULL;
+        }
+
+        char *data = strchr(payload, '\n');
+        if (data != NULL) {
+            *data++ = '\0';
+        } else {
+            data = payload + len;
+        }
+
+        int stop;
+        char status[32];
+        char error[256];
+        uint32_t seq = split_seq(payload);
+        snprintf(status, sizeof(status), "%u %u", handle_command(payload, data, &stop, error, sizeof(error)), seq);
+        free(payload);
+
+        if (send_frame(status, error) == -1) {
+            LOGE(LOG_ERROR, "Error sending response to node manager: %s", strerror(errno));
         }
-        
-        else {
-            LOGE(LOG_ERROR, "Unknown message received from node manager: %s", msg.msg.cmd);
+
+        if (stop) {
+            shutdown_server();
         }
     }
     return NULL;
 }
 
-void send_initialised_msg() {
-    struct msgbuf_uint32 msg;
+static void send_startup_msg(const char *status, const char *data) {
+    if (control_fd != -1) {
+        if (send_frame(status, data) == -1) {
+            LOGE(LOG_ERROR, "Error sending startup message to node manager: %s", strerror(errno));
+        }
+        return;
+    }
+
+    struct msgbuf msg;
     msg.mtype = send_type;
-    msg.data = htonl(200);
-    if (msgsnd(snode_mqueue_id, &msg, sizeof(uint32_t), 0) == -1) {
-        LOGE(LOG_ERROR, "Error sending initialisation message to node manager: %s", strerror(errno));
+    memset(&msg.msg, 0, sizeof(mqueue_msg));
+    strncpy(msg.msg.cmd, status, sizeof(msg.msg.cmd) - 1);
+    strncpy(msg.msg.data, data, sizeof(msg.msg.data) - 1);
+    if (msgsnd(snode_mqueue_id, &msg, sizeof(mqueue_msg), 0) == -1) {
+        LOGE(LOG_ERROR, "Error sending startup message to node manager: %s", strerror(errno));
     }
 }
 
+/*
+ * Reports the limits that were actually applied: cmd "200", data "<sm_cores>,<memory>,<cuda_version>"
+ */
+void send_initialised_msg() {
+    char data[64];
+    int cuda_version = 0;
+    cudaRuntimeGetVersion(&cuda_version);
+    snprintf(data, sizeof(data), "%u,%" PRIu64 ",%d", get_sm_cores(), get_mem_limit(), cuda_version);
+    send_startup_msg("200", data);
+}
+
+/*
+ * cmd is one of the STARTUP_ERR_* codes, data a short human readable reason
+ */
+void send_startup_error(const char *code, const char *reason) {
+    if (snode_mqueue_id == -1 && control_fd == -1) {
+        return;
+    }
+    send_startup_msg(code, reason);
+}
+
+/*
+ * With FLYT_CONTROL_SOCKET set the node manager listens on a Unix socket for this server
+ * instead of using the shared message queue.
+ */
+static int init_control_socket(const char *path) {
+    struct sockaddr_un addr;
+    memset(&addr, 0, sizeof(addr));
+    addr.sun_family = AF_UNIX;
+    if (strlen(path) >= sizeof(addr.sun_path)) {
+        LOGE(LOG_ERROR, "Control socket path is too long: %s", path);
+        return -1;
+    }
+    strncpy(addr.sun_path, path, sizeof(addr.sun_path) - 1);
+
+    int fd = socket(AF_UNIX, SOCK_STREAM, 0);
+    if (fd == -1) {
+        LOGE(LOG_ERROR, "Error creating control socket: %s", strerror(errno));
+        return -1;
+    }
+
+    if (connect(fd, (struct sockaddr *)&addr, sizeof(addr)) == -1) {
+        LOGE(LOG_ERROR, "Error connecting to node manager at %s: %s", path, strerror(errno));
+        close(fd);
+        return -1;
+    }
+
+    control_fd = fd;
+    pthread_create(&handler_thread, NULL, control_socket_handler, NULL);
+    return 0;
+}
+
 int init_listener(int rpc_id)
 {
     recv_type = (uint64_t)rpc_id;
     send_type = ((uint64_t)rpc_id) << 32;
     key_t key;
 
+    const char *control_socket = getenv(CONTROL_SOCKET_ENV);
+    if (control_socket != NULL && control_socket[0] != '\0') {
+        return init_control_socket(control_socket);
+    }
+
     if (access(SNODE_MQUEUE_PATH, F_OK) == -1) {
         if (mkdir(SNODE_MQUEUE_PATH, 0777) == -1) {
             LOGE(LOG_ERROR, "Error creating directory for node manager message queue: %s", strerror(errno));
@@ -146,4 +356,5 @@ int init_listener(int rpc_id)
     }
 
     pthread_create(&handler_thread, NULL, snode_msg_handler, NULL);
+    return 0;
 }
\ No newline at end of file
@@ -1,7 +1,13 @@
 #ifndef __CPU_SERVER_MGR_LISTENER_H__
 #define __CPU_SERVER_MGR_LISTENER_H__
 
+/* startup error codes reported to the node manager */
+#define STARTUP_ERR_INIT "E_INIT"
+#define STARTUP_ERR_GPU "E_GPU"
+#define STARTUP_ERR_RESOURCES "E_RESOURCES"
+
 int init_listener(int rpc_id);
 void send_initialised_msg(void); 
+void send_startup_error(const char *code, const char *reason);
 
 #endif // __CPU_SERVER_MGR_LISTENER_H__
\ No newline at end of file
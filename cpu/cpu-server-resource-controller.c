@@ -17,6 +17,8 @@
 static int active_device = -1;
 static uint64_t mem_limit = 0;
 static uint64_t current_mm_usage = 0;
+static uint32_t sm_cores = 0;
+static const char *last_error = "";
 
 static volatile uint32_t new_num_sm_cores = 0;
 static volatile uint64_t new_mem = 0;
@@ -64,6 +66,7 @@ int change_sm_cores(uint32_t nm_sm_cores) {
         const char *errStr;
         cuGetErrorString(res1, &errStr);
         LOGE(LOG_ERROR, "Failed to get current context: %s", errStr);
+        last_error = errStr;
         return -1;
     }
 
@@ -80,8 +83,18 @@ int change_sm_cores(uint32_t nm_sm_cores) {
         const char *errStr;
         cuGetErrorString(res2, &errStr);
         LOGE(LOG_ERROR, "Failed to create new context: %s", errStr);
+        // an SM count affinity needs the MPS control daemon
+        last_error = res2 == CUDA_ERROR_UNSUPPORTED_EXEC_AFFINITY ? "MPS is not running" : errStr;
         return -1;
     }
+
+    // the driver may round the requested SM count
+    CUexecAffinityParam applied_param;
+    if (cuCtxGetExecAffinity(&applied_param, CU_EXEC_AFFINITY_TYPE_SM_COUNT) == CUDA_SUCCESS) {
+        sm_cores = applied_param.param.smCount.val;
+    } else {
+        sm_cores = nm_sm_cores;
+    }
     
     int ret = server_driver_ctx_state_restore(currentContext);
 
@@ -118,6 +131,14 @@ uint64_t get_mem_limit() {
     return mem_limit;
 }
 
+uint32_t get_sm_cores() {
+    return sm_cores;
+}
+
+const char *get_resource_controller_error() {
+    return last_error;
+}
+
 int allow_mem_alloc(uint64_t size) {
     size_t gpu_memusage = get_gpu_memory_usage();
     LOGE(LOG_DEBUG, "Current memory usage: %zu, mem_limit: %llu", gpu_memusage, mem_limit);
@@ -199,6 +220,7 @@ int init_resource_controller(uint32_t num_sm_cores, uint64_t mem) {
         const char *errStr;
         cuGetErrorString(res, &errStr);
         LOGE(LOG_ERROR, "Failed to get primary context: %s", errStr);
+        last_error = errStr;
         return -1;
     }
 
@@ -13,6 +13,10 @@ int set_mem_limit(uint64_t limit);
 
 uint64_t get_mem_limit();
 
+uint32_t get_sm_cores();
+
+const char *get_resource_controller_error();
+
 void inc_mem_usage(uint64_t size);
 
 void dec_mem_usage(uint64_t size);
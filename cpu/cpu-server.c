@@ -8,6 +8,7 @@
 #include <sys/stat.h>
 #include <dlfcn.h>
 #include <link.h>
+#include <cuda_runtime_api.h>
 
 #include "cpu-server.h"
 #include "cpu_rpc_prot.h"
@@ -210,6 +211,7 @@ void cricket_main(size_t prog_num, size_t vers_num, uint32_t gpu_id, uint32_t nu
     init_log(LOG_LEVEL, __FILE__);
     LOG(LOG_DBG(1), "log level is %d", LOG_LEVEL);
     sigaction(SIGINT, &act, NULL);
+    sigaction(SIGTERM, &act, NULL);
 
     #ifdef WITH_IB
     char client[256];
@@ -304,11 +306,17 @@ void cricket_main(size_t prog_num, size_t vers_num, uint32_t gpu_id, uint32_t nu
     //     cudaRegisterAllv();
     // }
 
+    // listen early so that initialisation failures can be reported to the node manager
+    if (init_listener(vers) != 0) {
+        LOGE(LOG_ERROR, "initializing node manager listener failed.");
+    }
+
     sched = &sched_fixed;
     set_active_device(gpu_id);
 
     if (sched->init() != 0) {
         LOGE(LOG_ERROR, "initializing scheduler failed.");
+        send_startup_error(STARTUP_ERR_GPU, "scheduler initialisation failed");
         goto cleanup4;
     }
 
@@ -316,26 +324,31 @@ void cricket_main(size_t prog_num, size_t vers_num, uint32_t gpu_id, uint32_t nu
 
     if (list_init(&api_records, sizeof(api_record_t)) != 0) {
         LOGE(LOG_ERROR, "initializing api recorder failed.");
+        send_startup_error(STARTUP_ERR_INIT, "api recorder initialisation failed");
         goto cleanup4;
     }
 
     if (server_runtime_init(restore, gpu_id) != 0) {
         LOGE(LOG_ERROR, "initializing server_runtime failed.");
+        send_startup_error(STARTUP_ERR_GPU, cudaGetErrorString(cudaPeekAtLastError()));
         goto cleanup3;
     }
 
     if (server_driver_init(restore) != 0) {
         LOGE(LOG_ERROR, "initializing server_runtime failed.");
+        send_startup_error(STARTUP_ERR_GPU, "driver api initialisation failed");
         goto cleanup2;        
     }
     
     if (server_nvml_init(restore) != 0) {
         LOGE(LOG_ERROR, "initializing server_nvml failed.");
+        send_startup_error(STARTUP_ERR_GPU, "nvml initialisation failed");
         goto cleanup1;
     }
 
     if (server_cudnn_init(restore) != 0) {
         LOGE(LOG_ERROR, "initializing server_nvml failed.");
+        send_startup_error(STARTUP_ERR_INIT, "cudnn initialisation failed");
         goto cleanup0;
     }
 
@@ -355,14 +368,15 @@ void cricket_main(size_t prog_num, size_t vers_num, uint32_t gpu_id, uint32_t nu
     // }
 
 
-    init_listener(vers);
     if (init_resource_controller(num_sm_cores, memory) != 0) {
         LOGE(LOG_ERROR, "initializing resource controller failed.");
+        send_startup_error(STARTUP_ERR_RESOURCES, get_resource_controller_error());
         goto cleanup00;
     }
 
     if (init_cpu_server_client_mgr() != 0) {
         LOGE(LOG_ERROR, "initializing client manager failed.");
+        send_startup_error(STARTUP_ERR_INIT, "client manager initialisation failed");
         goto cleanup00;
     }
 